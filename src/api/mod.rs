@@ -1,11 +1,26 @@
 use std::fs;
-use std::path::PathBuf;
-use std::str::FromStr;
-use rocket::{get, http::Status, post};
-use rocket::data::Data;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use rocket::{get, http::Status, post, FromForm, State};
+use rocket::data::{Data, ToByteUnit};
 use rocket::http::ContentType;
+use rocket::response::status::Accepted;
+use rocket::serde::json::Json;
 use rocket_multipart_form_data::{MultipartFormData, MultipartFormDataField, MultipartFormDataOptions};
 use serde::{Deserialize, Serialize};
+use tracing::{debug, error, field, info, info_span, instrument, warn, Instrument, Span};
+
+use crate::audit::{to_csv, to_jsonl, Actor, AuditEntry, AuditLog, AuditQuery, AuditReader, Verification};
+use crate::autoscalar::forecast::{Forecast, ForecastError, MetricHistory};
+use crate::autoscalar::health::{HealthMonitor, Transition};
+use crate::autoscalar::ingest::{parse_exposition, IngestError, MetricsIngest, Push};
+use crate::autoscalar::policy::Metric;
+use crate::image_builder::{app_workspace, is_valid_app_id};
+use crate::interfaces::director::LoadProbe;
+use crate::queue::{BuildJob, JobQueue, NewJob};
+use crate::scheduler::{BuildRequest, BuildScheduler, BuildStatus, Decision, DecisionQuery, Priority};
+use crate::telemetry::logging::RequestSpan;
+use crate::telemetry::METRICS;
 
 #[derive(Debug,Serialize,Deserialize)]
 pub struct DeployPermissions {
@@ -22,11 +37,21 @@ pub fn deploy_permissions() -> Result<rocket::serde::json::Json<DeployPermission
     Ok(rocket::serde::json::Json(DeployPermissions::default()))
 }
 
-#[post("/app/<app_id>/build", data = "<data>")]
-pub async fn build<'a>(app_id: String, content_type: &ContentType, data: Data<'a>) -> Result<Status,Status> {
-    println!("Starting deploy handler");
-    println!("Content-Type: {:?}", content_type);
-    println!("Build started for app: {:#?}", app_id);
+#[post("/app/<app_id>/build?<priority>&<tenant>", data = "<data>")]
+#[instrument(parent = &span.0, skip_all, fields(app_id = %app_id, build_id = field::Empty))]
+#[allow(clippy::too_many_arguments)] // Rocket hands over every guard as an argument
+pub async fn build<'a>(app_id: String, priority: Option<String>, tenant: Option<String>, actor: Actor, content_type: &ContentType, data: Data<'a>, scheduler: &State<Arc<BuildScheduler>>, span: RequestSpan) -> Result<Accepted<Json<BuildStatus>>,Status> {
+    if !is_valid_app_id(&app_id) {
+        return Err(Status::new(400));
+    }
+    let priority: Priority = match priority.map(|p| p.parse()).transpose() {
+        Ok(priority) => priority.unwrap_or_default(),
+        Err(e) => {
+            warn!("{}", e);
+            return Err(Status::new(400));
+        }
+    };
+    debug!(content_type = %content_type, "receiving upload");
 
     let mut options = MultipartFormDataOptions::new();
 
@@ -43,83 +68,518 @@ pub async fn build<'a>(app_id: String, content_type: &ContentType, data: Data<'a
             
     // Parse form data with detailed error handling
     let form_data = match MultipartFormData::parse(content_type, data, options).await {
-        Ok(form) => {
-            println!("Successfully parsed form data");
-            form
-        }
+        Ok(form) => form,
         Err(e) => {
-            println!("Error parsing form data: {:?}", e);
+            warn!("Error parsing form data: {}", e);
             return Err(Status::new(400))
         }
     };
 
-    // Print ALL available fields for debugging
-    println!("Available fields in form_data:");
-    println!("Raw fields: {:#?}", form_data.raw);
-    println!("Text fields: {:#?}", form_data.texts);
-    println!("Files: {:#?}", form_data.files);
-
-    // Check each possible file field
+    // Check each possible file field. Only the field and size are logged, never names or contents.
     for field_name in ["media", "file", "upload"] {
         if let Some(files) = form_data.files.get(field_name) {
-            println!("Found files in field '{}': {:?}", field_name, files);
-
             if let Some(file) = files.first() {
-                println!("Processing file:");
-                println!("    Path: {:?}", file.path);
-                println!("    Filename: {:?}", file.file_name);
-                println!("    Content-Type: {:?}", file.content_type);
+                debug!(field = field_name, "found upload");
 
-                // Create App directory
-                match fs::create_dir_all("./App") {
+                // Create the app's working directory, builds for one app never run concurrently
+                let app_dir = PathBuf::from("./App").join(&app_id);
+                match fs::create_dir_all(&app_dir) {
                     Ok(_) => {
-                        let dir = std::path::PathBuf::from_str("./App").unwrap();
-                        let canon_dir = dir.canonicalize().unwrap();
-                        log::info!("Created Directory at {}",canon_dir.display())
+                        let canon_dir = app_dir.canonicalize().unwrap();
+                        debug!("Created Directory at {}",canon_dir.display())
                     },
                     Err(_) => {
-                        return Err::<Status,Status>(Status::new(500));
+                        return Err(Status::new(500));
                     },
                 }
-                    
-    
-                // Copy file with size verification
+
+                // Copy file with size verification, the upload's temp file is gone once we return
                 let source_size = fs::metadata(&file.path)
-                    .map_err(|_| return Err::<Status,Status>(Status::new(500))).unwrap()
+                    .map_err(|_| Status::new(500))?
                     .len();
 
-                println!("Source file size: {} bytes", source_size);
+                let build_id = scheduler.submit(BuildRequest {
+                    tenant: tenant.clone(),
+                    priority,
+                    actor: Some(actor.0.clone()),
+                    ..BuildRequest::new(&app_id)
+                });
+                Span::current().record("build_id", build_id.as_str());
+                info!(bytes = source_size, "upload received");
+                let archive_path = app_dir.join(format!("{}.tar.gz", build_id));
 
-                match fs::copy(&file.path, "./App/app.tar.gz") {
+                match fs::copy(&file.path, &archive_path) {
+                    Ok(bytes_written) if bytes_written == source_size => {}
                     Ok(bytes_written) => {
-                        println!("Successfully wrote {} bytes", bytes_written);
-                        if bytes_written == source_size {
-                            let file_path = PathBuf::from_str("./App/app.tar.gz")
-                                .expect("Failed to get app zip");
-                            let tar_gz = fs::File::open(&file_path).expect("Failed to open tar");
-                            let tar = flate2::read::GzDecoder::new(tar_gz);
-                            let mut archive = tar::Archive::new(tar);
-
-                            archive.unpack("./App").unwrap();
-
-                            // Clean up the tar.gz file
-                            fs::remove_file(&file_path).expect("Fail");
-                            return Ok(Status::new(200));
-                                
-                        } else {
-                            return Err(Status::new(500))
-                        }
+                        error!("Short write: {} of {} bytes", bytes_written, source_size);
+                        scheduler.cancel(&build_id, "upload could not be stored");
+                        return Err(Status::new(500))
                     }
                     Err(e) => {
-                        println!("Error copying file: {:?}", e);
+                        error!("Error copying upload: {}", e);
+                        scheduler.cancel(&build_id, "upload could not be stored");
                         return Err(Status::new(500))
                     }
                 }
+
+                let task_scheduler = scheduler.inner().clone();
+                let task_build_id = build_id.clone();
+                let workspace = app_workspace(&app_id);
+                let span = info_span!(parent: None, "build", app_id = %app_id, build_id = %build_id);
+                tokio::spawn(async move {
+                    let permit = match task_scheduler.acquire(&task_build_id).await {
+                        Ok(permit) => permit,
+                        Err(e) => {
+                            info!("{}", e);
+                            let _ = fs::remove_file(&archive_path);
+                            return;
+                        }
+                    };
+                    info!("build started");
+                    let build_span = Span::current();
+                    let result = tokio::task::spawn_blocking(move || build_span.in_scope(|| unpack_and_build(&archive_path, &workspace)))
+                        .await
+                        .unwrap_or_else(|e| Err(anyhow::anyhow!("build task panicked: {}", e)));
+                    match &result {
+                        Ok(()) => info!("build succeeded"),
+                        Err(e) => error!("build failed: {:#}", e),
+                    }
+                    permit.finish(&result);
+                }.instrument(span));
+
+                let status = scheduler.status(&build_id).ok_or(Status::new(500))?;
+                return Ok(Accepted(Json(status)));
             } else {
-                println!("No valid file found in request");
+                warn!("No valid file found in request");
                 return Err(Status::new(500))
             }
         }
     }
-    return Ok::<Status,Status>(Status::new(200));
-}
\ No newline at end of file
+    Err(Status::new(400))
+}
+
+#[get("/app/<app_id>/build/<build_id>")]
+pub fn build_status(app_id: String, build_id: String, scheduler: &State<Arc<BuildScheduler>>) -> Result<Json<BuildStatus>,Status> {
+    match scheduler.status(&build_id) {
+        Some(status) if status.app_id == app_id => Ok(Json(status)),
+        _ => Err(Status::NotFound),
+    }
+}
+
+#[get("/app/<app_id>/builds")]
+pub fn active_builds(app_id: String, scheduler: &State<Arc<BuildScheduler>>) -> Json<Vec<BuildStatus>> {
+    Json(scheduler.active_for_app(&app_id))
+}
+
+/// Explains scheduling: every queue, wait, start and cancel decision, filterable by build, app or tenant
+#[get("/scheduler/decisions?<build_id>&<app_id>&<tenant>&<limit>")]
+pub fn scheduler_decisions(build_id: Option<String>, app_id: Option<String>, tenant: Option<String>, limit: Option<usize>, scheduler: &State<Arc<BuildScheduler>>) -> Json<Vec<Decision>> {
+    Json(scheduler.decisions(&DecisionQuery { build_id, app_id, tenant, limit }))
+}
+
+/// Forecast demand for one of the app's metrics and the model's error on recent history,
+/// to judge whether predictive scaling can be trusted
+#[get("/app/<app_id>/forecast?<metric>&<hours>")]
+#[instrument(parent = &span.0, skip_all, fields(app_id = %app_id))]
+pub fn app_forecast(app_id: String, metric: Option<String>, hours: Option<u32>, history: &State<Arc<MetricHistory>>, span: RequestSpan) -> Result<Json<Forecast>,Status> {
+    if !is_valid_app_id(&app_id) {
+        return Err(Status::new(400));
+    }
+    let metric = match metric.as_deref().unwrap_or("cpu_load").parse::<Metric>() {
+        Ok(metric) => metric,
+        Err(_) => return Err(Status::new(400)),
+    };
+    // A week ahead at most, the model has nothing to say past its weekly season
+    let hours = hours.unwrap_or(24).clamp(1, 24 * 7);
+    match history.forecast(&app_id, metric, chrono::Utc::now(), hours) {
+        Ok(forecast) => Ok(Json(forecast)),
+        Err(ForecastError::NotEnoughHistory { .. }) => Err(Status::NotFound),
+        Err(e) => {
+            error!("Failed to forecast {:?}: {}", metric, e);
+            Err(Status::new(500))
+        }
+    }
+}
+
+/// Takes app-reported metrics for the autoscaler: a JSON push, or the Prometheus text format
+/// with the instance named in the query
+#[post("/app/<app_id>/metrics?<instance>", data = "<data>")]
+#[instrument(parent = &span.0, skip_all, fields(app_id = %app_id))]
+pub async fn push_metrics(app_id: String, instance: Option<String>, content_type: &ContentType, data: Data<'_>, ingest: &State<Arc<MetricsIngest>>, span: RequestSpan) -> Status {
+    if !is_valid_app_id(&app_id) {
+        return Status::new(400);
+    }
+    let body = match data.open(1.mebibytes()).into_string().await {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => return Status::PayloadTooLarge,
+        Err(_) => return Status::new(400),
+    };
+
+    let now = chrono::Utc::now();
+    let result = if content_type.is_json() {
+        match serde_json::from_str::<Push>(&body) {
+            Ok(push) if instance.as_ref().is_some_and(|instance| *instance != push.instance) => {
+                warn!("Metrics push names two instances");
+                return Status::new(400);
+            }
+            Ok(push) => ingest.push(&app_id, &push, now),
+            Err(e) => {
+                warn!("Invalid metrics push: {}", e);
+                return Status::new(400);
+            }
+        }
+    } else {
+        let Some(instance) = instance else {
+            return Status::new(400);
+        };
+        parse_exposition(&body).and_then(|series| ingest.record(&app_id, &instance, now, &series))
+    };
+
+    match result {
+        Ok(()) => Status::Accepted,
+        Err(e @ IngestError::TooManyInstances { .. }) => {
+            warn!("Rejected metrics: {}", e);
+            Status::TooManyRequests
+        }
+        Err(e @ IngestError::UnknownInstance { .. }) => {
+            warn!("Rejected metrics: {}", e);
+            Status::NotFound
+        }
+        Err(e) => {
+            warn!("Rejected metrics: {}", e);
+            Status::new(400)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueueSummary {
+    depth: usize,
+    dead_letters: Vec<BuildJob>,
+}
+
+/// Queues a rebuild of an app's last uploaded source tree for the queue consumer.
+/// Jobs name the app only, the consumer never builds a path taken from the request.
+#[post("/queue/jobs", data = "<job>")]
+#[instrument(parent = &span.0, skip_all, fields(app_id = %job.app_id))]
+pub async fn enqueue_job(job: Json<NewJob>, actor: Actor, queue: &State<Arc<dyn JobQueue>>, span: RequestSpan) -> Result<Accepted<Json<String>>,Status> {
+    if !is_valid_app_id(&job.app_id) || job.payload.get("path").is_some() {
+        return Err(Status::new(400));
+    }
+    let mut job = job.into_inner();
+    // The build is audited when the consumer submits it, under whoever queued it
+    if let Some(payload) = job.payload.as_object_mut() {
+        payload.insert("actor".to_string(), actor.0.into());
+    }
+    match queue.enqueue(job).await {
+        Ok(id) => Ok(Accepted(Json(id))),
+        Err(e) => {
+            error!("Failed to enqueue job: {}", e);
+            Err(Status::new(500))
+        }
+    }
+}
+
+#[get("/queue")]
+pub async fn queue_summary(queue: &State<Arc<dyn JobQueue>>) -> Result<Json<QueueSummary>,Status> {
+    let depth = queue.depth().await.map_err(|_| Status::new(500))?;
+    let dead_letters = queue.dead_letters().await.map_err(|_| Status::new(500))?;
+    Ok(Json(QueueSummary { depth, dead_letters }))
+}
+
+/// Why an operator blacklists an instance or clears its blacklist, recorded with the transition
+#[derive(Debug,Deserialize)]
+pub struct BlacklistRequest {
+    reason: String
+}
+
+/// Takes a deployed instance (`<host>/<container>`) out of rotation until an operator clears it.
+/// Audited under the request's actor, whether it took or not. 404 for an instance that isn't
+/// health checked, 409 when it already is blacklisted.
+#[post("/instances/<host>/<container>/blacklist", data = "<request>")]
+#[instrument(parent = &span.0, skip_all, fields(instance = %format!("{}/{}", host, container)))]
+pub fn blacklist_instance(host: String, container: String, request: Json<BlacklistRequest>, actor: Actor, health: &State<Arc<HealthMonitor>>, span: RequestSpan) -> Result<Json<Transition>,Status> {
+    let instance = format!("{}/{}", host, container);
+    operator_transition(health, &instance, &request.reason, |reason| health.blacklist(&actor.0, &instance, reason))
+}
+
+/// Puts a blacklisted instance back, in whatever state its probes report. 409 when it isn't blacklisted.
+#[post("/instances/<host>/<container>/blacklist/clear", data = "<request>")]
+#[instrument(parent = &span.0, skip_all, fields(instance = %format!("{}/{}", host, container)))]
+pub fn clear_instance_blacklist(host: String, container: String, request: Json<BlacklistRequest>, actor: Actor, health: &State<Arc<HealthMonitor>>, span: RequestSpan) -> Result<Json<Transition>,Status> {
+    let instance = format!("{}/{}", host, container);
+    operator_transition(health, &instance, &request.reason, |reason| health.clear_blacklist(&actor.0, &instance, reason))
+}
+
+/// The instance's latest changes of state and why, oldest first
+#[get("/instances/<host>/<container>/transitions")]
+pub fn instance_transitions(host: String, container: String, health: &State<Arc<HealthMonitor>>) -> Result<Json<Vec<Transition>>,Status> {
+    let instance = format!("{}/{}", host, container);
+    if health.state(&instance).is_none() {
+        return Err(Status::NotFound);
+    }
+    Ok(Json(health.transitions(&instance)))
+}
+
+/// Runs an operator's change of an instance's state, telling an unknown instance from one already
+/// in the state asked for
+fn operator_transition(health: &HealthMonitor, instance: &str, reason: &str, change: impl FnOnce(&str) -> Option<Transition>) -> Result<Json<Transition>,Status> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err(Status::new(400));
+    }
+    match change(reason) {
+        Some(transition) => Ok(Json(transition)),
+        None if health.state(instance).is_none() => Err(Status::NotFound),
+        None => Err(Status::Conflict),
+    }
+}
+
+/// OmniForge's own metrics for Prometheus. Queue depth and active builds are read at scrape time.
+#[get("/metrics")]
+pub async fn metrics(scheduler: &State<Arc<BuildScheduler>>, queue: &State<Arc<dyn JobQueue>>) -> (ContentType, String) {
+    let load = scheduler.load();
+    METRICS.builds_active.set(&[], load.active_builds as f64);
+    METRICS.queue_depth.set(&["scheduler"], load.queued_builds as f64);
+    match queue.depth().await {
+        Ok(depth) => METRICS.queue_depth.set(&["jobs"], depth as f64),
+        Err(e) => error!("Failed to read queue depth: {}", e),
+    }
+    (ContentType::new("text", "plain").with_params(("version", "0.0.4")), METRICS.render())
+}
+
+/// Filters of the audit routes, times are RFC 3339
+#[derive(Debug, FromForm)]
+pub struct AuditFilter {
+    actor: Option<String>,
+    action: Option<String>,
+    target: Option<String>,
+    outcome: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    limit: Option<usize>,
+}
+
+impl AuditFilter {
+    fn query(self) -> Result<AuditQuery, Status> {
+        let time = |value: Option<String>| {
+            value
+                .map(|value| chrono::DateTime::parse_from_rfc3339(&value).map(|at| at.to_utc()))
+                .transpose()
+                .map_err(|_| Status::new(400))
+        };
+        Ok(AuditQuery {
+            actor: self.actor,
+            action: self.action.map(|action| action.parse()).transpose().map_err(|_| Status::new(400))?,
+            target: self.target,
+            outcome: self.outcome,
+            since: time(self.since)?,
+            until: time(self.until)?,
+            limit: self.limit,
+        })
+    }
+}
+
+/// Who built what, deployed where and why the autoscaler scaled, oldest first. Like every
+/// audit route it needs `$OMNIFORGE_AUDIT_TOKEN` as a bearer token.
+#[get("/audit?<filter..>")]
+pub fn audit_log(_reader: AuditReader, filter: AuditFilter, audit: &State<Arc<AuditLog>>) -> Result<Json<Vec<AuditEntry>>,Status> {
+    audit.query(&filter.query()?).map(Json).map_err(|e| {
+        error!("Failed to read the audit log: {}", e);
+        Status::new(500)
+    })
+}
+
+/// The matching entries for export, `jsonl` (default) or `csv`
+#[get("/audit/export?<format>&<filter..>")]
+pub fn audit_export(_reader: AuditReader, format: Option<String>, filter: AuditFilter, audit: &State<Arc<AuditLog>>) -> Result<(ContentType, String),Status> {
+    let entries = audit.query(&filter.query()?).map_err(|e| {
+        error!("Failed to read the audit log: {}", e);
+        Status::new(500)
+    })?;
+    match format.as_deref() {
+        None | Some("jsonl") => Ok((ContentType::new("application", "x-ndjson"), to_jsonl(&entries).map_err(|_| Status::new(500))?)),
+        Some("csv") => Ok((ContentType::CSV, to_csv(&entries))),
+        Some(_) => Err(Status::new(400)),
+    }
+}
+
+/// Walks the hash chain and checks it against the checkpoint, reports the first entry that was
+/// altered, removed or reordered, or that entries are missing from the end
+#[get("/audit/verify")]
+pub fn audit_verify(_reader: AuditReader, audit: &State<Arc<AuditLog>>) -> Result<Json<Verification>,Status> {
+    audit.verify().map(Json).map_err(|e| {
+        error!("Failed to read the audit log: {}", e);
+        Status::new(500)
+    })
+}
+
+/// Unpacks an uploaded archive into a fresh workspace and builds it
+fn unpack_and_build(archive_path: &Path, workspace: &Path) -> anyhow::Result<()> {
+    if workspace.exists() {
+        fs::remove_dir_all(workspace)?;
+    }
+    fs::create_dir_all(workspace)?;
+
+    METRICS.build_stage_seconds.time(&["unpack"], || {
+        let tar_gz = fs::File::open(archive_path)?;
+        let tar = flate2::read::GzDecoder::new(tar_gz);
+        let mut archive = tar::Archive::new(tar);
+        archive.unpack(workspace)
+    })?;
+
+    // Clean up the tar.gz file
+    fs::remove_file(archive_path)?;
+
+    crate::image_builder::scan_and_build(workspace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+    use rocket::routes;
+
+    use crate::audit::{AuditAccess, AuditAction, AuditEvent, AuditOutcome, ACTOR_HEADER};
+    use crate::autoscalar::replicas::{InstanceLimits, ReplicaBounds, ReplicaInstance, ReplicaScaler, ReplicaTargets};
+    use crate::autoscalar::{AppInstance, ApplicationState, AutoscalerThresholds, InstanceMetrics};
+    use crate::image_builder::engine::ContainerEngineKind;
+    use crate::queue::memory::MemoryQueue;
+    use crate::scheduler::SchedulerConfig;
+    use crate::ssh::test_server::TestServer;
+    use crate::ssh::{SessionOptions, SessionPool};
+
+    #[rocket::async_test]
+    async fn serves_metrics_in_the_text_format() {
+        let queue: Arc<dyn JobQueue> = Arc::new(MemoryQueue::default());
+        let rocket = rocket::build()
+            .manage(Arc::new(BuildScheduler::new(SchedulerConfig::from_env())))
+            .manage(queue)
+            .mount("/", routes![metrics]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        // Planning alone counts as a decision
+        let scaler = ReplicaScaler::new(
+            "metrics-route",
+            ReplicaBounds::new(2, 4).unwrap(),
+            ReplicaTargets::default(),
+            InstanceLimits::default(),
+            AutoscalerThresholds::default()
+        );
+        let instance = ReplicaInstance {
+            id: "metrics-route-0".to_string(),
+            instance: AppInstance {
+                state: ApplicationState::Healthy,
+                allocated_memory: 1024,
+                allocated_cpu: 100,
+                allocated_disk_bandwidth: 100,
+                allocated_network_bandwidth: 100,
+            },
+            metrics: InstanceMetrics::default(),
+            window: Vec::new(),
+        };
+        assert_eq!(scaler.plan(&[instance], &[], None, chrono::Utc::now()).len(), 1);
+
+        let response = client.get("/metrics").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::new("text", "plain").with_params(("version", "0.0.4"))));
+        let body = response.into_string().await.unwrap();
+        assert!(body.contains("# TYPE omniforge_builds_active gauge\nomniforge_builds_active 0\n"));
+        assert!(body.contains("omniforge_queue_depth{queue=\"jobs\"} 0\n"));
+        assert!(body.contains("omniforge_autoscaler_decisions_total{app_id=\"metrics-route\",action=\"scale_out\"} 1\n"));
+    }
+
+    #[get("/whoami")]
+    fn whoami(actor: Actor) -> String {
+        actor.0
+    }
+
+    #[rocket::async_test]
+    async fn audit_routes_need_the_token_and_only_trusted_proxies_name_the_actor() {
+        let path = std::env::temp_dir().join(format!("omniforge-api-audit-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let audit = Arc::new(AuditLog::new(&path));
+        audit.record(AuditEvent {
+            actor: "ana".to_string(),
+            action: AuditAction::Deploy,
+            target: "shop".to_string(),
+            parameters: serde_json::json!({}),
+            outcome: AuditOutcome::Succeeded,
+        });
+        let access = AuditAccess { trusted_proxies: vec!["10.0.0.2".parse().unwrap()], token: Some("s3cret".to_string()) };
+        let rocket = rocket::build()
+            .manage(audit)
+            .manage(access)
+            .mount("/", routes![audit_log, audit_verify, whoami]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        assert_eq!(client.get("/audit").dispatch().await.status(), Status::Unauthorized);
+        let wrong = client.get("/audit/verify").header(Header::new("Authorization", "Bearer s3cre7"));
+        assert_eq!(wrong.dispatch().await.status(), Status::Unauthorized);
+        let response = client.get("/audit").header(Header::new("Authorization", "Bearer s3cret")).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.into_string().await.unwrap().contains("\"actor\":\"ana\""));
+
+        let proxy = "10.0.0.2:4000".parse().unwrap();
+        let named = client.get("/whoami").header(Header::new(ACTOR_HEADER, " carol\u{7} ")).remote(proxy);
+        assert_eq!(named.dispatch().await.into_string().await.unwrap(), "carol");
+        let forged = client
+            .get("/whoami")
+            .header(Header::new(ACTOR_HEADER, "carol"))
+            .remote("10.0.0.9:4000".parse().unwrap());
+        assert_eq!(forged.dispatch().await.into_string().await.unwrap(), "anonymous@10.0.0.9");
+
+        // Without a token the log isn't served at all
+        let closed = rocket::build()
+            .manage(Arc::new(AuditLog::new(&path)))
+            .manage(AuditAccess::default())
+            .mount("/", routes![audit_verify]);
+        let client = Client::tracked(closed).await.unwrap();
+        let request = client.get("/audit/verify").header(Header::new("Authorization", "Bearer "));
+        assert_eq!(request.dispatch().await.status(), Status::Forbidden);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{}.head", path.display()));
+    }
+
+    #[rocket::async_test]
+    async fn operators_blacklist_instances_and_are_audited() {
+        let path = std::env::temp_dir().join(format!("omniforge-api-blacklist-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let audit = Arc::new(AuditLog::new(&path));
+        let server = TestServer::start(|_| (Vec::new(), Vec::new(), 0)).await;
+        let pool = Arc::new(SessionPool::new(server.verifier(), SessionOptions::default()));
+        let health = Arc::new(HealthMonitor::new(pool, ContainerEngineKind::Docker).with_audit(audit.clone()));
+        // No probes, cleared it is whatever they all say: healthy
+        let _ = health.watch("db-1/web-0", server.host(), Vec::new());
+        let rocket = rocket::build()
+            .manage(health.clone())
+            .mount("/", routes![blacklist_instance, clear_instance_blacklist, instance_transitions]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let blacklist = |instance: &str, reason: &str| {
+            client.post(format!("/instances/{}/blacklist", instance)).json(&serde_json::json!({ "reason": reason }))
+        };
+        assert_eq!(blacklist("db-1/web-0", " ").dispatch().await.status(), Status::new(400));
+        assert_eq!(blacklist("db-1/web-0", "noisy neighbour").dispatch().await.status(), Status::Ok);
+        assert_eq!(health.state("db-1/web-0"), Some(ApplicationState::Blacklisted));
+        assert_eq!(blacklist("db-1/web-0", "again").dispatch().await.status(), Status::Conflict);
+        assert_eq!(blacklist("db-2/web-0", "unknown").dispatch().await.status(), Status::NotFound);
+
+        let clear = client.post("/instances/db-1/web-0/blacklist/clear").json(&serde_json::json!({ "reason": "moved" }));
+        assert_eq!(clear.dispatch().await.status(), Status::Ok);
+        assert_eq!(health.state("db-1/web-0"), Some(ApplicationState::Healthy));
+
+        let transitions: Vec<serde_json::Value> =
+            client.get("/instances/db-1/web-0/transitions").dispatch().await.into_json().await.unwrap();
+        assert_eq!(transitions.len(), 2);
+        assert!(transitions[1]["reason"].as_str().unwrap().contains("moved"));
+        assert_eq!(client.get("/instances/db-2/web-0/transitions").dispatch().await.status(), Status::NotFound);
+
+        // Attempts that changed nothing are audited too
+        let entries = audit.query(&AuditQuery { target: Some("db-1/web-0".to_string()), ..AuditQuery::default() }).unwrap();
+        assert_eq!(entries.len(), 3);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{}.head", path.display()));
+    }
+}
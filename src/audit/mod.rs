@@ -0,0 +1,877 @@
+//-----------------------------------------------------------------------------
+// Audit log - who built what, deployed where, and why the autoscaler scaled.
+// Entries are appended to one JSON-lines file and never rewritten. Each one
+// carries the hash of the entry before it and its own hash over both, so an
+// edited, removed or reordered entry breaks the chain from that point on and
+// `GET /audit/verify` says where. The last entry written is also kept in a
+// checkpoint next to the log, so entries cut off its end are noticed too. A
+// line left unreadable by a crash mid-write is not rewritten either: the next
+// append chains a `recover` entry to the last readable one, counting the lines
+// it skipped. Queried and exported via `GET /audit`, which needs a token.
+//-----------------------------------------------------------------------------
+
+use std::fmt::Write as _;
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, BufRead, BufReader, Read, Seek, SeekFrom, Write };
+use std::net::IpAddr;
+use std::path::{ Path, PathBuf };
+use std::sync::{ mpsc, Mutex, OnceLock };
+use std::thread;
+
+use chrono::{ DateTime, Utc };
+use fs4::fs_std::FileExt;
+use rocket::http::Status;
+use rocket::request::{ FromRequest, Outcome as RequestOutcome, Request };
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use sha2::{ Digest, Sha256 };
+use thiserror::Error;
+use tracing::{ error, warn };
+
+/// File the audit log is appended to
+pub const AUDIT_LOG_VAR: &str = "OMNIFORGE_AUDIT_LOG";
+/// Who is acting, for the CLI. Defaults to `$USER@host`.
+pub const ACTOR_VAR: &str = "OMNIFORGE_ACTOR";
+/// Request header naming who is acting. The API has no authentication of its own,
+/// so it is only believed from one of the trusted proxies, which authenticate the caller.
+pub const ACTOR_HEADER: &str = "X-Omniforge-Actor";
+/// Comma separated IPs of the proxies allowed to set `X-Omniforge-Actor`
+pub const TRUSTED_PROXIES_VAR: &str = "OMNIFORGE_TRUSTED_PROXIES";
+/// Bearer token the `/audit` routes require. Without one they refuse every request.
+pub const AUDIT_TOKEN_VAR: &str = "OMNIFORGE_AUDIT_TOKEN";
+/// Actor of decisions the autoscaler makes by itself
+pub const AUTOSCALER: &str = "autoscaler";
+/// `prev_hash` of the first entry
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Longest actor name kept, longer ones are cut
+const MAX_ACTOR_LEN: usize = 128;
+/// Actor of the entries the log writes about itself
+const RECOVERY_ACTOR: &str = "audit";
+
+#[derive(Debug, Error)]
+pub enum AuditError {
+    #[error("IO error occurred: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid audit entry: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("unknown audit action '{0}'")]
+    UnknownAction(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Build,
+    Deploy,
+    Rollback,
+    Scale,
+    Blacklist,
+    ClearBlacklist,
+    PolicyChange,
+    /// Unreadable lines at the end of the log were skipped, see `AuditLog::recover`
+    Recover,
+}
+
+impl AuditAction {
+    const ALL: [AuditAction; 8] = [
+        AuditAction::Build,
+        AuditAction::Deploy,
+        AuditAction::Rollback,
+        AuditAction::Scale,
+        AuditAction::Blacklist,
+        AuditAction::ClearBlacklist,
+        AuditAction::PolicyChange,
+        AuditAction::Recover,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuditAction::Build => "build",
+            AuditAction::Deploy => "deploy",
+            AuditAction::Rollback => "rollback",
+            AuditAction::Scale => "scale",
+            AuditAction::Blacklist => "blacklist",
+            AuditAction::ClearBlacklist => "clear_blacklist",
+            AuditAction::PolicyChange => "policy_change",
+            AuditAction::Recover => "recover",
+        }
+    }
+}
+
+impl std::str::FromStr for AuditAction {
+    type Err = AuditError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        AuditAction::ALL
+            .into_iter()
+            .find(|action| action.name() == s)
+            .ok_or_else(|| AuditError::UnknownAction(s.to_string()))
+    }
+}
+
+/// How an audited action ended. Long running ones get an `accepted` entry when they
+/// start and a second entry with the result.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Accepted,
+    Succeeded,
+    Failed { reason: String },
+    Cancelled { reason: String },
+}
+
+impl AuditOutcome {
+    pub fn status(&self) -> &'static str {
+        match self {
+            AuditOutcome::Accepted => "accepted",
+            AuditOutcome::Succeeded => "succeeded",
+            AuditOutcome::Failed { .. } => "failed",
+            AuditOutcome::Cancelled { .. } => "cancelled",
+        }
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            AuditOutcome::Failed { reason } | AuditOutcome::Cancelled { reason } => Some(reason),
+            AuditOutcome::Accepted | AuditOutcome::Succeeded => None,
+        }
+    }
+
+    /// `Succeeded` or `Failed` with the error's chain
+    pub fn of<T>(result: &anyhow::Result<T>) -> Self {
+        match result {
+            Ok(_) => AuditOutcome::Succeeded,
+            Err(e) => AuditOutcome::Failed { reason: format!("{:#}", e) },
+        }
+    }
+}
+
+/// Something to be audited, `AuditLog::record` turns it into an entry
+///
+/// # Fields
+/// actor - Who did it: a user, `user@host` from the CLI or `autoscaler`
+/// target - What it was done to: an app, an instance or `scheduler`
+/// parameters - The details needed to tell what exactly happened, e.g. build id or image
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub actor: String,
+    pub action: AuditAction,
+    pub target: String,
+    pub parameters: Value,
+    pub outcome: AuditOutcome,
+}
+
+/// One line of the audit log
+///
+/// # Fields
+/// seq - Position in the log, starting at 1 without gaps
+/// prev_hash - `hash` of the entry before, `GENESIS` for the first
+/// hash - SHA-256 over every other field, hex encoded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub at: DateTime<Utc>,
+    pub actor: String,
+    pub action: AuditAction,
+    pub target: String,
+    pub parameters: Value,
+    pub outcome: AuditOutcome,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+/// What the hash covers, the entry without its own hash
+#[derive(Serialize)]
+struct Hashed<'a> {
+    seq: u64,
+    at: &'a DateTime<Utc>,
+    actor: &'a str,
+    action: AuditAction,
+    target: &'a str,
+    parameters: &'a Value,
+    outcome: &'a AuditOutcome,
+    prev_hash: &'a str,
+}
+
+impl AuditEntry {
+    fn digest(&self) -> Result<String, AuditError> {
+        let hashed = Hashed {
+            seq: self.seq,
+            at: &self.at,
+            actor: &self.actor,
+            action: self.action,
+            target: &self.target,
+            parameters: &self.parameters,
+            outcome: &self.outcome,
+            prev_hash: &self.prev_hash,
+        };
+        let digest = Sha256::digest(serde_json::to_vec(&hashed)?);
+        Ok(digest.iter().fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        }))
+    }
+}
+
+/// Filters for `AuditLog::query`, every set field has to match
+///
+/// # Fields
+/// since - Entries at or after this time
+/// until - Entries before this time
+/// limit - Only the most recent n matches
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<AuditAction>,
+    pub target: Option<String>,
+    pub outcome: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.actor.as_ref().is_none_or(|actor| &entry.actor == actor)
+            && self.action.is_none_or(|action| entry.action == action)
+            && self.target.as_ref().is_none_or(|target| &entry.target == target)
+            && self.outcome.as_deref().is_none_or(|outcome| entry.outcome.status() == outcome)
+            && self.since.is_none_or(|since| entry.at >= since)
+            && self.until.is_none_or(|until| entry.at < until)
+    }
+}
+
+/// Result of walking the whole chain
+///
+/// # Fields
+/// entries - Lines checked
+/// head - Hash of the last entry, worth keeping elsewhere to notice a truncated log
+/// broken_at - Line number of the first entry that doesn't fit the chain, none if entries
+///             are missing from the end
+/// reason - What is wrong with it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Verification {
+    pub valid: bool,
+    pub entries: u64,
+    pub head: Option<String>,
+    pub broken_at: Option<u64>,
+    pub reason: Option<String>,
+}
+
+/// The last entry written, kept in `<log>.head` so `verify` notices entries cut off the end
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Checkpoint {
+    seq: u64,
+    hash: String,
+}
+
+/// Work for the thread writing what `record_later` was handed
+enum Deferred {
+    Record(Vec<AuditEvent>),
+    Flush(mpsc::Sender<()>),
+}
+
+/// Append-only, hash chained audit log in a JSON-lines file. Shared as `Arc<AuditLog>`.
+/// The file is locked while appending, so the server and the deploy CLI can share it.
+pub struct AuditLog {
+    path: PathBuf,
+    append: Mutex<()>,
+    writer: OnceLock<mpsc::Sender<Deferred>>,
+}
+
+impl AuditLog {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        AuditLog { path: path.as_ref().to_path_buf(), append: Mutex::new(()), writer: OnceLock::new() }
+    }
+
+    /// `$OMNIFORGE_AUDIT_LOG`, or ./Audit/audit.jsonl
+    pub fn from_env() -> Self {
+        let path = std::env::var(AUDIT_LOG_VAR).unwrap_or_else(|_| "./Audit/audit.jsonl".to_string());
+        AuditLog::new(path)
+    }
+
+    /// Appends the event and returns the entry as written
+    pub fn append(&self, event: AuditEvent) -> Result<AuditEntry, AuditError> {
+        let _guard = self.append.lock().unwrap();
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&self.path)?;
+        file.lock_exclusive()?;
+
+        let (seq, prev_hash) = match last_line(&mut file)? {
+            Some(line) => match serde_json::from_str::<AuditEntry>(&line) {
+                Ok(last) => (last.seq + 1, last.hash),
+                Err(e) => {
+                    let marker = self.recover(&mut file, &e)?;
+                    (marker.seq + 1, marker.hash)
+                }
+            },
+            None => (1, GENESIS.to_string()),
+        };
+        terminate(&mut file)?;
+        let entry = write_entry(&mut file, seq, prev_hash, event)?;
+        if let Err(e) = self.checkpoint(&entry) {
+            // The entry is written, `verify` just can't vouch for it until the next append
+            error!("Failed to update the audit checkpoint of {}: {}", self.path.display(), e);
+        }
+        Ok(entry)
+    }
+
+    /// Appends the event, a failure is logged rather than failing what is being audited
+    pub fn record(&self, event: AuditEvent) {
+        let (action, target) = (event.action, event.target.clone());
+        if let Err(e) = self.append(event) {
+            error!(action = action.name(), target = %target, "Failed to write audit entry to {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Hands the events to a background thread that records them in order. For callers that
+    /// hold a lock or run on the async runtime and must not wait for the disk.
+    pub fn record_later(&self, events: Vec<AuditEvent>) {
+        if events.is_empty() {
+            return;
+        }
+        let writer = self.writer.get_or_init(|| {
+            let (sender, receiver) = mpsc::channel();
+            // Its own handle, the file lock keeps it and this one from interleaving
+            let log = AuditLog::new(&self.path);
+            let spawned = thread::Builder::new().name("audit-writer".to_string()).spawn(move || {
+                for deferred in receiver {
+                    match deferred {
+                        Deferred::Record(events) => events.into_iter().for_each(|event| log.record(event)),
+                        Deferred::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            });
+            if let Err(e) = spawned {
+                error!("Failed to start the audit writer, recording in place: {}", e);
+            }
+            sender
+        });
+        if let Err(mpsc::SendError(Deferred::Record(events))) = writer.send(Deferred::Record(events)) {
+            events.into_iter().for_each(|event| self.record(event));
+        }
+    }
+
+    /// Waits until everything handed to `record_later` so far is written
+    pub fn flush(&self) {
+        let Some(writer) = self.writer.get() else {
+            return;
+        };
+        let (done, written) = mpsc::channel();
+        if writer.send(Deferred::Flush(done)).is_ok() {
+            let _ = written.recv();
+        }
+    }
+
+    /// Records `policy` as a policy change of `target`, unless it is the one recorded last
+    pub fn record_policy(&self, actor: &str, target: &str, policy: Value) {
+        let last = self.query(&AuditQuery {
+            action: Some(AuditAction::PolicyChange),
+            target: Some(target.to_string()),
+            limit: Some(1),
+            ..Default::default()
+        });
+        match last {
+            Ok(last) if last.first().map(|entry| &entry.parameters) == Some(&policy) => {}
+            Ok(_) => self.record(AuditEvent {
+                actor: actor.to_string(),
+                action: AuditAction::PolicyChange,
+                target: target.to_string(),
+                parameters: policy,
+                outcome: AuditOutcome::Succeeded,
+            }),
+            Err(e) => error!("Failed to read the audit log: {}", e),
+        }
+    }
+
+    /// Matching entries, oldest first, limited to the most recent `limit`. Unreadable lines
+    /// are skipped, `verify` reports them.
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, AuditError> {
+        let mut matches = Vec::new();
+        for line in self.lines()? {
+            let Ok(entry) = serde_json::from_str::<AuditEntry>(&line?) else {
+                continue;
+            };
+            if query.matches(&entry) {
+                matches.push(entry);
+            }
+        }
+        let skip = query.limit.map_or(0, |limit| matches.len().saturating_sub(limit));
+        Ok(matches.into_iter().skip(skip).collect())
+    }
+
+    /// Walks the whole log and checks every entry's sequence number, link and hash, then
+    /// that the entry in the checkpoint is still there
+    pub fn verify(&self) -> Result<Verification, AuditError> {
+        let mut verification = Verification { valid: true, entries: 0, head: None, broken_at: None, reason: None };
+        let checkpoint = match self.read_checkpoint() {
+            Ok(checkpoint) => checkpoint,
+            Err(e) => {
+                verification.valid = false;
+                verification.reason = Some(format!("unreadable checkpoint: {}", e));
+                return Ok(verification);
+            }
+        };
+        let mut prev_hash = GENESIS.to_string();
+        let mut seq = 0;
+        // Unreadable lines since the last entry, with the first one's line number and error
+        let mut unreadable: Option<(u64, String)> = None;
+        let mut discarded = 0;
+        let mut problem: Option<(Option<u64>, String)> = None;
+        for line in self.lines()? {
+            let line = line?;
+            verification.entries += 1;
+            let entry = match serde_json::from_str::<AuditEntry>(&line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    unreadable.get_or_insert((verification.entries, format!("unreadable entry: {}", e)));
+                    discarded += 1;
+                    continue;
+                }
+            };
+            let at = Some(verification.entries);
+            problem = match &unreadable {
+                Some((line, reason)) if entry.action != AuditAction::Recover => Some((Some(*line), reason.clone())),
+                _ if entry.action == AuditAction::Recover && entry.parameters["discarded_lines"] != json!(discarded) => {
+                    Some((at, "recovery marker does not match the unreadable lines before it".to_string()))
+                }
+                _ if entry.seq != seq + 1 => Some((at, format!("expected seq {}, found {}", seq + 1, entry.seq))),
+                _ if entry.prev_hash != prev_hash => Some((at, "does not link to the entry before".to_string())),
+                _ if entry.digest()? != entry.hash => Some((at, "hash does not match its contents".to_string())),
+                _ if checkpoint.as_ref().is_some_and(|c| c.seq == entry.seq && c.hash != entry.hash) => {
+                    Some((at, "differs from the checkpoint".to_string()))
+                }
+                _ => None,
+            };
+            if problem.is_some() {
+                break;
+            }
+            (seq, prev_hash) = (entry.seq, entry.hash);
+            (unreadable, discarded) = (None, 0);
+        }
+        // A crash between the append and the checkpoint leaves the log ahead of it, never behind
+        let problem = problem
+            .or_else(|| unreadable.map(|(line, reason)| (Some(line), reason)))
+            .or_else(|| match &checkpoint {
+                Some(checkpoint) if checkpoint.seq > seq => Some((
+                    None,
+                    format!("log ends at seq {} but the checkpoint has seq {}, entries were removed from its end", seq, checkpoint.seq),
+                )),
+                _ => None,
+            });
+        match problem {
+            Some((broken_at, reason)) => {
+                verification.valid = false;
+                verification.broken_at = broken_at;
+                verification.reason = Some(reason);
+            }
+            None if seq > 0 => verification.head = Some(prev_hash),
+            None => {}
+        }
+        Ok(verification)
+    }
+
+    /// Chains a `recover` entry to the last readable one when the log ends in lines that can't
+    /// be read, e.g. one cut short by a crash mid-write. They are left as they are, `verify`
+    /// accepts them only followed by a marker that counts them.
+    fn recover(&self, file: &mut File, e: &serde_json::Error) -> Result<AuditEntry, AuditError> {
+        file.seek(SeekFrom::Start(0))?;
+        let mut last: Option<AuditEntry> = None;
+        let mut discarded = 0;
+        for line in BufReader::new(&mut *file).split(b'\n') {
+            let line = line?;
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+            match serde_json::from_slice::<AuditEntry>(&line) {
+                Ok(entry) => (last, discarded) = (Some(entry), 0),
+                Err(_) => discarded += 1,
+            }
+        }
+        let (seq, prev_hash) = last.map_or((1, GENESIS.to_string()), |last| (last.seq + 1, last.hash));
+        error!(
+            "{} ends in {} unreadable line(s) ({}), recovering after entry {}",
+            self.path.display(), discarded, e, seq - 1
+        );
+        terminate(file)?;
+        write_entry(file, seq, prev_hash, AuditEvent {
+            actor: RECOVERY_ACTOR.to_string(),
+            action: AuditAction::Recover,
+            target: self.path.display().to_string(),
+            parameters: json!({ "discarded_lines": discarded, "error": e.to_string() }),
+            outcome: AuditOutcome::Succeeded,
+        })
+    }
+
+    fn checkpoint_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".head");
+        PathBuf::from(path)
+    }
+
+    /// Replaces the checkpoint in one rename, so it is never half written
+    fn checkpoint(&self, entry: &AuditEntry) -> Result<(), AuditError> {
+        let path = self.checkpoint_path();
+        let mut staged = path.clone().into_os_string();
+        staged.push(".tmp");
+        let checkpoint = Checkpoint { seq: entry.seq, hash: entry.hash.clone() };
+        fs::write(&staged, serde_json::to_vec(&checkpoint)?)?;
+        fs::rename(&staged, &path)?;
+        Ok(())
+    }
+
+    fn read_checkpoint(&self) -> Result<Option<Checkpoint>, AuditError> {
+        match fs::read(self.checkpoint_path()) {
+            Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Lines of the log, none if nothing was audited yet. Bytes that aren't UTF-8 are
+    /// replaced, the line then fails to parse like any other damaged one.
+    fn lines(&self) -> Result<Box<dyn Iterator<Item = io::Result<String>>>, AuditError> {
+        match File::open(&self.path) {
+            Ok(file) => Ok(Box::new(
+                BufReader::new(file)
+                    .split(b'\n')
+                    .map(|line| line.map(|line| String::from_utf8_lossy(line.trim_ascii_end()).into_owned()))
+                    .filter(|line| !matches!(line, Ok(l) if l.is_empty())),
+            )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Box::new(std::iter::empty())),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Writes the event as the entry after `prev_hash`
+fn write_entry(file: &mut File, seq: u64, prev_hash: String, event: AuditEvent) -> Result<AuditEntry, AuditError> {
+    let mut entry = AuditEntry {
+        seq,
+        at: Utc::now(),
+        actor: event.actor,
+        action: event.action,
+        target: event.target,
+        parameters: event.parameters,
+        outcome: event.outcome,
+        prev_hash,
+        hash: String::new(),
+    };
+    entry.hash = entry.digest()?;
+
+    let mut line = serde_json::to_vec(&entry)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    file.sync_data()?;
+    Ok(entry)
+}
+
+/// Ends a line cut short, so the next entry starts on its own
+fn terminate(file: &mut File) -> io::Result<()> {
+    let len = file.seek(SeekFrom::End(0))?;
+    if len == 0 {
+        return Ok(());
+    }
+    let mut last = [0; 1];
+    file.seek(SeekFrom::Start(len - 1))?;
+    file.read_exact(&mut last)?;
+    if last[0] != b'\n' {
+        file.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// The last non-empty line, read backwards from the end so appending stays cheap on a long log
+fn last_line(file: &mut File) -> io::Result<Option<String>> {
+    const CHUNK: u64 = 4096;
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut tail: Vec<u8> = Vec::new();
+    let mut at = len;
+    while at > 0 {
+        let start = at.saturating_sub(CHUNK);
+        let mut chunk = vec![0; (at - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+        at = start;
+
+        let trimmed = tail.trim_ascii_end();
+        if let Some(newline) = trimmed.iter().rposition(|b| *b == b'\n') {
+            return Ok(Some(String::from_utf8_lossy(&trimmed[newline + 1..]).into_owned()));
+        }
+    }
+    let trimmed = tail.trim_ascii_end();
+    Ok((!trimmed.is_empty()).then(|| String::from_utf8_lossy(trimmed).into_owned()))
+}
+
+/// Entries as CSV with a header row, parameters as a JSON column
+pub fn to_csv(entries: &[AuditEntry]) -> String {
+    fn field(value: &str) -> String {
+        if value.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value.to_string()
+        }
+    }
+
+    let mut out = String::from("seq,at,actor,action,target,outcome,reason,parameters,prev_hash,hash\n");
+    for entry in entries {
+        let row = [
+            entry.seq.to_string(),
+            entry.at.to_rfc3339(),
+            entry.actor.clone(),
+            entry.action.name().to_string(),
+            entry.target.clone(),
+            entry.outcome.status().to_string(),
+            entry.outcome.reason().unwrap_or_default().to_string(),
+            entry.parameters.to_string(),
+            entry.prev_hash.clone(),
+            entry.hash.clone(),
+        ];
+        let row: Vec<String> = row.iter().map(|value| field(value)).collect();
+        out.push_str(&row.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+/// Entries one JSON object per line, the log's own format
+pub fn to_jsonl(entries: &[AuditEntry]) -> Result<String, AuditError> {
+    let mut out = String::new();
+    for entry in entries {
+        out.push_str(&serde_json::to_string(entry)?);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// Keeps actor names printable and bounded
+fn clean_actor(actor: &str) -> String {
+    actor.trim().chars().filter(|c| !c.is_control()).take(MAX_ACTOR_LEN).collect()
+}
+
+/// Who runs the CLI: `$OMNIFORGE_ACTOR`, or `$USER@host`
+pub fn local_actor() -> String {
+    if let Some(actor) = std::env::var(ACTOR_VAR).ok().map(|actor| clean_actor(&actor)).filter(|actor| !actor.is_empty()) {
+        return actor;
+    }
+    let user = std::env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+    clean_actor(&format!("{}@{}", user, crate::interfaces::director::hostname()))
+}
+
+/// Who may name the actor of a request and read the audit log. Managed by Rocket, the
+/// `Actor` and `AuditReader` guards trust nobody without it.
+///
+/// # Fields
+/// trusted_proxies - Peers whose `X-Omniforge-Actor` header is believed
+/// token - Bearer token for the `/audit` routes, they are closed without one
+#[derive(Debug, Clone, Default)]
+pub struct AuditAccess {
+    pub trusted_proxies: Vec<IpAddr>,
+    pub token: Option<String>,
+}
+
+impl AuditAccess {
+    /// From `$OMNIFORGE_TRUSTED_PROXIES` and `$OMNIFORGE_AUDIT_TOKEN`, malformed IPs are skipped
+    pub fn from_env() -> Self {
+        let trusted_proxies = std::env::var(TRUSTED_PROXIES_VAR)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+            .filter_map(|ip| match ip.parse() {
+                Ok(ip) => Some(ip),
+                Err(_) => {
+                    warn!("Ignoring malformed trusted proxy '{}' in {}", ip, TRUSTED_PROXIES_VAR);
+                    None
+                }
+            })
+            .collect();
+        let token = std::env::var(AUDIT_TOKEN_VAR).ok().filter(|token| !token.is_empty());
+        AuditAccess { trusted_proxies, token }
+    }
+
+    fn trusts(&self, request: &Request<'_>) -> bool {
+        request.remote().is_some_and(|remote| self.trusted_proxies.contains(&remote.ip()))
+    }
+}
+
+/// Who sent the request. `X-Omniforge-Actor` when a trusted proxy forwarded it, otherwise
+/// `anonymous@<client ip>`; the header is ignored from anyone else.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Actor(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Actor {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> RequestOutcome<Self, Self::Error> {
+        let trusted = request.rocket().state::<AuditAccess>().is_some_and(|access| access.trusts(request));
+        let named = request.headers().get_one(ACTOR_HEADER);
+        if named.is_some() && !trusted {
+            warn!("Ignoring {} from untrusted peer {:?}", ACTOR_HEADER, request.remote());
+        }
+        let named = named
+            .filter(|_| trusted)
+            .map(clean_actor)
+            .filter(|actor| !actor.is_empty());
+        // Only a trusted proxy may say who the client is, through X-Real-IP
+        let ip = if trusted { request.client_ip() } else { request.remote().map(|remote| remote.ip()) };
+        let actor = named.unwrap_or_else(|| match ip {
+            Some(ip) => format!("anonymous@{}", ip),
+            None => "anonymous".to_string(),
+        });
+        RequestOutcome::Success(Actor(actor))
+    }
+}
+
+/// Guards the `/audit` routes: `Authorization: Bearer $OMNIFORGE_AUDIT_TOKEN`, 401 with
+/// anything else and 403 for everyone while no token is configured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditReader;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuditReader {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> RequestOutcome<Self, Self::Error> {
+        let Some(token) = request.rocket().state::<AuditAccess>().and_then(|access| access.token.as_deref()) else {
+            warn!("Refusing to serve the audit log, {} is not set", AUDIT_TOKEN_VAR);
+            return RequestOutcome::Error((Status::Forbidden, ()));
+        };
+        let given = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim);
+        match given {
+            Some(given) if same_secret(given.as_bytes(), token.as_bytes()) => RequestOutcome::Success(AuditReader),
+            _ => RequestOutcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Compares without stopping at the first difference, so timing doesn't reveal the token
+fn same_secret(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len() && given.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn log(name: &str) -> AuditLog {
+        let path = std::env::temp_dir().join(format!("omniforge-audit-{}-{}.jsonl", std::process::id(), name));
+        let audit = AuditLog::new(&path);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(audit.checkpoint_path());
+        audit
+    }
+
+    fn event(actor: &str, action: AuditAction, target: &str, outcome: AuditOutcome) -> AuditEvent {
+        AuditEvent { actor: actor.to_string(), action, target: target.to_string(), parameters: json!({ "n": 1 }), outcome }
+    }
+
+    #[test]
+    fn chain_verifies_and_detects_tampering() {
+        let audit = log("chain");
+        assert_eq!(audit.verify().unwrap(), Verification { valid: true, entries: 0, head: None, broken_at: None, reason: None });
+
+        let first = audit.append(event("ana", AuditAction::Build, "shop", AuditOutcome::Accepted)).unwrap();
+        let second = audit.append(event("ana", AuditAction::Build, "shop", AuditOutcome::Succeeded)).unwrap();
+        let third = audit.append(event(AUTOSCALER, AuditAction::Scale, "shop", AuditOutcome::Succeeded)).unwrap();
+        assert_eq!((first.seq, first.prev_hash.as_str()), (1, GENESIS));
+        assert_eq!(second.prev_hash, first.hash);
+        assert_eq!(third.seq, 3);
+        let verification = audit.verify().unwrap();
+        assert!(verification.valid);
+        assert_eq!((verification.entries, verification.head), (3, Some(third.hash.clone())));
+
+        // Rewriting who did it, even with a matching prev_hash, breaks the hash
+        let content = fs::read_to_string(&audit.path).unwrap();
+        fs::write(&audit.path, content.replacen("\"actor\":\"ana\"", "\"actor\":\"bob\"", 1)).unwrap();
+        let verification = audit.verify().unwrap();
+        assert_eq!((verification.valid, verification.broken_at), (false, Some(1)));
+
+        // Dropping an entry breaks the sequence
+        let lines: Vec<&str> = content.lines().collect();
+        fs::write(&audit.path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        let verification = audit.verify().unwrap();
+        assert_eq!(verification.broken_at, Some(2));
+        assert_eq!(verification.reason.as_deref(), Some("expected seq 2, found 3"));
+        let _ = fs::remove_file(&audit.path);
+    }
+
+    #[test]
+    fn queries_filter_and_export() {
+        let audit = log("query");
+        audit.record(event("ana", AuditAction::Build, "shop", AuditOutcome::Accepted));
+        audit.record(event("ana", AuditAction::Deploy, "shop", AuditOutcome::Failed { reason: "unhealthy, \"web-1\"".to_string() }));
+        audit.record(event("bob", AuditAction::Deploy, "blog", AuditOutcome::Succeeded));
+        audit.record(event("ana", AuditAction::Deploy, "blog", AuditOutcome::Succeeded));
+
+        let deploys = audit.query(&AuditQuery { action: Some(AuditAction::Deploy), ..Default::default() }).unwrap();
+        assert_eq!(deploys.iter().map(|entry| entry.seq).collect::<Vec<_>>(), vec![2, 3, 4]);
+        let ana = audit.query(&AuditQuery { actor: Some("ana".to_string()), limit: Some(2), ..Default::default() }).unwrap();
+        assert_eq!(ana.iter().map(|entry| entry.seq).collect::<Vec<_>>(), vec![2, 4]);
+        let failed = audit.query(&AuditQuery { outcome: Some("failed".to_string()), ..Default::default() }).unwrap();
+        assert_eq!(failed.len(), 1);
+        let future = audit.query(&AuditQuery { since: Some(Utc::now() + chrono::Duration::hours(1)), ..Default::default() }).unwrap();
+        assert!(future.is_empty());
+        assert_eq!("clear_blacklist".parse::<AuditAction>().unwrap(), AuditAction::ClearBlacklist);
+        assert!("delete".parse::<AuditAction>().is_err());
+
+        let csv = to_csv(&failed);
+        let row = csv.lines().nth(1).unwrap();
+        assert!(row.starts_with("2,"));
+        assert!(row.contains(",ana,deploy,shop,failed,\"unhealthy, \"\"web-1\"\"\",\"{\"\"n\"\":1}\","));
+        let jsonl = to_jsonl(&failed).unwrap();
+        assert_eq!(serde_json::from_str::<AuditEntry>(jsonl.trim()).unwrap(), failed[0]);
+        let _ = fs::remove_file(&audit.path);
+    }
+
+    #[test]
+    fn notices_truncation_and_recovers_from_a_partial_line() {
+        let audit = log("recover");
+        audit.append(event("ana", AuditAction::Build, "shop", AuditOutcome::Accepted)).unwrap();
+        let second = audit.append(event("ana", AuditAction::Build, "shop", AuditOutcome::Succeeded)).unwrap();
+        audit.append(event("ana", AuditAction::Deploy, "shop", AuditOutcome::Accepted)).unwrap();
+
+        // Cutting off the last entry keeps the chain intact, the checkpoint still knows it
+        let content = fs::read_to_string(&audit.path).unwrap();
+        let kept: String = content.lines().take(2).map(|line| format!("{}\n", line)).collect();
+        fs::write(&audit.path, &kept).unwrap();
+        let verification = audit.verify().unwrap();
+        assert!(!verification.valid);
+        assert_eq!(verification.broken_at, None);
+        assert!(verification.reason.unwrap().contains("checkpoint has seq 3"));
+
+        // A crash mid-write leaves half a line, which fails verification until the next append
+        fs::write(&audit.path, format!("{}{}", kept, &content.lines().nth(2).unwrap()[..40])).unwrap();
+        assert_eq!(audit.verify().unwrap().broken_at, Some(3));
+        let next = audit.append(event("bob", AuditAction::Deploy, "blog", AuditOutcome::Accepted)).unwrap();
+        let entries = audit.query(&Default::default()).unwrap();
+        let marker = &entries[2];
+        assert_eq!((marker.seq, marker.action, &marker.prev_hash), (3, AuditAction::Recover, &second.hash));
+        assert_eq!(marker.parameters["discarded_lines"], 1);
+        assert_eq!((next.seq, &next.prev_hash), (4, &marker.hash));
+        let verification = audit.verify().unwrap();
+        assert!(verification.valid, "{:?}", verification.reason);
+        assert_eq!((verification.entries, verification.head), (5, Some(next.hash)));
+
+        // Removing the skipped line afterwards is noticed as well
+        let content = fs::read_to_string(&audit.path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        fs::write(&audit.path, format!("{}\n{}\n{}\n{}\n", lines[0], lines[1], lines[3], lines[4])).unwrap();
+        assert_eq!(audit.verify().unwrap().broken_at, Some(3));
+        let _ = fs::remove_file(&audit.path);
+        let _ = fs::remove_file(audit.checkpoint_path());
+    }
+}
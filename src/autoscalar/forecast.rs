@@ -0,0 +1,471 @@
+//-------------------------------------------------------------------------
+// Predictive scaling. The app-wide demand for every metric (the sum over
+// its instances) is kept per app as hourly means, a few weeks deep, and
+// persisted so a restart doesn't forget the daily pattern. A seasonal
+// model is fitted to that series: a linear trend plus an offset for the
+// hour of the day on that day of the week. Before the forecast is handed
+// out the model is backtested on the last day of history, so every
+// forecast carries the error it would have had, and PredictiveScaling
+// ignores forecasts that are worse than the operator allows. The predicted replica count is only
+// ever a floor on top of the reactive decision, never a ceiling.
+//-------------------------------------------------------------------------
+
+use std::collections::{ BTreeMap, HashMap };
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::sync::Mutex;
+
+use chrono::{ DateTime, Datelike, Duration, Timelike, Utc };
+use serde::{ Deserialize, Serialize };
+use thiserror::Error;
+
+use super::policy::Metric;
+use super::InstanceMetrics;
+
+const HISTORY_DIR_VAR: &str = "OMNIFORGE_METRIC_HISTORY_DIR";
+/// Five weeks, enough for every weekday to show up more than once
+const RETENTION_HOURS: i64 = 24 * 7 * 5;
+/// The most recent day is held out to measure the model's error
+const HOLDOUT_HOURS: usize = 24;
+/// Two days to train on on top of the holdout
+const MIN_HISTORY_HOURS: usize = 48 + HOLDOUT_HOURS;
+
+#[derive(Debug, Error)]
+pub enum ForecastError {
+    #[error("{app_id} has {hours} hours of {metric:?} history, forecasting needs at least {needed}")]
+    NotEnoughHistory { app_id: String, metric: Metric, hours: usize, needed: usize },
+    #[error("metric history I/O failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("metric history is corrupt: {0}")]
+    Corrupt(#[from] serde_json::Error),
+}
+
+/// Running mean of one metric over one hour
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+struct Mean {
+    sum: f64,
+    count: u32,
+}
+
+/// Hour start (unix seconds) to the app-wide means of every metric seen in that hour
+type AppHistory = BTreeMap<i64, HashMap<Metric, Mean>>;
+
+const METRICS: [Metric; 7] = [
+    Metric::CpuLoad,
+    Metric::RamPressure,
+    Metric::RamUsage,
+    Metric::Clients,
+    Metric::AppResponseTime,
+    Metric::NetworkLatency,
+    Metric::DiskBandwidth,
+];
+
+pub struct MetricHistory {
+    dir: PathBuf,
+    apps: Mutex<HashMap<String, AppHistory>>,
+}
+
+impl MetricHistory {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        MetricHistory { dir: dir.as_ref().to_path_buf(), apps: Mutex::new(HashMap::new()) }
+    }
+
+    /// `$OMNIFORGE_METRIC_HISTORY_DIR`, or ./Metrics/history
+    pub fn from_env() -> Self {
+        let dir = std::env::var(HISTORY_DIR_VAR).unwrap_or_else(|_| "./Metrics/history".to_string());
+        MetricHistory::new(dir)
+    }
+
+    fn path(&self, app_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", app_id))
+    }
+
+    fn load(&self, app_id: &str) -> Result<AppHistory, ForecastError> {
+        match fs::read_to_string(self.path(app_id)) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(AppHistory::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, app_id: &str, history: &AppHistory) -> Result<(), ForecastError> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(app_id);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(history)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    fn with_app<T>(
+        &self,
+        app_id: &str,
+        f: impl FnOnce(&mut AppHistory) -> Result<T, ForecastError>
+    ) -> Result<T, ForecastError> {
+        let mut apps = self.apps.lock().unwrap();
+        if !apps.contains_key(app_id) {
+            let history = self.load(app_id)?;
+            apps.insert(app_id.to_string(), history);
+        }
+        f(apps.get_mut(app_id).unwrap())
+    }
+
+    /// Adds one sample of every instance of the app. The history is written out whenever an
+    /// hour completes, so a crash loses at most the hour in progress.
+    pub fn record(&self, app_id: &str, at: DateTime<Utc>, instances: &[InstanceMetrics]) -> Result<(), ForecastError> {
+        let hour = hour_start(at).timestamp();
+        self.with_app(app_id, |history| {
+            let new_hour = history.last_key_value().is_none_or(|(last, _)| *last < hour);
+            let means = history.entry(hour).or_default();
+            for metric in METRICS {
+                let values: Vec<u64> = instances.iter().filter_map(|instance| metric.value(instance)).collect();
+                if !values.is_empty() {
+                    let mean = means.entry(metric).or_default();
+                    mean.sum += values.iter().sum::<u64>() as f64;
+                    mean.count += 1;
+                }
+            }
+
+            if new_hour {
+                let oldest = hour - RETENTION_HOURS * 3600;
+                history.retain(|start, _| *start > oldest);
+                self.save(app_id, history)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Writes out the hour in progress as well, e.g. on shutdown
+    pub fn flush(&self, app_id: &str) -> Result<(), ForecastError> {
+        self.with_app(app_id, |history| self.save(app_id, history))
+    }
+
+    /// Hourly means of the app-wide metric, oldest first
+    pub fn series(&self, app_id: &str, metric: Metric) -> Result<Vec<(DateTime<Utc>, f64)>, ForecastError> {
+        self.with_app(app_id, |history| {
+            Ok(history
+                .iter()
+                .filter_map(|(start, means)| {
+                    let mean = means.get(&metric).filter(|mean| mean.count > 0)?;
+                    Some((DateTime::from_timestamp(*start, 0)?, mean.sum / mean.count as f64))
+                })
+                .collect())
+        })
+    }
+
+    /// Fits the model to the app's history and forecasts `hours` hours from `now`
+    pub fn forecast(
+        &self,
+        app_id: &str,
+        metric: Metric,
+        now: DateTime<Utc>,
+        hours: u32
+    ) -> Result<Forecast, ForecastError> {
+        let series = self.series(app_id, metric)?;
+        if series.len() < MIN_HISTORY_HOURS {
+            return Err(ForecastError::NotEnoughHistory {
+                app_id: app_id.to_string(),
+                metric,
+                hours: series.len(),
+                needed: MIN_HISTORY_HOURS,
+            });
+        }
+
+        let (train, holdout) = series.split_at(series.len() - HOLDOUT_HOURS);
+        let accuracy = ForecastAccuracy::backtest(&SeasonalModel::fit(train), holdout);
+        let model = SeasonalModel::fit(&series);
+        let start = hour_start(now);
+        let points = (0..hours as i64)
+            .map(|hour| {
+                let at = start + Duration::hours(hour);
+                ForecastPoint { at, value: model.predict(at) }
+            })
+            .collect();
+
+        Ok(Forecast {
+            app_id: app_id.to_string(),
+            metric,
+            generated_at: now,
+            history_hours: series.len(),
+            trend_per_hour: model.slope,
+            points,
+            accuracy,
+        })
+    }
+}
+
+fn hour_start(at: DateTime<Utc>) -> DateTime<Utc> {
+    DateTime::from_timestamp(at.timestamp() - at.timestamp().rem_euclid(3600), 0).unwrap_or(at)
+}
+
+/// Linear trend plus a per hour-of-week offset. Until an hour of the week has been seen,
+/// e.g. with only a few days of history, additive hour-of-day and day-of-week offsets stand in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeasonalModel {
+    origin: DateTime<Utc>,
+    intercept: f64,
+    slope: f64,
+    hour_of_week: [Option<f64>; 168],
+    hour_of_day: [Option<f64>; 24],
+    day_of_week: [Option<f64>; 7],
+}
+
+impl SeasonalModel {
+    /// Alternates a least-squares trend on the deseasonalized series with the mean residual
+    /// per slot, a few rounds so the weekly pattern doesn't skew the trend. The day-of-week
+    /// offsets are fitted to what the hour-of-day offsets leave over.
+    pub fn fit(series: &[(DateTime<Utc>, f64)]) -> Self {
+        let origin = series.first().map_or(DateTime::<Utc>::MIN_UTC, |(at, _)| *at);
+        let mut model = SeasonalModel {
+            origin,
+            intercept: 0.0,
+            slope: 0.0,
+            hour_of_week: [None; 168],
+            hour_of_day: [None; 24],
+            day_of_week: [None; 7],
+        };
+
+        for _ in 0..3 {
+            let deseasonalized: Vec<(f64, f64)> = series
+                .iter()
+                .map(|(at, value)| (model.hours(at), value - model.seasonal(at)))
+                .collect();
+            (model.intercept, model.slope) = linear_fit(&deseasonalized);
+
+            let residuals: Vec<(DateTime<Utc>, f64)> =
+                series.iter().map(|(at, value)| (*at, value - model.trend(at))).collect();
+            model.hour_of_week = offsets(residuals.iter().map(|(at, residual)| (week_hour(at), *residual)));
+            model.hour_of_day = offsets(residuals.iter().map(|(at, residual)| (at.hour() as usize, *residual)));
+            let hour_of_day = model.hour_of_day;
+            model.day_of_week = offsets(residuals.iter().map(|(at, residual)| {
+                (weekday(at), residual - hour_of_day[at.hour() as usize].unwrap_or(0.0))
+            }));
+        }
+        model
+    }
+
+    fn hours(&self, at: &DateTime<Utc>) -> f64 {
+        (*at - self.origin).num_seconds() as f64 / 3600.0
+    }
+
+    fn trend(&self, at: &DateTime<Utc>) -> f64 {
+        self.intercept + self.slope * self.hours(at)
+    }
+
+    fn seasonal(&self, at: &DateTime<Utc>) -> f64 {
+        self.hour_of_week[week_hour(at)].unwrap_or_else(|| {
+            self.hour_of_day[at.hour() as usize].unwrap_or(0.0) + self.day_of_week[weekday(at)].unwrap_or(0.0)
+        })
+    }
+
+    /// Demand can't go negative, neither does the prediction
+    pub fn predict(&self, at: DateTime<Utc>) -> f64 {
+        (self.trend(&at) + self.seasonal(&at)).max(0.0)
+    }
+}
+
+/// Least-squares intercept and slope
+fn linear_fit(points: &[(f64, f64)]) -> (f64, f64) {
+    let n = points.len().max(1) as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let covariance: f64 = points.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    let slope = if variance > 0.0 { covariance / variance } else { 0.0 };
+    (mean_y - slope * mean_x, slope)
+}
+
+fn weekday(at: &DateTime<Utc>) -> usize {
+    at.weekday().num_days_from_monday() as usize
+}
+
+fn week_hour(at: &DateTime<Utc>) -> usize {
+    weekday(at) * 24 + at.hour() as usize
+}
+
+/// Mean value per slot, `None` for slots without values
+fn offsets<const N: usize>(values: impl Iterator<Item = (usize, f64)>) -> [Option<f64>; N] {
+    let mut sums = [0.0; N];
+    let mut counts = [0u32; N];
+    for (slot, value) in values {
+        sums[slot] += value;
+        counts[slot] += 1;
+    }
+    std::array::from_fn(|slot| (counts[slot] > 0).then(|| sums[slot] / counts[slot] as f64))
+}
+
+/// How the model did on held-out history
+///
+/// # Fields
+/// holdout_hours - Hours of history the model was tested on, not trained on
+/// mae - Mean absolute error, in the metric's unit
+/// mape - Mean absolute percentage error, over the hours with non-zero demand
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ForecastAccuracy {
+    pub holdout_hours: usize,
+    pub mae: f64,
+    pub mape: Option<f64>,
+}
+
+impl ForecastAccuracy {
+    fn backtest(model: &SeasonalModel, holdout: &[(DateTime<Utc>, f64)]) -> Self {
+        let errors: Vec<(f64, f64)> =
+            holdout.iter().map(|(at, actual)| ((model.predict(*at) - actual).abs(), *actual)).collect();
+        let mae = errors.iter().map(|(error, _)| error).sum::<f64>() / errors.len().max(1) as f64;
+        let relative: Vec<f64> =
+            errors.iter().filter(|(_, actual)| *actual > 0.0).map(|(error, actual)| error / actual * 100.0).collect();
+        let mape = (!relative.is_empty()).then(|| relative.iter().sum::<f64>() / relative.len() as f64);
+        ForecastAccuracy { holdout_hours: holdout.len(), mae, mape }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ForecastPoint {
+    pub at: DateTime<Utc>,
+    pub value: f64,
+}
+
+/// Forecast app-wide demand, hourly
+///
+/// # Fields
+/// history_hours - Hours of history the model was fitted to
+/// trend_per_hour - How much demand grows per hour regardless of the time of day
+/// points - Predicted hourly means, starting with the current hour
+/// accuracy - The model's error on the last day of history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Forecast {
+    pub app_id: String,
+    pub metric: Metric,
+    pub generated_at: DateTime<Utc>,
+    pub history_hours: usize,
+    pub trend_per_hour: f64,
+    pub points: Vec<ForecastPoint>,
+    pub accuracy: ForecastAccuracy,
+}
+
+/// Scales out ahead of forecast demand
+///
+/// # Fields
+/// metric - The app-wide metric to forecast
+/// target_per_replica - Demand one replica should carry, e.g. 50 for 50% CPU
+/// lead_secs - How far ahead to provision, replicas take a while to start and warm up
+/// max_mape - Forecasts with a larger backtest error are ignored
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PredictiveScaling {
+    pub metric: Metric,
+    pub target_per_replica: f64,
+    pub lead_secs: u64,
+    pub max_mape: Option<f64>,
+}
+
+impl PredictiveScaling {
+    /// Replicas needed for the highest forecast between now and the lead time, `None` when
+    /// the forecast doesn't cover that or isn't trusted
+    pub fn replicas(&self, forecast: &Forecast, now: DateTime<Utc>) -> Option<u32> {
+        if self.target_per_replica <= 0.0 {
+            return None;
+        }
+        if let (Some(max), Some(mape)) = (self.max_mape, forecast.accuracy.mape) {
+            if mape > max {
+                return None;
+            }
+        }
+        let until = now + Duration::seconds(self.lead_secs.min(i64::MAX as u64) as i64);
+        let peak = forecast
+            .points
+            .iter()
+            .filter(|point| point.at + Duration::hours(1) > now && point.at <= until)
+            .map(|point| point.value)
+            .max_by(f64::total_cmp)?;
+        Some((peak / self.target_per_replica).ceil() as u32)
+    }
+
+    /// The reactive decision, raised to the forecast where that asks for more
+    pub fn floor(&self, reactive: u32, forecast: Option<&Forecast>, now: DateTime<Utc>) -> u32 {
+        forecast.and_then(|forecast| self.replicas(forecast, now)).map_or(reactive, |predicted| reactive.max(predicted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Busy from 08:00 to 18:00 on weekdays, quiet otherwise, slowly growing
+    fn demand(at: DateTime<Utc>, start: DateTime<Utc>) -> f64 {
+        let weekday = at.weekday().num_days_from_monday() < 5;
+        let busy = (8..18).contains(&at.hour());
+        let base = if weekday && busy { 400.0 } else { 100.0 };
+        base + (at - start).num_hours() as f64 * 0.5
+    }
+
+    fn monday() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn seasonal_model_learns_daily_ramps_and_trend() {
+        let dir = std::env::temp_dir().join(format!("omniforge-forecast-{}", std::process::id()));
+        let history = MetricHistory::new(&dir);
+        let start = monday();
+        // Two weeks, two instances sharing the load, two samples an hour
+        for hour in 0..24 * 14 {
+            for minute in [0, 30] {
+                let at = start + Duration::hours(hour) + Duration::minutes(minute);
+                let half = (demand(at, start) / 2.0) as u64;
+                let instance = InstanceMetrics { clients: Some(half), ..InstanceMetrics::default() };
+                history.record("web", at, &[instance.clone(), instance]).unwrap();
+            }
+        }
+
+        // Monday 07:50 of the third week, the ramp is ten minutes out
+        let now = start + Duration::days(14) + Duration::hours(7) + Duration::minutes(50);
+        let forecast = history.forecast("web", Metric::Clients, now, 24).unwrap();
+        assert_eq!(forecast.history_hours, 24 * 14);
+        assert!(forecast.accuracy.mape.unwrap() < 5.0, "{:?}", forecast.accuracy);
+        assert!(forecast.trend_per_hour > 0.0);
+        let nine = forecast.points.iter().find(|point| point.at.hour() == 9).unwrap();
+        let expected = demand(nine.at, start);
+        assert!((nine.value - expected).abs() / expected < 0.1, "{} vs {}", nine.value, expected);
+
+        // Reactive says 3, the forecast for 08:00 needs ceil(~570 / 100)
+        let predictive = PredictiveScaling {
+            metric: Metric::Clients,
+            target_per_replica: 100.0,
+            lead_secs: 900,
+            max_mape: Some(10.0),
+        };
+        assert_eq!(predictive.floor(3, Some(&forecast), now), 6);
+        // Never lower than the reactive decision
+        assert_eq!(predictive.floor(9, Some(&forecast), now), 9);
+        // Untrusted forecasts are ignored
+        let strict = PredictiveScaling { max_mape: Some(0.0), ..predictive };
+        assert_eq!(strict.floor(3, Some(&forecast), now), 3);
+
+        // The history survives a restart
+        history.flush("web").unwrap();
+        let reloaded = MetricHistory::new(&dir);
+        assert_eq!(reloaded.series("web", Metric::Clients).unwrap(), history.series("web", Metric::Clients).unwrap());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn short_history_is_refused() {
+        let dir = std::env::temp_dir().join(format!("omniforge-forecast-short-{}", std::process::id()));
+        let history = MetricHistory::new(&dir);
+        let start = monday();
+        for hour in 0..30 {
+            let metrics = InstanceMetrics { cpu_load: Some(50), ..InstanceMetrics::default() };
+            history.record("api", start + Duration::hours(hour), &[metrics]).unwrap();
+        }
+        assert!(matches!(
+            history.forecast("api", Metric::CpuLoad, start + Duration::hours(30), 12),
+            Err(ForecastError::NotEnoughHistory { hours: 30, .. })
+        ));
+        assert!(matches!(
+            history.forecast("api", Metric::Clients, start, 12),
+            Err(ForecastError::NotEnoughHistory { hours: 0, .. })
+        ));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,656 @@
+//-------------------------------------------------------------------------
+// Health checking for app instances. Probes (HTTP, TCP, a command over SSH
+// or the container engine's own health status) run on their own interval
+// and each keeps a verdict based on consecutive results. The instance's
+// ApplicationState is the worst verdict across its probes:
+//
+//   every probe passing                       -> Healthy
+//   a probe failing, below failure_threshold  -> Suspicious
+//   a probe failing failure_threshold times   -> Erroneous
+//   a probe unreachable failure_threshold times -> Down
+//
+// A failing probe only counts as passing again after success_threshold
+// successes in a row. Blacklisted is set and cleared by operators only;
+// probes keep running underneath so clearing it lands on the current
+// state. The latest changes of state are kept with the reason, blacklisting
+// and clearing it also go to the audit log with the operator who did it.
+// Operators do both through the `/instances` routes.
+//-------------------------------------------------------------------------
+
+use std::collections::{ HashMap, VecDeque };
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+
+use chrono::{ DateTime, Utc };
+use serde::{ Deserialize, Serialize };
+use serde_json::json;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tracing::info;
+
+use super::ApplicationState;
+use crate::audit::{ AuditAction, AuditEvent, AuditLog, AuditOutcome };
+use crate::image_builder::engine::ContainerEngineKind;
+use crate::ssh::{ shell_quote, Host, SessionPool };
+
+/// State changes kept per instance, the oldest are dropped first
+pub const MAX_TRANSITIONS: usize = 100;
+
+/// What a probe checks. `{host}` in a URL or address is replaced with the instance's host address,
+/// `{container}` and `{port}` with a deployed container's name and first published port.
+///
+/// # Variants
+/// Http - GET the URL, passes on `expected_status` or any 2xx/3xx
+/// Tcp - Connect to `host:port`
+/// Exec - Run a command on the instance's host over SSH, passes on exit 0
+/// Container - Ask the container engine on the host, passes when healthy, or running without a health check
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProbeKind {
+    Http {
+        url: String,
+        #[serde(default)]
+        expected_status: Option<u16>,
+    },
+    Tcp {
+        address: String,
+    },
+    Exec {
+        command: String,
+    },
+    Container {
+        name: String,
+    },
+}
+
+impl ProbeKind {
+    /// Fills in `{container}` and, when the container publishes one, `{port}`
+    pub fn for_container(&self, container: &str, port: Option<u16>) -> ProbeKind {
+        let fill = |text: &str| {
+            let text = text.replace("{container}", container);
+            match port {
+                Some(port) => text.replace("{port}", &port.to_string()),
+                None => text,
+            }
+        };
+        match self {
+            ProbeKind::Http { url, expected_status } => {
+                ProbeKind::Http { url: fill(url), expected_status: *expected_status }
+            }
+            ProbeKind::Tcp { address } => ProbeKind::Tcp { address: fill(address) },
+            ProbeKind::Exec { command } => ProbeKind::Exec { command: fill(command) },
+            ProbeKind::Container { name } => ProbeKind::Container { name: fill(name) },
+        }
+    }
+}
+
+impl std::fmt::Display for ProbeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeKind::Http { url, .. } => write!(f, "http {}", url),
+            ProbeKind::Tcp { address } => write!(f, "tcp {}", address),
+            ProbeKind::Exec { command } => write!(f, "exec `{}`", command),
+            ProbeKind::Container { name } => write!(f, "container {}", name),
+        }
+    }
+}
+
+/// A probe and how often and how strictly to run it
+///
+/// # Fields
+/// kind - What to check
+/// interval_secs - Time between two runs
+/// timeout_secs - A run taking longer than this fails
+/// failure_threshold - Failures in a row before the instance is Erroneous, or Down when unreachable
+/// success_threshold - Successes in a row before a failing probe passes again
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProbeConfig {
+    #[serde(flatten)]
+    pub kind: ProbeKind,
+    #[serde(default = "default_interval")]
+    pub interval_secs: u64,
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u64,
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    #[serde(default = "default_success_threshold")]
+    pub success_threshold: u32,
+}
+
+fn default_interval() -> u64 {
+    10
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+fn default_success_threshold() -> u32 {
+    1
+}
+
+impl ProbeConfig {
+    pub fn new(kind: ProbeKind) -> Self {
+        ProbeConfig {
+            kind,
+            interval_secs: default_interval(),
+            timeout_secs: default_timeout(),
+            failure_threshold: default_failure_threshold(),
+            success_threshold: default_success_threshold(),
+        }
+    }
+}
+
+/// Result of one probe run
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeOutcome {
+    Success,
+    /// The instance answered, but wrongly
+    Failure(String),
+    /// Nothing answered at all
+    Unreachable(String),
+}
+
+/// Where a single probe stands after its recent results
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verdict {
+    Passing,
+    Degraded,
+    Failing,
+    Unreachable,
+}
+
+impl Verdict {
+    fn state(self) -> ApplicationState {
+        match self {
+            Verdict::Passing => ApplicationState::Healthy,
+            Verdict::Degraded => ApplicationState::Suspicious,
+            Verdict::Failing => ApplicationState::Erroneous,
+            Verdict::Unreachable => ApplicationState::Down,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ProbeTracker {
+    config: ProbeConfig,
+    verdict: Verdict,
+    successes: u32,
+    failures: u32,
+    unreachable: u32,
+    last_message: Option<String>,
+}
+
+impl ProbeTracker {
+    fn record(&mut self, outcome: &ProbeOutcome) {
+        let threshold = self.config.failure_threshold.max(1);
+        match outcome {
+            ProbeOutcome::Success => {
+                self.successes += 1;
+                self.failures = 0;
+                self.unreachable = 0;
+                if self.successes >= self.config.success_threshold.max(1) {
+                    self.verdict = Verdict::Passing;
+                }
+                self.last_message = None;
+            }
+            ProbeOutcome::Failure(message) => {
+                self.failures += 1;
+                self.successes = 0;
+                self.unreachable = 0;
+                if self.failures >= threshold {
+                    self.verdict = Verdict::Failing;
+                } else if matches!(self.verdict, Verdict::Passing | Verdict::Unreachable) {
+                    self.verdict = Verdict::Degraded;
+                }
+                self.last_message = Some(format!("{}: {} ({} in a row)", self.config.kind, message, self.failures));
+            }
+            ProbeOutcome::Unreachable(message) => {
+                self.unreachable += 1;
+                self.successes = 0;
+                self.failures = 0;
+                if self.unreachable >= threshold {
+                    self.verdict = Verdict::Unreachable;
+                } else if self.verdict == Verdict::Passing {
+                    self.verdict = Verdict::Degraded;
+                }
+                self.last_message = Some(
+                    format!("{}: unreachable, {} ({} in a row)", self.config.kind, message, self.unreachable)
+                );
+            }
+        }
+    }
+}
+
+/// A change of an instance's state
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Transition {
+    pub from: ApplicationState,
+    pub to: ApplicationState,
+    pub reason: String,
+    pub at: DateTime<Utc>,
+}
+
+/// The state machine for one instance, fed with probe results
+#[derive(Debug, Clone)]
+pub struct HealthTracker {
+    probes: Vec<ProbeTracker>,
+    /// Operator's reason, while blacklisted
+    blacklist: Option<String>,
+    state: ApplicationState,
+    transitions: VecDeque<Transition>,
+}
+
+impl HealthTracker {
+    /// Starts out Down, an instance is only Healthy once its probes have passed
+    pub fn new(probes: Vec<ProbeConfig>) -> Self {
+        HealthTracker {
+            probes: probes
+                .into_iter()
+                .map(|config| ProbeTracker {
+                    config,
+                    verdict: Verdict::Unreachable,
+                    successes: 0,
+                    failures: 0,
+                    unreachable: 0,
+                    last_message: None,
+                })
+                .collect(),
+            blacklist: None,
+            state: ApplicationState::Down,
+            transitions: VecDeque::new(),
+        }
+    }
+
+    pub fn state(&self) -> ApplicationState {
+        self.state
+    }
+
+    /// The last `MAX_TRANSITIONS` state changes, oldest first
+    pub fn transitions(&self) -> &VecDeque<Transition> {
+        &self.transitions
+    }
+
+    pub fn probes(&self) -> impl Iterator<Item = &ProbeConfig> {
+        self.probes.iter().map(|probe| &probe.config)
+    }
+
+    /// Feeds one result of the probe at `index`, returns the transition if the state changed
+    pub fn record(&mut self, index: usize, outcome: &ProbeOutcome, at: DateTime<Utc>) -> Option<Transition> {
+        let probe = self.probes.get_mut(index)?;
+        probe.record(outcome);
+        let reason = match &probe.last_message {
+            Some(message) => message.clone(),
+            None => format!("{}: passing", probe.config.kind),
+        };
+        if self.blacklist.is_some() {
+            return None;
+        }
+        self.transition(self.derived(), reason, at)
+    }
+
+    /// Takes the instance out of rotation until an operator clears it
+    pub fn blacklist(&mut self, reason: &str, at: DateTime<Utc>) -> Option<Transition> {
+        self.blacklist = Some(reason.to_string());
+        self.transition(ApplicationState::Blacklisted, format!("blacklisted by operator: {}", reason), at)
+    }
+
+    /// Lifts a blacklist, the instance goes straight to whatever its probes say
+    pub fn clear_blacklist(&mut self, reason: &str, at: DateTime<Utc>) -> Option<Transition> {
+        self.blacklist.take()?;
+        self.transition(self.derived(), format!("blacklist cleared by operator: {}", reason), at)
+    }
+
+    /// The worst verdict across all probes
+    fn derived(&self) -> ApplicationState {
+        self.probes
+            .iter()
+            .map(|probe| probe.verdict)
+            .max()
+            .unwrap_or(Verdict::Passing)
+            .state()
+    }
+
+    fn transition(&mut self, to: ApplicationState, reason: String, at: DateTime<Utc>) -> Option<Transition> {
+        if to == self.state {
+            return None;
+        }
+        let transition = Transition { from: self.state, to, reason, at };
+        self.state = to;
+        if self.transitions.len() == MAX_TRANSITIONS {
+            self.transitions.pop_front();
+        }
+        self.transitions.push_back(transition.clone());
+        Some(transition)
+    }
+}
+
+/// Runs probes against instances and keeps their health state
+pub struct HealthMonitor {
+    pool: Arc<SessionPool>,
+    engine: ContainerEngineKind,
+    http: reqwest::Client,
+    instances: Mutex<HashMap<String, HealthTracker>>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl HealthMonitor {
+    pub fn new(pool: Arc<SessionPool>, engine: ContainerEngineKind) -> Self {
+        HealthMonitor {
+            pool,
+            engine,
+            http: reqwest::Client::new(),
+            instances: Mutex::new(HashMap::new()),
+            audit: None,
+        }
+    }
+
+    /// Audits every blacklisting and clearing of it
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Starts probing an instance, one task per probe. Abort the handles to stop.
+    pub fn watch(self: &Arc<Self>, instance_id: &str, host: Host, probes: Vec<ProbeConfig>) -> Vec<JoinHandle<()>> {
+        self.instances
+            .lock()
+            .unwrap()
+            .insert(instance_id.to_string(), HealthTracker::new(probes.clone()));
+
+        probes
+            .into_iter()
+            .enumerate()
+            .map(|(index, probe)| {
+                let monitor = self.clone();
+                let instance_id = instance_id.to_string();
+                let host = host.clone();
+                tokio::spawn(async move {
+                    let mut ticker = tokio::time::interval(Duration::from_secs(probe.interval_secs.max(1)));
+                    loop {
+                        ticker.tick().await;
+                        let outcome = monitor.probe(&probe, &host).await;
+                        monitor.record(&instance_id, index, &outcome);
+                    }
+                })
+            })
+            .collect()
+    }
+
+    pub fn unwatch(&self, instance_id: &str) -> Option<HealthTracker> {
+        self.instances.lock().unwrap().remove(instance_id)
+    }
+
+    pub fn state(&self, instance_id: &str) -> Option<ApplicationState> {
+        self.instances.lock().unwrap().get(instance_id).map(HealthTracker::state)
+    }
+
+    pub fn transitions(&self, instance_id: &str) -> Vec<Transition> {
+        self.instances
+            .lock()
+            .unwrap()
+            .get(instance_id)
+            .map(|tracker| tracker.transitions().iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Takes the instance out of service on an operator's (`actor`) word
+    pub fn blacklist(&self, actor: &str, instance_id: &str, reason: &str) -> Option<Transition> {
+        let transition = self.instances
+            .lock()
+            .unwrap()
+            .get_mut(instance_id)
+            .and_then(|tracker| tracker.blacklist(reason, Utc::now()));
+        log_transition(instance_id, transition.as_ref());
+        self.audit(actor, AuditAction::Blacklist, instance_id, reason, transition.as_ref());
+        transition
+    }
+
+    pub fn clear_blacklist(&self, actor: &str, instance_id: &str, reason: &str) -> Option<Transition> {
+        let transition = self.instances
+            .lock()
+            .unwrap()
+            .get_mut(instance_id)
+            .and_then(|tracker| tracker.clear_blacklist(reason, Utc::now()));
+        log_transition(instance_id, transition.as_ref());
+        self.audit(actor, AuditAction::ClearBlacklist, instance_id, reason, transition.as_ref());
+        transition
+    }
+
+    fn audit(&self, actor: &str, action: AuditAction, instance_id: &str, reason: &str, transition: Option<&Transition>) {
+        let Some(audit) = &self.audit else {
+            return;
+        };
+        audit.record(AuditEvent {
+            actor: actor.to_string(),
+            action,
+            target: instance_id.to_string(),
+            parameters: json!({ "reason": reason, "transition": transition }),
+            outcome: match transition {
+                Some(_) => AuditOutcome::Succeeded,
+                None => AuditOutcome::Failed { reason: "unknown instance or no change of state".to_string() },
+            },
+        });
+    }
+
+    fn record(&self, instance_id: &str, index: usize, outcome: &ProbeOutcome) {
+        let transition = match self.instances.lock().unwrap().get_mut(instance_id) {
+            Some(tracker) => tracker.record(index, outcome, Utc::now()),
+            None => return,
+        };
+        log_transition(instance_id, transition.as_ref());
+    }
+
+    /// Runs a probe once
+    pub async fn probe(&self, probe: &ProbeConfig, host: &Host) -> ProbeOutcome {
+        let timeout = Duration::from_secs(probe.timeout_secs.max(1));
+        match tokio::time::timeout(timeout, self.run_probe(&probe.kind, host)).await {
+            Ok(outcome) => outcome,
+            // A TCP connect that hangs means nothing is there, anything else is a slow answer
+            Err(_) if matches!(probe.kind, ProbeKind::Tcp { .. }) => {
+                ProbeOutcome::Unreachable(format!("no answer within {}s", timeout.as_secs()))
+            }
+            Err(_) => ProbeOutcome::Failure(format!("timed out after {}s", timeout.as_secs())),
+        }
+    }
+
+    async fn run_probe(&self, kind: &ProbeKind, host: &Host) -> ProbeOutcome {
+        match kind {
+            ProbeKind::Http { url, expected_status } => {
+                let url = url.replace("{host}", &host.address);
+                match self.http.get(&url).send().await {
+                    Ok(response) => {
+                        let status = response.status();
+                        let passed = match expected_status {
+                            Some(expected) => status.as_u16() == *expected,
+                            None => status.is_success() || status.is_redirection(),
+                        };
+                        if passed {
+                            ProbeOutcome::Success
+                        } else {
+                            ProbeOutcome::Failure(format!("status {}", status.as_u16()))
+                        }
+                    }
+                    Err(e) if e.is_connect() => ProbeOutcome::Unreachable(e.to_string()),
+                    Err(e) => ProbeOutcome::Failure(e.to_string()),
+                }
+            }
+            ProbeKind::Tcp { address } => {
+                match TcpStream::connect(address.replace("{host}", &host.address)).await {
+                    Ok(_) => ProbeOutcome::Success,
+                    Err(e) => ProbeOutcome::Unreachable(e.to_string()),
+                }
+            }
+            ProbeKind::Exec { command } => self.exec_probe(host, command, |_| Ok(())).await,
+            ProbeKind::Container { name } => {
+                let command = format!(
+                    "{} inspect --format {} {}",
+                    self.engine.binary(),
+                    shell_quote("{{if .State.Health}}{{.State.Health.Status}}{{else}}{{.State.Status}}{{end}}"),
+                    shell_quote(name)
+                );
+                self.exec_probe(host, &command, |stdout| {
+                    match stdout.trim() {
+                        "healthy" | "running" => Ok(()),
+                        status => Err(format!("container is {}", status)),
+                    }
+                }).await
+            }
+        }
+    }
+
+    /// Runs a command on the host; SSH trouble means unreachable, a bad exit or output means failing
+    async fn exec_probe(
+        &self,
+        host: &Host,
+        command: &str,
+        check: impl FnOnce(&str) -> Result<(), String>
+    ) -> ProbeOutcome {
+        match self.pool.exec(host, command).await {
+            Ok(output) if output.success() => {
+                match check(&output.stdout_str()) {
+                    Ok(()) => ProbeOutcome::Success,
+                    Err(message) => ProbeOutcome::Failure(message),
+                }
+            }
+            Ok(output) => {
+                ProbeOutcome::Failure(format!("exited with {:?}: {}", output.exit_status, output.stderr_str().trim()))
+            }
+            Err(e) => ProbeOutcome::Unreachable(e.to_string()),
+        }
+    }
+}
+
+fn log_transition(instance_id: &str, transition: Option<&Transition>) {
+    if let Some(transition) = transition {
+        info!("[{}] {:?} -> {:?}: {}", instance_id, transition.from, transition.to, transition.reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::test_server::TestServer;
+    use crate::ssh::SessionOptions;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    fn tracker() -> HealthTracker {
+        let http = ProbeConfig {
+            failure_threshold: 2,
+            success_threshold: 2,
+            ..ProbeConfig::new(ProbeKind::Http { url: "http://{host}/health".to_string(), expected_status: None })
+        };
+        HealthTracker::new(vec![http, ProbeConfig::new(ProbeKind::Tcp { address: "{host}:5432".to_string() })])
+    }
+
+    #[test]
+    fn probe_results_drive_state_transitions() {
+        let now = Utc::now();
+        let failure = ProbeOutcome::Failure("status 500".to_string());
+        let mut health = tracker();
+        assert_eq!(health.state(), ApplicationState::Down);
+
+        // Healthy needs every probe passing, the HTTP probe twice
+        health.record(0, &ProbeOutcome::Success, now);
+        health.record(1, &ProbeOutcome::Success, now);
+        assert_eq!(health.state(), ApplicationState::Down);
+        health.record(0, &ProbeOutcome::Success, now);
+        assert_eq!(health.state(), ApplicationState::Healthy);
+
+        health.record(0, &failure, now);
+        assert_eq!(health.state(), ApplicationState::Suspicious);
+        let transition = health.record(0, &failure, now).unwrap();
+        assert_eq!((transition.from, transition.to), (ApplicationState::Suspicious, ApplicationState::Erroneous));
+        assert!(transition.reason.contains("status 500 (2 in a row)"));
+
+        // One success isn't enough to recover, and the worst probe wins
+        health.record(0, &ProbeOutcome::Success, now);
+        assert_eq!(health.state(), ApplicationState::Erroneous);
+        for _ in 0..3 {
+            health.record(1, &ProbeOutcome::Unreachable("connection refused".to_string()), now);
+        }
+        assert_eq!(health.state(), ApplicationState::Down);
+
+        health.record(0, &ProbeOutcome::Success, now);
+        health.record(1, &ProbeOutcome::Success, now);
+        assert_eq!(health.state(), ApplicationState::Healthy);
+        assert_eq!(health.transitions().len(), 5);
+    }
+
+    #[test]
+    fn blacklist_is_operator_only() {
+        let now = Utc::now();
+        let mut health = tracker();
+        health.blacklist("noisy neighbour", now).unwrap();
+
+        // Probes keep counting but can't move the instance out of the blacklist
+        for _ in 0..2 {
+            assert!(health.record(0, &ProbeOutcome::Success, now).is_none());
+            assert!(health.record(1, &ProbeOutcome::Success, now).is_none());
+        }
+        assert_eq!(health.state(), ApplicationState::Blacklisted);
+
+        let cleared = health.clear_blacklist("moved", now).unwrap();
+        assert_eq!(cleared.to, ApplicationState::Healthy);
+        assert!(cleared.reason.contains("moved"));
+        assert!(health.clear_blacklist("again", now).is_none());
+
+        // Only the latest changes are kept
+        for _ in 0..MAX_TRANSITIONS {
+            health.blacklist("flapping", now).unwrap();
+            health.clear_blacklist("flapping", now).unwrap();
+        }
+        assert_eq!(health.transitions().len(), MAX_TRANSITIONS);
+        assert!(health.transitions().iter().all(|transition| transition.reason.contains("flapping")));
+    }
+
+    #[tokio::test]
+    async fn runs_each_probe_kind() {
+        let server = TestServer::start(|command| {
+            if command.contains("inspect") {
+                (b"unhealthy\n".to_vec(), Vec::new(), 0)
+            } else if command == "true" {
+                (Vec::new(), Vec::new(), 0)
+            } else {
+                (Vec::new(), b"nope\n".to_vec(), 1)
+            }
+        }).await;
+        let pool = Arc::new(SessionPool::new(server.verifier(), SessionOptions::default()));
+        let monitor = HealthMonitor::new(pool, ContainerEngineKind::Docker);
+        let host = server.host();
+
+        // A one-shot HTTP server answering 503
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream.write_all(b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n").await;
+        });
+
+        let probe = ProbeConfig::new;
+        let http = probe(ProbeKind::Http { url: format!("http://{{host}}:{}/health", port), expected_status: None });
+        assert_eq!(monitor.probe(&http, &host).await, ProbeOutcome::Failure("status 503".to_string()));
+
+        let tcp = probe(ProbeKind::Tcp { address: format!("{{host}}:{}", server.addr.port()) });
+        assert_eq!(monitor.probe(&tcp, &host).await, ProbeOutcome::Success);
+        let unused = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let closed = probe(ProbeKind::Tcp { address: format!("{{host}}:{}", unused) });
+        assert!(matches!(monitor.probe(&closed, &host).await, ProbeOutcome::Unreachable(_)));
+
+        let exec = probe(ProbeKind::Exec { command: "true".to_string() });
+        assert_eq!(monitor.probe(&exec, &host).await, ProbeOutcome::Success);
+        let exec = probe(ProbeKind::Exec { command: "false".to_string() });
+        assert!(matches!(monitor.probe(&exec, &host).await, ProbeOutcome::Failure(message) if message.contains("nope")));
+
+        let container = probe(ProbeKind::Container { name: "web-0-abc".to_string() });
+        assert_eq!(
+            monitor.probe(&container, &host).await,
+            ProbeOutcome::Failure("container is unhealthy".to_string())
+        );
+    }
+}
@@ -0,0 +1,728 @@
+//-------------------------------------------------------------------------
+// Application-reported metrics. Apps either push named values (JSON, or
+// the Prometheus text exposition format) or expose a `/metrics` endpoint
+// that is scraped on an interval. Each app can map metric names, or exact
+// series such as `http_latency_ms{quantile="0.95"}`, onto the autoscaler
+// inputs; names that already match an InstanceMetrics field map onto it
+// without configuration. Several series mapped onto the same input are
+// summed. Everything else is kept as a custom metric under its series key
+// (and, for labelled series, summed under its bare name too), so policies
+// can use it as `custom:<name>`.
+//
+// What every instance reports is also added to the forecast history once a
+// minute, so predictive scaling learns the apps' daily pattern.
+//
+// Pushes aren't authenticated, so for the apps the fleet runs only the
+// instances it runs may report, anything else is rejected rather than
+// ending up in policies or the forecast. Other apps are capped per app,
+// so one of them can't take the room the rest need.
+//
+// Mappings and scrape targets come from the JSON file named by
+// $OMNIFORGE_CUSTOM_METRICS:
+//
+//   { "mappings": { "web": { "http_connections_active": "clients" } },
+//     "scrape": [ { "app_id": "web", "instance": "web-0",
+//                   "url": "http://10.0.0.5:8080/metrics" } ] }
+//-------------------------------------------------------------------------
+
+use std::collections::{ BTreeMap, BTreeSet, HashMap, VecDeque };
+use std::sync::{ Arc, Mutex };
+use std::time::Duration as StdDuration;
+
+use chrono::{ DateTime, Duration, Utc };
+use rocket::fairing::AdHoc;
+use serde::{ Deserialize, Serialize };
+use thiserror::Error;
+use tokio::sync::watch;
+use tracing::warn;
+
+use super::forecast::MetricHistory;
+use super::policy::Metric;
+use super::InstanceMetrics;
+
+const CONFIG_VAR: &str = "OMNIFORGE_CUSTOM_METRICS";
+/// Reported values older than this no longer describe the instance
+const STALE_AFTER_SECS: i64 = 120;
+/// History kept per instance for policy rules
+const RETENTION_SECS: i64 = 900;
+/// How often the latest samples are added to the forecast history
+const FORECAST_INTERVAL_SECS: u64 = 60;
+/// How far ahead of the server's clock a pushed sample may be
+const MAX_SKEW_SECS: i64 = 30;
+/// Instances tracked at most per app the fleet doesn't run
+const MAX_INSTANCES_PER_APP: usize = 100;
+/// Instances tracked at most, across all apps the fleet doesn't run
+const MAX_INSTANCES: usize = 10_000;
+
+#[derive(Debug, Error)]
+pub enum IngestError {
+    #[error("invalid metric name `{0}`")]
+    InvalidName(String),
+    #[error("invalid instance id `{0}`")]
+    InvalidInstance(String),
+    #[error("`{name}` is not a finite number")]
+    NotFinite { name: String },
+    #[error("sample time {at} is more than {MAX_SKEW_SECS}s ahead of the server's clock")]
+    FromTheFuture { at: DateTime<Utc> },
+    #[error("sample time {at} is older than the {RETENTION_SECS}s of history kept")]
+    TooOld { at: DateTime<Utc> },
+    #[error("already tracking {max} instances")]
+    TooManyInstances { max: usize },
+    #[error("{app_id} runs no instance `{instance}`")]
+    UnknownInstance { app_id: String, instance: String },
+    #[error("`{name}` maps onto {metric:?}, which can't be negative, got {value}")]
+    Negative { name: String, metric: Metric, value: f64 },
+    #[error("exposition line {line}: {reason}")]
+    Exposition { line: usize, reason: String },
+    #[error("scraping {url} failed: {source}")]
+    Scrape { url: String, source: reqwest::Error },
+    #[error("scraping {url} returned status {status}")]
+    ScrapeStatus { url: String, status: u16 },
+    #[error("failed to read {CONFIG_VAR}: {0}")]
+    ConfigIo(#[from] std::io::Error),
+    #[error("invalid {CONFIG_VAR} file: {0}")]
+    Config(#[from] serde_json::Error),
+}
+
+/// One series of the Prometheus text exposition format
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub name: String,
+    /// Sorted by label name
+    pub labels: Labels,
+    pub value: f64,
+}
+
+impl Series {
+    /// `name`, or `name{a="x",b="y"}` with the labels sorted
+    pub fn key(&self) -> String {
+        if self.labels.is_empty() {
+            return self.name.clone();
+        }
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect();
+        format!("{}{{{}}}", self.name, labels.join(","))
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Parses the text exposition format. Comments, timestamps and non-finite values (NaN, ±Inf)
+/// are skipped, everything else has to be well-formed.
+pub fn parse_exposition(text: &str) -> Result<Vec<Series>, IngestError> {
+    let mut series = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |reason: &str| IngestError::Exposition { line: index + 1, reason: reason.to_string() };
+
+        let name_end = line.find(|c: char| c == '{' || c.is_whitespace()).ok_or_else(|| error("missing value"))?;
+        let name = &line[..name_end];
+        if !valid_name(name) {
+            return Err(error(&format!("invalid metric name `{}`", name)));
+        }
+
+        let mut rest = &line[name_end..];
+        let mut labels = Vec::new();
+        if let Some(after_brace) = rest.strip_prefix('{') {
+            let (parsed, remainder) = parse_labels(after_brace).map_err(|reason| error(&reason))?;
+            labels = parsed;
+            rest = remainder;
+        }
+
+        let value = rest.split_whitespace().next().ok_or_else(|| error("missing value"))?;
+        let value: f64 = match value {
+            "+Inf" | "Inf" => f64::INFINITY,
+            "-Inf" => f64::NEG_INFINITY,
+            value => value.parse().map_err(|_| error(&format!("invalid value `{}`", value)))?,
+        };
+        if value.is_finite() {
+            labels.sort();
+            series.push(Series { name: name.to_string(), labels, value });
+        }
+    }
+    Ok(series)
+}
+
+type Labels = Vec<(String, String)>;
+
+/// `a="x",b="y"}` up to and including the closing brace, and what follows it
+fn parse_labels(mut text: &str) -> Result<(Labels, &str), String> {
+    let mut labels = Vec::new();
+    loop {
+        text = text.trim_start();
+        if let Some(rest) = text.strip_prefix('}') {
+            return Ok((labels, rest));
+        }
+        let (name, rest) = text.split_once('=').ok_or("label without a value")?;
+        let name = name.trim();
+        if !valid_name(name) || name.contains(':') {
+            return Err(format!("invalid label name `{}`", name));
+        }
+        let mut chars = rest.trim_start().strip_prefix('"').ok_or("label value must be quoted")?.char_indices();
+        let mut value = String::new();
+        let end = loop {
+            match chars.next() {
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, escaped)) => value.push(escaped),
+                    None => return Err("unterminated label value".to_string()),
+                },
+                Some((i, '"')) => break i,
+                Some((_, c)) => value.push(c),
+                None => return Err("unterminated label value".to_string()),
+            }
+        };
+        labels.push((name.to_string(), value));
+
+        text = rest.trim_start()[1 + end + 1..].trim_start();
+        text = text.strip_prefix(',').unwrap_or(text);
+    }
+}
+
+/// An app instance's `/metrics` endpoint
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScrapeTarget {
+    pub app_id: String,
+    pub instance: String,
+    pub url: String,
+    #[serde(default = "default_interval")]
+    pub interval_secs: u64,
+}
+
+fn default_interval() -> u64 {
+    15
+}
+
+/// # Fields
+/// mappings - Per app, metric names or series keys to the autoscaler input they feed
+/// scrape - Endpoints to scrape
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IngestConfig {
+    #[serde(default)]
+    pub mappings: HashMap<String, HashMap<String, Metric>>,
+    #[serde(default)]
+    pub scrape: Vec<ScrapeTarget>,
+}
+
+impl IngestConfig {
+    /// The file named by `$OMNIFORGE_CUSTOM_METRICS`, nothing configured if it isn't set
+    pub fn from_env() -> Result<Self, IngestError> {
+        match std::env::var(CONFIG_VAR) {
+            Ok(path) => Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?),
+            Err(_) => Ok(IngestConfig::default()),
+        }
+    }
+}
+
+/// A JSON push: `{ "instance": "web-0", "metrics": { "clients": 42, "queue_depth": 7 } }`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Push {
+    pub instance: String,
+    /// Defaults to when the push arrives, at most a few seconds ahead of the server's clock
+    #[serde(default)]
+    pub at: Option<DateTime<Utc>>,
+    pub metrics: HashMap<String, f64>,
+}
+
+type Samples = VecDeque<(DateTime<Utc>, InstanceMetrics)>;
+
+pub struct MetricsIngest {
+    mappings: HashMap<String, HashMap<String, Metric>>,
+    /// (app, instance) to its reported samples, oldest first
+    samples: Mutex<HashMap<(String, String), Samples>>,
+    /// The instances the fleet runs by app, only these report for the apps in here
+    fleet: Mutex<HashMap<String, BTreeSet<String>>>,
+    /// Instances tracked at most per app outside the fleet, new ones are rejected beyond it
+    max_instances: usize,
+    http: reqwest::Client,
+}
+
+impl MetricsIngest {
+    pub fn new(mappings: HashMap<String, HashMap<String, Metric>>) -> Self {
+        MetricsIngest {
+            mappings,
+            samples: Mutex::new(HashMap::new()),
+            fleet: Mutex::new(HashMap::new()),
+            max_instances: MAX_INSTANCES_PER_APP,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Caps the instances tracked per app the fleet doesn't run
+    pub fn with_max_instances(mut self, max_instances: usize) -> Self {
+        self.max_instances = max_instances;
+        self
+    }
+
+    /// Has only these instances of the app report from now on, the ones the fleet runs. What
+    /// any other instance reported is dropped.
+    pub fn set_instances(&self, app_id: &str, instances: &[String]) {
+        let mut fleet = self.fleet.lock().unwrap();
+        let known: BTreeSet<String> = instances.iter().cloned().collect();
+        self.samples.lock().unwrap().retain(|(app, instance), _| app != app_id || known.contains(instance));
+        fleet.insert(app_id.to_string(), known);
+    }
+
+    pub fn push(&self, app_id: &str, push: &Push, now: DateTime<Utc>) -> Result<(), IngestError> {
+        let series = push
+            .metrics
+            .iter()
+            .map(|(name, value)| {
+                if !valid_name(name) {
+                    return Err(IngestError::InvalidName(name.clone()));
+                }
+                if !value.is_finite() {
+                    return Err(IngestError::NotFinite { name: name.clone() });
+                }
+                Ok(Series { name: name.clone(), labels: Vec::new(), value: *value })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let at = push.at.unwrap_or(now);
+        // A sample from the future would outlive every real one and push them out of the history
+        if at > now + Duration::seconds(MAX_SKEW_SECS) {
+            return Err(IngestError::FromTheFuture { at });
+        }
+        if at < now - Duration::seconds(RETENTION_SECS) {
+            return Err(IngestError::TooOld { at });
+        }
+        self.record(app_id, &push.instance, at, &series)
+    }
+
+    /// Maps the series onto InstanceMetrics and stores them as one sample of the instance. `at`
+    /// is taken as the current time, instances that reported nothing within the retention
+    /// before it are forgotten when a new one shows up.
+    pub fn record(&self, app_id: &str, instance: &str, at: DateTime<Utc>, series: &[Series]) -> Result<(), IngestError> {
+        if instance.is_empty() || instance.chars().any(|c| c.is_control()) {
+            return Err(IngestError::InvalidInstance(instance.to_string()));
+        }
+        let metrics = self.map(app_id, series)?;
+        let oldest = at - Duration::seconds(RETENTION_SECS);
+
+        let fleet = self.fleet.lock().unwrap();
+        let known = fleet.get(app_id).map(|instances| instances.contains(instance));
+        if known == Some(false) {
+            return Err(IngestError::UnknownInstance { app_id: app_id.to_string(), instance: instance.to_string() });
+        }
+        let mut samples = self.samples.lock().unwrap();
+        let key = (app_id.to_string(), instance.to_string());
+        // The fleet's instances always have room, the rest share what's left
+        if known.is_none() && !samples.contains_key(&key) {
+            samples.retain(|_, history| history.back().is_some_and(|(last, _)| *last >= oldest));
+            let outside = samples.keys().filter(|(app, _)| !fleet.contains_key(app));
+            let (of_app, total) = outside.fold((0, 0), |(of_app, total), (app, _)| (of_app + usize::from(app == app_id), total + 1));
+            if of_app >= self.max_instances {
+                return Err(IngestError::TooManyInstances { max: self.max_instances });
+            }
+            if total >= MAX_INSTANCES {
+                return Err(IngestError::TooManyInstances { max: MAX_INSTANCES });
+            }
+        }
+        drop(fleet);
+        let history = samples.entry(key).or_default();
+        let position = history.partition_point(|(existing, _)| *existing <= at);
+        history.insert(position, (at, metrics));
+        while history.front().is_some_and(|(existing, _)| *existing < oldest) {
+            history.pop_front();
+        }
+        Ok(())
+    }
+
+    fn map(&self, app_id: &str, series: &[Series]) -> Result<InstanceMetrics, IngestError> {
+        let mapping = self.mappings.get(app_id);
+        let mut builtin: HashMap<Metric, f64> = HashMap::new();
+        let mut metrics = InstanceMetrics::default();
+
+        for series in series {
+            let key = series.key();
+            let mapped = mapping
+                .and_then(|mapping| mapping.get(&key).or_else(|| mapping.get(&series.name)))
+                .copied()
+                .or_else(|| series.name.parse().ok());
+            match mapped {
+                Some(metric) => {
+                    if series.value < 0.0 {
+                        return Err(IngestError::Negative { name: key, metric, value: series.value });
+                    }
+                    *builtin.entry(metric).or_default() += series.value;
+                }
+                None => {
+                    if !series.labels.is_empty() {
+                        *metrics.custom.entry(series.name.clone()).or_default() += series.value;
+                    }
+                    *metrics.custom.entry(key).or_default() += series.value;
+                }
+            }
+        }
+
+        for (metric, value) in builtin {
+            metrics.set_field(metric.name(), Some(value.round() as u64));
+        }
+        Ok(metrics)
+    }
+
+    /// What the instance reported within the staleness window, newer values winning
+    pub fn latest(&self, app_id: &str, instance: &str, now: DateTime<Utc>) -> Option<InstanceMetrics> {
+        let samples = self.samples.lock().unwrap();
+        let history = samples.get(&(app_id.to_string(), instance.to_string()))?;
+        let fresh_since = now - Duration::seconds(STALE_AFTER_SECS);
+
+        let mut latest: Option<InstanceMetrics> = None;
+        for (_, metrics) in history.iter().rev().take_while(|(at, _)| *at >= fresh_since) {
+            match &mut latest {
+                None => latest = Some(metrics.clone()),
+                Some(latest) => fill_missing(latest, metrics),
+            }
+        }
+        latest
+    }
+
+    /// Fills in whatever the runtime couldn't measure, e.g. clients and response time, from
+    /// what the instance reported itself
+    pub fn overlay(&self, app_id: &str, instance: &str, metrics: &mut InstanceMetrics, now: DateTime<Utc>) {
+        if let Some(reported) = self.latest(app_id, instance, now) {
+            fill_missing(metrics, &reported);
+        }
+    }
+
+    /// Every sample of every instance of the app since `since`, for `ScalingPolicy::evaluate`
+    pub fn history(&self, app_id: &str, since: DateTime<Utc>) -> Vec<(InstanceMetrics, DateTime<Utc>)> {
+        let samples = self.samples.lock().unwrap();
+        let mut history: Vec<(InstanceMetrics, DateTime<Utc>)> = samples
+            .iter()
+            .filter(|((app, _), _)| app == app_id)
+            .flat_map(|(_, history)| history.iter().filter(|(at, _)| *at >= since))
+            .map(|(at, metrics)| (metrics.clone(), *at))
+            .collect();
+        history.sort_by_key(|(_, at)| *at);
+        history
+    }
+
+    pub async fn scrape(&self, target: &ScrapeTarget, now: DateTime<Utc>) -> Result<(), IngestError> {
+        let scrape_error = |source| IngestError::Scrape { url: target.url.clone(), source };
+        let response = self
+            .http
+            .get(&target.url)
+            .timeout(StdDuration::from_secs(target.interval_secs.clamp(1, 30)))
+            .send()
+            .await
+            .map_err(scrape_error)?;
+        if !response.status().is_success() {
+            return Err(IngestError::ScrapeStatus { url: target.url.clone(), status: response.status().as_u16() });
+        }
+        let text = response.text().await.map_err(scrape_error)?;
+        self.record(&target.app_id, &target.instance, now, &parse_exposition(&text)?)
+    }
+
+    /// Adds the latest sample of every instance to the forecast history, one sample per app.
+    /// Returns the apps recorded.
+    pub fn record_history(&self, history: &MetricHistory, now: DateTime<Utc>) -> Vec<String> {
+        let instances: Vec<(String, String)> = self.samples.lock().unwrap().keys().cloned().collect();
+        let mut apps: BTreeMap<String, Vec<InstanceMetrics>> = BTreeMap::new();
+        for (app_id, instance) in instances {
+            if let Some(latest) = self.latest(&app_id, &instance, now) {
+                apps.entry(app_id).or_default().push(latest);
+            }
+        }
+        apps.into_iter()
+            .filter_map(|(app_id, instances)| match history.record(&app_id, now, &instances) {
+                Ok(()) => Some(app_id),
+                Err(e) => {
+                    warn!("Failed to record the metric history of {}: {}", app_id, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Records into the forecast history on an interval until shutdown, then writes out the
+    /// hour in progress
+    pub fn spawn_history(self: &Arc<Self>, history: Arc<MetricHistory>, mut shutdown: watch::Receiver<bool>) {
+        let ingest = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(StdDuration::from_secs(FORECAST_INTERVAL_SECS));
+            let mut recorded = BTreeSet::new();
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => recorded.extend(ingest.record_history(&history, Utc::now())),
+                    _ = shutdown.changed() => break,
+                }
+            }
+            for app_id in recorded {
+                if let Err(e) = history.flush(&app_id) {
+                    warn!("Failed to write the metric history of {}: {}", app_id, e);
+                }
+            }
+        });
+    }
+
+    /// Scrapes every target on its own interval until shutdown
+    pub fn spawn_scrapers(self: &Arc<Self>, targets: Vec<ScrapeTarget>, shutdown: watch::Receiver<bool>) {
+        for target in targets {
+            let ingest = self.clone();
+            let mut shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(StdDuration::from_secs(target.interval_secs.max(1)));
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {
+                            if let Err(e) = ingest.scrape(&target, Utc::now()).await {
+                                warn!("{}/{}: {}", target.app_id, target.instance, e);
+                            }
+                        }
+                        _ = shutdown.changed() => break,
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Copies every metric `from` has and `into` doesn't
+fn fill_missing(into: &mut InstanceMetrics, from: &InstanceMetrics) {
+    let missing: Vec<(&str, Option<u64>)> = into
+        .get_fields()
+        .into_iter()
+        .zip(from.get_fields())
+        .filter(|((_, current), _)| current.is_none())
+        .map(|(_, reported)| reported)
+        .collect();
+    for (name, value) in missing {
+        into.set_field(name, value);
+    }
+    for (name, value) in &from.custom {
+        into.custom.entry(name.clone()).or_insert(*value);
+    }
+}
+
+/// Manages the MetricsIngest and scrapes the configured targets while the server runs. With
+/// a MetricHistory managed, what the instances report is added to it.
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Custom metrics", |rocket| async move {
+        let config = IngestConfig::from_env().unwrap_or_else(|e| {
+            warn!("Custom metrics are unconfigured: {}", e);
+            IngestConfig::default()
+        });
+        let ingest = Arc::new(MetricsIngest::new(config.mappings));
+        let scrapers = ingest.clone();
+        let history = rocket.state::<Arc<MetricHistory>>().cloned();
+        let (shutdown, shutdown_rx) = watch::channel(false);
+
+        rocket
+            .manage(ingest)
+            .attach(AdHoc::on_liftoff("Custom metrics scrapers", move |_| Box::pin(async move {
+                if let Some(history) = history {
+                    scrapers.spawn_history(history, shutdown_rx.clone());
+                }
+                scrapers.spawn_scrapers(config.scrape, shutdown_rx);
+            })))
+            .attach(AdHoc::on_shutdown("Custom metrics shutdown", move |_| Box::pin(async move {
+                let _ = shutdown.send(true);
+            })))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::autoscalar::policy::ScalingPolicy;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    const EXPOSITION: &str = r#"
+# HELP http_connections_active Open connections
+# TYPE http_connections_active gauge
+http_connections_active{listener="public"} 30
+http_connections_active{listener="admin"} 2
+http_latency_ms{quantile="0.5"} 40
+http_latency_ms{quantile="0.95"} 180.4 1700000000000
+jobs_queued 12
+cache_hit_ratio{cache="a \"quoted\" name"} 0.9
+broken_gauge NaN
+"#;
+
+    fn ingest() -> MetricsIngest {
+        let mapping = HashMap::from([
+            ("http_connections_active".to_string(), Metric::Clients),
+            ("http_latency_ms{quantile=\"0.95\"}".to_string(), Metric::AppResponseTime),
+        ]);
+        MetricsIngest::new(HashMap::from([("web".to_string(), mapping)]))
+    }
+
+    #[test]
+    fn parses_and_maps_exposition_series() {
+        let series = parse_exposition(EXPOSITION).unwrap();
+        assert_eq!(series.len(), 6);
+        assert_eq!(series[5].key(), r#"cache_hit_ratio{cache="a \"quoted\" name"}"#);
+        assert!(parse_exposition("up{job=\"x\" 1").is_err());
+        assert!(matches!(parse_exposition("\n9lives 1"), Err(IngestError::Exposition { line: 2, .. })));
+
+        let ingest = ingest();
+        let now = Utc::now();
+        ingest.record("web", "web-0", now, &series).unwrap();
+        let metrics = ingest.latest("web", "web-0", now).unwrap();
+        // Both listeners summed, the exact p95 series mapped, the rest kept as custom metrics
+        assert_eq!(metrics.clients, Some(32));
+        assert_eq!(metrics.app_response_time, Some(180));
+        assert_eq!(metrics.custom["jobs_queued"], 12.0);
+        assert_eq!(metrics.custom["http_latency_ms"], 40.0);
+        assert_eq!(metrics.custom["http_latency_ms{quantile=\"0.5\"}"], 40.0);
+
+        // Field names map without configuration, pushes fill in what the runtime can't measure
+        let push = Push {
+            instance: "web-1".to_string(),
+            at: None,
+            metrics: HashMap::from([("network_latency".to_string(), 25.0), ("jobs_queued".to_string(), 3.0)]),
+        };
+        ingest.push("web", &push, now).unwrap();
+        let mut measured = InstanceMetrics { cpu_load: Some(70), network_latency: Some(5), ..InstanceMetrics::default() };
+        ingest.overlay("web", "web-1", &mut measured, now);
+        assert_eq!((measured.cpu_load, measured.network_latency), (Some(70), Some(5)));
+        assert_eq!(measured.custom["jobs_queued"], 3.0);
+        // Stale reports are ignored
+        assert!(ingest.latest("web", "web-1", now + Duration::seconds(STALE_AFTER_SECS + 1)).is_none());
+
+        let negative = Push { metrics: HashMap::from([("clients".to_string(), -1.0)]), ..push.clone() };
+        assert!(matches!(ingest.push("web", &negative, now), Err(IngestError::Negative { .. })));
+        let invalid = Push { metrics: HashMap::from([("jobs queued".to_string(), 1.0)]), ..push };
+        assert!(matches!(ingest.push("web", &invalid, now), Err(IngestError::InvalidName(_))));
+
+        // Custom metrics drive policies
+        let policy = ScalingPolicy::from_toml(
+            r#"
+            [[rules]]
+            name = "backlog"
+            priority = 1
+            metric = "custom:jobs_queued"
+            aggregation = "max"
+            comparator = ">="
+            threshold = 10
+            action = { type = "scale_out", by = 1 }
+            "#,
+        )
+        .unwrap();
+        let history = ingest.history("web", now - Duration::minutes(1));
+        assert_eq!(history.len(), 2);
+        assert_eq!(policy.evaluate(2, &history, now).replicas, 3);
+    }
+
+    #[test]
+    fn records_reported_samples_into_the_forecast_history() {
+        let dir = std::env::temp_dir().join(format!("omniforge-ingest-history-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let history = MetricHistory::new(&dir);
+        let ingest = ingest();
+        let now = Utc::now();
+        for (instance, clients) in [("web-0", 30.0), ("web-1", 12.0)] {
+            let push = Push { instance: instance.to_string(), at: None, metrics: HashMap::from([("clients".to_string(), clients)]) };
+            ingest.push("web", &push, now).unwrap();
+        }
+        let stale = Push { instance: "api-0".to_string(), at: None, metrics: HashMap::from([("clients".to_string(), 5.0)]) };
+        ingest.push("api", &stale, now - Duration::seconds(STALE_AFTER_SECS + 1)).unwrap();
+
+        // The app-wide demand, instances that went quiet don't count
+        assert_eq!(ingest.record_history(&history, now), ["web"]);
+        let series = history.series("web", Metric::Clients).unwrap();
+        assert_eq!(series.iter().map(|(_, value)| *value).collect::<Vec<_>>(), [42.0]);
+        assert!(history.series("api", Metric::Clients).unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn bounds_push_times_and_tracked_instances() {
+        let ingest = ingest().with_max_instances(2);
+        let now = Utc::now();
+        let push = |instance: &str, at: DateTime<Utc>| Push {
+            instance: instance.to_string(),
+            at: Some(at),
+            metrics: HashMap::from([("clients".to_string(), 1.0)]),
+        };
+
+        // A little clock skew is fine, a sample from next year isn't
+        ingest.push("web", &push("web-0", now + Duration::seconds(5)), now).unwrap();
+        let future = ingest.push("web", &push("web-0", now + Duration::days(365)), now);
+        assert!(matches!(future, Err(IngestError::FromTheFuture { .. })));
+        let old = ingest.push("web", &push("web-0", now - Duration::seconds(RETENTION_SECS + 1)), now);
+        assert!(matches!(old, Err(IngestError::TooOld { .. })));
+        assert_eq!(ingest.latest("web", "web-0", now).unwrap().clients, Some(1));
+
+        ingest.push("web", &push("web-1", now), now).unwrap();
+        let full = ingest.push("web", &push("web-2", now), now);
+        assert!(matches!(full, Err(IngestError::TooManyInstances { max: 2 })));
+        // The cap is per app, a full one doesn't keep the others out
+        ingest.push("api", &push("api-0", now), now).unwrap();
+        // Known instances still report, and room is made once the others went quiet
+        ingest.push("web", &push("web-1", now), now).unwrap();
+        let later = now + Duration::seconds(RETENTION_SECS + 60);
+        ingest.push("web", &push("web-2", later), later).unwrap();
+        assert!(ingest.latest("web", "web-0", later).is_none());
+        assert_eq!(ingest.samples.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn only_the_fleets_instances_report_for_its_apps() {
+        let dir = std::env::temp_dir().join(format!("omniforge-ingest-fleet-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let history = MetricHistory::new(&dir);
+        let ingest = ingest().with_max_instances(1);
+        let now = Utc::now();
+        let push = |instance: &str, clients: f64| Push {
+            instance: instance.to_string(),
+            at: None,
+            metrics: HashMap::from([("clients".to_string(), clients)]),
+        };
+        ingest.push("web", &push("made-up", 1000.0), now).unwrap();
+
+        // What the made-up instance reported is dropped once the fleet names its instances,
+        // which aren't held to the cap
+        ingest.set_instances("web", &["web-0".to_string(), "web-1".to_string()]);
+        ingest.push("web", &push("web-0", 30.0), now).unwrap();
+        ingest.push("web", &push("web-1", 12.0), now).unwrap();
+        let unknown = ingest.push("web", &push("made-up", 1000.0), now);
+        assert!(matches!(unknown, Err(IngestError::UnknownInstance { .. })));
+
+        assert_eq!(ingest.record_history(&history, now), ["web"]);
+        let series = history.series("web", Metric::Clients).unwrap();
+        assert_eq!(series.iter().map(|(_, value)| *value).collect::<Vec<_>>(), [42.0]);
+        assert_eq!(ingest.history("web", now - Duration::seconds(RETENTION_SECS)).len(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn scrapes_metrics_endpoints() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: {}\r\n\r\n{}",
+                EXPOSITION.len(),
+                EXPOSITION
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+
+        let ingest = ingest();
+        let target = ScrapeTarget {
+            app_id: "web".to_string(),
+            instance: "web-0".to_string(),
+            url: format!("http://127.0.0.1:{}/metrics", port),
+            interval_secs: 5,
+        };
+        let now = Utc::now();
+        ingest.scrape(&target, now).await.unwrap();
+        assert_eq!(ingest.latest("web", "web-0", now).unwrap().clients, Some(32));
+
+        // Nothing listens there anymore
+        assert!(matches!(ingest.scrape(&target, now).await, Err(IngestError::Scrape { .. })));
+    }
+}
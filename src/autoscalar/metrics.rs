@@ -0,0 +1,405 @@
+//-------------------------------------------------------------------------
+// Metrics collection into InstanceMetrics. A MetricsSource takes raw
+// samples, either from a cgroup v2 directory (cpu.stat, memory.current,
+// memory.pressure, io.stat) or from the container engine's `stats` over
+// SSH. CPU load and disk bandwidth are rates, so the collector keeps the
+// previous sample and works them out from the difference; the very first
+// collection takes two samples a short window apart.
+//-------------------------------------------------------------------------
+
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+
+use async_trait::async_trait;
+use thiserror::Error;
+
+use super::InstanceMetrics;
+use crate::image_builder::engine::ContainerEngineKind;
+use crate::ssh::{ shell_quote, Host, SessionPool, SshError };
+
+/// Where cgroup v2 is mounted
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+#[derive(Debug, Error)]
+pub enum MetricsError {
+    #[error("failed to read {path}: {source}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("could not parse {what}: {detail}")]
+    Parse { what: String, detail: String },
+    #[error("no cgroup found for {0}")]
+    NoCgroup(String),
+    #[error(transparent)]
+    Ssh(#[from] SshError),
+    #[error("`{command}` exited with {status:?}: {stderr}")]
+    CommandFailed { command: String, status: Option<u32>, stderr: String },
+}
+
+/// Raw readings at one point in time. Counters are cumulative, `None` when the source can't tell.
+///
+/// # Fields
+/// cpu_usage_usec - CPU time used so far
+/// cpu_percent - CPU load the source already averaged, in percent of one core
+/// memory_bytes - Memory in use right now
+/// memory_pressure - Share of the last 10s some task stalled on memory, in percent
+/// io_bytes - Bytes read and written so far
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sample {
+    pub cpu_usage_usec: Option<u64>,
+    pub cpu_percent: Option<f64>,
+    pub memory_bytes: Option<u64>,
+    pub memory_pressure: Option<f64>,
+    pub io_bytes: Option<u64>,
+}
+
+impl Sample {
+    /// Turns two samples taken `elapsed` apart into metrics. Units follow `AppInstance`:
+    /// CPU in percent of one core, memory in MB, disk bandwidth in MB/s.
+    pub fn metrics(&self, previous: &Sample, elapsed: Duration) -> InstanceMetrics {
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        let rate = |current: Option<u64>, previous: Option<u64>| {
+            Some(current?.saturating_sub(previous?) as f64 / seconds)
+        };
+
+        let cpu_load = self.cpu_percent.or_else(|| {
+            rate(self.cpu_usage_usec, previous.cpu_usage_usec).map(|usec_per_sec| usec_per_sec / 10_000.0)
+        });
+        InstanceMetrics {
+            cpu_load: cpu_load.map(|percent| percent.round() as u64),
+            ram_pressure: self.memory_pressure.map(|percent| percent.round() as u64),
+            ram_usage: self.memory_bytes.map(|bytes| (bytes as f64 / MIB).round() as u64),
+            disk_bandwidth: rate(self.io_bytes, previous.io_bytes).map(|bytes| (bytes / MIB).round() as u64),
+            ..InstanceMetrics::default()
+        }
+    }
+}
+
+/// Anything that can take a raw sample of an instance
+#[async_trait]
+pub trait MetricsSource: Send + Sync {
+    async fn sample(&self) -> Result<Sample, MetricsError>;
+}
+
+/// Reads a cgroup v2 directory
+pub struct CgroupSource {
+    dir: PathBuf,
+}
+
+impl CgroupSource {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        CgroupSource { dir: dir.as_ref().to_path_buf() }
+    }
+
+    /// The cgroup a process lives in, from the `0::` line of /proc/<pid>/cgroup
+    pub fn for_pid(pid: u32) -> Result<Self, MetricsError> {
+        let path = PathBuf::from(format!("/proc/{}/cgroup", pid));
+        let content = read(&path)?;
+        let relative = content
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .ok_or_else(|| MetricsError::NoCgroup(format!("pid {} (not on cgroup v2)", pid)))?;
+        Ok(CgroupSource::new(Path::new(CGROUP_ROOT).join(relative.trim_start_matches('/'))))
+    }
+
+    /// A container's cgroup under `root`, trying the layouts of the systemd and cgroupfs drivers
+    pub fn for_container(root: &Path, engine: ContainerEngineKind, id: &str) -> Result<Self, MetricsError> {
+        let candidates = match engine {
+            ContainerEngineKind::Docker => vec![
+                format!("system.slice/docker-{}.scope", id),
+                format!("docker/{}", id),
+            ],
+            ContainerEngineKind::Podman => vec![
+                format!("machine.slice/libpod-{}.scope", id),
+                format!("libpod_parent/libpod-{}", id),
+            ],
+            ContainerEngineKind::Nerdctl => vec![
+                format!("system.slice/nerdctl-{}.scope", id),
+                format!("default/{}", id),
+            ],
+        };
+        candidates
+            .into_iter()
+            .map(|candidate| root.join(candidate))
+            .find(|dir| dir.join("cgroup.controllers").exists())
+            .map(CgroupSource::new)
+            .ok_or_else(|| MetricsError::NoCgroup(format!("{} container {}", engine, id)))
+    }
+
+    fn read_file(&self, name: &str) -> Result<Option<String>, MetricsError> {
+        let path = self.dir.join(name);
+        match fs::read_to_string(&path) {
+            Ok(content) => Ok(Some(content)),
+            // Controllers that aren't enabled for this cgroup simply don't have their files
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(source) => Err(MetricsError::Io { path, source }),
+        }
+    }
+}
+
+#[async_trait]
+impl MetricsSource for CgroupSource {
+    async fn sample(&self) -> Result<Sample, MetricsError> {
+        if !self.dir.is_dir() {
+            return Err(MetricsError::NoCgroup(self.dir.display().to_string()));
+        }
+        Ok(Sample {
+            cpu_usage_usec: self.read_file("cpu.stat")?.as_deref().map(parse_cpu_stat).transpose()?,
+            cpu_percent: None,
+            memory_bytes: self
+                .read_file("memory.current")?
+                .map(|content| parse_number("memory.current", content.trim()))
+                .transpose()?,
+            memory_pressure: self.read_file("memory.pressure")?.as_deref().map(parse_pressure).transpose()?,
+            io_bytes: self.read_file("io.stat")?.as_deref().map(parse_io_stat).transpose()?,
+        })
+    }
+}
+
+fn read(path: &Path) -> Result<String, MetricsError> {
+    fs::read_to_string(path).map_err(|source| MetricsError::Io { path: path.to_path_buf(), source })
+}
+
+fn parse_number<T: std::str::FromStr>(what: &str, value: &str) -> Result<T, MetricsError> {
+    value.parse().map_err(|_| MetricsError::Parse { what: what.to_string(), detail: format!("`{}`", value) })
+}
+
+/// `usage_usec` from cpu.stat
+pub fn parse_cpu_stat(content: &str) -> Result<u64, MetricsError> {
+    let value = content
+        .lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .ok_or_else(|| MetricsError::Parse { what: "cpu.stat".to_string(), detail: "no usage_usec".to_string() })?;
+    parse_number("cpu.stat", value.trim())
+}
+
+/// `avg10` of the `some` line of a PSI file
+pub fn parse_pressure(content: &str) -> Result<f64, MetricsError> {
+    let value = content
+        .lines()
+        .find_map(|line| line.strip_prefix("some "))
+        .and_then(|line| line.split_whitespace().find_map(|field| field.strip_prefix("avg10=")))
+        .ok_or_else(|| MetricsError::Parse { what: "memory.pressure".to_string(), detail: "no some avg10".to_string() })?;
+    parse_number("memory.pressure", value)
+}
+
+/// `rbytes` plus `wbytes` over every device in io.stat
+pub fn parse_io_stat(content: &str) -> Result<u64, MetricsError> {
+    let mut total = 0u64;
+    for field in content.split_whitespace() {
+        if let Some(value) = field.strip_prefix("rbytes=").or_else(|| field.strip_prefix("wbytes=")) {
+            total += parse_number::<u64>("io.stat", value)?;
+        }
+    }
+    Ok(total)
+}
+
+/// Reads `<engine> stats` for a container over SSH
+pub struct EngineStatsSource {
+    pool: Arc<SessionPool>,
+    host: Host,
+    engine: ContainerEngineKind,
+    container: String,
+}
+
+impl EngineStatsSource {
+    pub fn new(pool: Arc<SessionPool>, host: Host, engine: ContainerEngineKind, container: &str) -> Self {
+        EngineStatsSource { pool, host, engine, container: container.to_string() }
+    }
+}
+
+#[async_trait]
+impl MetricsSource for EngineStatsSource {
+    async fn sample(&self) -> Result<Sample, MetricsError> {
+        let command = format!(
+            "{} stats --no-stream --format {} {}",
+            self.engine.binary(),
+            shell_quote("{{.CPUPerc}}|{{.MemUsage}}|{{.MemPerc}}|{{.BlockIO}}"),
+            shell_quote(&self.container)
+        );
+        let output = self.pool.exec(&self.host, &command).await?;
+        if !output.success() {
+            return Err(MetricsError::CommandFailed {
+                command,
+                status: output.exit_status,
+                stderr: output.stderr_str().trim().to_string(),
+            });
+        }
+        parse_engine_stats(&output.stdout_str())
+    }
+}
+
+/// Parses one `CPUPerc|MemUsage|MemPerc|BlockIO` line, e.g. `12.5%|100MiB / 1GiB|9.77%|1.2MB / 3.4MB`.
+/// The engines don't report PSI, so memory pressure is the share of the memory limit in use.
+pub fn parse_engine_stats(output: &str) -> Result<Sample, MetricsError> {
+    let line = output.lines().next().unwrap_or_default().trim();
+    let fields: Vec<&str> = line.split('|').map(str::trim).collect();
+    let [cpu, mem_usage, mem_percent, block_io] = fields[..] else {
+        return Err(MetricsError::Parse { what: "container stats".to_string(), detail: format!("`{}`", line) });
+    };
+    let percent = |value: &str| parse_number::<f64>("container stats", value.trim_end_matches('%'));
+    let used = |value: &str| parse_size(value.split('/').next().unwrap_or_default());
+
+    let (read, written) = block_io.split_once('/').unwrap_or((block_io, "0B"));
+    Ok(Sample {
+        cpu_usage_usec: None,
+        cpu_percent: Some(percent(cpu)?),
+        memory_bytes: Some(used(mem_usage)?),
+        memory_pressure: Some(percent(mem_percent)?),
+        io_bytes: Some(parse_size(read)? + parse_size(written)?),
+    })
+}
+
+/// Parses sizes as the engines print them: `0B`, `1.5kB`, `100MiB`, `3.4GB`
+pub fn parse_size(value: &str) -> Result<u64, MetricsError> {
+    let value = value.trim();
+    let split = value.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier: f64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1.0,
+        "kb" => 1e3,
+        "mb" => 1e6,
+        "gb" => 1e9,
+        "tb" => 1e12,
+        "kib" => 1024.0,
+        "mib" => MIB,
+        "gib" => MIB * 1024.0,
+        "tib" => MIB * MIB,
+        _ => {
+            return Err(MetricsError::Parse { what: "size".to_string(), detail: format!("`{}`", value) });
+        }
+    };
+    Ok((parse_number::<f64>("size", number.trim())? * multiplier).round() as u64)
+}
+
+/// Turns a source's samples into InstanceMetrics, remembering the last sample for the rates
+pub struct MetricsCollector<S: MetricsSource> {
+    source: S,
+    window: Duration,
+    last: Mutex<Option<(Sample, Instant)>>,
+}
+
+impl<S: MetricsSource> MetricsCollector<S> {
+    /// `window` is how far apart the two samples of the first collection are
+    pub fn new(source: S, window: Duration) -> Self {
+        MetricsCollector { source, window, last: Mutex::new(None) }
+    }
+
+    pub async fn collect(&self) -> Result<InstanceMetrics, MetricsError> {
+        let previous = self.last.lock().unwrap().take();
+        let (previous, taken_at) = match previous {
+            Some(previous) => previous,
+            None => {
+                let first = self.source.sample().await?;
+                let taken_at = Instant::now();
+                tokio::time::sleep(self.window).await;
+                (first, taken_at)
+            }
+        };
+
+        let current = self.source.sample().await?;
+        let now = Instant::now();
+        let metrics = current.metrics(&previous, now - taken_at);
+        *self.last.lock().unwrap() = Some((current, now));
+        Ok(metrics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a cgroup v2 directory like the kernel would present it
+    fn fixture(name: &str, usage_usec: u64, memory: u64, io: (u64, u64)) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("omniforge-cgroup-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cgroup.controllers"), "cpu io memory pids\n").unwrap();
+        fs::write(
+            dir.join("cpu.stat"),
+            format!("usage_usec {}\nuser_usec 900\nsystem_usec 100\nnr_periods 0\n", usage_usec)
+        ).unwrap();
+        fs::write(dir.join("memory.current"), format!("{}\n", memory)).unwrap();
+        fs::write(
+            dir.join("memory.pressure"),
+            "some avg10=12.50 avg60=3.00 avg300=1.00 total=120000\nfull avg10=2.00 avg60=0.00 avg300=0.00 total=4000\n"
+        ).unwrap();
+        fs::write(
+            dir.join("io.stat"),
+            format!(
+                "8:0 rbytes={} wbytes={} rios=10 wios=20 dbytes=0 dios=0\n253:0 rbytes=0 wbytes={} rios=0 wios=1 dbytes=0 dios=0\n",
+                io.0,
+                io.1,
+                io.1
+            )
+        ).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn cgroup_samples_fill_every_field() {
+        let before = CgroupSource::new(fixture("before", 1_000_000, 256 * 1024 * 1024, (0, 0))).sample().await.unwrap();
+        assert_eq!(before.memory_pressure, Some(12.5));
+        assert_eq!(before.io_bytes, Some(0));
+
+        // 3s of CPU and 10 MiB of IO over 2s
+        let after_dir = fixture("after", 4_000_000, 512 * 1024 * 1024, (4 * 1024 * 1024, 3 * 1024 * 1024));
+        let after = CgroupSource::new(&after_dir).sample().await.unwrap();
+        let metrics = after.metrics(&before, Duration::from_secs(2));
+        assert_eq!(metrics.cpu_load, Some(150));
+        assert_eq!(metrics.ram_pressure, Some(13));
+        assert_eq!(metrics.ram_usage, Some(512));
+        assert_eq!(metrics.disk_bandwidth, Some(5));
+
+        // Without PSI the field stays empty rather than failing the whole sample
+        fs::remove_file(after_dir.join("memory.pressure")).unwrap();
+        assert_eq!(CgroupSource::new(&after_dir).sample().await.unwrap().memory_pressure, None);
+
+        let missing = CgroupSource::for_container(&std::env::temp_dir(), ContainerEngineKind::Docker, "nope");
+        assert!(matches!(missing, Err(MetricsError::NoCgroup(_))));
+    }
+
+    #[tokio::test]
+    async fn collector_works_out_rates_from_engine_stats() {
+        struct Stats(Mutex<Vec<&'static str>>);
+
+        #[async_trait]
+        impl MetricsSource for Stats {
+            async fn sample(&self) -> Result<Sample, MetricsError> {
+                parse_engine_stats(self.0.lock().unwrap().remove(0))
+            }
+        }
+
+        let collector = MetricsCollector::new(
+            Stats(Mutex::new(vec![
+                "0.50%|100MiB / 1GiB|9.77%|1MB / 0B",
+                "42.25%|1.5GiB / 2GiB|75.00%|1MB / 0B",
+            ])),
+            Duration::from_millis(10)
+        );
+        let metrics = collector.collect().await.unwrap();
+        assert_eq!(metrics.cpu_load, Some(42));
+        assert_eq!(metrics.ram_usage, Some(1536));
+        assert_eq!(metrics.ram_pressure, Some(75));
+        assert_eq!(metrics.disk_bandwidth, Some(0));
+
+        assert!(parse_engine_stats("garbage").is_err());
+        assert_eq!(parse_size("1.5kB").unwrap(), 1500);
+    }
+
+    #[test]
+    fn set_field_round_trips_get_fields() {
+        let source = InstanceMetrics::new(Some(1), Some(2), Some(3), Some(4), Some(5), Some(6), Some(7));
+        let mut copy = InstanceMetrics::default();
+        for (name, value) in source.get_fields() {
+            copy.set_field(name, value);
+        }
+        assert_eq!(copy, source);
+    }
+}
@@ -1,7 +1,18 @@
+pub mod forecast;
+pub mod health;
+pub mod ingest;
+pub mod metrics;
+pub mod policy;
+pub mod replicas;
+pub mod simulator;
+pub mod stabilization;
+
 use std::sync::{ Arc, Mutex };
-use std::collections::HashMap;
+use std::collections::{ BTreeMap, HashMap };
 use std::time::Duration;
 use anyhow::Result;
+use serde::{ Deserialize, Serialize };
+use tracing::{ debug, error, warn };
 
 /// A struct that represents the metrics that can be gathered from an AppInstance
 /// # Fields
@@ -13,6 +24,7 @@ use anyhow::Result;
 /// * `app_response_time` - For more advanced use cases in which an in-container monitor exists to monitor app response time
 /// * `network_latency` - For more advanced use cases in which an in-container monitor exists to monitor external response time
 /// * `disk_bandwidth` - Generally applicable disk bandwidth usage for any container (Gathered from the runtime if supported)
+/// * `custom` - Any other metric the app pushes or exposes, by name (see `ingest`)
 ///
 /// # Example
 /// ```
@@ -24,17 +36,20 @@ use anyhow::Result;
 ///    app_response_time: Some(50),
 ///    network_latency: Some(50),
 ///    disk_bandwidth: Some(50),
+///    custom: BTreeMap::new(),
 /// };
 /// ```
-#[derive(Default, Debug)]
-struct InstanceMetrics {
-    cpu_load:          Option<u64>,
-    ram_pressure:      Option<u64>,
-    ram_usage:         Option<u64>,
-    clients:           Option<u64>,
-    app_response_time: Option<u64>,
-    network_latency:   Option<u64>,
-    disk_bandwidth:    Option<u64>,
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstanceMetrics {
+    pub cpu_load:          Option<u64>,
+    pub ram_pressure:      Option<u64>,
+    pub ram_usage:         Option<u64>,
+    pub clients:           Option<u64>,
+    pub app_response_time: Option<u64>,
+    pub network_latency:   Option<u64>,
+    pub disk_bandwidth:    Option<u64>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub custom:            BTreeMap<String, f64>,
 }
 
 impl InstanceMetrics {
@@ -55,6 +70,7 @@ impl InstanceMetrics {
             app_response_time,
             network_latency,
             disk_bandwidth,
+            custom: BTreeMap::new(),
         }
     }
     fn get_fields(&self) -> Vec<(&str, Option<u64>)> {
@@ -65,13 +81,29 @@ impl InstanceMetrics {
             ("clients", self.clients),
             ("app_response_time", self.app_response_time),
             ("network_latency", self.network_latency),
-            ("disk_bandwith", self.disk_bandwidth)
+            ("disk_bandwidth", self.disk_bandwidth)
         ]
     }
-    fn set_field(&mut self, field_name: &str, field_value: Option<u64>) {}
+    fn set_field(&mut self, field_name: &str, field_value: Option<u64>) {
+        let field = match field_name {
+            "cpu_load" => &mut self.cpu_load,
+            "ram_pressure" => &mut self.ram_pressure,
+            "ram_usage" => &mut self.ram_usage,
+            "clients" => &mut self.clients,
+            "app_response_time" => &mut self.app_response_time,
+            "network_latency" => &mut self.network_latency,
+            "disk_bandwidth" => &mut self.disk_bandwidth,
+            _ => {
+                warn!("Unknown metric field {}", field_name);
+                return;
+            }
+        };
+        *field = field_value;
+    }
 }
 
-enum ScaleAction {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScaleAction {
     ScaleUp,
     ScaleDown,
     ScaleLeft,
@@ -79,67 +111,129 @@ enum ScaleAction {
     NoAction,
 }
 
-#[derive(Hash, Eq, PartialEq, Debug)]
-enum ResourceType {
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+pub enum ResourceType {
     CPU,
     RAM,
     Clients,
     ResponseTime,
 }
 
-struct AutoscalerThresholds {
-    cpu_threshold:           u64,
-    ram_threshold:           u64,
-    client_threshold:        u64,
-    response_time_threshold: u64,
+/// Separate thresholds per direction. Scale up above `up`, down below `down`, and leave
+/// anything in between alone so a value hovering around one threshold doesn't flap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThresholdBand {
+    pub up:   u64,
+    pub down: u64,
+}
+
+impl ThresholdBand {
+    /// The old single threshold: up above it, down below half of it
+    pub fn from_threshold(threshold: u64) -> Self {
+        ThresholdBand { up: threshold, down: threshold / 2 }
+    }
+
+    fn decide(&self, value: Option<u64>, up: ScaleAction, down: ScaleAction) -> ScaleAction {
+        match value {
+            Some(value) if value > self.up => up,
+            Some(value) if value < self.down => down,
+            _ => ScaleAction::NoAction,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AutoscalerThresholds {
+    cpu:           ThresholdBand,
+    ram:           ThresholdBand,
+    clients:       ThresholdBand,
+    response_time: ThresholdBand,
+}
+
+/// Bands nothing ever leaves, so no instance is ever resized
+impl Default for AutoscalerThresholds {
+    fn default() -> Self {
+        let never = ThresholdBand { up: u64::MAX, down: 0 };
+        AutoscalerThresholds { cpu: never, ram: never, clients: never, response_time: never }
+    }
 }
 
 impl AutoscalerThresholds {
-    fn new(
+    pub fn new(
         cpu_threshold:           u64,
         ram_threshold:           u64,
         client_threshold:        u64,
         response_time_threshold: u64,
     ) -> Self {
         AutoscalerThresholds {
-            cpu_threshold,
-            ram_threshold,
-            client_threshold,
-            response_time_threshold,
+            cpu:           ThresholdBand::from_threshold(cpu_threshold),
+            ram:           ThresholdBand::from_threshold(ram_threshold),
+            clients:       ThresholdBand::from_threshold(client_threshold),
+            response_time: ThresholdBand::from_threshold(response_time_threshold),
         }
     }
 
-    fn decide_all(&self, metrics: &InstanceMetrics) -> HashMap<ResourceType, ScaleAction> {
+    /// Checks what deserializing can't, that every `down` is below its `up`
+    pub fn validate(&self) -> Result<()> {
+        for (name, band) in [("cpu", self.cpu), ("ram", self.ram), ("clients", self.clients), ("response_time", self.response_time)] {
+            if band.down >= band.up {
+                anyhow::bail!("{} threshold: down ({}) must be below up ({})", name, band.down, band.up);
+            }
+        }
+        Ok(())
+    }
+
+    /// Thresholds with explicit up and down values, every `down` has to be below its `up`
+    pub fn with_bands(
+        cpu:           ThresholdBand,
+        ram:           ThresholdBand,
+        clients:       ThresholdBand,
+        response_time: ThresholdBand,
+    ) -> Result<Self> {
+        let thresholds = AutoscalerThresholds { cpu, ram, clients, response_time };
+        thresholds.validate()?;
+        Ok(thresholds)
+    }
+
+    pub fn decide_all(&self, metrics: &InstanceMetrics) -> HashMap<ResourceType, ScaleAction> {
         let mut actions = HashMap::new();
 
-        let cpu_action = match metrics.cpu_load {
-            Some(cpu_load) if cpu_load > self.cpu_threshold => ScaleAction::ScaleUp,
-            Some(cpu_load) if cpu_load < self.cpu_threshold / 2 => ScaleAction::ScaleDown,
-            _ => ScaleAction::NoAction,
-        };
+        let cpu_action = self.cpu.decide(metrics.cpu_load, ScaleAction::ScaleUp, ScaleAction::ScaleDown);
         actions.insert(ResourceType::CPU, cpu_action);
 
-        let ram_action = match metrics.ram_usage {
-            Some(ram_usage) if ram_usage > self.ram_threshold => ScaleAction::ScaleUp,
-            Some(ram_usage) if ram_usage < self.ram_threshold / 2 => ScaleAction::ScaleLeft,
-            _ => ScaleAction::NoAction,
-        };
+        let ram_action = self.ram.decide(metrics.ram_usage, ScaleAction::ScaleUp, ScaleAction::ScaleLeft);
         actions.insert(ResourceType::RAM, ram_action);
 
-        let client_action = match metrics.clients {
-            Some(clients) if clients > self.client_threshold => ScaleAction::ScaleRight,
-            _ => ScaleAction::NoAction,
-        };
+        // Clients and response time only ever ask for more network
+        let client_action = self.clients.decide(metrics.clients, ScaleAction::ScaleRight, ScaleAction::NoAction);
         actions.insert(ResourceType::Clients, client_action);
 
-        let response_time_action = match metrics.app_response_time {
-            Some(response_time) if response_time > self.response_time_threshold => ScaleAction::ScaleRight,
-            _ => ScaleAction::NoAction,
-        };
+        let response_time_action =
+            self.response_time.decide(metrics.app_response_time, ScaleAction::ScaleRight, ScaleAction::NoAction);
         actions.insert(ResourceType::ResponseTime, response_time_action);
 
         actions // Return the actions to complete for each scaling category
     }
+
+    /// Decides over a window of samples, e.g. from `query_over_period`. A resource only gets an
+    /// action when every sample in the window asks for the same one.
+    pub fn decide_window(
+        &self,
+        samples: &[(InstanceMetrics, chrono::DateTime<chrono::Utc>)]
+    ) -> HashMap<ResourceType, ScaleAction> {
+        let decisions: Vec<HashMap<ResourceType, ScaleAction>> =
+            samples.iter().map(|(metrics, _)| self.decide_all(metrics)).collect();
+
+        [ResourceType::CPU, ResourceType::RAM, ResourceType::Clients, ResourceType::ResponseTime]
+            .into_iter()
+            .map(|resource| {
+                let mut actions = decisions.iter().map(|decision| decision[&resource]);
+                let first = actions.next().unwrap_or(ScaleAction::NoAction);
+                let unanimous = actions.all(|action| action == first);
+                (resource, if unanimous { first } else { ScaleAction::NoAction })
+            })
+            .collect()
+    }
 }
 
 /// A struct that represents an AppInstance
@@ -158,12 +252,13 @@ impl AutoscalerThresholds {
 ///   allocated_network_bandwidth: 100,
 /// };
 /// ```
-struct AppInstance {
-    state: ApplicationState,
-    allocated_memory:            u64,
-    allocated_cpu:               u64,
-    allocated_disk_bandwidth:    u64,
-    allocated_network_bandwidth: u64,
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AppInstance {
+    pub state: ApplicationState,
+    pub allocated_memory:            u64,
+    pub allocated_cpu:               u64,
+    pub allocated_disk_bandwidth:    u64,
+    pub allocated_network_bandwidth: u64,
 }
 
 /// An enum that represents the state of an application
@@ -173,12 +268,15 @@ struct AppInstance {
 /// Erroneous - Host is exhibiting unexpected behavior and has been lowered on the load balancers priority list, avoiding routing traffic to the erroneous host if possible.
 /// Blacklisted - Host has been blacklisted from the network by an operator.
 /// Down - Host is offline or otherwise unreachable. (Its probably DNS's fault)
+///
+/// Set by `health::HealthTracker` from probe results, except Blacklisted which only operators set.
 /// 
 /// # Example
 /// ```
 /// let state = ApplicationState::Healthy;
 /// ```
-enum ApplicationState {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ApplicationState {
     Healthy,
     Suspicious,
     Erroneous,
@@ -248,10 +346,10 @@ impl AutoScaler for ExampleScaler {
     fn query(&self) -> InstanceMetrics {
         let mut metric_collection = InstanceMetrics::new(None, None, None, None, None, None, None);
         for field in self.metrics.try_lock().unwrap().get_fields() {
-            println!("{field:#?}");
+            debug!("{field:#?}");
             metric_collection.set_field(field.0, field.1);
         }
-        println!("{:#?}", self.metrics.lock().unwrap().get_fields());
+        debug!("{:#?}", self.metrics.lock().unwrap().get_fields());
 
         metric_collection
     }
@@ -297,7 +395,7 @@ impl AutoScaler for ExampleScaler {
         if let Ok(mut metrics) = self.metrics.lock() {
             metrics.ram_usage = Some(megabytes);
         } else {
-            eprintln!("Failed to acquire lock for reallocate_memory");
+            error!("Failed to acquire lock for reallocate_memory");
         }
     }
     
@@ -305,7 +403,7 @@ impl AutoScaler for ExampleScaler {
         if let Ok(mut metrics) = self.metrics.lock() {
             metrics.cpu_load = Some(percentage);
         } else {
-            eprintln!("Failed to acquire lock for reallocate_cpu");
+            error!("Failed to acquire lock for reallocate_cpu");
         }
     }
     
@@ -313,7 +411,7 @@ impl AutoScaler for ExampleScaler {
         if let Ok(mut metrics) = self.metrics.lock() {
             metrics.disk_bandwidth = Some(megabytes_per_second);
         } else {
-            eprintln!("Failed to acquire lock for reallocate_disk_bandwidth");
+            error!("Failed to acquire lock for reallocate_disk_bandwidth");
         }
     }
     
@@ -321,7 +419,7 @@ impl AutoScaler for ExampleScaler {
         if let Ok(mut metrics) = self.metrics.lock() {
             metrics.network_latency = Some(megabytes_per_second);
         } else {
-            eprintln!("Failed to acquire lock for reallocate_network_bandwidth");
+            error!("Failed to acquire lock for reallocate_network_bandwidth");
         }
     }
 }
@@ -342,9 +440,49 @@ fn run() {
             ScaleAction::NoAction => println!("No scaling action needed for {:?}.", resource),
         }
     }
+
+    let actions = autoscaler.decide_all(&metrics);
+    // CPU sits between 40 and 80, memory is above 75, clients and response time are over their thresholds
+    assert_eq!(actions[&ResourceType::CPU], ScaleAction::NoAction);
+    assert_eq!(actions[&ResourceType::RAM], ScaleAction::ScaleUp);
+    assert_eq!(actions[&ResourceType::Clients], ScaleAction::ScaleRight);
+    assert_eq!(actions[&ResourceType::ResponseTime], ScaleAction::ScaleRight);
 }
 
 //#[cfg(test)]
 fn test(scaler: impl AutoScaler) {
     scaler.query();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{ DateTime, Utc };
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    #[test]
+    fn thresholds_decide_over_a_window_with_hysteresis() {
+        let band = ThresholdBand { up: 80, down: 40 };
+        let thresholds = AutoscalerThresholds::with_bands(band, ThresholdBand { up: 4096, down: 512 }, band, band).unwrap();
+        let window = |loads: &[u64]| -> Vec<(InstanceMetrics, DateTime<Utc>)> {
+            loads
+                .iter()
+                .enumerate()
+                .map(|(i, load)| {
+                    (InstanceMetrics { cpu_load: Some(*load), ..InstanceMetrics::default() }, at(i as i64))
+                })
+                .collect()
+        };
+
+        assert_eq!(thresholds.decide_window(&window(&[90, 95, 85]))[&ResourceType::CPU], ScaleAction::ScaleUp);
+        // One sample in the band breaks the streak
+        assert_eq!(thresholds.decide_window(&window(&[90, 60, 85]))[&ResourceType::CPU], ScaleAction::NoAction);
+        assert_eq!(thresholds.decide_window(&window(&[30, 20, 35]))[&ResourceType::CPU], ScaleAction::ScaleDown);
+        assert_eq!(thresholds.decide_window(&[])[&ResourceType::CPU], ScaleAction::NoAction);
+
+        assert!(AutoscalerThresholds::with_bands(ThresholdBand { up: 50, down: 50 }, band, band, band).is_err());
+    }
+}
@@ -0,0 +1,524 @@
+//-------------------------------------------------------------------------
+// Declarative scaling policies. A policy is a per-app TOML or JSON file
+// holding rules; each rule aggregates one metric over a trailing window
+// (avg, p95 or max over every sample of every instance in it), compares
+// the result against a threshold and names the action to take when it
+// holds. Rules are combined by priority: the highest-priority firing rule
+// decides the replica count, and vertical actions are taken from the
+// firing rules in priority order, skipping ones that contradict an action
+// already taken. Policies are validated when loaded so a broken file is
+// rejected with the rule and the reason, not silently ignored. Besides the
+// InstanceMetrics fields a rule can use any metric the app pushes or
+// exposes itself, named `custom:<name>`.
+//
+// Example:
+//
+//   [[rules]]
+//   name = "cpu-hot"
+//   priority = 100
+//   metric = "cpu_load"
+//   aggregation = "p95"
+//   comparator = ">"
+//   threshold = 80
+//   duration_secs = 120
+//   action = { type = "scale_out", by = 2 }
+//-------------------------------------------------------------------------
+
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+
+use chrono::{ DateTime, Duration, Utc };
+use serde::{ Deserialize, Serialize };
+use thiserror::Error;
+
+use super::{ InstanceMetrics, ScaleAction };
+
+#[derive(Debug, Error)]
+pub enum PolicyError {
+    #[error("failed to read scaling policy {path}: {source}")]
+    Io { path: PathBuf, source: std::io::Error },
+    #[error("scaling policy {path} has no .toml or .json extension")]
+    UnknownFormat { path: PathBuf },
+    #[error("invalid TOML scaling policy: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("invalid JSON scaling policy: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("scaling policy has no rules")]
+    NoRules,
+    #[error("rule #{index} has an empty name")]
+    EmptyName { index: usize },
+    #[error("rule `{0}` is defined more than once")]
+    DuplicateName(String),
+    #[error("rules `{first}` and `{second}` both have priority {priority}, precedence must be explicit")]
+    DuplicatePriority { priority: u32, first: String, second: String },
+    #[error("rule `{rule}`: {reason}")]
+    InvalidRule { rule: String, reason: String },
+}
+
+/// Any of the InstanceMetrics fields, named the same
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    CpuLoad,
+    RamPressure,
+    RamUsage,
+    Clients,
+    AppResponseTime,
+    NetworkLatency,
+    DiskBandwidth,
+}
+
+impl std::str::FromStr for Metric {
+    type Err = serde_json::Error;
+
+    /// The same names as in policy files, e.g. `cpu_load`
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(name.to_string()))
+    }
+}
+
+impl Metric {
+    /// The InstanceMetrics field name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::CpuLoad => "cpu_load",
+            Metric::RamPressure => "ram_pressure",
+            Metric::RamUsage => "ram_usage",
+            Metric::Clients => "clients",
+            Metric::AppResponseTime => "app_response_time",
+            Metric::NetworkLatency => "network_latency",
+            Metric::DiskBandwidth => "disk_bandwidth",
+        }
+    }
+
+    pub fn value(&self, metrics: &InstanceMetrics) -> Option<u64> {
+        match self {
+            Metric::CpuLoad => metrics.cpu_load,
+            Metric::RamPressure => metrics.ram_pressure,
+            Metric::RamUsage => metrics.ram_usage,
+            Metric::Clients => metrics.clients,
+            Metric::AppResponseTime => metrics.app_response_time,
+            Metric::NetworkLatency => metrics.network_latency,
+            Metric::DiskBandwidth => metrics.disk_bandwidth,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    Avg,
+    P95,
+    Max,
+}
+
+impl Aggregation {
+    /// `None` for no values. P95 is nearest-rank.
+    pub fn apply(&self, values: &mut [f64]) -> Option<f64> {
+        if values.is_empty() {
+            return None;
+        }
+        let value = match self {
+            Aggregation::Avg => values.iter().sum::<f64>() / values.len() as f64,
+            Aggregation::Max => values.iter().copied().max_by(f64::total_cmp)?,
+            Aggregation::P95 => {
+                values.sort_unstable_by(f64::total_cmp);
+                let rank = (values.len() as f64 * 0.95).ceil() as usize;
+                values[rank.saturating_sub(1)]
+            }
+        };
+        Some(value)
+    }
+}
+
+const CUSTOM_PREFIX: &str = "custom:";
+
+/// What a rule looks at: one of the InstanceMetrics fields by name, or `custom:<name>` for
+/// a metric the app reports itself
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum RuleMetric {
+    Builtin(Metric),
+    Custom(String),
+}
+
+impl RuleMetric {
+    pub fn value(&self, metrics: &InstanceMetrics) -> Option<f64> {
+        match self {
+            RuleMetric::Builtin(metric) => metric.value(metrics).map(|value| value as f64),
+            RuleMetric::Custom(name) => metrics.custom.get(name).copied(),
+        }
+    }
+}
+
+impl TryFrom<String> for RuleMetric {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        match name.strip_prefix(CUSTOM_PREFIX) {
+            Some("") => Err(format!("`{}` needs a metric name", name)),
+            Some(custom) => Ok(RuleMetric::Custom(custom.to_string())),
+            None => name.parse().map(RuleMetric::Builtin).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl From<RuleMetric> for String {
+    fn from(metric: RuleMetric) -> Self {
+        match metric {
+            RuleMetric::Builtin(metric) => metric.name().to_string(),
+            RuleMetric::Custom(name) => format!("{}{}", CUSTOM_PREFIX, name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Comparator {
+    #[serde(rename = ">", alias = "gt")]
+    Above,
+    #[serde(rename = ">=", alias = "ge")]
+    AtLeast,
+    #[serde(rename = "<", alias = "lt")]
+    Below,
+    #[serde(rename = "<=", alias = "le")]
+    AtMost,
+}
+
+impl Comparator {
+    pub fn holds(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparator::Above => value > threshold,
+            Comparator::AtLeast => value >= threshold,
+            Comparator::Below => value < threshold,
+            Comparator::AtMost => value <= threshold,
+        }
+    }
+}
+
+/// What a firing rule asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RuleAction {
+    ScaleOut { by: u32 },
+    ScaleIn { by: u32 },
+    /// Keep the replica count, overriding lower-priority scale-out and scale-in rules
+    Hold,
+    /// A vertical action for every instance
+    Resize { action: ScaleAction },
+}
+
+/// A single policy rule
+///
+/// # Fields
+/// name - Unique within the policy, reported when the rule fires or is invalid
+/// priority - Higher wins, unique within the policy
+/// metric - The metric to look at, e.g. `cpu_load` or `custom:queue_depth`
+/// aggregation - How the samples in the window are combined
+/// comparator, threshold - The rule fires when `aggregate <comparator> threshold`
+/// duration_secs - The trailing window. The rule can't fire before there is history going back this far.
+/// action - What to do when it fires
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScalingRule {
+    pub name: String,
+    pub priority: u32,
+    pub metric: RuleMetric,
+    pub aggregation: Aggregation,
+    pub comparator: Comparator,
+    pub threshold: f64,
+    #[serde(default)]
+    pub duration_secs: u64,
+    pub action: RuleAction,
+}
+
+impl ScalingRule {
+    /// The aggregate if the rule fires
+    fn fires(&self, samples: &[(InstanceMetrics, DateTime<Utc>)], now: DateTime<Utc>) -> Option<f64> {
+        let since = i64::try_from(self.duration_secs)
+            .ok()
+            .and_then(Duration::try_seconds)
+            .and_then(|duration| now.checked_sub_signed(duration))
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        if !samples.iter().any(|(_, at)| *at <= since) {
+            return None;
+        }
+
+        let mut values: Vec<f64> = samples
+            .iter()
+            .filter(|(_, at)| *at >= since && *at <= now)
+            .filter_map(|(metrics, _)| self.metric.value(metrics))
+            .collect();
+        let value = self.aggregation.apply(&mut values)?;
+        self.comparator.holds(value, self.threshold).then_some(value)
+    }
+
+    fn validate(&self, index: usize) -> Result<(), PolicyError> {
+        let invalid = |reason: String| PolicyError::InvalidRule { rule: self.name.clone(), reason };
+        if self.name.trim().is_empty() {
+            return Err(PolicyError::EmptyName { index });
+        }
+        if !self.threshold.is_finite() {
+            return Err(invalid(format!("threshold {} must be a finite number", self.threshold)));
+        }
+        // The built-in metrics are unsigned, these could never hold. Custom ones can be anything.
+        if let RuleMetric::Builtin(metric) = &self.metric {
+            if self.threshold < 0.0 {
+                return Err(invalid(format!("{:?} is never negative, threshold {} can't be crossed", metric, self.threshold)));
+            }
+            if self.threshold == 0.0 && self.comparator == Comparator::Below {
+                return Err(invalid(format!("{:?} can never be below 0", metric)));
+            }
+        }
+        match self.action {
+            RuleAction::ScaleOut { by: 0 } | RuleAction::ScaleIn { by: 0 } => {
+                Err(invalid("scaling by 0 replicas does nothing".to_string()))
+            }
+            RuleAction::Resize { action: ScaleAction::NoAction } => {
+                Err(invalid("resize needs an action other than NoAction".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The outcome of evaluating a policy
+///
+/// # Fields
+/// replicas - The replica count asked for, not yet clamped to any bounds
+/// resizes - Vertical actions for every instance, in priority order
+/// fired - Names of the rules that fired, in priority order, with their aggregate
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyDecision {
+    pub replicas: u32,
+    pub resizes: Vec<ScaleAction>,
+    pub fired: Vec<(String, f64)>,
+}
+
+/// A validated per-app scaling policy, rules are kept highest priority first
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScalingPolicy {
+    rules: Vec<ScalingRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    rules: Vec<ScalingRule>,
+}
+
+impl ScalingPolicy {
+    pub fn new(mut rules: Vec<ScalingRule>) -> Result<Self, PolicyError> {
+        if rules.is_empty() {
+            return Err(PolicyError::NoRules);
+        }
+        let mut names = HashMap::new();
+        let mut priorities: HashMap<u32, &str> = HashMap::new();
+        for (index, rule) in rules.iter().enumerate() {
+            rule.validate(index)?;
+            if names.insert(rule.name.as_str(), index).is_some() {
+                return Err(PolicyError::DuplicateName(rule.name.clone()));
+            }
+            if let Some(first) = priorities.insert(rule.priority, &rule.name) {
+                return Err(PolicyError::DuplicatePriority {
+                    priority: rule.priority,
+                    first: first.to_string(),
+                    second: rule.name.clone(),
+                });
+            }
+        }
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.priority));
+        Ok(ScalingPolicy { rules })
+    }
+
+    /// Loads a `.toml` or `.json` policy file
+    pub fn load(path: &Path) -> Result<Self, PolicyError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|source| PolicyError::Io { path: path.to_path_buf(), source })?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Self::from_toml(&contents),
+            Some("json") => Self::from_json(&contents),
+            _ => Err(PolicyError::UnknownFormat { path: path.to_path_buf() }),
+        }
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self, PolicyError> {
+        let file: PolicyFile = toml::from_str(contents)?;
+        Self::new(file.rules)
+    }
+
+    pub fn from_json(contents: &str) -> Result<Self, PolicyError> {
+        let file: PolicyFile = serde_json::from_str(contents)?;
+        Self::new(file.rules)
+    }
+
+    pub fn rules(&self) -> &[ScalingRule] {
+        &self.rules
+    }
+
+    /// Evaluates every rule against the app's metric history. `samples` holds the samples of
+    /// all its instances, in any order.
+    pub fn evaluate(
+        &self,
+        current: u32,
+        samples: &[(InstanceMetrics, DateTime<Utc>)],
+        now: DateTime<Utc>
+    ) -> PolicyDecision {
+        let mut decision = PolicyDecision { replicas: current, resizes: Vec::new(), fired: Vec::new() };
+        let mut horizontal_decided = false;
+
+        for rule in &self.rules {
+            let Some(value) = rule.fires(samples, now) else {
+                continue;
+            };
+            decision.fired.push((rule.name.clone(), value));
+
+            match rule.action {
+                RuleAction::Resize { action } => {
+                    // Either already taken or undone by a higher-priority rule
+                    if !decision.resizes.iter().any(|taken| same_axis(*taken, action)) {
+                        decision.resizes.push(action);
+                    }
+                }
+                _ if horizontal_decided => {}
+                RuleAction::ScaleOut { by } => {
+                    decision.replicas = current.saturating_add(by);
+                    horizontal_decided = true;
+                }
+                RuleAction::ScaleIn { by } => {
+                    decision.replicas = current.saturating_sub(by);
+                    horizontal_decided = true;
+                }
+                RuleAction::Hold => horizontal_decided = true,
+            }
+        }
+        decision
+    }
+}
+
+/// ScaleUp/ScaleDown and ScaleLeft/ScaleRight act on the same resources
+fn same_axis(a: ScaleAction, b: ScaleAction) -> bool {
+    use ScaleAction::*;
+    matches!((a, b), (ScaleUp | ScaleDown, ScaleUp | ScaleDown) | (ScaleLeft | ScaleRight, ScaleLeft | ScaleRight))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    const POLICY: &str = r#"
+        [[rules]]
+        name = "slow-responses"
+        priority = 200
+        metric = "app_response_time"
+        aggregation = "p95"
+        comparator = ">"
+        threshold = 500
+        duration_secs = 60
+        action = { type = "scale_out", by = 3 }
+
+        [[rules]]
+        name = "disk-busy"
+        priority = 150
+        metric = "disk_bandwidth"
+        aggregation = "max"
+        comparator = ">="
+        threshold = 90
+        action = { type = "resize", action = "ScaleRight" }
+
+        [[rules]]
+        name = "cpu-hot"
+        priority = 100
+        metric = "cpu_load"
+        aggregation = "avg"
+        comparator = ">"
+        threshold = 70
+        duration_secs = 60
+        action = { type = "scale_out", by = 1 }
+
+        [[rules]]
+        name = "idle"
+        priority = 10
+        metric = "cpu_load"
+        aggregation = "max"
+        comparator = "<"
+        threshold = 20
+        duration_secs = 300
+        action = { type = "scale_in", by = 1 }
+    "#;
+
+    fn sample(cpu_load: u64, response_time: u64, secs: i64) -> (InstanceMetrics, DateTime<Utc>) {
+        let metrics = InstanceMetrics {
+            cpu_load: Some(cpu_load),
+            app_response_time: Some(response_time),
+            disk_bandwidth: Some(50),
+            ..InstanceMetrics::default()
+        };
+        (metrics, at(secs))
+    }
+
+    #[test]
+    fn highest_priority_firing_rule_decides() {
+        let policy = ScalingPolicy::from_toml(POLICY).unwrap();
+        assert_eq!(policy.rules()[0].name, "slow-responses");
+
+        // Hot CPU alone adds one replica
+        let hot: Vec<_> = (0..=60).step_by(10).map(|secs| sample(80, 100, secs)).collect();
+        let decision = policy.evaluate(3, &hot, at(60));
+        assert_eq!(decision.replicas, 4);
+        assert_eq!(decision.fired, [("cpu-hot".to_string(), 80.0)]);
+
+        // A single slow response is under p95 of 20 samples, a couple of them aren't
+        let mut slow: Vec<_> = (0..20).map(|i| sample(80, 100, i * 3)).collect();
+        slow[5].0.app_response_time = Some(900);
+        assert_eq!(policy.evaluate(3, &slow, at(60)).replicas, 4);
+        slow[6].0.app_response_time = Some(900);
+        let decision = policy.evaluate(3, &slow, at(60));
+        assert_eq!(decision.replicas, 6);
+        assert_eq!(decision.fired.len(), 2);
+
+        // Not enough history for the idle rule yet, then enough
+        let idle: Vec<_> = (0..=120).step_by(10).map(|secs| sample(5, 100, secs)).collect();
+        assert_eq!(policy.evaluate(3, &idle, at(120)).replicas, 3);
+        let idle: Vec<_> = (0..=300).step_by(10).map(|secs| sample(5, 100, secs)).collect();
+        assert_eq!(policy.evaluate(3, &idle, at(300)).replicas, 2);
+
+        let mut busy_disk = idle.clone();
+        busy_disk[30].0.disk_bandwidth = Some(95);
+        assert_eq!(policy.evaluate(3, &busy_disk, at(300)).resizes, [ScaleAction::ScaleRight]);
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected_precisely() {
+        let json = r#"{ "rules": [
+            { "name": "a", "priority": 1, "metric": "ram_pressure", "aggregation": "avg",
+              "comparator": ">", "threshold": 60, "action": { "type": "scale_out", "by": 1 } },
+            { "name": "b", "priority": 1, "metric": "network_latency", "aggregation": "max",
+              "comparator": ">", "threshold": 200, "action": { "type": "hold" } }
+        ] }"#;
+        let error = ScalingPolicy::from_json(json).unwrap_err();
+        assert!(matches!(error, PolicyError::DuplicatePriority { priority: 1, .. }), "{}", error);
+        assert!(ScalingPolicy::from_json(&json.replace("\"priority\": 1, \"metric\": \"network", "\"priority\": 2, \"metric\": \"network")).is_ok());
+
+        let typo = POLICY.replace("metric = \"cpu_load\"", "metric = \"cpu\"");
+        let error = ScalingPolicy::from_toml(&typo).unwrap_err().to_string();
+        assert!(error.contains("unknown variant `cpu`"), "{}", error);
+        assert!(error.contains("line"), "{}", error);
+
+        let zero = POLICY.replace("by = 3", "by = 0");
+        let error = ScalingPolicy::from_toml(&zero).unwrap_err().to_string();
+        assert_eq!(error, "rule `slow-responses`: scaling by 0 replicas does nothing");
+
+        let never = POLICY.replace("threshold = 20", "threshold = 0");
+        assert!(matches!(ScalingPolicy::from_toml(&never), Err(PolicyError::InvalidRule { rule, .. }) if rule == "idle"));
+
+        assert!(matches!(ScalingPolicy::from_toml("rules = []"), Err(PolicyError::NoRules)));
+        assert!(matches!(
+            ScalingPolicy::load(Path::new("/nonexistent/policy.yaml")),
+            Err(PolicyError::Io { .. })
+        ));
+    }
+}
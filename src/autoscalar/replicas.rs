@@ -0,0 +1,804 @@
+//-------------------------------------------------------------------------
+// App-level horizontal scaling. A ReplicaScaler owns the replica bounds of
+// one app, averages the metrics of its serving instances against per-
+// instance targets and works out how many replicas the app needs, the
+// same proportional rule as the Kubernetes HPA: ceil(ready * avg / target)
+// per metric, highest wins, clamped to min/max. The plan it emits is
+// scale-out/scale-in actions for a ReplicaDriver to carry out, plus the
+// existing vertical actions per instance, clamped to InstanceLimits.
+// Each action carried out is audited with the averages that caused it.
+//-------------------------------------------------------------------------
+
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::{ Arc, Mutex };
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{ DateTime, Utc };
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use tracing::{ debug, info, warn };
+
+use super::forecast::{ Forecast, ForecastError, MetricHistory, PredictiveScaling };
+use super::policy::{ PolicyDecision, ScalingPolicy };
+use super::stabilization::{ StabilizationPolicy, Stabilizer };
+use super::{ AppInstance, ApplicationState, AutoscalerThresholds, InstanceMetrics, ScaleAction };
+use crate::audit::{ AuditAction, AuditEvent, AuditLog, AuditOutcome, AUTOSCALER };
+use crate::telemetry::METRICS;
+
+/// How many replicas an app may run
+///
+/// # Fields
+/// min - Never scale in below this, also the floor when nothing is running
+/// max - Never scale out above this
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicaBounds {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl ReplicaBounds {
+    pub fn new(min: u32, max: u32) -> Result<Self> {
+        if max == 0 || min > max {
+            anyhow::bail!("replica bounds need 0 < max and min <= max, got min {} max {}", min, max);
+        }
+        Ok(ReplicaBounds { min, max })
+    }
+
+    pub fn clamp(&self, replicas: u32) -> u32 {
+        replicas.clamp(self.min, self.max)
+    }
+}
+
+/// What one instance should average at, `None` leaves the metric out of the decision
+///
+/// # Fields
+/// cpu_load - CPU load per instance in percent of its allocation
+/// ram_utilization - Memory in use per instance as percent of its allocated memory
+/// clients - Connected clients per instance
+/// response_time - App response time in ms
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReplicaTargets {
+    pub cpu_load: Option<u64>,
+    pub ram_utilization: Option<u64>,
+    pub clients: Option<u64>,
+    pub response_time: Option<u64>,
+}
+
+/// Bounds for vertical resizing of a single instance
+///
+/// # Fields
+/// min_cpu, max_cpu - Allocated CPU, 100 per core
+/// min_memory, max_memory - Allocated memory in MB
+/// min_disk_bandwidth - Disk bandwidth never shrinks below this, in MB/s
+/// max_network_bandwidth - Network bandwidth never grows above this, in MB/s
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstanceLimits {
+    pub min_cpu: u64,
+    pub max_cpu: u64,
+    pub min_memory: u64,
+    pub max_memory: u64,
+    pub min_disk_bandwidth: u64,
+    pub max_network_bandwidth: u64,
+}
+
+impl Default for InstanceLimits {
+    fn default() -> Self {
+        InstanceLimits {
+            min_cpu: 10,
+            max_cpu: 400,
+            min_memory: 256,
+            max_memory: 8192,
+            min_disk_bandwidth: 10,
+            max_network_bandwidth: 1000,
+        }
+    }
+}
+
+impl InstanceLimits {
+    /// Applies a vertical action the way `ExampleScaler::scale` does, but clamped to the limits.
+    /// Returns false when the instance is already at the limit and nothing changed.
+    pub fn resize(&self, instance: &mut AppInstance, action: ScaleAction) -> bool {
+        let before = instance.clone();
+        match action {
+            ScaleAction::ScaleUp => {
+                instance.allocated_cpu = (instance.allocated_cpu + 10).min(self.max_cpu.max(instance.allocated_cpu));
+                instance.allocated_memory =
+                    (instance.allocated_memory + 1024).min(self.max_memory.max(instance.allocated_memory));
+            }
+            ScaleAction::ScaleDown => {
+                instance.allocated_cpu = instance.allocated_cpu.saturating_sub(10).max(self.min_cpu.min(instance.allocated_cpu));
+                instance.allocated_memory =
+                    instance.allocated_memory.saturating_sub(1024).max(self.min_memory.min(instance.allocated_memory));
+            }
+            ScaleAction::ScaleLeft => {
+                instance.allocated_disk_bandwidth = instance.allocated_disk_bandwidth
+                    .saturating_sub(10)
+                    .max(self.min_disk_bandwidth.min(instance.allocated_disk_bandwidth));
+            }
+            ScaleAction::ScaleRight => {
+                instance.allocated_network_bandwidth = (instance.allocated_network_bandwidth + 10).min(
+                    self.max_network_bandwidth.max(instance.allocated_network_bandwidth)
+                );
+            }
+            ScaleAction::NoAction => {}
+        }
+        *instance != before
+    }
+}
+
+/// One member of the replica set as the scaler sees it
+///
+/// # Fields
+/// id - How the driver names the instance
+/// instance - Its state and current allocation
+/// metrics - Its latest metrics
+/// window - Its metrics over the thresholds' window, oldest first and including the latest. It is
+/// only resized when every sample asks for it, on the latest metrics alone when empty.
+#[derive(Debug, Clone)]
+pub struct ReplicaInstance {
+    pub id: String,
+    pub instance: AppInstance,
+    pub metrics: InstanceMetrics,
+    pub window: Vec<(InstanceMetrics, DateTime<Utc>)>,
+}
+
+impl ReplicaInstance {
+    /// Takes traffic, so its metrics say something about the app's load
+    fn serving(&self) -> bool {
+        matches!(self.instance.state, ApplicationState::Healthy | ApplicationState::Suspicious)
+    }
+}
+
+/// What the driver should do
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum ReplicaAction {
+    ScaleOut { app_id: String, from: u32, to: u32 },
+    /// `instances` are the ones to remove, worst first
+    ScaleIn { app_id: String, from: u32, to: u32, instances: Vec<String> },
+    /// A vertical action and the allocation it results in
+    Resize { instance: String, action: ScaleAction, allocation: AppInstance },
+}
+
+impl ReplicaAction {
+    /// Short name of the action, as reported in metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ReplicaAction::ScaleOut { .. } => "scale_out",
+            ReplicaAction::ScaleIn { .. } => "scale_in",
+            ReplicaAction::Resize { action, .. } => match action {
+                ScaleAction::ScaleUp => "scale_up",
+                ScaleAction::ScaleDown => "scale_down",
+                ScaleAction::ScaleLeft => "scale_left",
+                ScaleAction::ScaleRight => "scale_right",
+                ScaleAction::NoAction => "no_action",
+            },
+        }
+    }
+}
+
+/// Carries out the scaler's plan, e.g. by starting and removing containers
+#[async_trait]
+pub trait ReplicaDriver: Send + Sync {
+    async fn execute(&self, action: &ReplicaAction) -> Result<()>;
+}
+
+pub struct ReplicaScaler {
+    pub app_id: String,
+    pub bounds: ReplicaBounds,
+    pub targets: ReplicaTargets,
+    pub limits: InstanceLimits,
+    /// Per-instance vertical decisions, as before
+    pub thresholds: AutoscalerThresholds,
+    /// Where `run` records the actions it carried out
+    pub audit: Option<Arc<AuditLog>>,
+    /// What `run` plans through so its decisions don't flap, none plans every tick afresh
+    pub stabilizer: Option<Mutex<Stabilizer>>,
+    /// What `run` plans from instead of the targets and thresholds
+    pub policy: Option<ScalingPolicy>,
+    /// Keeps `run` from going below what the app's forecast demand needs
+    pub predictive: Option<(PredictiveScaling, Arc<MetricHistory>)>,
+}
+
+impl ReplicaScaler {
+    pub fn new(
+        app_id: &str,
+        bounds: ReplicaBounds,
+        targets: ReplicaTargets,
+        limits: InstanceLimits,
+        thresholds: AutoscalerThresholds
+    ) -> Self {
+        ReplicaScaler { app_id: app_id.to_string(), bounds, targets, limits, thresholds, audit: None, stabilizer: None, policy: None, predictive: None }
+    }
+
+    /// Has `run` scale out ahead of the demand forecast from `history`
+    pub fn with_forecast(mut self, predictive: PredictiveScaling, history: Arc<MetricHistory>) -> Self {
+        self.predictive = Some((predictive, history));
+        self
+    }
+
+    /// Has `run` plan from a scaling policy instead of the targets and thresholds
+    pub fn with_policy(mut self, policy: ScalingPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Has `run` plan through a stabilizer with this policy
+    pub fn with_stabilization(mut self, policy: StabilizationPolicy) -> Self {
+        self.stabilizer = Some(Mutex::new(Stabilizer::new(policy)));
+        self
+    }
+
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Replicas the app needs for its serving instances to sit at the targets
+    pub fn desired_replicas(&self, instances: &[ReplicaInstance]) -> u32 {
+        let current = instances.len() as u32;
+        let serving: Vec<&ReplicaInstance> = instances.iter().filter(|instance| instance.serving()).collect();
+        if serving.is_empty() {
+            // Nothing to measure, keep what's there but at least the minimum
+            return self.bounds.clamp(current);
+        }
+
+        let proposals = [
+            (self.targets.cpu_load, average(&serving, |instance| instance.metrics.cpu_load)),
+            (self.targets.ram_utilization, average(&serving, ram_utilization)),
+            (self.targets.clients, average(&serving, |instance| instance.metrics.clients)),
+            (self.targets.response_time, average(&serving, |instance| instance.metrics.app_response_time)),
+        ];
+
+        let desired = proposals
+            .into_iter()
+            .filter_map(|(target, average)| {
+                let target = target.filter(|target| *target > 0)?;
+                Some((serving.len() as f64 * average? / target as f64).ceil() as u32)
+            })
+            .max()
+            .unwrap_or(current);
+        self.bounds.clamp(desired)
+    }
+
+    fn evaluate(
+        &self,
+        instances: &[ReplicaInstance],
+        policy: &ScalingPolicy,
+        history: &[(InstanceMetrics, DateTime<Utc>)],
+        now: DateTime<Utc>
+    ) -> PolicyDecision {
+        let decision = policy.evaluate(instances.len() as u32, history, now);
+        for (rule, value) in &decision.fired {
+            info!("{}: scaling rule {} fired at {}", self.app_id, rule, value);
+        }
+        decision
+    }
+
+    /// Vertical actions the thresholds ask for on one instance
+    fn decide(&self, replica: &ReplicaInstance) -> Vec<ScaleAction> {
+        let actions = if replica.window.is_empty() {
+            self.thresholds.decide_all(&replica.metrics)
+        } else {
+            self.thresholds.decide_window(&replica.window)
+        };
+        actions.into_values().collect()
+    }
+
+    /// Every plan ends up here, so this is where planned actions are counted
+    fn plan_for(
+        &self,
+        instances: &[ReplicaInstance],
+        desired: u32,
+        decide: impl Fn(&ReplicaInstance) -> Vec<ScaleAction>,
+        mut allow_resize: impl FnMut(&str, ScaleAction) -> bool
+    ) -> Vec<ReplicaAction> {
+        let current = instances.len() as u32;
+        let mut actions = Vec::new();
+
+        let mut removed = BTreeSet::new();
+        if desired > current {
+            actions.push(ReplicaAction::ScaleOut { app_id: self.app_id.clone(), from: current, to: desired });
+        } else if desired < current {
+            let victims = removal_order(instances)
+                .into_iter()
+                .take((current - desired) as usize)
+                .map(|instance| instance.id.clone())
+                .collect::<Vec<_>>();
+            removed.extend(victims.iter().cloned());
+            actions.push(ReplicaAction::ScaleIn {
+                app_id: self.app_id.clone(),
+                from: current,
+                to: desired,
+                instances: victims,
+            });
+        }
+
+        for replica in instances.iter().filter(|instance| !removed.contains(&instance.id)) {
+            let mut decided: Vec<ScaleAction> = decide(replica)
+                .into_iter()
+                .filter(|action| *action != ScaleAction::NoAction)
+                .filter(|action| match action {
+                    ScaleAction::ScaleUp | ScaleAction::ScaleRight => desired <= current,
+                    ScaleAction::ScaleDown | ScaleAction::ScaleLeft => desired >= current,
+                    ScaleAction::NoAction => false,
+                })
+                .collect();
+            // decide_all comes from a HashMap, keep the plan stable
+            decided.sort_by_key(|action| *action as u8);
+            decided.dedup();
+
+            let mut allocation = replica.instance.clone();
+            for action in decided {
+                let mut resized = allocation.clone();
+                if self.limits.resize(&mut resized, action) && allow_resize(&replica.id, action) {
+                    allocation = resized;
+                    actions.push(ReplicaAction::Resize {
+                        instance: replica.id.clone(),
+                        action,
+                        allocation: allocation.clone(),
+                    });
+                }
+            }
+        }
+        for action in &actions {
+            METRICS.autoscaler_decisions.inc(&[&self.app_id, action.kind()]);
+        }
+        actions
+    }
+
+    /// Plans, from the policy and through the stabilizer if there are any, and hands every action
+    /// to the driver, stopping at the first failure. `history` is what the policy evaluates.
+    pub async fn run(
+        &self,
+        instances: &[ReplicaInstance],
+        history: &[(InstanceMetrics, DateTime<Utc>)],
+        driver: &dyn ReplicaDriver
+    ) -> Result<Vec<ReplicaAction>> {
+        let actions = {
+            let mut stabilizer = self.stabilizer.as_ref().map(|stabilizer| stabilizer.lock().unwrap());
+            self.plan(instances, history, stabilizer.as_deref_mut(), Utc::now())
+        };
+        let reasons = self.audit.as_ref().map(|_| self.reasons(instances));
+        for action in &actions {
+            let result = driver.execute(action).await;
+            if let (Some(audit), Some(reasons)) = (&self.audit, &reasons) {
+                audit.record(AuditEvent {
+                    actor: AUTOSCALER.to_string(),
+                    action: AuditAction::Scale,
+                    target: self.app_id.clone(),
+                    parameters: json!({ "action": action, "reasons": reasons }),
+                    outcome: AuditOutcome::of(&result),
+                });
+            }
+            result?;
+        }
+        Ok(actions)
+    }
+
+    /// The replica count from the policy or the targets, raised to the forecast, then resizes
+    /// from the policy or the thresholds, all of it through `stabilizer` if given. A horizontal
+    /// action comes first, then vertical actions for the instances that stay, leaving out those
+    /// in the same direction as the horizontal one: the app shouldn't react twice to the same load.
+    /// `history` is what the policy evaluates, `now` the clock the policy, forecast and stabilizer
+    /// go by, so the simulator can plan on a virtual one.
+    pub fn plan(
+        &self,
+        instances: &[ReplicaInstance],
+        history: &[(InstanceMetrics, DateTime<Utc>)],
+        stabilizer: Option<&mut Stabilizer>,
+        now: DateTime<Utc>
+    ) -> Vec<ReplicaAction> {
+        let decision = self.policy.as_ref().map(|policy| self.evaluate(instances, policy, history, now));
+        let mut desired = match &decision {
+            Some(decision) => self.bounds.clamp(decision.replicas),
+            None => self.desired_replicas(instances),
+        };
+        if let Some((predictive, history)) = &self.predictive {
+            let forecast = self.forecast(predictive, history, now);
+            desired = self.bounds.clamp(predictive.floor(desired, forecast.as_ref(), now));
+        }
+        let decide = |replica: &ReplicaInstance| match &decision {
+            Some(decision) => decision.resizes.clone(),
+            None => self.decide(replica),
+        };
+
+        match stabilizer {
+            Some(stabilizer) => {
+                let desired = stabilizer.replicas(now, instances.len() as u32, desired);
+                self.plan_for(instances, desired, decide, |instance, action| stabilizer.allow_resize(now, instance, action))
+            }
+            None => self.plan_for(instances, desired, decide, |_, _| true),
+        }
+    }
+
+    /// The forecast covering the lead time, none until there is enough history
+    fn forecast(&self, predictive: &PredictiveScaling, history: &MetricHistory, now: DateTime<Utc>) -> Option<Forecast> {
+        let hours = predictive.lead_secs.div_ceil(3600).saturating_add(1).min(24 * 7) as u32;
+        match history.forecast(&self.app_id, predictive.metric, now, hours) {
+            Ok(forecast) => Some(forecast),
+            Err(e @ ForecastError::NotEnoughHistory { .. }) => {
+                debug!("{}: not scaling ahead, {}", self.app_id, e);
+                None
+            }
+            Err(e) => {
+                warn!("{}: failed to forecast, not scaling ahead: {}", self.app_id, e);
+                None
+            }
+        }
+    }
+
+    /// What the plan was based on: replica counts, the serving instances' averages and the targets
+    fn reasons(&self, instances: &[ReplicaInstance]) -> Value {
+        let serving: Vec<&ReplicaInstance> = instances.iter().filter(|instance| instance.serving()).collect();
+        json!({
+            "replicas": instances.len(),
+            "serving": serving.len(),
+            "desired": self.desired_replicas(instances),
+            "averages": {
+                "cpu_load": average(&serving, |instance| instance.metrics.cpu_load),
+                "ram_utilization": average(&serving, ram_utilization),
+                "clients": average(&serving, |instance| instance.metrics.clients),
+                "response_time": average(&serving, |instance| instance.metrics.app_response_time),
+            },
+            "targets": self.targets,
+        })
+    }
+}
+
+/// How the server scales one deployed app, an entry of the deployment config's `autoscale`
+///
+/// # Fields
+/// bounds - Replicas across all of the app's hosts
+/// targets - Per-instance averages to scale out and in around
+/// limits - How far a single instance may be resized
+/// thresholds - When to resize a single instance, never when unset
+/// policy - A `.toml` or `.json` scaling policy to plan from instead of the targets and thresholds
+/// predictive - Scale out ahead of the forecast demand, never below it
+/// stabilization - Windows, cooldowns and step caps against flapping
+/// interval_secs - Time between two decisions
+/// threshold_window_secs - How long an instance's metrics must keep crossing a threshold before it
+/// is resized, two intervals when unset
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScalingConfig {
+    pub bounds: ReplicaBounds,
+    #[serde(default)]
+    pub targets: ReplicaTargets,
+    #[serde(default)]
+    pub limits: InstanceLimits,
+    #[serde(default)]
+    pub thresholds: Option<AutoscalerThresholds>,
+    #[serde(default)]
+    pub policy: Option<PathBuf>,
+    #[serde(default)]
+    pub predictive: Option<PredictiveScaling>,
+    #[serde(default)]
+    pub stabilization: StabilizationPolicy,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default)]
+    pub threshold_window_secs: Option<u64>,
+}
+
+fn default_interval_secs() -> u64 {
+    30
+}
+
+impl ScalingConfig {
+    /// How far back an instance's metrics are decided over by the thresholds
+    pub fn threshold_window_secs(&self) -> u64 {
+        self.threshold_window_secs.unwrap_or(2 * self.interval_secs)
+    }
+
+    /// The app's scaler forecasting from `history`, checking what deserializing couldn't
+    pub fn scaler(&self, app_id: &str, history: &Arc<MetricHistory>) -> Result<ReplicaScaler> {
+        let bounds = ReplicaBounds::new(self.bounds.min, self.bounds.max)?;
+        let thresholds = match &self.thresholds {
+            Some(thresholds) => {
+                thresholds.validate()?;
+                thresholds.clone()
+            }
+            None => AutoscalerThresholds::default(),
+        };
+        let scaler = ReplicaScaler::new(app_id, bounds, self.targets.clone(), self.limits.clone(), thresholds)
+            .with_stabilization(self.stabilization.clone());
+        let scaler = match &self.policy {
+            Some(path) => scaler.with_policy(ScalingPolicy::load(path)?),
+            None => scaler,
+        };
+        Ok(match &self.predictive {
+            Some(predictive) => scaler.with_forecast(predictive.clone(), history.clone()),
+            None => scaler,
+        })
+    }
+}
+
+/// Memory in use as percent of the allocation
+fn ram_utilization(instance: &ReplicaInstance) -> Option<u64> {
+    let used = instance.metrics.ram_usage?;
+    (instance.instance.allocated_memory > 0).then(|| used * 100 / instance.instance.allocated_memory)
+}
+
+fn average(instances: &[&ReplicaInstance], metric: impl Fn(&ReplicaInstance) -> Option<u64>) -> Option<f64> {
+    let values: Vec<u64> = instances.iter().filter_map(|instance| metric(instance)).collect();
+    if values.is_empty() {
+        return None;
+    }
+    Some(values.iter().sum::<u64>() as f64 / values.len() as f64)
+}
+
+/// Instances not serving go first, worst state first, then the least loaded
+fn removal_order(instances: &[ReplicaInstance]) -> Vec<&ReplicaInstance> {
+    let rank = |state: ApplicationState| match state {
+        ApplicationState::Down => 0,
+        ApplicationState::Blacklisted => 1,
+        ApplicationState::Erroneous => 2,
+        ApplicationState::Suspicious => 3,
+        ApplicationState::Healthy => 4,
+    };
+    let mut ordered: Vec<&ReplicaInstance> = instances.iter().collect();
+    ordered.sort_by_key(|instance| (rank(instance.instance.state), instance.metrics.cpu_load.unwrap_or(0)));
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::autoscalar::policy::Metric;
+    use crate::autoscalar::stabilization::DirectionPolicy;
+
+    fn replica(id: &str, state: ApplicationState, cpu_load: u64) -> ReplicaInstance {
+        ReplicaInstance {
+            id: id.to_string(),
+            instance: AppInstance {
+                state,
+                allocated_memory: 1024,
+                allocated_cpu: 100,
+                allocated_disk_bandwidth: 100,
+                allocated_network_bandwidth: 100,
+            },
+            metrics: InstanceMetrics { cpu_load: Some(cpu_load), ram_usage: Some(256), ..InstanceMetrics::default() },
+            window: Vec::new(),
+        }
+    }
+
+    /// The replica with a sample per cpu load, 30s apart, the last one its latest metrics
+    fn windowed(id: &str, cpu_loads: &[u64]) -> ReplicaInstance {
+        let mut replica = replica(id, ApplicationState::Healthy, *cpu_loads.last().unwrap());
+        replica.window = cpu_loads
+            .iter()
+            .enumerate()
+            .map(|(i, cpu_load)| {
+                let at = DateTime::from_timestamp(1_700_000_000 + 30 * i as i64, 0).unwrap();
+                (InstanceMetrics { cpu_load: Some(*cpu_load), ..replica.metrics.clone() }, at)
+            })
+            .collect();
+        replica
+    }
+
+    fn scaler() -> ReplicaScaler {
+        ReplicaScaler::new(
+            "web",
+            ReplicaBounds::new(2, 5).unwrap(),
+            ReplicaTargets { cpu_load: Some(50), ram_utilization: Some(80), ..ReplicaTargets::default() },
+            InstanceLimits { max_cpu: 100, ..InstanceLimits::default() },
+            AutoscalerThresholds::new(90, 300, 1000, 1000)
+        )
+    }
+
+    #[test]
+    fn scales_out_to_targets_within_bounds() {
+        let scaler = scaler();
+        let hot = [replica("a", ApplicationState::Healthy, 90), replica("b", ApplicationState::Healthy, 80)];
+        // ceil(2 * 85 / 50) = 4
+        assert_eq!(scaler.desired_replicas(&hot), 4);
+
+        let burning = [replica("a", ApplicationState::Healthy, 400), replica("b", ApplicationState::Healthy, 400)];
+        let actions = scaler.plan(&burning, &[], None, Utc::now());
+        assert_eq!(actions[0], ReplicaAction::ScaleOut { app_id: "web".to_string(), from: 2, to: 5 });
+        // Already scaling out, and max_cpu is reached anyway: no vertical scale-up on top
+        assert_eq!(actions.len(), 1);
+
+        // Down instances don't count as load but do count as replicas
+        let mostly_down = [
+            replica("a", ApplicationState::Healthy, 50),
+            replica("b", ApplicationState::Down, 0),
+            replica("c", ApplicationState::Down, 0),
+        ];
+        assert_eq!(scaler.desired_replicas(&mostly_down), 2);
+    }
+
+    #[test]
+    fn scales_in_worst_instances_first_and_keeps_vertical_actions() {
+        let scaler = scaler();
+        let idle = [
+            replica("a", ApplicationState::Healthy, 20),
+            replica("b", ApplicationState::Healthy, 10),
+            replica("c", ApplicationState::Erroneous, 70),
+            replica("d", ApplicationState::Healthy, 30),
+        ];
+        let actions = scaler.plan(&idle, &[], None, Utc::now());
+        assert_eq!(
+            actions[0],
+            ReplicaAction::ScaleIn {
+                app_id: "web".to_string(),
+                from: 4,
+                to: 2,
+                instances: vec!["c".to_string(), "b".to_string()],
+            }
+        );
+
+        // Scaling in already takes capacity away, the idle survivors aren't shrunk as well
+        assert_eq!(actions.len(), 1);
+
+        // At the minimum they are, but not below min_memory
+        let actions = scaler.plan(&idle[..2], &[], None, Utc::now());
+        let resized: Vec<&AppInstance> = actions
+            .iter()
+            .map(|action| match action {
+                ReplicaAction::Resize { action: ScaleAction::ScaleDown, allocation, .. } => allocation,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(resized.len(), 2);
+        assert!(resized.iter().all(|allocation| allocation.allocated_cpu == 90 && allocation.allocated_memory == 256));
+
+        assert!(ReplicaBounds::new(3, 2).is_err());
+    }
+
+    #[test]
+    fn instances_are_resized_only_when_their_whole_window_agrees() {
+        let scaler = scaler();
+        // Both idle now, but "a" was in the band a sample ago
+        let actions = scaler.plan(&[windowed("a", &[60, 10]), windowed("b", &[10, 10])], &[], None, Utc::now());
+        assert_eq!(actions.len(), 1);
+        assert!(matches!(
+            &actions[0],
+            ReplicaAction::Resize { instance, action: ScaleAction::ScaleDown, .. } if instance == "b"
+        ));
+
+        // Without a window the latest metrics decide alone
+        let actions = scaler.plan(&[replica("a", ApplicationState::Healthy, 10), windowed("b", &[10, 10])], &[], None, Utc::now());
+        assert_eq!(actions.len(), 2);
+    }
+
+    #[test]
+    fn policy_decisions_are_clamped_and_applied_to_survivors() {
+        let scaler = scaler();
+        let policy = ScalingPolicy::from_json(r#"{ "rules": [
+            { "name": "latency", "priority": 2, "metric": "network_latency", "aggregation": "max",
+              "comparator": ">", "threshold": 100, "action": { "type": "scale_out", "by": 10 } },
+            { "name": "pressure", "priority": 1, "metric": "ram_pressure", "aggregation": "avg",
+              "comparator": ">=", "threshold": 40, "action": { "type": "resize", "action": "ScaleUp" } }
+        ] }"#).unwrap();
+        let scaler = scaler.with_policy(policy);
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let instances = [replica("a", ApplicationState::Healthy, 10), replica("b", ApplicationState::Healthy, 10)];
+        let history = [(InstanceMetrics { network_latency: Some(250), ram_pressure: Some(50), ..InstanceMetrics::default() }, now)];
+
+        // Thresholds alone would scale these idle instances down, the policy doesn't look at cpu_load
+        let actions = scaler.plan(&instances, &history, None, now);
+        assert_eq!(actions, [ReplicaAction::ScaleOut { app_id: "web".to_string(), from: 2, to: 5 }]);
+
+        let history = [(InstanceMetrics { ram_pressure: Some(50), ..InstanceMetrics::default() }, now)];
+        let actions = scaler.plan(&instances, &history, None, now);
+        assert_eq!(actions.len(), 2);
+        assert!(actions.iter().all(|action| matches!(action, ReplicaAction::Resize { action: ScaleAction::ScaleUp, .. })));
+    }
+
+    #[tokio::test]
+    async fn run_stabilizes_policy_decisions() {
+        let policy = ScalingPolicy::from_json(r#"{ "rules": [
+            { "name": "latency", "priority": 2, "metric": "network_latency", "aggregation": "max",
+              "comparator": ">", "threshold": 100, "duration_secs": 60, "action": { "type": "scale_out", "by": 10 } },
+            { "name": "pressure", "priority": 1, "metric": "ram_pressure", "aggregation": "avg",
+              "comparator": ">=", "threshold": 40, "duration_secs": 60, "action": { "type": "resize", "action": "ScaleUp" } }
+        ] }"#).unwrap();
+        let stabilization = StabilizationPolicy {
+            up: DirectionPolicy { stabilization_window_secs: 0, cooldown_secs: 600, max_step: 1, step_period_secs: 600 },
+            ..StabilizationPolicy::default()
+        };
+        let scaler = ReplicaScaler::new(
+            "web",
+            ReplicaBounds::new(1, 10).unwrap(),
+            ReplicaTargets::default(),
+            InstanceLimits::default(),
+            AutoscalerThresholds::default()
+        )
+            .with_policy(policy)
+            .with_stabilization(stabilization);
+        let hot = InstanceMetrics { network_latency: Some(250), ram_pressure: Some(50), ..InstanceMetrics::default() };
+        let history = [
+            (hot.clone(), Utc::now() - chrono::Duration::seconds(90)),
+            (hot, Utc::now() - chrono::Duration::seconds(1)),
+        ];
+        let instances = [replica("a", ApplicationState::Healthy, 10), replica("b", ApplicationState::Healthy, 10)];
+
+        // The policy asks for 10 more, the stabilizer allows one step
+        let actions = scaler.run(&instances, &history, &AcceptingDriver).await.unwrap();
+        assert_eq!(actions, [ReplicaAction::ScaleOut { app_id: "web".to_string(), from: 2, to: 3 }]);
+
+        // Scaling out is cooling down, the resizes the policy asks for are let through once
+        let instances = [
+            replica("a", ApplicationState::Healthy, 10),
+            replica("b", ApplicationState::Healthy, 10),
+            replica("c", ApplicationState::Healthy, 10),
+        ];
+        let actions = scaler.run(&instances, &history, &AcceptingDriver).await.unwrap();
+        assert_eq!(actions.len(), 3);
+        assert!(actions.iter().all(|action| matches!(action, ReplicaAction::Resize { action: ScaleAction::ScaleUp, .. })));
+        assert!(scaler.run(&instances, &history, &AcceptingDriver).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn run_scales_out_ahead_of_the_forecast() {
+        let dir = std::env::temp_dir().join(format!("omniforge-replicas-forecast-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let history = Arc::new(MetricHistory::new(&dir));
+        let now = Utc::now();
+        let busy = InstanceMetrics { cpu_load: Some(200), ..InstanceMetrics::default() };
+        for hours_ago in (1..=96).rev() {
+            history.record("web", now - chrono::Duration::hours(hours_ago), &[busy.clone(), busy.clone()]).unwrap();
+        }
+        let predictive = PredictiveScaling { metric: Metric::CpuLoad, target_per_replica: 100.0, lead_secs: 600, max_mape: None };
+        let scaler = scaler().with_forecast(predictive, history);
+
+        // The targets are met right now, but the app has needed 4 replicas every hour
+        let instances = [replica("a", ApplicationState::Healthy, 50), replica("b", ApplicationState::Healthy, 50)];
+        let actions = scaler.run(&instances, &[], &AcceptingDriver).await.unwrap();
+        assert_eq!(actions, [ReplicaAction::ScaleOut { app_id: "web".to_string(), from: 2, to: 4 }]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    struct AcceptingDriver;
+
+    #[async_trait]
+    impl ReplicaDriver for AcceptingDriver {
+        async fn execute(&self, _: &ReplicaAction) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    struct FailingDriver;
+
+    #[async_trait]
+    impl ReplicaDriver for FailingDriver {
+        async fn execute(&self, action: &ReplicaAction) -> Result<()> {
+            match action {
+                ReplicaAction::ScaleOut { .. } => Ok(()),
+                _ => Err(anyhow::anyhow!("no capacity")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn run_audits_actions_with_their_reasons() {
+        let path = std::env::temp_dir().join(format!("omniforge-replicas-audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let audit = Arc::new(AuditLog::new(&path));
+        let scaler = scaler().with_audit(audit.clone());
+
+        let hot = [replica("a", ApplicationState::Healthy, 90), replica("b", ApplicationState::Healthy, 80)];
+        scaler.run(&hot, &[], &FailingDriver).await.unwrap();
+        let entries = audit.query(&Default::default()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].actor.as_str(), entries[0].action, &entries[0].outcome), (AUTOSCALER, AuditAction::Scale, &AuditOutcome::Succeeded));
+        let reasons = &entries[0].parameters["reasons"];
+        assert_eq!((reasons["replicas"].as_u64(), reasons["desired"].as_u64()), (Some(2), Some(4)));
+        assert_eq!(reasons["averages"]["cpu_load"].as_f64(), Some(85.0));
+        assert_eq!(reasons["targets"]["cpu_load"].as_u64(), Some(50));
+
+        // A failed action is audited as such and stops the run
+        let idle = [
+            replica("a", ApplicationState::Healthy, 10),
+            replica("b", ApplicationState::Healthy, 10),
+            replica("c", ApplicationState::Healthy, 10),
+        ];
+        assert!(scaler.run(&idle, &[], &FailingDriver).await.is_err());
+        let last = audit.query(&Default::default()).unwrap().pop().unwrap();
+        assert_eq!(last.outcome, AuditOutcome::Failed { reason: "no capacity".to_string() });
+        assert!(audit.verify().unwrap().valid);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,658 @@
+//-------------------------------------------------------------------------
+// Offline replay of metric traces against the autoscaler. A trace is a
+// CSV or JSON time series of InstanceMetrics, recorded at some replica
+// count. The simulator steps a virtual clock over it, spreads the load of
+// each point over the replicas that are serving at that moment, runs the
+// same ReplicaScaler planning (thresholds, or a ScalingPolicy, through a
+// Stabilizer when configured) the live autoscaler uses and applies the plan to its simulated replica set, new
+// replicas only serving after a startup delay. A scaler with a forecast
+// scales ahead of the recorded MetricHistory as of the virtual clock. The result is a timeline
+// of actions and replica counts plus every tick an SLO was violated, so a
+// policy can be judged before it is enabled.
+//
+// CSV traces have a header naming the columns: `at` (RFC 3339 or seconds),
+// an optional `replicas`, any InstanceMetrics fields and custom metrics as
+// `custom:<name>`. Empty cells are missing metrics.
+//-------------------------------------------------------------------------
+
+use std::collections::VecDeque;
+use std::env;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+
+use anyhow::Context;
+use chrono::{ DateTime, Duration, Utc };
+use serde::{ Deserialize, Serialize };
+use thiserror::Error;
+
+use super::forecast::{ MetricHistory, PredictiveScaling };
+use super::policy::{ Metric, RuleMetric, ScalingPolicy };
+use super::replicas::{ InstanceLimits, ReplicaAction, ReplicaBounds, ReplicaInstance, ReplicaScaler, ReplicaTargets };
+use super::stabilization::{ StabilizationPolicy, Stabilizer };
+use super::{ AppInstance, ApplicationState, AutoscalerThresholds, InstanceMetrics };
+
+#[derive(Debug, Error)]
+pub enum SimulationError {
+    #[error("failed to read trace {path}: {source}")]
+    Io { path: PathBuf, source: std::io::Error },
+    #[error("trace {path} has no .csv or .json extension")]
+    UnknownFormat { path: PathBuf },
+    #[error("trace line {line}: {reason}")]
+    Csv { line: usize, reason: String },
+    #[error("invalid JSON trace: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("trace point {0} is out of the representable time range")]
+    OutOfRange(usize),
+    #[error("trace has no points")]
+    Empty,
+}
+
+/// When a trace point was recorded, a timestamp or seconds on the virtual clock
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(untagged)]
+enum TraceTime {
+    Seconds(i64),
+    Timestamp(DateTime<Utc>),
+}
+
+impl TraceTime {
+    fn parse(value: &str) -> Option<Self> {
+        match value.parse::<i64>() {
+            Ok(seconds) => Some(TraceTime::Seconds(seconds)),
+            Err(_) => DateTime::parse_from_rfc3339(value).ok().map(|at| TraceTime::Timestamp(at.with_timezone(&Utc))),
+        }
+    }
+
+    fn at(self) -> Option<DateTime<Utc>> {
+        match self {
+            TraceTime::Seconds(seconds) => DateTime::from_timestamp(seconds, 0),
+            TraceTime::Timestamp(at) => Some(at),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonPoint {
+    at: TraceTime,
+    #[serde(default = "one")]
+    replicas: u32,
+    #[serde(flatten)]
+    metrics: InstanceMetrics,
+}
+
+fn one() -> u32 {
+    1
+}
+
+/// Per-instance metrics as recorded with `replicas` replicas serving
+#[derive(Debug, Clone, PartialEq)]
+pub struct TracePoint {
+    pub at: DateTime<Utc>,
+    pub replicas: u32,
+    pub metrics: InstanceMetrics,
+}
+
+impl TracePoint {
+    /// The metrics each of `serving` replicas would see under the same load. Everything but
+    /// network latency, which depends on the outside world, follows the load per replica.
+    /// Custom metrics mean whatever the app makes them mean, they are replayed as recorded.
+    fn spread(&self, serving: u32) -> InstanceMetrics {
+        let factor = self.replicas.max(1) as f64 / serving.max(1) as f64;
+        let scale = |value: Option<u64>| value.map(|value| (value as f64 * factor).round() as u64);
+        InstanceMetrics {
+            cpu_load: scale(self.metrics.cpu_load),
+            ram_pressure: scale(self.metrics.ram_pressure).map(|pressure| pressure.min(100)),
+            ram_usage: scale(self.metrics.ram_usage),
+            clients: scale(self.metrics.clients),
+            app_response_time: scale(self.metrics.app_response_time),
+            network_latency: self.metrics.network_latency,
+            disk_bandwidth: scale(self.metrics.disk_bandwidth),
+            custom: self.metrics.custom.clone(),
+        }
+    }
+}
+
+/// A metric time series, ordered by time
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    points: Vec<TracePoint>,
+}
+
+impl Trace {
+    pub fn new(mut points: Vec<TracePoint>) -> Result<Self, SimulationError> {
+        if points.is_empty() {
+            return Err(SimulationError::Empty);
+        }
+        points.sort_by_key(|point| point.at);
+        Ok(Trace { points })
+    }
+
+    /// Loads a `.csv` or `.json` trace
+    pub fn load(path: &Path) -> Result<Self, SimulationError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|source| SimulationError::Io { path: path.to_path_buf(), source })?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("csv") => Self::from_csv(&contents),
+            Some("json") => Self::from_json(&contents),
+            _ => Err(SimulationError::UnknownFormat { path: path.to_path_buf() }),
+        }
+    }
+
+    /// A JSON array of objects with `at`, an optional `replicas` and the metric fields
+    pub fn from_json(contents: &str) -> Result<Self, SimulationError> {
+        let points: Vec<JsonPoint> = serde_json::from_str(contents)?;
+        let points = points
+            .into_iter()
+            .enumerate()
+            .map(|(index, point)| {
+                let at = point.at.at().ok_or(SimulationError::OutOfRange(index))?;
+                Ok(TracePoint { at, replicas: point.replicas, metrics: point.metrics })
+            })
+            .collect::<Result<Vec<_>, SimulationError>>()?;
+        Self::new(points)
+    }
+
+    pub fn from_csv(contents: &str) -> Result<Self, SimulationError> {
+        let mut lines = contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+        let (_, header) = lines.next().ok_or(SimulationError::Empty)?;
+        let columns: Vec<&str> = header.split(',').map(str::trim).collect();
+        if !columns.contains(&"at") {
+            return Err(SimulationError::Csv { line: 1, reason: "header has no `at` column".to_string() });
+        }
+        let mut metrics = Vec::with_capacity(columns.len());
+        for column in &columns {
+            metrics.push(match *column {
+                "at" | "replicas" => None,
+                name => Some(RuleMetric::try_from(name.to_string()).map_err(|_| SimulationError::Csv {
+                    line: 1,
+                    reason: format!("unknown column `{}`", name),
+                })?),
+            });
+        }
+
+        let mut points = Vec::new();
+        for (index, line) in lines {
+            let error = |reason: String| SimulationError::Csv { line: index + 1, reason };
+            let cells: Vec<&str> = line.split(',').map(str::trim).collect();
+            if cells.len() != columns.len() {
+                return Err(error(format!("expected {} cells, found {}", columns.len(), cells.len())));
+            }
+
+            let mut point = TracePoint { at: DateTime::<Utc>::MIN_UTC, replicas: 1, metrics: InstanceMetrics::default() };
+            for ((column, metric), cell) in columns.iter().zip(&metrics).zip(cells) {
+                match *column {
+                    "at" => {
+                        point.at = TraceTime::parse(cell)
+                            .and_then(TraceTime::at)
+                            .ok_or_else(|| error(format!("`{}` is neither seconds nor an RFC 3339 time", cell)))?;
+                    }
+                    "replicas" => {
+                        point.replicas = cell.parse().map_err(|_| error(format!("invalid replica count `{}`", cell)))?;
+                    }
+                    _ if cell.is_empty() => {}
+                    name => {
+                        let invalid = || error(format!("invalid {} `{}`", name, cell));
+                        match metric {
+                            Some(RuleMetric::Custom(custom)) => {
+                                let value: f64 = cell.parse().map_err(|_| invalid())?;
+                                point.metrics.custom.insert(custom.clone(), value);
+                            }
+                            _ => point.metrics.set_field(name, Some(cell.parse().map_err(|_| invalid())?)),
+                        }
+                    }
+                }
+            }
+            points.push(point);
+        }
+        Self::new(points)
+    }
+
+    pub fn start(&self) -> DateTime<Utc> {
+        self.points[0].at
+    }
+
+    pub fn end(&self) -> DateTime<Utc> {
+        self.points[self.points.len() - 1].at
+    }
+
+    /// The point in effect at `at`, the latest one recorded at or before it
+    fn at(&self, at: DateTime<Utc>) -> &TracePoint {
+        let index = self.points.partition_point(|point| point.at <= at);
+        &self.points[index.saturating_sub(1)]
+    }
+}
+
+/// A service level objective, violated whenever the metric per serving replica exceeds `max`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Slo {
+    pub metric: Metric,
+    pub max: u64,
+}
+
+/// # Fields
+/// tick_secs - Virtual time between two autoscaler runs
+/// startup_secs - Time before a new replica serves traffic
+/// initial_replicas - Replicas running when the trace starts
+/// slos - Objectives to check every tick
+/// stabilization - Windows, cooldowns and step caps the plans go through, none plans every tick afresh
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SimulationConfig {
+    pub tick_secs: u64,
+    pub startup_secs: u64,
+    pub initial_replicas: u32,
+    pub slos: Vec<Slo>,
+    #[serde(default)]
+    pub stabilization: Option<StabilizationPolicy>,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        SimulationConfig { tick_secs: 15, startup_secs: 30, initial_replicas: 1, slos: Vec::new(), stabilization: None }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SloViolation {
+    pub metric: Metric,
+    /// `None` when no replica was serving at all
+    pub value: Option<u64>,
+    pub max: u64,
+}
+
+/// One autoscaler run on the virtual clock
+///
+/// # Fields
+/// replicas - Replicas after the tick's actions, including ones still starting
+/// serving - Replicas that were serving when the tick's metrics were taken
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Tick {
+    pub at: DateTime<Utc>,
+    pub replicas: u32,
+    pub serving: u32,
+    pub metrics: InstanceMetrics,
+    pub actions: Vec<ReplicaAction>,
+    pub violations: Vec<SloViolation>,
+}
+
+/// # Fields
+/// violating_ticks - Ticks with at least one SLO violation
+/// replica_hours - Replicas running over time, what the policy costs
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SimulationSummary {
+    pub ticks: usize,
+    pub scale_outs: usize,
+    pub scale_ins: usize,
+    pub resizes: usize,
+    pub violating_ticks: usize,
+    pub peak_replicas: u32,
+    pub replica_hours: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SimulationReport {
+    pub timeline: Vec<Tick>,
+    pub summary: SimulationSummary,
+}
+
+/// A simulated replica
+struct Replica {
+    id: String,
+    allocation: AppInstance,
+    ready_at: DateTime<Utc>,
+}
+
+pub struct Simulation {
+    scaler: ReplicaScaler,
+    config: SimulationConfig,
+}
+
+impl Simulation {
+    /// Plans the way the scaler does, with `policy` instead of the targets and thresholds when given
+    pub fn new(scaler: ReplicaScaler, policy: Option<ScalingPolicy>, config: SimulationConfig) -> Self {
+        let scaler = ReplicaScaler { policy: policy.or(scaler.policy), ..scaler };
+        Simulation { scaler, config }
+    }
+
+    pub fn run(&self, trace: &Trace) -> SimulationReport {
+        let tick = Duration::seconds(self.config.tick_secs.clamp(1, i64::MAX as u64) as i64);
+        let startup = Duration::seconds(self.config.startup_secs.min(i64::MAX as u64) as i64);
+        // Policy rules only look back this far
+        let keep = self.scaler
+            .policy
+            .iter()
+            .flat_map(|policy| policy.rules())
+            .map(|rule| rule.duration_secs)
+            .max()
+            .unwrap_or(0);
+        let keep = Duration::seconds(keep.min(i64::MAX as u64) as i64) + tick;
+
+        let mut next_id = 0;
+        let mut new_replica = |ready_at: DateTime<Utc>| {
+            next_id += 1;
+            Replica {
+                id: format!("replica-{}", next_id),
+                allocation: AppInstance {
+                    state: ApplicationState::Healthy,
+                    allocated_memory: self.scaler.limits.min_memory.max(1024),
+                    allocated_cpu: 100,
+                    allocated_disk_bandwidth: 100,
+                    allocated_network_bandwidth: 100,
+                },
+                ready_at,
+            }
+        };
+        let mut replicas: Vec<Replica> =
+            (0..self.config.initial_replicas).map(|_| new_replica(trace.start())).collect();
+        let mut history: VecDeque<(InstanceMetrics, DateTime<Utc>)> = VecDeque::new();
+        let mut stabilizer = self.config.stabilization.clone().map(Stabilizer::new);
+        let mut timeline = Vec::new();
+
+        let mut now = trace.start();
+        while now <= trace.end() {
+            let serving = replicas.iter().filter(|replica| replica.ready_at <= now).count() as u32;
+            let metrics = trace.at(now).spread(serving);
+            let violations = self.violations(&metrics, serving);
+
+            let instances: Vec<ReplicaInstance> = replicas
+                .iter()
+                .map(|replica| {
+                    let ready = replica.ready_at <= now;
+                    ReplicaInstance {
+                        id: replica.id.clone(),
+                        instance: AppInstance {
+                            state: if ready { ApplicationState::Healthy } else { ApplicationState::Down },
+                            ..replica.allocation.clone()
+                        },
+                        metrics: if ready { metrics.clone() } else { InstanceMetrics::default() },
+                        window: Vec::new(),
+                    }
+                })
+                .collect();
+            for _ in 0..serving {
+                history.push_back((metrics.clone(), now));
+            }
+            while history.front().is_some_and(|(_, at)| *at < now - keep) {
+                history.pop_front();
+            }
+
+            let actions = self.scaler.plan(&instances, history.make_contiguous(), stabilizer.as_mut(), now);
+            for action in &actions {
+                match action {
+                    ReplicaAction::ScaleOut { from, to, .. } => {
+                        for _ in *from..*to {
+                            replicas.push(new_replica(now + startup));
+                        }
+                    }
+                    ReplicaAction::ScaleIn { instances, .. } => {
+                        replicas.retain(|replica| !instances.contains(&replica.id));
+                    }
+                    ReplicaAction::Resize { instance, allocation, .. } => {
+                        if let Some(replica) = replicas.iter_mut().find(|replica| &replica.id == instance) {
+                            replica.allocation = allocation.clone();
+                        }
+                    }
+                }
+            }
+
+            timeline.push(Tick {
+                at: now,
+                replicas: replicas.len() as u32,
+                serving,
+                metrics,
+                actions,
+                violations,
+            });
+            now += tick;
+        }
+
+        let summary = summarize(&timeline, self.config.tick_secs);
+        SimulationReport { timeline, summary }
+    }
+
+    fn violations(&self, metrics: &InstanceMetrics, serving: u32) -> Vec<SloViolation> {
+        self.config
+            .slos
+            .iter()
+            .filter_map(|slo| {
+                if serving == 0 {
+                    return Some(SloViolation { metric: slo.metric, value: None, max: slo.max });
+                }
+                let value = slo.metric.value(metrics)?;
+                (value > slo.max).then_some(SloViolation { metric: slo.metric, value: Some(value), max: slo.max })
+            })
+            .collect()
+    }
+}
+
+fn summarize(timeline: &[Tick], tick_secs: u64) -> SimulationSummary {
+    let count = |matches: fn(&ReplicaAction) -> bool| {
+        timeline.iter().flat_map(|tick| &tick.actions).filter(|action| matches(action)).count()
+    };
+    SimulationSummary {
+        ticks: timeline.len(),
+        scale_outs: count(|action| matches!(action, ReplicaAction::ScaleOut { .. })),
+        scale_ins: count(|action| matches!(action, ReplicaAction::ScaleIn { .. })),
+        resizes: count(|action| matches!(action, ReplicaAction::Resize { .. })),
+        violating_ticks: timeline.iter().filter(|tick| !tick.violations.is_empty()).count(),
+        peak_replicas: timeline.iter().map(|tick| tick.replicas).max().unwrap_or(0),
+        replica_hours: timeline.iter().map(|tick| tick.replicas as f64).sum::<f64>() * tick_secs as f64 / 3600.0,
+    }
+}
+
+/// `omniforge simulate <trace> [options]`: replays a trace and prints the report as JSON
+///
+/// Options: `--policy <file>`, `--min <replicas>`, `--max <replicas>`, `--replicas <initial>`,
+/// `--tick <secs>`, `--startup <secs>`, `--cpu-target <percent>`, `--stabilization <JSON file>`
+/// or `--stabilization default`, `--forecast <JSON file>` to scale ahead of the history recorded
+/// for `--app <id>` and `--slo <metric>=<max>`, which may be repeated.
+pub fn run() -> anyhow::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    // args[1] is the `simulate` subcommand
+    let trace_path = args.get(2).context("simulate needs a trace file")?;
+    let mut policy = None;
+    let mut predictive: Option<PredictiveScaling> = None;
+    let mut app_id = "simulation".to_string();
+    let mut bounds = (1, 10);
+    let mut cpu_target = 70;
+    let mut config = SimulationConfig::default();
+
+    let mut i = 3;
+    while i < args.len() {
+        let value = args.get(i + 1).with_context(|| format!("{} needs a value", args[i]))?;
+        let number = || value.parse::<u64>().with_context(|| format!("{} needs a number", args[i]));
+        match args[i].as_str() {
+            "--policy" => policy = Some(ScalingPolicy::load(Path::new(value))?),
+            "--min" => bounds.0 = number()? as u32,
+            "--max" => bounds.1 = number()? as u32,
+            "--replicas" => config.initial_replicas = number()? as u32,
+            "--tick" => config.tick_secs = number()?,
+            "--startup" => config.startup_secs = number()?,
+            "--cpu-target" => cpu_target = number()?,
+            "--stabilization" => {
+                config.stabilization = Some(match value.as_str() {
+                    "default" => StabilizationPolicy::default(),
+                    path => {
+                        let content = std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path))?;
+                        serde_json::from_str(&content).with_context(|| format!("invalid stabilization policy {}", path))?
+                    }
+                });
+            }
+            "--forecast" => {
+                let content = std::fs::read_to_string(value).with_context(|| format!("failed to read {}", value))?;
+                predictive = Some(serde_json::from_str(&content).with_context(|| format!("invalid predictive scaling {}", value))?);
+            }
+            "--app" => app_id = value.clone(),
+            "--slo" => {
+                let (metric, max) = value.split_once('=').context("--slo needs <metric>=<max>")?;
+                let metric = metric.parse().map_err(|_| anyhow::anyhow!("unknown metric `{}`", metric))?;
+                let max = max.parse().with_context(|| format!("invalid SLO maximum `{}`", max))?;
+                config.slos.push(Slo { metric, max });
+            }
+            other => anyhow::bail!("unknown simulate option `{}`", other),
+        }
+        i += 2;
+    }
+
+    let trace = Trace::load(Path::new(trace_path))?;
+    let mut scaler = ReplicaScaler::new(
+        &app_id,
+        ReplicaBounds::new(bounds.0, bounds.1)?,
+        ReplicaTargets { cpu_load: Some(cpu_target), ..ReplicaTargets::default() },
+        InstanceLimits::default(),
+        AutoscalerThresholds::new(90, 4096, 1000, 1000)
+    );
+    if let Some(predictive) = predictive {
+        scaler = scaler.with_forecast(predictive, Arc::new(MetricHistory::from_env()));
+    }
+    let report = Simulation::new(scaler, policy, config).run(&trace);
+    eprintln!(
+        "{} ticks, {} scale-outs, {} scale-ins, {} resizes, {} ticks violating SLOs, peak {} replicas, {:.2} replica hours",
+        report.summary.ticks,
+        report.summary.scale_outs,
+        report.summary.scale_ins,
+        report.summary.resizes,
+        report.summary.violating_ticks,
+        report.summary.peak_replicas,
+        report.summary.replica_hours
+    );
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::autoscalar::ThresholdBand;
+
+    /// An hour of one replica's worth of CPU, with a spike to four replicas' worth from minute 10 to 40
+    fn spike() -> Trace {
+        let mut csv = String::from("at,replicas,cpu_load,network_latency\n");
+        for minute in 0..60 {
+            let load = if (10..40).contains(&minute) { 200 } else { 50 };
+            csv.push_str(&format!("{},1,{},20\n", minute * 60, load));
+        }
+        Trace::from_csv(&csv).unwrap()
+    }
+
+    fn scaler() -> ReplicaScaler {
+        let band = ThresholdBand { up: 1_000_000, down: 0 };
+        ReplicaScaler::new(
+            "web",
+            ReplicaBounds::new(1, 6).unwrap(),
+            ReplicaTargets { cpu_load: Some(60), ..ReplicaTargets::default() },
+            InstanceLimits::default(),
+            // Horizontal scaling only
+            AutoscalerThresholds::with_bands(band, band, band, band).unwrap()
+        )
+    }
+
+    #[test]
+    fn replays_a_spike_and_reports_slo_violations() {
+        let config = SimulationConfig {
+            tick_secs: 30,
+            startup_secs: 60,
+            initial_replicas: 1,
+            slos: vec![Slo { metric: Metric::CpuLoad, max: 90 }],
+            stabilization: None,
+        };
+        let report = Simulation::new(scaler(), None, config.clone()).run(&spike());
+
+        // Straight to ceil(200 / 60) replicas, and back to one once the spike is over
+        assert_eq!(report.summary.scale_outs, 1);
+        assert_eq!(report.summary.scale_ins, 1);
+        assert_eq!(report.summary.peak_replicas, 4);
+        assert_eq!(report.timeline.last().unwrap().replicas, 1);
+
+        // New replicas take a minute to serve, the old one is overloaded meanwhile
+        assert_eq!(report.summary.violating_ticks, 2);
+        let after_startup = report.timeline.iter().filter(|tick| tick.at >= spike().start() + Duration::minutes(11));
+        assert!(after_startup.clone().take(58).all(|tick| tick.serving == 4 && tick.violations.is_empty()));
+
+        // The same trace with instant startup and a scaler that never reacts violates throughout the plateau
+        let frozen = ReplicaScaler { bounds: ReplicaBounds::new(1, 1).unwrap(), ..scaler() };
+        let report = Simulation::new(frozen, None, SimulationConfig { startup_secs: 0, ..config }).run(&spike());
+        assert_eq!(report.summary.scale_outs, 0);
+        assert_eq!(report.summary.violating_ticks, 30 * 2);
+    }
+
+    #[test]
+    fn replays_through_the_stabilizer() {
+        let config = SimulationConfig {
+            tick_secs: 30,
+            startup_secs: 0,
+            initial_replicas: 1,
+            slos: Vec::new(),
+            stabilization: Some(StabilizationPolicy::default()),
+        };
+        let report = Simulation::new(scaler(), None, config).run(&spike());
+        let scaling: Vec<(i64, u32)> = report
+            .timeline
+            .iter()
+            .filter(|tick| !tick.actions.is_empty())
+            .map(|tick| ((tick.at - spike().start()).num_seconds(), tick.replicas))
+            .collect();
+
+        // Up right away, down only once the spike left the window, one replica per cooldown
+        assert_eq!(scaling, [(600, 4), (2700, 3), (3000, 2), (3300, 1)]);
+    }
+
+    #[test]
+    fn replays_predictive_scaling_on_the_virtual_clock() {
+        let dir = std::env::temp_dir().join(format!("omniforge-simulator-forecast-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let history = Arc::new(MetricHistory::new(&dir));
+        // The trace's own load only needs one replica, but four were needed every hour before it
+        let start = spike().start();
+        let busy = InstanceMetrics { cpu_load: Some(240), ..InstanceMetrics::default() };
+        for hours_ago in (1..=96).rev() {
+            history.record("web", start - Duration::hours(hours_ago), std::slice::from_ref(&busy)).unwrap();
+        }
+        let predictive = PredictiveScaling { metric: Metric::CpuLoad, target_per_replica: 60.0, lead_secs: 600, max_mape: None };
+        let config = SimulationConfig { tick_secs: 60, startup_secs: 0, initial_replicas: 1, slos: Vec::new(), stabilization: None };
+        let report = Simulation::new(scaler().with_forecast(predictive, history), None, config).run(&spike());
+
+        assert_eq!(report.timeline[0].actions, [ReplicaAction::ScaleOut { app_id: "web".to_string(), from: 1, to: 4 }]);
+        assert_eq!(report.summary.scale_ins, 0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn replays_json_traces_against_a_policy() {
+        let trace = Trace::from_json(
+            r#"[
+                { "at": "2024-01-01T00:00:00Z", "replicas": 2, "clients": 100 },
+                { "at": "2024-01-01T00:05:00Z", "replicas": 2, "clients": 400 },
+                { "at": "2024-01-01T00:15:00Z", "replicas": 2, "clients": 400 }
+            ]"#,
+        )
+        .unwrap();
+        let policy = ScalingPolicy::from_toml(
+            r#"
+            [[rules]]
+            name = "busy"
+            priority = 1
+            metric = "clients"
+            aggregation = "avg"
+            comparator = ">"
+            threshold = 250
+            action = { type = "scale_out", by = 1 }
+            "#,
+        )
+        .unwrap();
+        let config = SimulationConfig { tick_secs: 60, startup_secs: 0, initial_replicas: 2, slos: Vec::new(), stabilization: None };
+        let report = Simulation::new(scaler(), Some(policy), config).run(&trace);
+
+        // Busy from minute 5, one replica per tick until 800 clients spread below 250 each
+        let scaling: Vec<(i64, u32)> = report
+            .timeline
+            .iter()
+            .filter(|tick| !tick.actions.is_empty())
+            .map(|tick| ((tick.at - trace.start()).num_minutes(), tick.replicas))
+            .collect();
+        assert_eq!(scaling, [(5, 3), (6, 4)]);
+
+        assert!(matches!(Trace::from_csv("at,cpu\n0,5"), Err(SimulationError::Csv { line: 1, .. })));
+        assert!(matches!(Trace::from_csv("at,cpu_load\n0,5,6"), Err(SimulationError::Csv { line: 2, .. })));
+        assert!(matches!(Trace::from_json("[]"), Err(SimulationError::Empty)));
+    }
+}
@@ -0,0 +1,307 @@
+//-------------------------------------------------------------------------
+// Keeps scaling decisions from flapping. Replica recommendations are kept
+// for a stabilization window per direction: scaling up goes to the lowest
+// recommendation in the up window, scaling down to the highest in the down
+// window, so one noisy sample can't move the app. On top of that each
+// direction has a cooldown and a cap on how many replicas it may add or
+// remove per step period. Vertical resizes get the same cooldowns per
+// instance and resource, so growing the network bandwidth doesn't wait
+// for a CPU and memory resize.
+//-------------------------------------------------------------------------
+
+use std::collections::{ HashMap, VecDeque };
+
+use chrono::{ DateTime, Duration, Utc };
+use serde::{ Deserialize, Serialize };
+
+use super::ScaleAction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+impl Direction {
+    /// Which way a vertical action goes, `None` for NoAction
+    pub fn of(action: ScaleAction) -> Option<Self> {
+        match action {
+            ScaleAction::ScaleUp | ScaleAction::ScaleRight => Some(Direction::Up),
+            ScaleAction::ScaleDown | ScaleAction::ScaleLeft => Some(Direction::Down),
+            ScaleAction::NoAction => None,
+        }
+    }
+}
+
+/// What a vertical action resizes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Resource {
+    /// CPU and memory, ScaleUp and ScaleDown
+    Compute,
+    /// ScaleLeft
+    DiskBandwidth,
+    /// ScaleRight
+    NetworkBandwidth,
+}
+
+impl Resource {
+    fn of(action: ScaleAction) -> Option<Self> {
+        match action {
+            ScaleAction::ScaleUp | ScaleAction::ScaleDown => Some(Resource::Compute),
+            ScaleAction::ScaleLeft => Some(Resource::DiskBandwidth),
+            ScaleAction::ScaleRight => Some(Resource::NetworkBandwidth),
+            ScaleAction::NoAction => None,
+        }
+    }
+}
+
+/// Limits for one scaling direction
+///
+/// # Fields
+/// stabilization_window_secs - How far back recommendations are considered
+/// cooldown_secs - Minimum time after a scaling event before the next one in this direction.
+///     Scaling down also waits this long after a scale up.
+/// max_step - Replicas that may be added or removed per step period
+/// step_period_secs - Length of the step period
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirectionPolicy {
+    pub stabilization_window_secs: u64,
+    pub cooldown_secs: u64,
+    pub max_step: u32,
+    pub step_period_secs: u64,
+}
+
+/// Stabilization for both directions. The defaults react to load quickly and release it slowly.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StabilizationPolicy {
+    pub up: DirectionPolicy,
+    pub down: DirectionPolicy,
+}
+
+impl Default for StabilizationPolicy {
+    fn default() -> Self {
+        StabilizationPolicy {
+            up: DirectionPolicy {
+                stabilization_window_secs: 0,
+                cooldown_secs: 60,
+                max_step: 4,
+                step_period_secs: 60,
+            },
+            down: DirectionPolicy {
+                stabilization_window_secs: 300,
+                cooldown_secs: 300,
+                max_step: 1,
+                step_period_secs: 60,
+            },
+        }
+    }
+}
+
+impl StabilizationPolicy {
+    fn direction(&self, direction: Direction) -> &DirectionPolicy {
+        match direction {
+            Direction::Up => &self.up,
+            Direction::Down => &self.down,
+        }
+    }
+}
+
+/// A scaling event that happened
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ScaleEvent {
+    at: DateTime<Utc>,
+    direction: Direction,
+    step: u32,
+}
+
+pub struct Stabilizer {
+    policy: StabilizationPolicy,
+    recommendations: VecDeque<(DateTime<Utc>, u32)>,
+    events: VecDeque<ScaleEvent>,
+    /// Last resize of each instance and resource, until its cooldowns are over
+    resized: HashMap<(String, Resource), ScaleEvent>,
+}
+
+impl Stabilizer {
+    pub fn new(policy: StabilizationPolicy) -> Self {
+        Stabilizer {
+            policy,
+            recommendations: VecDeque::new(),
+            events: VecDeque::new(),
+            resized: HashMap::new(),
+        }
+    }
+
+    /// Turns a raw replica recommendation into the count to scale to now. Anything other
+    /// than `current` is recorded as a scaling event.
+    pub fn replicas(&mut self, now: DateTime<Utc>, current: u32, recommended: u32) -> u32 {
+        self.recommendations.push_back((now, recommended));
+        self.prune(now);
+
+        let window = |secs: u64| {
+            let since = before(now, secs);
+            self.recommendations.iter().filter(move |(at, _)| *at >= since).map(|(_, replicas)| *replicas)
+        };
+        let up_floor = window(self.policy.up.stabilization_window_secs).min().unwrap_or(recommended);
+        let down_ceiling = window(self.policy.down.stabilization_window_secs).max().unwrap_or(recommended);
+
+        let (direction, wanted) = if up_floor > current {
+            (Direction::Up, up_floor - current)
+        } else if down_ceiling < current {
+            (Direction::Down, current - down_ceiling)
+        } else {
+            return current;
+        };
+
+        if self.cooling_down(now, direction, self.events.iter()) {
+            return current;
+        }
+        let policy = self.policy.direction(direction);
+        let period_start = before(now, policy.step_period_secs);
+        let used: u32 = self
+            .events
+            .iter()
+            .filter(|event| event.direction == direction && event.at > period_start)
+            .map(|event| event.step)
+            .sum();
+        let step = wanted.min(policy.max_step.saturating_sub(used));
+        if step == 0 {
+            return current;
+        }
+
+        self.events.push_back(ScaleEvent { at: now, direction, step });
+        match direction {
+            Direction::Up => current + step,
+            Direction::Down => current - step,
+        }
+    }
+
+    /// Whether a vertical action may run on the instance now, recorded as the last resize of
+    /// the resource it changes if so
+    pub fn allow_resize(&mut self, now: DateTime<Utc>, instance: &str, action: ScaleAction) -> bool {
+        let (Some(direction), Some(resource)) = (Direction::of(action), Resource::of(action)) else {
+            return false;
+        };
+        self.prune(now);
+        let key = (instance.to_string(), resource);
+        if self.cooling_down(now, direction, self.resized.get(&key).into_iter()) {
+            return false;
+        }
+        self.resized.insert(key, ScaleEvent { at: now, direction, step: 1 });
+        true
+    }
+
+    /// Up waits for the last up event, down waits for the last event either way
+    fn cooling_down<'a>(
+        &self,
+        now: DateTime<Utc>,
+        direction: Direction,
+        events: impl Iterator<Item = &'a ScaleEvent>
+    ) -> bool {
+        let since = before(now, self.policy.direction(direction).cooldown_secs);
+        events
+            .filter(|event| direction == Direction::Down || event.direction == direction)
+            .any(|event| event.at > since)
+    }
+
+    fn prune(&mut self, now: DateTime<Utc>) {
+        let keep = [
+            self.policy.up.stabilization_window_secs,
+            self.policy.down.stabilization_window_secs,
+            self.policy.up.cooldown_secs,
+            self.policy.down.cooldown_secs,
+            self.policy.up.step_period_secs,
+            self.policy.down.step_period_secs,
+        ]
+            .into_iter()
+            .max()
+            .unwrap_or(0);
+        let oldest = before(now, keep);
+        while self.recommendations.front().is_some_and(|(at, _)| *at < oldest) {
+            self.recommendations.pop_front();
+        }
+        while self.events.front().is_some_and(|event| event.at < oldest) {
+            self.events.pop_front();
+        }
+        // Also forgets instances that are gone
+        self.resized.retain(|_, event| event.at >= oldest);
+    }
+}
+
+/// `secs` before `now`, saturating for absurdly long windows
+fn before(now: DateTime<Utc>, secs: u64) -> DateTime<Utc> {
+    i64::try_from(secs)
+        .ok()
+        .and_then(Duration::try_seconds)
+        .and_then(|duration| now.checked_sub_signed(duration))
+        .unwrap_or(DateTime::<Utc>::MIN_UTC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    #[test]
+    fn noisy_recommendations_do_not_flap() {
+        let mut stabilizer = Stabilizer::new(StabilizationPolicy::default());
+        // Up reacts right away, but only by max_step
+        assert_eq!(stabilizer.replicas(at(0), 2, 9), 6);
+
+        // Dips inside the down window are outvoted by the earlier high recommendation
+        let mut replicas = 6;
+        for (i, recommended) in [3, 6, 2, 6, 3, 2].into_iter().enumerate() {
+            replicas = stabilizer.replicas(at(30 * (i as i64 + 1)), replicas, recommended);
+            assert_eq!(replicas, 6);
+        }
+
+        // Once the window only holds low values it scales down, one step per cooldown
+        for secs in (200..=480).step_by(30) {
+            replicas = stabilizer.replicas(at(secs), replicas, 2);
+        }
+        assert_eq!(replicas, 5);
+        assert_eq!(stabilizer.replicas(at(700), replicas, 2), 5);
+        assert_eq!(stabilizer.replicas(at(741), replicas, 2), 4);
+    }
+
+    #[test]
+    fn cooldowns_apply_per_direction_and_per_instance() {
+        let policy = StabilizationPolicy {
+            up: DirectionPolicy { stabilization_window_secs: 0, cooldown_secs: 60, max_step: 10, step_period_secs: 60 },
+            down: DirectionPolicy { stabilization_window_secs: 0, cooldown_secs: 120, max_step: 10, step_period_secs: 60 },
+        };
+        let mut stabilizer = Stabilizer::new(policy);
+        assert_eq!(stabilizer.replicas(at(0), 2, 4), 4);
+        assert_eq!(stabilizer.replicas(at(30), 4, 6), 4);
+        // Scaling down right after scaling up is exactly the flapping we want to avoid
+        assert_eq!(stabilizer.replicas(at(90), 4, 2), 4);
+        assert_eq!(stabilizer.replicas(at(121), 4, 2), 2);
+
+        assert!(stabilizer.allow_resize(at(0), "a", ScaleAction::ScaleUp));
+        assert!(!stabilizer.allow_resize(at(10), "a", ScaleAction::ScaleUp));
+        assert!(stabilizer.allow_resize(at(10), "b", ScaleAction::ScaleUp));
+        assert!(!stabilizer.allow_resize(at(100), "a", ScaleAction::ScaleDown));
+        assert!(stabilizer.allow_resize(at(121), "a", ScaleAction::ScaleDown));
+    }
+
+    #[test]
+    fn resize_cooldowns_are_per_resource_and_expire() {
+        let mut stabilizer = Stabilizer::new(StabilizationPolicy::default());
+        // Network bandwidth doesn't wait for a CPU and memory resize, nor disk for network
+        assert!(stabilizer.allow_resize(at(0), "a", ScaleAction::ScaleUp));
+        assert!(stabilizer.allow_resize(at(10), "a", ScaleAction::ScaleRight));
+        assert!(stabilizer.allow_resize(at(20), "a", ScaleAction::ScaleLeft));
+        assert!(!stabilizer.allow_resize(at(30), "a", ScaleAction::ScaleRight));
+        assert!(!stabilizer.allow_resize(at(40), "a", ScaleAction::ScaleDown));
+        assert!(!stabilizer.allow_resize(at(50), "a", ScaleAction::NoAction));
+        assert_eq!(stabilizer.resized.len(), 3);
+
+        // Resizes of instances long gone are forgotten
+        assert!(stabilizer.allow_resize(at(1000), "b", ScaleAction::ScaleUp));
+        assert_eq!(stabilizer.resized.len(), 1);
+    }
+}
@@ -0,0 +1,556 @@
+//-------------------------------------------------------------------------
+// Keeps an eye on deployed apps while the server runs. With the deployment
+// config named by $OMNIFORGE_DEPLOY_CONFIG, every app in the rollout
+// history is looked up on the hosts its current revision was rolled out to
+// and each container of that revision is health checked with the spec's probes, feeding the
+// instance's ApplicationState. Containers are listed again on an interval
+// so rollouts and scaling are picked up. Instances are named
+// `<host>/<container>`, the same as the scaling driver names them.
+//
+// Apps in the config's `autoscale` section are also scaled: on their
+// interval (unless a rollout of the app is in progress) the hosts are
+// first brought back to the replica count last scaled to, then the app's
+// ReplicaScaler plans from the instances' health, engine stats and custom
+// metrics, and the DeploymentDriver carries it out.
+// With the server's audit log, what the scaler did, the containers a
+// reconcile replaced, blacklistings and each app's scaling config (when it
+// differs from the one recorded last) are audited.
+//-------------------------------------------------------------------------
+
+use std::collections::{ BTreeMap, HashMap, HashSet, VecDeque };
+use std::path::Path;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+
+use anyhow::{ Context, Result };
+use chrono::{ DateTime, Utc };
+use futures_util::future::join_all;
+use rocket::fairing::AdHoc;
+use serde_json::json;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{ debug, error, info, warn };
+
+use super::reconcile::{ parse_containers, DeploymentEngine, ResourceLimits };
+use super::rollout::{ HistoryStore, Revision };
+use super::scaling::DeploymentDriver;
+use super::read_config;
+use crate::audit::AuditLog;
+use crate::autoscalar::forecast::MetricHistory;
+use crate::autoscalar::health::HealthMonitor;
+use crate::autoscalar::ingest::MetricsIngest;
+use crate::autoscalar::metrics::{ EngineStatsSource, MetricsCollector };
+use crate::autoscalar::replicas::{ InstanceLimits, ReplicaAction, ReplicaInstance, ReplicaScaler, ScalingConfig };
+use crate::autoscalar::{ AppInstance, ApplicationState, InstanceMetrics };
+use crate::image_builder::engine::ContainerEngineKind;
+use crate::ssh::{ FleetOptions, Host, SessionPool };
+
+/// Env var naming the deployment config whose apps the server watches
+pub const CONFIG_VAR: &str = "OMNIFORGE_DEPLOY_CONFIG";
+/// How often the hosts are asked which containers they run
+const SYNC_INTERVAL_SECS: u64 = 30;
+/// How often autoscaled apps are checked for being due, their own interval decides
+const AUTOSCALE_TICK_SECS: u64 = 5;
+/// How far apart the two stats samples of a new instance's first collection are
+const STATS_WINDOW: Duration = Duration::from_secs(1);
+
+/// An autoscaled app's scaler and what it remembers between decisions
+///
+/// # Fields
+/// scaler - Plans the app's replicas and resizes
+/// interval - Time between two decisions
+/// last_run - When the last decision was made
+/// collectors - Engine stats per instance, each keeps the previous sample for the rates
+/// allocations - What each instance is allowed, as resizes left it
+/// history - Every instance's metrics of the last decisions, as far back as the scaling policy looks
+/// window - How far back the thresholds look at an instance's metrics
+/// windows - Each instance's metrics of the last decisions within the window, for the thresholds
+/// policy - The config and policy rules it was built from, for the audit log
+struct AppAutoscaler {
+    scaler: ReplicaScaler,
+    policy: serde_json::Value,
+    interval: Duration,
+    last_run: Option<Instant>,
+    collectors: HashMap<String, MetricsCollector<EngineStatsSource>>,
+    allocations: HashMap<String, AppInstance>,
+    history: VecDeque<(InstanceMetrics, DateTime<Utc>)>,
+    window: chrono::Duration,
+    windows: HashMap<String, VecDeque<(InstanceMetrics, DateTime<Utc>)>>,
+}
+
+impl AppAutoscaler {
+    fn due(&self) -> bool {
+        self.last_run.is_none_or(|last_run| last_run.elapsed() >= self.interval)
+    }
+
+    /// Keeps each instance's metrics over the thresholds' window and hands it the window, and
+    /// keeps the instances' metrics for the policy, one decision further back than its longest
+    /// rule so that rule can fire
+    fn remember(&mut self, instances: &mut [ReplicaInstance], now: DateTime<Utc>) {
+        let oldest = now - self.window;
+        self.windows.retain(|id, _| instances.iter().any(|instance| instance.id == *id));
+        for instance in instances.iter_mut() {
+            let window = self.windows.entry(instance.id.clone()).or_default();
+            window.push_back((instance.metrics.clone(), now));
+            while window.front().is_some_and(|(_, at)| *at < oldest) {
+                window.pop_front();
+            }
+            instance.window = window.iter().cloned().collect();
+        }
+
+        let Some(policy) = &self.scaler.policy else {
+            return;
+        };
+        let longest = policy.rules().iter().map(|rule| rule.duration_secs).max().unwrap_or(0);
+        let keep = chrono::Duration::seconds((longest + 2 * self.interval.as_secs()) as i64);
+        self.history.extend(instances.iter().map(|instance| (instance.metrics.clone(), now)));
+        while self.history.front().is_some_and(|(_, at)| *at < now - keep) {
+            self.history.pop_front();
+        }
+    }
+}
+
+pub struct Fleet {
+    pool: Arc<SessionPool>,
+    kind: ContainerEngineKind,
+    engine: Arc<DeploymentEngine>,
+    hosts: Vec<Host>,
+    history: HistoryStore,
+    health: Arc<HealthMonitor>,
+    ingest: Option<Arc<MetricsIngest>>,
+    audit: Option<Arc<AuditLog>>,
+    /// Watched instances and their probe tasks
+    watched: Mutex<HashMap<String, Vec<JoinHandle<()>>>>,
+    /// Autoscaled apps by app id
+    autoscalers: tokio::sync::Mutex<BTreeMap<String, AppAutoscaler>>,
+}
+
+impl Fleet {
+    pub fn new(pool: Arc<SessionPool>, kind: ContainerEngineKind, hosts: Vec<Host>, history: HistoryStore) -> Self {
+        Fleet {
+            engine: Arc::new(DeploymentEngine::new(pool.clone(), kind, FleetOptions::default().parallelism)),
+            health: Arc::new(HealthMonitor::new(pool.clone(), kind)),
+            pool,
+            kind,
+            hosts,
+            history,
+            ingest: None,
+            audit: None,
+            watched: Mutex::new(HashMap::new()),
+            autoscalers: tokio::sync::Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Autoscales these apps, forecasting from `history`, failing on a config the scaler can't
+    /// work with
+    pub fn with_autoscaling(
+        mut self,
+        configs: &BTreeMap<String, ScalingConfig>,
+        history: &Arc<MetricHistory>
+    ) -> Result<Self> {
+        let autoscalers = self.autoscalers.get_mut();
+        for (app_id, config) in configs {
+            let mut scaler = config.scaler(app_id, history).with_context(|| format!("invalid autoscale config for {}", app_id))?;
+            let policy = json!({ "config": config, "rules": scaler.policy.as_ref().map(|policy| policy.rules()) });
+            if let Some(audit) = &self.audit {
+                audit.record_policy(&crate::audit::local_actor(), app_id, policy.clone());
+                scaler = scaler.with_audit(audit.clone());
+            }
+            autoscalers.insert(app_id.clone(), AppAutoscaler {
+                scaler,
+                policy,
+                interval: Duration::from_secs(config.interval_secs),
+                last_run: None,
+                collectors: HashMap::new(),
+                allocations: HashMap::new(),
+                history: VecDeque::new(),
+                window: chrono::Duration::seconds(config.threshold_window_secs() as i64),
+                windows: HashMap::new(),
+            });
+        }
+        Ok(self)
+    }
+
+    /// Audits what the autoscaler does and the blacklisting of instances, and records each app's
+    /// scaling config as a policy change when it differs from the one recorded last. It replaces
+    /// the health monitor, so call it before `sync`.
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.health = Arc::new(HealthMonitor::new(self.pool.clone(), self.kind).with_audit(audit.clone()));
+        for (app_id, autoscaler) in self.autoscalers.get_mut() {
+            audit.record_policy(&crate::audit::local_actor(), app_id, autoscaler.policy.clone());
+            autoscaler.scaler.audit = Some(audit.clone());
+        }
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Has the autoscaler use the custom metrics pushed or scraped for the instances
+    pub fn with_metrics(mut self, ingest: Arc<MetricsIngest>) -> Self {
+        self.ingest = Some(ingest);
+        self
+    }
+
+    /// Every host of the config's inventory, with rollout history from the environment
+    pub fn from_config<P: AsRef<Path>>(path: P, history: &Arc<MetricHistory>) -> Result<Self> {
+        let config = read_config(&path).with_context(|| format!("failed to read {}", path.as_ref().display()))?;
+        let pool = config.session_pool()?;
+        let kind = config.engine();
+        let hosts = config.inventory.resolve()?.hosts.into_iter().map(|entry| entry.host).collect();
+        Fleet::new(pool, kind, hosts, HistoryStore::from_env()).with_autoscaling(&config.autoscale, history)
+    }
+
+    pub fn health(&self) -> &Arc<HealthMonitor> {
+        &self.health
+    }
+
+    /// Every app's current revision. Revisions recorded before their hosts were are skipped, the
+    /// fleet wouldn't know where the app runs.
+    pub fn apps(&self) -> Result<Vec<Revision>> {
+        let mut revisions = Vec::new();
+        for app_id in self.history.apps()? {
+            match self.history.current(&app_id)? {
+                Some(revision) if revision.hosts.is_empty() => {
+                    debug!("{} revision {} doesn't name its hosts, roll it out again to have it watched", app_id, revision.revision);
+                }
+                Some(revision) => revisions.push(revision),
+                None => {}
+            }
+        }
+        Ok(revisions)
+    }
+
+    /// Drives the revision on the inventory hosts it was rolled out to, never on the others
+    pub fn driver(&self, revision: &Revision) -> DeploymentDriver {
+        let hosts = self.hosts.iter().filter(|host| revision.hosts.contains(&host.name)).cloned().collect();
+        let driver = DeploymentDriver::new(self.engine.clone(), revision.spec.clone(), hosts, self.history.clone());
+        match &self.audit {
+            Some(audit) => driver.with_audit(audit.clone()),
+            None => driver,
+        }
+    }
+
+    /// The host and container of a `<host>/<container>` instance
+    fn locate<'a>(&'a self, instance: &'a str) -> Option<(&'a Host, &'a str)> {
+        let (host_name, container) = instance.split_once('/')?;
+        let host = self.hosts.iter().find(|host| host.name == host_name)?;
+        Some((host, container))
+    }
+
+    /// Starts probing containers that appeared and stops probing those that are gone. An app
+    /// whose containers can't be listed keeps its instances watched. Returns how many are.
+    pub async fn sync(&self) -> Result<usize> {
+        let mut found = Vec::new();
+        let mut unlisted = Vec::new();
+        for revision in self.apps()? {
+            let spec = &revision.spec;
+            match self.driver(&revision).instances().await {
+                Ok(instances) => found.extend(instances.into_iter().map(|instance| (instance, spec.clone()))),
+                Err(e) => {
+                    warn!("Failed to list the containers of {}: {}", spec.app_id, e);
+                    unlisted.push(spec.app_id.clone());
+                }
+            }
+        }
+
+        let mut watched = self.watched.lock().unwrap();
+        let current: HashSet<&str> = found.iter().map(|(instance, _)| instance.as_str()).collect();
+        watched.retain(|instance, tasks| {
+            let container = instance.split_once('/').map_or("", |(_, container)| container);
+            let keep = current.contains(instance.as_str())
+                || unlisted.iter().any(|app_id| !parse_containers(app_id, container).is_empty());
+            if !keep {
+                info!("[{}] no longer deployed, stopped health checks", instance);
+                tasks.iter().for_each(JoinHandle::abort);
+                self.health.unwatch(instance);
+            }
+            keep
+        });
+        for (instance, spec) in &found {
+            if watched.contains_key(instance) {
+                continue;
+            }
+            let Some((host, container)) = self.locate(instance) else {
+                continue;
+            };
+            info!("[{}] health checking", instance);
+            let tasks = self.health.watch(instance, host.clone(), spec.probes_for(container));
+            watched.insert(instance.clone(), tasks);
+        }
+        Ok(watched.len())
+    }
+
+    /// Runs the autoscaler of every deployed app whose interval is up, returning what each did
+    pub async fn autoscale(&self) -> Result<BTreeMap<String, Vec<ReplicaAction>>> {
+        let mut autoscalers = self.autoscalers.lock().await;
+        let mut done = BTreeMap::new();
+        for revision in self.apps()? {
+            let Some(autoscaler) = autoscalers.get_mut(&revision.spec.app_id) else {
+                continue;
+            };
+            if !autoscaler.due() {
+                continue;
+            }
+            // The driver refuses to touch it anyway, the rollout decides the replicas for now
+            if let Some(rolling) = self.history.rolling_out(&revision.spec.app_id)? {
+                debug!("{} revision {} is rolling out, not autoscaling it", revision.spec.app_id, rolling.revision);
+                continue;
+            }
+            autoscaler.last_run = Some(Instant::now());
+            match self.autoscale_app(&revision, autoscaler).await {
+                Ok(actions) => {
+                    done.insert(revision.spec.app_id, actions);
+                }
+                Err(e) => warn!("Failed to autoscale {}: {:#}", revision.spec.app_id, e),
+            }
+        }
+        Ok(done)
+    }
+
+    async fn autoscale_app(&self, revision: &Revision, autoscaler: &mut AppAutoscaler) -> Result<Vec<ReplicaAction>> {
+        let spec = &revision.spec;
+        let driver = self.driver(revision);
+        // Whatever went missing since the last decision is replaced before deciding anything new
+        driver.reconcile().await?;
+        let ids = driver.instances().await?;
+        if let Some(ingest) = &self.ingest {
+            ingest.set_instances(&spec.app_id, &ids);
+        }
+        autoscaler.collectors.retain(|id, _| ids.contains(id));
+        autoscaler.allocations.retain(|id, _| ids.contains(id));
+        for id in &ids {
+            if autoscaler.collectors.contains_key(id) {
+                continue;
+            }
+            let (host, container) = self.locate(id).with_context(|| format!("{} is not on this app's hosts", id))?;
+            let source = EngineStatsSource::new(self.pool.clone(), host.clone(), self.kind, container);
+            autoscaler.collectors.insert(id.clone(), MetricsCollector::new(source, STATS_WINDOW));
+        }
+
+        let collected = join_all(ids.iter().map(|id| autoscaler.collectors[id].collect())).await;
+        let now = Utc::now();
+        let mut instances = Vec::with_capacity(ids.len());
+        for (id, collected) in ids.iter().zip(collected) {
+            let mut metrics = collected.unwrap_or_else(|e| {
+                warn!("[{}] no engine stats: {}", id, e);
+                InstanceMetrics::default()
+            });
+            if let Some(ingest) = &self.ingest {
+                ingest.overlay(&spec.app_id, id, &mut metrics, now);
+            }
+            let allocation = autoscaler
+                .allocations
+                .entry(id.clone())
+                .or_insert_with(|| allocation(&spec.resources, &autoscaler.scaler.limits));
+            let state = self.health.state(id).unwrap_or(ApplicationState::Down);
+            instances.push(ReplicaInstance {
+                id: id.clone(),
+                instance: AppInstance { state, ..allocation.clone() },
+                metrics,
+                window: Vec::new(),
+            });
+        }
+
+        autoscaler.remember(&mut instances, now);
+        let actions = autoscaler.scaler.run(&instances, autoscaler.history.make_contiguous(), &driver).await?;
+        for action in &actions {
+            if let ReplicaAction::Resize { instance, allocation, .. } = action {
+                autoscaler.allocations.insert(instance.clone(), allocation.clone());
+            }
+        }
+        Ok(actions)
+    }
+
+    /// Syncs and autoscales on their intervals until shutdown, then stops every probe
+    pub async fn run(&self, mut shutdown: watch::Receiver<bool>) {
+        let mut sync = tokio::time::interval(Duration::from_secs(SYNC_INTERVAL_SECS));
+        let mut autoscale = tokio::time::interval(Duration::from_secs(AUTOSCALE_TICK_SECS));
+        loop {
+            tokio::select! {
+                _ = sync.tick() => {
+                    if let Err(e) = self.sync().await {
+                        warn!("Failed to sync deployed apps: {:#}", e);
+                    }
+                }
+                _ = autoscale.tick() => {
+                    if let Err(e) = self.autoscale().await {
+                        warn!("Failed to autoscale deployed apps: {:#}", e);
+                    }
+                }
+                _ = shutdown.changed() => break,
+            }
+        }
+        for (instance, tasks) in self.watched.lock().unwrap().drain() {
+            tasks.iter().for_each(JoinHandle::abort);
+            self.health.unwatch(&instance);
+        }
+    }
+}
+
+/// What a container is allowed before the autoscaler resized it: the spec's limits, or the most
+/// the scaler would give it where the spec sets none. Containers get no disk or network limits.
+fn allocation(resources: &ResourceLimits, limits: &InstanceLimits) -> AppInstance {
+    AppInstance {
+        state: ApplicationState::Down,
+        allocated_cpu: resources.cpus.map_or(limits.max_cpu, |cpus| (cpus * 100.0).round() as u64),
+        allocated_memory: resources.memory_mb.unwrap_or(limits.max_memory),
+        allocated_disk_bandwidth: limits.min_disk_bandwidth,
+        allocated_network_bandwidth: limits.max_network_bandwidth,
+    }
+}
+
+/// Manages the Fleet and its HealthMonitor (for the `/instances` routes), health checks and
+/// autoscales deployed apps while the server runs, when configured. Attach it after the custom metrics stage so the autoscaler gets their metrics.
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Deployed apps", |rocket| async move {
+        let Ok(path) = std::env::var(CONFIG_VAR) else {
+            info!("{} isn't set, deployed apps aren't health checked", CONFIG_VAR);
+            return Ok(rocket);
+        };
+        // The server's forecast history if it manages one, ingested metrics are recorded there
+        let history = rocket.state::<Arc<MetricHistory>>().cloned().unwrap_or_else(|| Arc::new(MetricHistory::from_env()));
+        let fleet = match Fleet::from_config(&path, &history) {
+            Ok(mut fleet) => {
+                if let Some(ingest) = rocket.state::<Arc<MetricsIngest>>() {
+                    fleet = fleet.with_metrics(ingest.clone());
+                }
+                if let Some(audit) = rocket.state::<Arc<AuditLog>>() {
+                    fleet = fleet.with_audit(audit.clone());
+                }
+                Arc::new(fleet)
+            }
+            Err(e) => {
+                error!("Failed to load the deployment config {} ({}): {:#}", path, CONFIG_VAR, e);
+                return Err(rocket);
+            }
+        };
+        let runner = fleet.clone();
+        let (shutdown, shutdown_rx) = watch::channel(false);
+
+        Ok(rocket
+            .manage(fleet.health().clone())
+            .manage(fleet)
+            .attach(AdHoc::on_liftoff("Deployed apps health checks and autoscaling", move |_| Box::pin(async move {
+                tokio::spawn(async move { runner.run(shutdown_rx).await });
+            })))
+            .attach(AdHoc::on_shutdown("Deployed apps shutdown", move |_| Box::pin(async move {
+                let _ = shutdown.send(true);
+            }))))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditAction;
+    use crate::autoscalar::replicas::{ ReplicaBounds, ReplicaTargets };
+    use crate::autoscalar::stabilization::StabilizationPolicy;
+    use crate::deployment::reconcile::tests::{ spec, FakeDocker };
+    use crate::deployment::reconcile::Action;
+    use crate::deployment::rollout::tests::deployed;
+    use crate::ssh::test_server::TestServer;
+    use crate::ssh::SessionOptions;
+
+    async fn healthy(fleet: &Fleet, instances: &[String]) {
+        for _ in 0..50 {
+            if instances.iter().all(|instance| fleet.health().state(instance) == Some(ApplicationState::Healthy)) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("{:?} never became healthy", instances);
+    }
+
+    #[tokio::test]
+    async fn health_checks_the_current_revisions_containers() {
+        let docker = FakeDocker::default();
+        let fake = docker.clone();
+        let server = TestServer::start(move |command| fake.handle(command)).await;
+        let pool = Arc::new(SessionPool::new(server.verifier(), SessionOptions::default()));
+        let engine = Arc::new(DeploymentEngine::new(pool.clone(), ContainerEngineKind::Docker, 1));
+        let host = server.host();
+
+        let spec = spec();
+        let history = deployed("fleet", &spec);
+        engine.reconcile(&spec, &host).await.unwrap();
+
+        let fleet = Fleet::new(pool, ContainerEngineKind::Docker, vec![host.clone()], history);
+        assert_eq!(fleet.sync().await.unwrap(), 2);
+        let instance = format!("test/{}", spec.container_name(0, &spec.hash()));
+        healthy(&fleet, std::slice::from_ref(&instance)).await;
+
+        // A removed container is no longer checked
+        let name = spec.container_name(0, &spec.hash());
+        engine.apply(&spec, &host, &Action::Remove { name }).await.unwrap();
+        assert_eq!(fleet.sync().await.unwrap(), 1);
+        assert_eq!(fleet.health().state(&instance), None);
+    }
+
+    #[tokio::test]
+    async fn autoscales_configured_apps_keeps_the_count_and_audits_both() {
+        let docker = FakeDocker::default();
+        let fake = docker.clone();
+        let server = TestServer::start(move |command| fake.handle(command)).await;
+        let pool = Arc::new(SessionPool::new(server.verifier(), SessionOptions::default()));
+        let engine = Arc::new(DeploymentEngine::new(pool.clone(), ContainerEngineKind::Docker, 1));
+        let host = server.host();
+
+        let spec = spec();
+        let history = deployed("fleet-autoscale", &spec);
+        engine.reconcile(&spec, &host).await.unwrap();
+
+        // The fake engine reports 80% CPU for every container
+        let config = ScalingConfig {
+            bounds: ReplicaBounds { min: 1, max: 3 },
+            targets: ReplicaTargets { cpu_load: Some(40), ..Default::default() },
+            limits: InstanceLimits::default(),
+            thresholds: None,
+            policy: None,
+            predictive: None,
+            stabilization: StabilizationPolicy::default(),
+            interval_secs: 60,
+            threshold_window_secs: None,
+        };
+        let path = std::env::temp_dir().join(format!("omniforge-fleet-audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let audit = Arc::new(AuditLog::new(&path));
+        // Another inventory host the app was never rolled out to, it must be left alone
+        let other = Host { name: "db".to_string(), ..host.clone() };
+        let fleet = Fleet::new(pool, ContainerEngineKind::Docker, vec![host.clone(), other], history.clone())
+            .with_autoscaling(&BTreeMap::from([(spec.app_id.clone(), config)]), &Arc::new(MetricHistory::from_env()))
+            .unwrap()
+            .with_audit(audit.clone());
+        fleet.sync().await.unwrap();
+        let driver = || fleet.driver(&history.current(&spec.app_id).unwrap().unwrap());
+        let instances = driver().instances().await.unwrap();
+        assert!(instances.iter().all(|instance| instance.starts_with("test/")));
+        healthy(&fleet, &instances).await;
+
+        let done = fleet.autoscale().await.unwrap();
+        assert_eq!(done[&spec.app_id], [ReplicaAction::ScaleOut { app_id: spec.app_id.clone(), from: 2, to: 3 }]);
+        assert_eq!(engine.containers(&spec.app_id, &host).await.unwrap().len(), 3);
+        assert_eq!(history.current(&spec.app_id).unwrap().unwrap().scaled_replicas, Some(3));
+
+        // Not due again before its interval
+        assert!(fleet.autoscale().await.unwrap().is_empty());
+
+        // A container that died is replaced on the next decision, from the saved count
+        let name = instances[0].split_once('/').unwrap().1.to_string();
+        engine.apply(&spec, &host, &Action::Remove { name }).await.unwrap();
+        driver().reconcile().await.unwrap();
+        assert_eq!(engine.containers(&spec.app_id, &host).await.unwrap().len(), 3);
+        assert_eq!(driver().instances().await.unwrap().len(), 3);
+
+        // The config it scaled by, the scale out with its reasons and the replaced container
+        let entries = audit.query(&Default::default()).unwrap();
+        let seen: Vec<(AuditAction, &str)> = entries.iter().map(|entry| (entry.action, entry.target.as_str())).collect();
+        let app_id = spec.app_id.as_str();
+        assert_eq!(seen, [(AuditAction::PolicyChange, app_id), (AuditAction::Scale, app_id), (AuditAction::Scale, app_id)]);
+        assert_eq!(entries[0].parameters["config"]["bounds"]["max"], 3);
+        assert_eq!(entries[1].parameters["action"]["ScaleOut"]["to"], 3);
+        assert!(entries[1].parameters["reasons"].is_object());
+        assert_eq!(entries[2].parameters["reconcile"][0]["to"], 3);
+        assert!(audit.verify().unwrap().valid);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}.head", path.display()));
+    }
+}
@@ -0,0 +1,359 @@
+//-------------------------------------------------------------------------
+// Host inventory: SSH hosts plus the roles, groups and labels deployments
+// target them by. Roles can be set on a host or assigned by rules that
+// select hosts, and the inventory is validated before anything connects.
+//
+// Selector syntax, terms separated by commas and all of them must match:
+//   role:NAME  group:NAME  name:GLOB     membership / host name
+//   key=value  key!=value                label equality
+//   key>=4  key<4 ...                    numeric label comparison
+//   key  !key                            label present / absent
+//   *                                    every host
+//-------------------------------------------------------------------------
+
+use std::collections::{ BTreeMap, BTreeSet, HashSet };
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::ssh::known_hosts::wildcard_match;
+use crate::ssh::Host;
+
+#[derive(Debug, Error)]
+pub enum InventoryError {
+    #[error("invalid selector `{selector}`: {reason}")]
+    InvalidSelector { selector: String, reason: String },
+    #[error("host name `{0}` is used more than once")]
+    DuplicateHost(String),
+    #[error("role `{0}` has no hosts")]
+    EmptyRole(String),
+    #[error("role `{role}` wants {wanted} hosts but only {matched} match `{selector}`")]
+    NotEnoughHosts { role: String, wanted: usize, matched: usize, selector: String },
+    #[error("inventory is invalid:\n  {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n  "))]
+    Invalid(Vec<InventoryError>),
+}
+
+/// An SSH host and what it is for
+///
+/// # Fields
+/// host - Connection settings, the same fields as a plain SSH host entry
+/// roles - Roles set explicitly, rules may add more
+/// groups - Free-form groups, e.g. "edge" or "gpu"
+/// labels - Key/value attributes such as zone, arch and capacity
+#[derive(Debug, Clone, Deserialize)]
+pub struct InventoryHost {
+    #[serde(flatten)]
+    pub host: Host,
+    #[serde(default)]
+    pub roles: BTreeSet<String>,
+    #[serde(default)]
+    pub groups: BTreeSet<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl InventoryHost {
+    pub fn name(&self) -> &str {
+        &self.host.name
+    }
+}
+
+/// Assigns `role` to the hosts matching `selector`, only the first `count` in inventory order if set
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoleRule {
+    pub role: String,
+    pub selector: Selector,
+    #[serde(default)]
+    pub count: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Inventory {
+    pub hosts: Vec<InventoryHost>,
+    #[serde(default)]
+    pub role_rules: Vec<RoleRule>,
+}
+
+impl Inventory {
+    /// Applies the role rules on top of the roles set on hosts, then validates the result
+    pub fn resolve(mut self) -> Result<Self, InventoryError> {
+        let mut problems = Vec::new();
+
+        let mut seen = HashSet::new();
+        for host in &self.hosts {
+            if !seen.insert(host.name()) {
+                problems.push(InventoryError::DuplicateHost(host.name().to_string()));
+            }
+        }
+
+        for rule in &self.role_rules {
+            let matching: Vec<usize> = (0..self.hosts.len())
+                .filter(|&i| rule.selector.matches(&self.hosts[i]))
+                .collect();
+            let wanted = rule.count.unwrap_or(matching.len());
+            if matching.len() < wanted {
+                problems.push(InventoryError::NotEnoughHosts {
+                    role: rule.role.clone(),
+                    wanted,
+                    matched: matching.len(),
+                    selector: rule.selector.to_string(),
+                });
+            }
+            for i in matching.into_iter().take(wanted) {
+                self.hosts[i].roles.insert(rule.role.clone());
+            }
+        }
+
+        let roles = self.roles();
+        for rule in &self.role_rules {
+            if roles.get(&rule.role).is_none_or(Vec::is_empty) {
+                problems.push(InventoryError::EmptyRole(rule.role.clone()));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(self)
+        } else {
+            Err(InventoryError::Invalid(problems))
+        }
+    }
+
+    /// Role -> names of the hosts that have it
+    pub fn roles(&self) -> BTreeMap<String, Vec<String>> {
+        let mut roles: BTreeMap<String, Vec<String>> = self
+            .role_rules
+            .iter()
+            .map(|rule| (rule.role.clone(), Vec::new()))
+            .collect();
+        for host in &self.hosts {
+            for role in &host.roles {
+                roles.entry(role.clone()).or_default().push(host.name().to_string());
+            }
+        }
+        roles
+    }
+
+    pub fn select(&self, selector: &Selector) -> Vec<&InventoryHost> {
+        self.hosts.iter().filter(|host| selector.matches(host)).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Term {
+    Role(String),
+    Group(String),
+    Name(String),
+    Label { key: String, op: Op, value: String },
+    Has(String),
+    Missing(String),
+}
+
+impl Term {
+    fn matches(&self, host: &InventoryHost) -> bool {
+        match self {
+            Term::Role(role) => host.roles.contains(role),
+            Term::Group(group) => host.groups.contains(group),
+            Term::Name(pattern) => wildcard_match(pattern, host.name()),
+            Term::Has(key) => host.labels.contains_key(key),
+            Term::Missing(key) => !host.labels.contains_key(key),
+            Term::Label { key, op, value } => {
+                let Some(actual) = host.labels.get(key) else {
+                    return *op == Op::Ne;
+                };
+                match op {
+                    Op::Eq => actual == value,
+                    Op::Ne => actual != value,
+                    _ => match (actual.parse::<f64>(), value.parse::<f64>()) {
+                        (Ok(actual), Ok(value)) => match op {
+                            Op::Gt => actual > value,
+                            Op::Ge => actual >= value,
+                            Op::Lt => actual < value,
+                            _ => actual <= value,
+                        },
+                        _ => false,
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// Picks hosts by role, group, name and labels, see the module header for the syntax
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Selector {
+    source: String,
+    terms: Vec<Term>,
+}
+
+impl Selector {
+    pub fn all() -> Self {
+        Selector { source: "*".to_string(), terms: Vec::new() }
+    }
+
+    pub fn matches(&self, host: &InventoryHost) -> bool {
+        self.terms.iter().all(|term| term.matches(host))
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl TryFrom<String> for Selector {
+    type Error = InventoryError;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        source.parse()
+    }
+}
+
+impl FromStr for Selector {
+    type Err = InventoryError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| InventoryError::InvalidSelector {
+            selector: source.to_string(),
+            reason: reason.to_string(),
+        };
+
+        let mut terms = Vec::new();
+        for raw in source.split(',').map(str::trim) {
+            if raw.is_empty() || raw == "*" {
+                continue;
+            }
+            let term = if let Some(role) = raw.strip_prefix("role:") {
+                Term::Role(role.trim().to_string())
+            } else if let Some(group) = raw.strip_prefix("group:") {
+                Term::Group(group.trim().to_string())
+            } else if let Some(name) = raw.strip_prefix("name:") {
+                // wildcard_match expects a lowercase pattern
+                Term::Name(name.trim().to_lowercase())
+            } else if let Some(key) = raw.strip_prefix('!') {
+                Term::Missing(key.trim().to_string())
+            } else {
+                // Two-character operators first so `>=` isn't read as `>`
+                let ops = [("!=", Op::Ne), (">=", Op::Ge), ("<=", Op::Le), ("=", Op::Eq), (">", Op::Gt), ("<", Op::Lt)];
+                match ops.iter().find_map(|(token, op)| raw.split_once(token).map(|(k, v)| (k, *op, v))) {
+                    Some((key, op, value)) => Term::Label {
+                        key: key.trim().to_string(),
+                        op,
+                        value: value.trim().to_string(),
+                    },
+                    None => Term::Has(raw.to_string()),
+                }
+            };
+
+            let key = match &term {
+                Term::Role(key) | Term::Group(key) | Term::Name(key) | Term::Has(key) | Term::Missing(key) => key,
+                Term::Label { key, op, value } => {
+                    if !matches!(op, Op::Eq | Op::Ne) && value.parse::<f64>().is_err() {
+                        return Err(invalid(&format!("`{}` needs a number", raw)));
+                    }
+                    key
+                }
+            };
+            if key.is_empty() {
+                return Err(invalid(&format!("`{}` is missing a name", raw)));
+            }
+            if key.contains(char::is_whitespace) {
+                return Err(invalid(&format!("`{}` contains whitespace", raw)));
+            }
+            terms.push(term);
+        }
+
+        Ok(Selector { source: source.to_string(), terms })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inventory(json: &str) -> Inventory {
+        serde_json::from_str(json).unwrap()
+    }
+
+    const HOSTS: &str = r#"
+        { "name": "eu-1", "address": "10.0.0.1", "port": 22, "username": "deploy",
+          "groups": ["edge"], "labels": { "zone": "eu", "arch": "amd64", "capacity": "8" } },
+        { "name": "eu-2", "address": "10.0.0.2", "port": 22, "username": "deploy",
+          "labels": { "zone": "eu", "arch": "arm64", "capacity": "4" } },
+        { "name": "us-1", "address": "10.0.1.1", "port": 22, "username": "deploy", "roles": ["master"],
+          "labels": { "zone": "us", "arch": "amd64", "capacity": "16" } }
+    "#;
+
+    #[test]
+    fn selectors_combine_labels_groups_and_names() {
+        let inventory = inventory(&format!(r#"{{ "hosts": [{}] }}"#, HOSTS));
+        let names = |selector: &str| -> Vec<String> {
+            inventory
+                .select(&selector.parse().unwrap())
+                .iter()
+                .map(|host| host.name().to_string())
+                .collect()
+        };
+
+        assert_eq!(names("*"), ["eu-1", "eu-2", "us-1"]);
+        assert_eq!(names("zone=eu, arch!=arm64"), ["eu-1"]);
+        assert_eq!(names("capacity>=8"), ["eu-1", "us-1"]);
+        assert_eq!(names("group:edge"), ["eu-1"]);
+        assert_eq!(names("name:EU-*"), ["eu-1", "eu-2"]);
+        assert_eq!(names("role:master"), ["us-1"]);
+        assert_eq!(names("!gpu, zone"), ["eu-1", "eu-2", "us-1"]);
+        assert!("capacity>=lots".parse::<Selector>().is_err());
+        assert!("=eu".parse::<Selector>().is_err());
+    }
+
+    #[test]
+    fn rules_assign_roles_on_top_of_explicit_ones() {
+        let inventory = inventory(&format!(
+            r#"{{ "hosts": [{}], "role_rules": [
+                {{ "role": "master", "selector": "zone=eu", "count": 1 }},
+                {{ "role": "worker", "selector": "role:master" }}
+            ] }}"#,
+            HOSTS
+        ))
+        .resolve()
+        .unwrap();
+
+        let roles = inventory.roles();
+        assert_eq!(roles["master"], ["eu-1", "us-1"]);
+        // Rules run in order, so the second one sees the masters the first one assigned
+        assert_eq!(roles["worker"], ["eu-1", "us-1"]);
+    }
+
+    #[test]
+    fn validation_reports_every_problem() {
+        let result = inventory(&format!(
+            r#"{{ "hosts": [{}, {{ "name": "eu-1", "address": "10.0.0.9", "port": 22, "username": "deploy" }}],
+                "role_rules": [
+                    {{ "role": "gpu", "selector": "group:gpu" }},
+                    {{ "role": "db", "selector": "zone=us", "count": 2 }}
+                ] }}"#,
+            HOSTS
+        ))
+        .resolve();
+
+        let Err(InventoryError::Invalid(problems)) = result else {
+            panic!("expected the inventory to be rejected");
+        };
+        assert!(matches!(&problems[0], InventoryError::DuplicateHost(name) if name == "eu-1"));
+        assert!(matches!(&problems[1], InventoryError::NotEnoughHosts { role, matched: 1, .. } if role == "db"));
+        assert!(matches!(&problems[2], InventoryError::EmptyRole(role) if role == "gpu"));
+    }
+}
@@ -1,10 +1,14 @@
+pub mod fleet;
+pub mod inventory;
+pub mod reconcile;
+pub mod rollout;
+pub mod scaling;
+
 use anyhow::{Context, Result};
-use async_trait::async_trait;
-use russh::client;
-use russh::*;
-use russh_keys::load_secret_key;
+use futures_util::stream::{self, StreamExt};
 use serde::Deserialize;
 use std::{
+    collections::BTreeMap,
     env,
     fs::File,
     io::BufReader,
@@ -12,126 +16,104 @@ use std::{
     sync::Arc,
     time::Duration,
 };
-use tokio::io::AsyncWriteExt;
-use tokio::net::ToSocketAddrs;
-
-#[derive(Debug, Deserialize)]
-struct Host {
-    name: String,
-    address: String,
-    port: u16,
-    username: String,
-    password: Option<String>,
-    use_key: bool,
-    key_path: Option<PathBuf>,
-}
+use tracing::info;
+
+use crate::audit::AuditLog;
+use crate::autoscalar::health::HealthMonitor;
+use crate::autoscalar::replicas::ScalingConfig;
+use crate::ssh::known_hosts::{HostKeyPolicy, HostKeyVerifier};
+use crate::ssh::{
+    FailureMode, FleetExecutor, FleetOptions, Host, SessionOptions, SessionPool, Transfer, TransferOptions,
+};
+use crate::image_builder::engine::ContainerEngineKind;
+use inventory::{Inventory, Selector};
+use reconcile::{AppSpec, DeploymentEngine};
+use rollout::{HistoryStore, ProbeGate, Revision, Rollout, RolloutStrategy};
 
+/// The deployment config file: the host inventory plus SSH settings shared by every host
 #[derive(Debug, Deserialize)]
-struct ConfigFile {
-    hosts: Vec<Host>,
+pub struct DeploymentConfig {
+    #[serde(flatten)]
+    pub inventory: Inventory,
+    /// Defaults to ~/.ssh/known_hosts
+    #[serde(default)]
+    pub known_hosts: Option<PathBuf>,
+    #[serde(default)]
+    pub host_key_policy: HostKeyPolicy,
+    /// Container engine on the hosts, docker unless set. `--engine` overrides it.
+    #[serde(default)]
+    pub engine: Option<ContainerEngineKind>,
+    /// Apps the server autoscales while it runs, by app id
+    #[serde(default)]
+    pub autoscale: BTreeMap<String, ScalingConfig>,
 }
 
-struct Client {}
-
-#[async_trait]
-impl client::Handler for Client {
-    type Error = russh::Error;
-
-    async fn check_server_key(
-        &mut self,
-        server_public_key: &russh_keys::key::PublicKey,
-    ) -> Result<bool, Self::Error> {
-        Ok(true)
+impl DeploymentConfig {
+    /// A session pool checking host keys against the configured known_hosts
+    pub fn session_pool(&self) -> Result<Arc<SessionPool>> {
+        let verifier = Arc::new(HostKeyVerifier::from_file(self.known_hosts.as_deref(), self.host_key_policy)?);
+        Ok(Arc::new(SessionPool::new(verifier, SessionOptions::default())))
     }
-}
-
-pub struct Session {
-    session: client::Handle<Client>,
-}
 
-impl Session {
-    async fn connect<P: AsRef<Path>, A: ToSocketAddrs>(
-        config: Arc<client::Config>,
-        addrs: A,
-        username: String,
-        key_path: Option<P>,
-        password: Option<String>,
-    ) -> Result<Self> {
-        let sh = Client {};
-        let mut session = client::connect(config, addrs, sh).await?;
-
-        if let Some(key_path) = key_path {
-            let key_pair = load_secret_key(key_path, None)?;
-            let auth_res = session
-                .authenticate_publickey(username, Arc::new(key_pair))
-                .await?;
-
-            if !auth_res {
-                anyhow::bail!("Public key authentication failed");
-            }
-        } else if let Some(password) = password {
-            let auth_res = session.authenticate_password(username, password).await?;
-            if !auth_res {
-                anyhow::bail!("Password authentication failed");
-            }
-        } else {
-            anyhow::bail!("No authentication method provided");
-        }
-
-        Ok(Self { session })
-    }
-
-    async fn execute_command(&mut self, command: &str) -> Result<u32> {
-        let mut channel = self.session.channel_open_session().await?;
-        channel.exec(true, command).await?;
-
-        let mut code = None;
-        let mut stdout = tokio::io::stdout();
-
-        loop {
-            let Some(msg) = channel.wait().await else {
-                break;
-            };
-            match msg {
-                ChannelMsg::Data { ref data } => {
-                    stdout.write_all(data).await?;
-                    stdout.flush().await?;
-                }
-                ChannelMsg::ExitStatus { exit_status } => {
-                    code = Some(exit_status);
-                }
-                _ => {}
-            }
-        }
-        Ok(code.expect("program did not exit cleanly"))
+    pub fn engine(&self) -> ContainerEngineKind {
+        self.engine.unwrap_or(ContainerEngineKind::Docker)
     }
+}
 
-    async fn close(&mut self) -> Result<()> {
-        self.session
-            .disconnect(Disconnect::ByApplication, "", "English")
-            .await?;
-        Ok(())
-    }
+/// An app spec file: the spec plus how to roll it out
+#[derive(Debug, Deserialize)]
+pub struct DeployFile {
+    #[serde(flatten)]
+    pub spec: AppSpec,
+    #[serde(default)]
+    pub strategy: RolloutStrategy,
 }
 
-fn read_config<P: AsRef<Path>>(config_path: P) -> Result<ConfigFile> {
+pub fn read_config<P: AsRef<Path>>(config_path: P) -> Result<DeploymentConfig> {
     let file = File::open(config_path)?;
     let reader = BufReader::new(file);
-    let config: ConfigFile = serde_json5::from_reader(reader)?;
+    let config: DeploymentConfig = serde_json5::from_reader(reader)?;
     Ok(config)
 }
 
 struct Args {
     config: PathBuf,
     command: Option<String>,
+    fleet: FleetOptions,
+    /// Hosts to run on, every host by default
+    selector: Selector,
+    /// Local file or directory and the remote path to push it to before running the command
+    upload: Option<(PathBuf, String)>,
+    /// App spec to deploy instead of running a command
+    app: Option<PathBuf>,
+    /// `rollback <app> <revision>`: roll an earlier revision out again
+    rollback: Option<(String, u32)>,
+    /// Container engine on the hosts, overrides the config's
+    engine: Option<ContainerEngineKind>,
 }
 
 fn parse_args() -> Result<Args> {
     let args: Vec<String> = env::args().collect();
     let mut config = PathBuf::from("config.json");
     let mut command = None;
-
-    let mut i = 1;
+    let mut fleet = FleetOptions::default();
+    let mut upload = None;
+    let mut selector = Selector::all();
+    let mut app = None;
+    let mut engine = None;
+    let mut rollback = None;
+
+    // args[1] is the `deploy` subcommand
+    let mut i = 2;
+    if args.get(i).map(String::as_str) == Some("rollback") {
+        let app = args.get(i + 1).context("rollback needs an app id")?;
+        let revision = args
+            .get(i + 2)
+            .and_then(|value| value.parse().ok())
+            .context("rollback needs a revision number")?;
+        rollback = Some((app.clone(), revision));
+        i += 3;
+    }
     while i < args.len() {
         match args[i].as_str() {
             "-c" | "--config" => {
@@ -150,6 +132,46 @@ fn parse_args() -> Result<Args> {
                     anyhow::bail!("Missing value for command argument");
                 }
             }
+            "-p" | "--parallel" => {
+                i += 1;
+                fleet.parallelism = args
+                    .get(i)
+                    .and_then(|value| value.parse().ok())
+                    .context("--parallel needs a number of hosts")?;
+            }
+            "-t" | "--timeout" => {
+                i += 1;
+                fleet.host_timeout = args
+                    .get(i)
+                    .and_then(|value| value.parse().ok())
+                    .map(Duration::from_secs)
+                    .context("--timeout needs a number of seconds")?;
+            }
+            "-s" | "--selector" => {
+                i += 1;
+                selector = args.get(i).context("--selector needs a selector")?.parse()?;
+            }
+            "-a" | "--app" => {
+                i += 1;
+                app = Some(PathBuf::from(args.get(i).context("--app needs an app spec file")?));
+            }
+            "--engine" => {
+                i += 1;
+                engine = Some(
+                    args.get(i)
+                        .and_then(|value| ContainerEngineKind::parse(value))
+                        .context("--engine needs docker, podman or nerdctl")?,
+                );
+            }
+            "--fail-fast" => fleet.failure_mode = FailureMode::FailFast,
+            "-u" | "--upload" => {
+                i += 1;
+                let (local, remote) = args
+                    .get(i)
+                    .and_then(|value| value.split_once(':'))
+                    .context("--upload needs LOCAL:REMOTE")?;
+                upload = Some((PathBuf::from(local), remote.to_string()));
+            }
             _ => {
                 if command.is_none() {
                     command = Some(args[i].clone());
@@ -159,70 +181,109 @@ fn parse_args() -> Result<Args> {
         i += 1;
     }
 
-    Ok(Args { config, command })
+    Ok(Args {
+        config,
+        command,
+        fleet,
+        selector,
+        upload,
+        app,
+        rollback,
+        engine,
+    })
 }
 
-#[tokio::main]
-pub async fn main() -> Result<()> {
+pub async fn run() -> Result<()> {
     // Parse command line arguments
     let args = parse_args()?;
     let config = read_config(args.config).context("failed to read configuration")?;
 
-    const MASTER_RATIO: f32 = 0.2;
-    const WORKER_RATIO: f32 = 0.8;
+    let inventory = config.inventory.resolve()?;
 
-    let total_hosts = config.hosts.len();
-    let num_masters = (total_hosts as f32 * MASTER_RATIO).ceil() as usize;
-    let num_workers = (total_hosts as f32 * WORKER_RATIO).floor() as usize;
+    for (role, hosts) in inventory.roles() {
+        info!("{}: {}", role, hosts.join(", "));
+    }
 
-    let mut masters = Vec::new();
-    let mut workers = Vec::new();
+    let hosts: Vec<Host> = inventory
+        .select(&args.selector)
+        .into_iter()
+        .map(|entry| entry.host.clone())
+        .collect();
+    if hosts.is_empty() {
+        anyhow::bail!("no hosts match `{}`", args.selector);
+    }
 
-    for (i, host) in config.hosts.iter().enumerate() {
-        if i < num_masters {
-            masters.push(host);
-        } else if i < num_masters + num_workers {
-            workers.push(host);
+    let pool = config.session_pool()?;
+    let engine = args.engine.unwrap_or_else(|| config.engine());
+
+    if let Some((local, remote)) = &args.upload {
+        let results: Vec<Result<()>> = stream::iter(&hosts)
+            .map(|host| upload(&pool, host, local, remote))
+            .buffer_unordered(args.fleet.parallelism.max(1))
+            .collect()
+            .await;
+        if let Some(e) = results.into_iter().find_map(Result::err) {
+            pool.close_all().await;
+            return Err(e);
         }
     }
 
-    println!("Masters: {:?}", masters);
-    println!("Workers: {:?}", workers);
-
-    for host in config.hosts {
-        println!("Connecting to {}:{}", host.address, host.port);
-
-        let client_config = client::Config {
-            inactivity_timeout: Some(Duration::from_secs(5)),
-            ..<_>::default()
-        };
+    if args.app.is_some() || args.rollback.is_some() {
+        let result = roll_out(&pool, engine, &args, &hosts).await;
+        pool.close_all().await;
+        return result;
+    }
 
-        let mut ssh = Session::connect(
-            Arc::new(client_config),
-            (host.address, host.port),
-            host.username,
-            host.key_path.as_ref(),
-            host.password,
-        )
-        .await?;
+    let executor = FleetExecutor::new(pool.clone(), args.fleet);
 
-        // Execute a command to test the connection
-        let cmd = ssh
-            .execute_command("ls -l /team/lab/HomeLab/")
-            .await
-            .context("failed to execute shell command")?;
+    // Without a command, only check that every host is reachable and accepts commands
+    let command = args.command.unwrap_or_else(|| "true".to_string());
+    let report = executor.run(&hosts, &command).await;
+    println!("{}", report);
 
-        println!("Dir: {}", cmd);
+    pool.close_all().await;
 
-        println!("Connected to {}", host.name);
+    let failed: Vec<&str> = report.failures().map(|result| result.host.as_str()).collect();
+    if !failed.is_empty() {
+        anyhow::bail!("`{}` failed on {}", report.command, failed.join(", "));
+    }
+    Ok(())
+}
 
-        if let Some(ref cmd) = args.command {
-            let exit_code = ssh.execute_command(cmd).await?;
-            println!("Exit code for {}: {}", host.name, exit_code);
+async fn roll_out(pool: &Arc<SessionPool>, engine: ContainerEngineKind, args: &Args, hosts: &[Host]) -> Result<()> {
+    let gate = Arc::new(ProbeGate::new(Arc::new(HealthMonitor::new(pool.clone(), engine))));
+    let engine = Arc::new(DeploymentEngine::new(pool.clone(), engine, args.fleet.parallelism));
+    let rollout = Rollout::new(engine, gate, HistoryStore::from_env())
+        .with_audit(Arc::new(AuditLog::from_env()), &crate::audit::local_actor());
+
+    let revision: Revision = match (&args.rollback, &args.app) {
+        (Some((app_id, revision)), _) => rollout.rollback_to(app_id, *revision, hosts).await?,
+        (None, Some(spec_path)) => {
+            let file = File::open(spec_path)
+                .with_context(|| format!("failed to open {}", spec_path.display()))?;
+            let deploy: DeployFile = serde_json5::from_reader(BufReader::new(file))?;
+            rollout.run(&deploy.spec, &deploy.strategy, hosts).await?
         }
+        (None, None) => unreachable!("only called with an app or a rollback"),
+    };
+    println!(
+        "{} revision {} ({}) rolled out to {} hosts",
+        revision.spec.app_id,
+        revision.revision,
+        revision.spec.image,
+        hosts.len()
+    );
+    Ok(())
+}
 
-        ssh.close().await?;
+async fn upload(pool: &SessionPool, host: &Host, local: &Path, remote: &str) -> Result<()> {
+    let session = pool.get(host).await?;
+    let transfer = Transfer::open(&session, &host.name, TransferOptions::default()).await?;
+    if local.is_dir() {
+        transfer.sync_dir(local, remote).await
+    } else {
+        transfer.upload_file(local, remote).await
     }
-
+    .with_context(|| format!("failed to upload {} to {}", local.display(), host.name))?;
     Ok(())
-}
\ No newline at end of file
+}
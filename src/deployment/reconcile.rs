@@ -0,0 +1,559 @@
+//-------------------------------------------------------------------------
+// Deploys an app's image to hosts over SSH by reconciling the containers
+// running there with the desired spec. Containers are named
+// `<app>-<slot>-<spec hash>`, so a host already running the current spec
+// needs no changes and re-running a deployment is a no-op. The slot picks
+// the published ports; replicas are interchangeable and any free slot will
+// do, which lets rollouts run old and new containers side by side.
+//-------------------------------------------------------------------------
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use futures_util::stream::{ self, StreamExt };
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use thiserror::Error;
+use tracing::info;
+
+use crate::autoscalar::health::{ ProbeConfig, ProbeKind };
+use crate::image_builder::engine::ContainerEngineKind;
+use crate::ssh::{ shell_quote, Host, SessionPool, SshError };
+
+/// Label put on every container we start, used to find them again
+pub const APP_LABEL: &str = "omniforge.app";
+
+#[derive(Debug, Error)]
+pub enum DeployError {
+    #[error(transparent)]
+    Ssh(#[from] SshError),
+    #[error("`{command}` exited with {status:?}: {stderr}")]
+    CommandFailed { command: String, status: Option<u32>, stderr: String },
+    #[error("invalid app spec: {0}")]
+    InvalidSpec(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortMapping {
+    pub host: u16,
+    pub container: u16,
+    #[serde(default = "default_protocol")]
+    pub protocol: String,
+}
+
+fn default_protocol() -> String {
+    "tcp".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VolumeMount {
+    pub source: String,
+    pub target: String,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    pub cpus: Option<f64>,
+    pub memory_mb: Option<u64>,
+}
+
+/// What should run on every selected host
+///
+/// # Fields
+/// app_id - The app, also the container name prefix
+/// image - Image reference to run, use a unique tag per build so changes are detected
+/// ports - Published ports, the container in slot N publishes on `host + N`
+/// env - Environment variables
+/// volumes - Bind mounts or named volumes
+/// replicas - Containers per host, 0 removes the app from the host
+/// resources - CPU and memory limits per container
+/// command - Overrides the image's command when not empty
+/// probes - Health probes every container has to pass, the engine's health status when empty
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AppSpec {
+    pub app_id: String,
+    pub image: String,
+    #[serde(default)]
+    pub ports: Vec<PortMapping>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub volumes: Vec<VolumeMount>,
+    #[serde(default = "default_replicas")]
+    pub replicas: u32,
+    #[serde(default)]
+    pub resources: ResourceLimits,
+    #[serde(default)]
+    pub command: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub probes: Vec<ProbeConfig>,
+}
+
+fn default_replicas() -> u32 {
+    1
+}
+
+impl AppSpec {
+    pub fn validate(&self) -> Result<(), DeployError> {
+        self.validate_slots(self.replicas)
+    }
+
+    /// Validates the spec for rollouts that may use up to `slots` slots per host
+    pub fn validate_slots(&self, slots: u32) -> Result<(), DeployError> {
+        let valid_id = !self.app_id.is_empty()
+            && !self.app_id.starts_with(['.', '-'])
+            && self.app_id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid_id {
+            return Err(DeployError::InvalidSpec(format!("`{}` is not a valid app id", self.app_id)));
+        }
+        if self.image.trim().is_empty() {
+            return Err(DeployError::InvalidSpec("image is empty".to_string()));
+        }
+        for port in &self.ports {
+            if u32::from(port.host) + slots.saturating_sub(1) > u32::from(u16::MAX) {
+                return Err(
+                    DeployError::InvalidSpec(format!("host port {} + {} slots is out of range", port.host, slots))
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Short hash of everything that affects the containers, part of their names. The replica
+    /// count and probes aren't part of it, so scaling or changing probes leaves the running
+    /// containers alone.
+    pub fn hash(&self) -> String {
+        let containers = AppSpec { replicas: 0, probes: Vec::new(), ..self.clone() };
+        let json = serde_json::to_vec(&containers).expect("app spec always serializes");
+        Sha256::digest(json)
+            .iter()
+            .take(5)
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub fn container_name(&self, slot: u32, hash: &str) -> String {
+        format!("{}-{}-{}", self.app_id, slot, hash)
+    }
+
+    /// The probes for one container of the app, with its name and the host port its slot
+    /// publishes the first port on filled in
+    pub fn probes_for(&self, container: &str) -> Vec<ProbeConfig> {
+        let slot = parse_containers(&self.app_id, container).first().map(|container| container.slot);
+        let port = slot
+            .zip(self.ports.first())
+            .and_then(|(slot, port)| u16::try_from(u32::from(port.host) + slot).ok());
+        if self.probes.is_empty() {
+            return vec![ProbeConfig::new(ProbeKind::Container { name: container.to_string() })];
+        }
+        self.probes
+            .iter()
+            .map(|probe| ProbeConfig { kind: probe.kind.for_container(container, port), ..probe.clone() })
+            .collect()
+    }
+}
+
+/// A container of the app found on a host
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExistingContainer {
+    pub name: String,
+    pub slot: u32,
+    pub hash: String,
+    pub running: bool,
+}
+
+/// One step of bringing a host to the desired state
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Pull { image: String },
+    Run { name: String, slot: u32 },
+    Start { name: String },
+    Remove { name: String },
+    /// Changes a running container's limits in place, used by the autoscaler
+    Update { name: String, resources: ResourceLimits },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Pull { image } => write!(f, "pull {}", image),
+            Action::Run { name, .. } => write!(f, "run {}", name),
+            Action::Start { name } => write!(f, "start {}", name),
+            Action::Remove { name } => write!(f, "remove {}", name),
+            Action::Update { name, .. } => write!(f, "update {}", name),
+        }
+    }
+}
+
+/// Lowest slot not taken by any of the containers
+pub fn free_slot<'a>(taken: impl IntoIterator<Item = &'a ExistingContainer>) -> u32 {
+    let taken: Vec<u32> = taken.into_iter().map(|container| container.slot).collect();
+    (0..).find(|slot| !taken.contains(slot)).unwrap()
+}
+
+/// Works out what to change on a host. Empty when it already runs the spec.
+pub fn plan(spec: &AppSpec, existing: &[ExistingContainer]) -> Vec<Action> {
+    let hash = spec.hash();
+    let (mut current, mut old): (Vec<&ExistingContainer>, Vec<&ExistingContainer>) =
+        existing.iter().partition(|container| container.hash == hash);
+    // Keep running containers over stopped ones, and low slots over high ones
+    current.sort_by_key(|container| (!container.running, container.slot));
+    old.sort_by_key(|container| container.slot);
+
+    let mut actions = Vec::new();
+    let keep = current.len().min(spec.replicas as usize);
+    for container in &current[..keep] {
+        if !container.running {
+            actions.push(Action::Start { name: container.name.clone() });
+        }
+    }
+    for extra in &current[keep..] {
+        actions.push(Action::Remove { name: extra.name.clone() });
+    }
+
+    let mut taken: Vec<ExistingContainer> = current[..keep].iter().map(|container| (*container).clone()).collect();
+    let mut old = old.into_iter();
+    for _ in keep..spec.replicas as usize {
+        // Reuse an old container's slot first, it holds the ports clients already use
+        let slot = match old.next() {
+            Some(container) => {
+                actions.push(Action::Remove { name: container.name.clone() });
+                container.slot
+            }
+            None => free_slot(taken.iter().chain(existing.iter())),
+        };
+        let name = spec.container_name(slot, &hash);
+        taken.push(ExistingContainer { name: name.clone(), slot, hash: hash.clone(), running: true });
+        actions.push(Action::Run { name, slot });
+    }
+    for container in old {
+        actions.push(Action::Remove { name: container.name.clone() });
+    }
+
+    // Pull before touching anything so the old containers keep serving while the image downloads
+    if actions.iter().any(|action| matches!(action, Action::Run { .. })) {
+        actions.insert(0, Action::Pull { image: spec.image.clone() });
+    }
+    actions
+}
+
+/// Parses `ps --format '{{.Names}} {{.Status}}'` output, ignoring containers that don't follow our naming
+pub fn parse_containers(app_id: &str, output: &str) -> Vec<ExistingContainer> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (name, status) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+            let rest = name.strip_prefix(app_id)?.strip_prefix('-')?;
+            let (slot, hash) = rest.split_once('-')?;
+            Some(ExistingContainer {
+                name: name.to_string(),
+                slot: slot.parse().ok()?,
+                hash: hash.to_string(),
+                running: status.starts_with("Up"),
+            })
+        })
+        .collect()
+}
+
+/// Builds the `run` command line for the container in one slot
+pub fn run_command(engine: ContainerEngineKind, spec: &AppSpec, name: &str, slot: u32) -> String {
+    let mut args: Vec<String> = vec![
+        "run".into(),
+        "-d".into(),
+        "--name".into(),
+        name.into(),
+        "--label".into(),
+        format!("{}={}", APP_LABEL, spec.app_id),
+        "--restart".into(),
+        "unless-stopped".into(),
+    ];
+    for port in &spec.ports {
+        args.push("-p".into());
+        args.push(format!("{}:{}/{}", u32::from(port.host) + slot, port.container, port.protocol));
+    }
+    for (key, value) in &spec.env {
+        args.push("-e".into());
+        args.push(format!("{}={}", key, value));
+    }
+    for volume in &spec.volumes {
+        args.push("-v".into());
+        let mode = if volume.read_only { ":ro" } else { "" };
+        args.push(format!("{}:{}{}", volume.source, volume.target, mode));
+    }
+    if let Some(cpus) = spec.resources.cpus {
+        args.push("--cpus".into());
+        args.push(cpus.to_string());
+    }
+    if let Some(memory_mb) = spec.resources.memory_mb {
+        args.push("--memory".into());
+        args.push(format!("{}m", memory_mb));
+    }
+    args.push(spec.image.clone());
+    args.extend(spec.command.iter().cloned());
+
+    let quoted: Vec<String> = args.iter().map(|arg| shell_quote(arg)).collect();
+    format!("{} {}", engine.binary(), quoted.join(" "))
+}
+
+/// What a deployment did, or tried to do, on one host
+#[derive(Debug)]
+pub struct HostDeployment {
+    pub host: String,
+    pub result: Result<Vec<Action>, DeployError>,
+}
+
+pub struct DeploymentEngine {
+    pool: Arc<SessionPool>,
+    engine: ContainerEngineKind,
+    parallelism: usize,
+}
+
+impl DeploymentEngine {
+    pub fn new(pool: Arc<SessionPool>, engine: ContainerEngineKind, parallelism: usize) -> Self {
+        DeploymentEngine { pool, engine, parallelism: parallelism.max(1) }
+    }
+
+    /// Reconciles every host, hosts are independent so one failing doesn't stop the others
+    pub async fn deploy(&self, spec: &AppSpec, hosts: &[Host]) -> Result<Vec<HostDeployment>, DeployError> {
+        spec.validate()?;
+        let results = stream::iter(hosts)
+            .map(|host| async move {
+                HostDeployment {
+                    host: host.name.clone(),
+                    result: self.reconcile(spec, host).await,
+                }
+            })
+            .buffered(self.parallelism)
+            .collect()
+            .await;
+        Ok(results)
+    }
+
+    /// Brings one host to the spec and returns the actions taken
+    pub async fn reconcile(&self, spec: &AppSpec, host: &Host) -> Result<Vec<Action>, DeployError> {
+        let existing = self.containers(&spec.app_id, host).await?;
+        let actions = plan(spec, &existing);
+        if actions.is_empty() {
+            info!("[{}] {} is up to date", host.name, spec.app_id);
+        }
+        for action in &actions {
+            self.apply(spec, host, action).await?;
+        }
+        Ok(actions)
+    }
+
+    /// Runs one action on a host
+    pub async fn apply(&self, spec: &AppSpec, host: &Host, action: &Action) -> Result<(), DeployError> {
+        info!("[{}] {}", host.name, action);
+        let binary = self.engine.binary();
+        let command = match action {
+            Action::Pull { image } => format!("{} pull {}", binary, shell_quote(image)),
+            Action::Run { name, slot } => run_command(self.engine, spec, name, *slot),
+            Action::Start { name } => format!("{} start {}", binary, shell_quote(name)),
+            Action::Remove { name } => format!("{} rm -f {}", binary, shell_quote(name)),
+            Action::Update { name, resources } => {
+                let mut command = format!("{} update", binary);
+                if let Some(cpus) = resources.cpus {
+                    command.push_str(&format!(" --cpus {}", cpus));
+                }
+                if let Some(memory_mb) = resources.memory_mb {
+                    // Swap has to grow with the limit, keep docker's default of twice the memory
+                    command.push_str(&format!(" --memory {}m --memory-swap {}m", memory_mb, memory_mb * 2));
+                }
+                format!("{} {}", command, shell_quote(name))
+            }
+        };
+        self.run(host, &command).await?;
+        Ok(())
+    }
+
+    /// The app's containers on a host, running or not
+    pub async fn containers(&self, app_id: &str, host: &Host) -> Result<Vec<ExistingContainer>, DeployError> {
+        let command = format!(
+            "{} ps -a --filter {} --format {}",
+            self.engine.binary(),
+            shell_quote(&format!("label={}={}", APP_LABEL, app_id)),
+            shell_quote("{{.Names}} {{.Status}}")
+        );
+        let output = self.run(host, &command).await?;
+        Ok(parse_containers(app_id, &output))
+    }
+
+    /// Runs a shell command on a host, a non-zero exit is an error
+    pub async fn run(&self, host: &Host, command: &str) -> Result<String, DeployError> {
+        let output = self.pool.exec(host, command).await?;
+        if !output.success() {
+            return Err(DeployError::CommandFailed {
+                command: command.to_string(),
+                status: output.exit_status,
+                stderr: output.stderr_str().trim().to_string(),
+            });
+        }
+        Ok(output.stdout_str())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    use crate::ssh::test_server::TestServer;
+    use crate::ssh::SessionOptions;
+
+    /// Splits a command line built with `shell_quote`
+    pub(crate) fn shell_words(command: &str) -> Vec<String> {
+        let mut words = Vec::new();
+        let mut word = String::new();
+        let mut in_word = false;
+        let mut quoted = false;
+        let mut escaped = false;
+        for c in command.chars() {
+            if escaped {
+                word.push(c);
+                escaped = false;
+                continue;
+            }
+            match c {
+                '\'' => {
+                    quoted = !quoted;
+                    in_word = true;
+                }
+                '\\' if !quoted => {
+                    escaped = true;
+                    in_word = true;
+                }
+                ' ' if !quoted => {
+                    if in_word {
+                        words.push(std::mem::take(&mut word));
+                    }
+                    in_word = false;
+                }
+                _ => {
+                    word.push(c);
+                    in_word = true;
+                }
+            }
+        }
+        if in_word {
+            words.push(word);
+        }
+        words
+    }
+
+    /// Just enough of the docker CLI to deploy against: name -> running
+    #[derive(Clone, Default)]
+    pub(crate) struct FakeDocker {
+        pub containers: Arc<Mutex<BTreeMap<String, bool>>>,
+        pub commands: Arc<Mutex<Vec<String>>>,
+        /// Running containers after every command that changed something
+        pub running: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl FakeDocker {
+        pub(crate) fn handle(&self, command: &str) -> (Vec<u8>, Vec<u8>, u32) {
+            let words = shell_words(command);
+            self.commands.lock().unwrap().push(words[1..].join(" "));
+            let mut containers = self.containers.lock().unwrap();
+            let result = match words.get(1).map(String::as_str) {
+                Some("ps") => {
+                    let listing: String = containers
+                        .iter()
+                        .map(|(name, running)| format!("{} {}\n", name, if *running { "Up 2 minutes" } else { "Exited (0)" }))
+                        .collect();
+                    (listing.into_bytes(), Vec::new(), 0)
+                }
+                Some("pull" | "update") => (Vec::new(), Vec::new(), 0),
+                Some("stats") => (b"80.00%|100MiB / 256MiB|39.06%|0B / 0B\n".to_vec(), Vec::new(), 0),
+                Some("inspect") => match containers.get(words.last().unwrap()) {
+                    Some(true) => (b"running\n".to_vec(), Vec::new(), 0),
+                    Some(false) => (b"exited\n".to_vec(), Vec::new(), 0),
+                    None => (Vec::new(), b"no such container\n".to_vec(), 1),
+                },
+                Some("run") => {
+                    let name = words.iter().skip_while(|w| *w != "--name").nth(1).unwrap().clone();
+                    containers.insert(name, true);
+                    (b"0123456789ab\n".to_vec(), Vec::new(), 0)
+                }
+                Some("start") => {
+                    containers.insert(words[2].clone(), true);
+                    (Vec::new(), Vec::new(), 0)
+                }
+                Some("rm") => {
+                    containers.remove(words.last().unwrap());
+                    (Vec::new(), Vec::new(), 0)
+                }
+                _ => (Vec::new(), b"unknown command\n".to_vec(), 1),
+            };
+            if matches!(words.get(1).map(String::as_str), Some("run" | "start" | "rm")) {
+                let running = containers.values().filter(|running| **running).count();
+                self.running.lock().unwrap().push(running);
+            }
+            result
+        }
+
+        pub(crate) fn take_commands(&self) -> Vec<String> {
+            std::mem::take(&mut *self.commands.lock().unwrap())
+        }
+    }
+
+    pub(crate) fn spec() -> AppSpec {
+        AppSpec {
+            app_id: "web".to_string(),
+            image: "registry.local/web:build-1".to_string(),
+            ports: vec![PortMapping { host: 8080, container: 80, protocol: "tcp".to_string() }],
+            env: BTreeMap::from([("MODE".to_string(), "prod".to_string())]),
+            volumes: Vec::new(),
+            replicas: 2,
+            resources: ResourceLimits { cpus: Some(0.5), memory_mb: Some(256) },
+            command: Vec::new(),
+            probes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn run_command_publishes_offset_ports_per_slot() {
+        let words = shell_words(&run_command(ContainerEngineKind::Podman, &spec(), "web-1-abc", 1));
+        assert_eq!(words[0], "podman");
+        assert!(words.windows(2).any(|w| w == ["-p", "8081:80/tcp"]));
+        assert!(words.windows(2).any(|w| w == ["--memory", "256m"]));
+        assert_eq!(words.last().unwrap(), "registry.local/web:build-1");
+    }
+
+    #[tokio::test]
+    async fn reconciles_and_is_idempotent() {
+        let docker = FakeDocker::default();
+        let fake = docker.clone();
+        let server = TestServer::start(move |command| fake.handle(command)).await;
+        let pool = Arc::new(SessionPool::new(server.verifier(), SessionOptions::default()));
+        let engine = DeploymentEngine::new(pool, ContainerEngineKind::Docker, 4);
+        let host = server.host();
+
+        let mut spec = spec();
+        let actions = engine.reconcile(&spec, &host).await.unwrap();
+        assert_eq!(actions.len(), 3);
+        assert!(matches!(actions[0], Action::Pull { .. }));
+        assert_eq!(docker.containers.lock().unwrap().len(), 2);
+
+        // Same spec again: only the listing runs
+        docker.take_commands();
+        assert!(engine.reconcile(&spec, &host).await.unwrap().is_empty());
+        assert_eq!(docker.take_commands().len(), 1);
+
+        // A stopped replica is started rather than replaced
+        let first = spec.container_name(0, &spec.hash());
+        docker.containers.lock().unwrap().insert(first.clone(), false);
+        assert_eq!(engine.reconcile(&spec, &host).await.unwrap(), [Action::Start { name: first }]);
+
+        // A new image replaces both replicas, scaling down removes the extra
+        spec.image = "registry.local/web:build-2".to_string();
+        spec.replicas = 1;
+        let actions = engine.reconcile(&spec, &host).await.unwrap();
+        let names: Vec<String> = docker.containers.lock().unwrap().keys().cloned().collect();
+        assert_eq!(names, [spec.container_name(0, &spec.hash())]);
+        assert_eq!(actions.iter().filter(|a| matches!(a, Action::Remove { .. })).count(), 2);
+    }
+}
@@ -0,0 +1,819 @@
+//-------------------------------------------------------------------------
+// Rollouts on top of the reconciler. Rolling replaces replicas host by
+// host within max-unavailable/max-surge, canary moves a growing share of
+// all replicas across the hosts in weighted steps, blue-green starts a
+// full new set next to the old one and switches over. Every step waits on
+// a health gate; when one fails the previous good revision is put back on
+// every host. Each rollout is recorded as a revision in the app's history
+// and, with who started it, in the audit log.
+//-------------------------------------------------------------------------
+
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{ DateTime, Utc };
+use futures_util::future::join_all;
+use serde::{ Deserialize, Serialize };
+use serde_json::json;
+use thiserror::Error;
+use tracing::{ error, info };
+
+use super::reconcile::{ free_slot, Action, AppSpec, DeployError, DeploymentEngine, ExistingContainer };
+use crate::audit::{ AuditAction, AuditEvent, AuditLog, AuditOutcome };
+use crate::autoscalar::health::{ HealthMonitor, ProbeConfig, ProbeOutcome };
+use crate::ssh::Host;
+
+/// Env var overriding where rollout history is kept
+pub const HISTORY_DIR_VAR: &str = "OMNIFORGE_DEPLOY_HISTORY_DIR";
+
+#[derive(Debug, Error)]
+pub enum RolloutError {
+    #[error(transparent)]
+    Deploy(#[from] DeployError),
+    #[error("{host}: {containers:?} did not become healthy, {restored}")]
+    Unhealthy { host: String, containers: Vec<String>, restored: String },
+    #[error("invalid rollout strategy: {0}")]
+    InvalidStrategy(String),
+    #[error("{app} has no revision {revision}")]
+    UnknownRevision { app: String, revision: u32 },
+    #[error("{0} has never been rolled out successfully")]
+    NotDeployed(String),
+    #[error("{app} revision {revision} is still rolling out")]
+    RollingOut { app: String, revision: u32 },
+    #[error("IO error occurred: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid rollout history: {0}")]
+    History(#[from] serde_json::Error),
+}
+
+/// One canary step: move `weight` percent of all replicas to the new version, then wait
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CanaryStep {
+    pub weight: u8,
+    #[serde(default)]
+    pub pause_secs: u64,
+}
+
+/// How a new spec replaces the running one
+///
+/// # Fields
+/// max_unavailable - Replicas per host that may be down at once
+/// max_surge - Extra replicas per host started before old ones are removed
+/// steps - Canary weights in percent, a final 100% step is added when missing
+/// switch_command - Run on every host once the green set is healthy, e.g. to repoint a proxy
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum RolloutStrategy {
+    Rolling {
+        #[serde(default = "default_max_unavailable")]
+        max_unavailable: u32,
+        #[serde(default)]
+        max_surge: u32,
+    },
+    Canary {
+        steps: Vec<CanaryStep>,
+    },
+    BlueGreen {
+        #[serde(default)]
+        switch_command: Option<String>,
+    },
+}
+
+fn default_max_unavailable() -> u32 {
+    1
+}
+
+impl Default for RolloutStrategy {
+    fn default() -> Self {
+        RolloutStrategy::Rolling { max_unavailable: 1, max_surge: 0 }
+    }
+}
+
+impl RolloutStrategy {
+    pub fn validate(&self) -> Result<(), RolloutError> {
+        match self {
+            RolloutStrategy::Rolling { max_unavailable: 0, max_surge: 0 } => {
+                Err(RolloutError::InvalidStrategy("max_unavailable and max_surge can't both be 0".to_string()))
+            }
+            RolloutStrategy::Canary { steps } => {
+                let mut last = 0;
+                for step in steps {
+                    if step.weight <= last || step.weight > 100 {
+                        return Err(
+                            RolloutError::InvalidStrategy(
+                                "canary weights must increase and stay within 1..=100".to_string()
+                            )
+                        );
+                    }
+                    last = step.weight;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Slots a host may need at once, old and new containers together
+    pub fn slots(&self, replicas: u32) -> u32 {
+        match self {
+            RolloutStrategy::Rolling { max_surge, .. } => replicas + max_surge,
+            RolloutStrategy::Canary { .. } => replicas,
+            RolloutStrategy::BlueGreen { .. } => replicas * 2,
+        }
+    }
+}
+
+/// Decides whether freshly started containers may take traffic
+#[async_trait]
+pub trait HealthGate: Send + Sync {
+    async fn healthy(&self, host: &Host, spec: &AppSpec, containers: &[String]) -> Result<bool, DeployError>;
+}
+
+/// Runs the spec's probes against every new container. A probe passes after `success_threshold`
+/// successes in a row and fails the gate after `failure_threshold` failures, so a container gets
+/// that many chances to come up.
+pub struct ProbeGate {
+    health: Arc<HealthMonitor>,
+}
+
+impl ProbeGate {
+    pub fn new(health: Arc<HealthMonitor>) -> Self {
+        ProbeGate { health }
+    }
+
+    async fn passes(&self, host: &Host, container: &str, probe: &ProbeConfig) -> bool {
+        let (mut successes, mut failures) = (0, 0);
+        loop {
+            match self.health.probe(probe, host).await {
+                ProbeOutcome::Success => {
+                    successes += 1;
+                    if successes >= probe.success_threshold.max(1) {
+                        return true;
+                    }
+                }
+                ProbeOutcome::Failure(message) | ProbeOutcome::Unreachable(message) => {
+                    successes = 0;
+                    failures += 1;
+                    info!(
+                        "[{}] {} {}: {} ({} of {})",
+                        host.name,
+                        container,
+                        probe.kind,
+                        message,
+                        failures,
+                        probe.failure_threshold
+                    );
+                    if failures >= probe.failure_threshold.max(1) {
+                        return false;
+                    }
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(probe.interval_secs.max(1))).await;
+        }
+    }
+}
+
+#[async_trait]
+impl HealthGate for ProbeGate {
+    async fn healthy(&self, host: &Host, spec: &AppSpec, containers: &[String]) -> Result<bool, DeployError> {
+        let checks = containers.iter().flat_map(|container| {
+            spec.probes_for(container).into_iter().map(move |probe| async move {
+                self.passes(host, container, &probe).await
+            })
+        });
+        Ok(join_all(checks).await.into_iter().all(|passed| passed))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RevisionStatus {
+    InProgress,
+    Succeeded,
+    RolledBack,
+    Failed,
+}
+
+/// One rollout of an app
+///
+/// # Fields
+/// revision - Increases by one per rollout of the app
+/// spec - What was rolled out
+/// strategy - How it was rolled out
+/// status - Whether it is still running, finished, or was undone
+/// reason - Why it was rolled back or failed
+/// rollback_of - The earlier revision this one restored, for manual rollbacks
+/// hosts - Names of the hosts it was rolled out to, the only ones the autoscaler scales it across
+/// scaled_replicas - Replicas across its hosts the autoscaler last scaled to, `spec.replicas` per host until then
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Revision {
+    pub revision: u32,
+    pub spec: AppSpec,
+    pub strategy: RolloutStrategy,
+    pub status: RevisionStatus,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub rollback_of: Option<u32>,
+    pub started_at: DateTime<Utc>,
+    #[serde(default)]
+    pub finished_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub scaled_replicas: Option<u32>,
+}
+
+/// Rollout history, one JSON file per app
+#[derive(Clone)]
+pub struct HistoryStore {
+    dir: PathBuf,
+}
+
+impl HistoryStore {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        HistoryStore { dir: dir.as_ref().to_path_buf() }
+    }
+
+    /// `$OMNIFORGE_DEPLOY_HISTORY_DIR`, or ./Deployments/history
+    pub fn from_env() -> Self {
+        let dir = std::env::var(HISTORY_DIR_VAR).unwrap_or_else(|_| "./Deployments/history".to_string());
+        HistoryStore::new(dir)
+    }
+
+    fn path(&self, app_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", app_id))
+    }
+
+    /// Every revision of the app, oldest first
+    pub fn load(&self, app_id: &str) -> Result<Vec<Revision>, RolloutError> {
+        match fs::read_to_string(self.path(app_id)) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Apps with a history, sorted
+    pub fn apps(&self) -> Result<Vec<String>, RolloutError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut apps = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "json") {
+                if let Some(app_id) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    apps.push(app_id.to_string());
+                }
+            }
+        }
+        apps.sort();
+        Ok(apps)
+    }
+
+    /// The app's last revision that rolled out successfully, what its hosts should be running
+    pub fn current(&self, app_id: &str) -> Result<Option<Revision>, RolloutError> {
+        Ok(self.load(app_id)?.into_iter().rev().find(|revision| revision.status == RevisionStatus::Succeeded))
+    }
+
+    /// The app's last revision if it is still rolling out. Its hosts are in between two specs
+    /// then, nothing else should touch them.
+    pub fn rolling_out(&self, app_id: &str) -> Result<Option<Revision>, RolloutError> {
+        Ok(self.load(app_id)?.pop().filter(|revision| revision.status == RevisionStatus::InProgress))
+    }
+
+    /// Records the replica count the autoscaler scaled the app's current revision to
+    pub fn scale(&self, app_id: &str, replicas: u32) -> Result<(), RolloutError> {
+        let mut revision = self.current(app_id)?.ok_or_else(|| RolloutError::NotDeployed(app_id.to_string()))?;
+        revision.scaled_replicas = Some(replicas);
+        self.record(&revision)
+    }
+
+    /// Adds the revision or replaces the one with the same number
+    pub fn record(&self, revision: &Revision) -> Result<(), RolloutError> {
+        let mut history = self.load(&revision.spec.app_id)?;
+        match history.iter_mut().find(|existing| existing.revision == revision.revision) {
+            Some(existing) => *existing = revision.clone(),
+            None => history.push(revision.clone()),
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(&revision.spec.app_id);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&history)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+/// Why a rollout stopped before finishing
+enum Failure {
+    Unhealthy { host: String, containers: Vec<String> },
+    Error(DeployError),
+}
+
+impl From<DeployError> for Failure {
+    fn from(e: DeployError) -> Self {
+        Failure::Error(e)
+    }
+}
+
+/// Containers of the app on one host
+struct HostState {
+    all: Vec<ExistingContainer>,
+    /// Running the spec being rolled out, stopped ones are started by the final reconcile
+    new: Vec<ExistingContainer>,
+    old: Vec<ExistingContainer>,
+}
+
+pub struct Rollout {
+    engine: Arc<DeploymentEngine>,
+    gate: Arc<dyn HealthGate>,
+    history: HistoryStore,
+    audit: Option<(Arc<AuditLog>, String)>,
+}
+
+impl Rollout {
+    pub fn new(engine: Arc<DeploymentEngine>, gate: Arc<dyn HealthGate>, history: HistoryStore) -> Self {
+        Rollout { engine, gate, history, audit: None }
+    }
+
+    /// Audits every rollout and rollback as started by `actor`
+    pub fn with_audit(mut self, audit: Arc<AuditLog>, actor: &str) -> Self {
+        self.audit = Some((audit, actor.to_string()));
+        self
+    }
+
+    fn audit(&self, revision: &Revision, hosts: &[Host], outcome: AuditOutcome) {
+        let Some((audit, actor)) = &self.audit else {
+            return;
+        };
+        audit.record(AuditEvent {
+            actor: actor.clone(),
+            action: if revision.rollback_of.is_some() { AuditAction::Rollback } else { AuditAction::Deploy },
+            target: revision.spec.app_id.clone(),
+            parameters: json!({
+                "revision": revision.revision,
+                "rollback_of": revision.rollback_of,
+                "image": revision.spec.image,
+                "replicas": revision.spec.replicas,
+                "strategy": revision.strategy,
+                "hosts": hosts.iter().map(|host| host.name.as_str()).collect::<Vec<_>>(),
+            }),
+            outcome,
+        });
+    }
+
+    /// Rolls the spec out to the hosts and records it as the app's next revision
+    pub async fn run(&self, spec: &AppSpec, strategy: &RolloutStrategy, hosts: &[Host]) -> Result<Revision, RolloutError> {
+        self.start(spec, strategy, hosts, None).await
+    }
+
+    /// Rolls an earlier revision's spec out again, with the strategy it was first rolled out with
+    pub async fn rollback_to(&self, app_id: &str, revision: u32, hosts: &[Host]) -> Result<Revision, RolloutError> {
+        let target = self
+            .history
+            .load(app_id)?
+            .into_iter()
+            .find(|existing| existing.revision == revision)
+            .ok_or_else(|| RolloutError::UnknownRevision { app: app_id.to_string(), revision })?;
+        self.start(&target.spec, &target.strategy, hosts, Some(revision)).await
+    }
+
+    async fn start(
+        &self,
+        spec: &AppSpec,
+        strategy: &RolloutStrategy,
+        hosts: &[Host],
+        rollback_of: Option<u32>
+    ) -> Result<Revision, RolloutError> {
+        strategy.validate()?;
+        spec.validate_slots(strategy.slots(spec.replicas))?;
+
+        let history = self.history.load(&spec.app_id)?;
+        let previous = history
+            .iter()
+            .rev()
+            .find(|existing| existing.status == RevisionStatus::Succeeded)
+            .map(|existing| existing.spec.clone());
+        let mut revision = Revision {
+            revision: history.iter().map(|existing| existing.revision).max().unwrap_or(0) + 1,
+            spec: spec.clone(),
+            strategy: strategy.clone(),
+            status: RevisionStatus::InProgress,
+            reason: None,
+            rollback_of,
+            started_at: Utc::now(),
+            finished_at: None,
+            hosts: hosts.iter().map(|host| host.name.clone()).collect(),
+            scaled_replicas: None,
+        };
+        self.history.record(&revision)?;
+        self.audit(&revision, hosts, AuditOutcome::Accepted);
+        info!("Rolling out {} revision {} to {} hosts", spec.app_id, revision.revision, hosts.len());
+
+        let result = match strategy {
+            RolloutStrategy::Rolling { max_unavailable, max_surge } => {
+                self.rolling(spec, hosts, *max_unavailable, *max_surge).await
+            }
+            RolloutStrategy::Canary { steps } => self.canary(spec, hosts, steps).await,
+            RolloutStrategy::BlueGreen { switch_command } => {
+                self.blue_green(spec, hosts, switch_command.as_deref()).await
+            }
+        };
+
+        let error = match result {
+            Ok(()) => None,
+            Err(failure) => {
+                let restored = self.restore(spec, previous.as_ref(), hosts).await;
+                Some(match failure {
+                    Failure::Unhealthy { host, containers } => {
+                        RolloutError::Unhealthy { host, containers, restored }
+                    }
+                    Failure::Error(e) => {
+                        error!("Rollout of {} failed: {}, {}", spec.app_id, e, restored);
+                        e.into()
+                    }
+                })
+            }
+        };
+
+        revision.finished_at = Some(Utc::now());
+        match &error {
+            None => revision.status = RevisionStatus::Succeeded,
+            Some(e) => {
+                revision.status = match e {
+                    RolloutError::Unhealthy { .. } => RevisionStatus::RolledBack,
+                    _ => RevisionStatus::Failed,
+                };
+                revision.reason = Some(e.to_string());
+            }
+        }
+        self.audit(&revision, hosts, match &revision.reason {
+            None => AuditOutcome::Succeeded,
+            Some(reason) => AuditOutcome::Failed { reason: reason.clone() },
+        });
+        self.history.record(&revision)?;
+        match error {
+            None => Ok(revision),
+            Some(e) => Err(e),
+        }
+    }
+
+    /// Puts the previous good revision back everywhere, or removes the app if there is none.
+    /// Best effort: a host that can't be restored is reported and the rest still are.
+    async fn restore(&self, failed: &AppSpec, previous: Option<&AppSpec>, hosts: &[Host]) -> String {
+        let (target, restored) = match previous {
+            Some(spec) => (spec.clone(), format!("rolled back to {}", spec.image)),
+            None => (AppSpec { replicas: 0, ..failed.clone() }, "removed, no earlier revision".to_string()),
+        };
+        match self.engine.deploy(&target, hosts).await {
+            Ok(results) => {
+                for deployment in results {
+                    if let Err(e) = deployment.result {
+                        error!("[{}] rollback failed: {}", deployment.host, e);
+                    }
+                }
+            }
+            Err(e) => error!("Rollback of {} failed: {}", failed.app_id, e),
+        }
+        restored
+    }
+
+    async fn gate(&self, spec: &AppSpec, host: &Host, containers: &[String]) -> Result<(), Failure> {
+        if containers.is_empty() || self.gate.healthy(host, spec, containers).await? {
+            return Ok(());
+        }
+        Err(Failure::Unhealthy { host: host.name.clone(), containers: containers.to_vec() })
+    }
+
+    /// Starts a container of the spec in the lowest slot nobody holds
+    async fn run_in_free_slot(
+        &self,
+        spec: &AppSpec,
+        host: &Host,
+        taken: &mut Vec<ExistingContainer>
+    ) -> Result<String, DeployError> {
+        let hash = spec.hash();
+        let slot = free_slot(taken.iter());
+        let name = spec.container_name(slot, &hash);
+        self.engine.apply(spec, host, &Action::Run { name: name.clone(), slot }).await?;
+        taken.push(ExistingContainer { name: name.clone(), slot, hash, running: true });
+        Ok(name)
+    }
+
+    /// Replaces an old container with a new one in the same slot
+    async fn replace(&self, spec: &AppSpec, host: &Host, old: &ExistingContainer) -> Result<String, DeployError> {
+        let name = spec.container_name(old.slot, &spec.hash());
+        self.engine.apply(spec, host, &Action::Remove { name: old.name.clone() }).await?;
+        self.engine.apply(spec, host, &Action::Run { name: name.clone(), slot: old.slot }).await?;
+        Ok(name)
+    }
+
+    /// The app's containers on a host, split by whether they run the spec
+    async fn host_state(&self, spec: &AppSpec, host: &Host) -> Result<HostState, DeployError> {
+        let hash = spec.hash();
+        let all = self.engine.containers(&spec.app_id, host).await?;
+        let (new, mut old): (Vec<_>, Vec<_>) = all.iter().cloned().partition(|container| container.hash == hash);
+        // Stopped old containers go first, they aren't serving anyone
+        old.sort_by_key(|container| (container.running, container.slot));
+        Ok(HostState { all, new, old })
+    }
+
+    async fn rolling(&self, spec: &AppSpec, hosts: &[Host], max_unavailable: u32, max_surge: u32) -> Result<(), Failure> {
+        let desired = spec.replicas as usize;
+        for host in hosts {
+            let mut pulled = false;
+            loop {
+                let HostState { all: mut taken, new, old } = self.host_state(spec, host).await?;
+                if new.len() >= desired {
+                    break;
+                }
+                if !pulled {
+                    self.engine.apply(spec, host, &Action::Pull { image: spec.image.clone() }).await?;
+                    pulled = true;
+                }
+
+                let needed = desired - new.len();
+                let mut started = Vec::new();
+                if max_surge > 0 || old.is_empty() {
+                    let batch = if old.is_empty() { needed } else { needed.min(max_surge as usize) };
+                    for _ in 0..batch {
+                        started.push(self.run_in_free_slot(spec, host, &mut taken).await?);
+                    }
+                } else {
+                    for container in old.iter().take(needed.min(max_unavailable as usize)) {
+                        started.push(self.replace(spec, host, container).await?);
+                    }
+                }
+                self.gate(spec, host, &started).await?;
+
+                if max_surge > 0 {
+                    // The new containers are healthy, drop old ones down to what max_unavailable allows
+                    let available = new.len() + started.len() + old.iter().filter(|c| c.running).count();
+                    let floor = desired.saturating_sub(max_unavailable as usize);
+                    let surplus = available.saturating_sub(floor);
+                    for container in old.iter().take(surplus) {
+                        self.engine.apply(spec, host, &Action::Remove { name: container.name.clone() }).await?;
+                    }
+                }
+            }
+            // Leftover old or stopped containers
+            self.engine.reconcile(spec, host).await?;
+        }
+        Ok(())
+    }
+
+    async fn canary(&self, spec: &AppSpec, hosts: &[Host], steps: &[CanaryStep]) -> Result<(), Failure> {
+        let desired = spec.replicas as usize;
+        let total = hosts.len() * desired;
+        let mut steps = steps.to_vec();
+        if steps.last().map(|step| step.weight) != Some(100) {
+            steps.push(CanaryStep { weight: 100, pause_secs: 0 });
+        }
+
+        let mut converted = Vec::with_capacity(hosts.len());
+        for host in hosts {
+            self.engine.apply(spec, host, &Action::Pull { image: spec.image.clone() }).await?;
+            converted.push(self.host_state(spec, host).await?.new.len().min(desired));
+        }
+
+        for step in &steps {
+            let target = (total * usize::from(step.weight)).div_ceil(100);
+            info!("Canary {}: {}% ({} of {} replicas)", spec.app_id, step.weight, target, total);
+            let mut started: Vec<Vec<String>> = vec![Vec::new(); hosts.len()];
+            while converted.iter().sum::<usize>() < target {
+                // Spread the canary over the hosts rather than filling one up first
+                let Some(index) = (0..hosts.len())
+                    .filter(|i| converted[*i] < desired)
+                    .min_by_key(|i| converted[*i]) else {
+                    break;
+                };
+                let host = &hosts[index];
+                let HostState { all: mut taken, old, .. } = self.host_state(spec, host).await?;
+                let name = match old.first() {
+                    Some(container) => self.replace(spec, host, container).await?,
+                    None => self.run_in_free_slot(spec, host, &mut taken).await?,
+                };
+                started[index].push(name);
+                converted[index] += 1;
+            }
+            for (host, names) in hosts.iter().zip(&started) {
+                self.gate(spec, host, names).await?;
+            }
+            if step.pause_secs > 0 && step.weight < 100 {
+                tokio::time::sleep(Duration::from_secs(step.pause_secs)).await;
+            }
+        }
+
+        for host in hosts {
+            self.engine.reconcile(spec, host).await?;
+        }
+        Ok(())
+    }
+
+    async fn blue_green(&self, spec: &AppSpec, hosts: &[Host], switch_command: Option<&str>) -> Result<(), Failure> {
+        let desired = spec.replicas as usize;
+        let mut green = Vec::with_capacity(hosts.len());
+        for host in hosts {
+            self.engine.apply(spec, host, &Action::Pull { image: spec.image.clone() }).await?;
+            let HostState { all: mut taken, new, .. } = self.host_state(spec, host).await?;
+            let mut started = Vec::new();
+            for _ in new.len()..desired {
+                started.push(self.run_in_free_slot(spec, host, &mut taken).await?);
+            }
+            green.push(started);
+        }
+        for (host, started) in hosts.iter().zip(&green) {
+            self.gate(spec, host, started).await?;
+        }
+
+        if let Some(command) = switch_command {
+            for host in hosts {
+                info!("[{}] switching {} to green", host.name, spec.app_id);
+                self.engine.run(host, command).await?;
+            }
+        }
+        // Blue goes away only after every host has switched
+        for host in hosts {
+            self.engine.reconcile(spec, host).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::autoscalar::health::ProbeKind;
+    use crate::deployment::reconcile::tests::{ spec, FakeDocker };
+    use crate::image_builder::engine::ContainerEngineKind;
+    use crate::ssh::test_server::TestServer;
+    use crate::ssh::{ SessionOptions, SessionPool };
+
+    /// Fails every container running a blacklisted image
+    struct ImageGate {
+        engine: Arc<DeploymentEngine>,
+        bad_image: String,
+    }
+
+    #[async_trait]
+    impl HealthGate for ImageGate {
+        async fn healthy(&self, host: &Host, spec: &AppSpec, containers: &[String]) -> Result<bool, DeployError> {
+            let existing = self.engine.containers(&spec.app_id, host).await?;
+            assert!(containers.iter().all(|name| existing.iter().any(|c| &c.name == name)));
+            Ok(spec.image != self.bad_image)
+        }
+    }
+
+    async fn setup(name: &str, bad_image: &str) -> (TestServer, FakeDocker, Rollout) {
+        let docker = FakeDocker::default();
+        let fake = docker.clone();
+        let server = TestServer::start(move |command| fake.handle(command)).await;
+        let pool = Arc::new(SessionPool::new(server.verifier(), SessionOptions::default()));
+        let engine = Arc::new(DeploymentEngine::new(pool, ContainerEngineKind::Docker, 1));
+        let gate = Arc::new(ImageGate { engine: engine.clone(), bad_image: bad_image.to_string() });
+
+        let dir = std::env::temp_dir().join(format!("omniforge-rollout-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        let audit = Arc::new(AuditLog::new(dir.join("audit.jsonl")));
+        (server, docker, Rollout::new(engine, gate, HistoryStore::new(dir)).with_audit(audit, "ana"))
+    }
+
+    /// A history in a fresh temp dir where `spec` rolled out successfully to the test server's host
+    pub(crate) fn deployed(name: &str, spec: &AppSpec) -> HistoryStore {
+        let dir = std::env::temp_dir().join(format!("omniforge-history-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        let history = HistoryStore::new(dir);
+        history
+            .record(&Revision {
+                revision: 1,
+                spec: spec.clone(),
+                strategy: RolloutStrategy::default(),
+                status: RevisionStatus::Succeeded,
+                reason: None,
+                rollback_of: None,
+                started_at: Utc::now(),
+                finished_at: Some(Utc::now()),
+                hosts: vec!["test".to_string()],
+                scaled_replicas: None,
+            })
+            .unwrap();
+        history
+    }
+
+    fn audited(rollout: &Rollout) -> Vec<crate::audit::AuditEntry> {
+        rollout.audit.as_ref().unwrap().0.query(&Default::default()).unwrap()
+    }
+
+    fn names(docker: &FakeDocker) -> Vec<String> {
+        docker.containers.lock().unwrap().keys().cloned().collect()
+    }
+
+    #[tokio::test]
+    async fn probe_gate_runs_the_spec_probes_per_container() {
+        let server = TestServer::start(|command| {
+            let passed = command == "check web-0-abc 8080" || command.ends_with("'web-0-abc'");
+            let stdout = if command.contains("inspect") { b"running\n".to_vec() } else { Vec::new() };
+            (stdout, Vec::new(), if passed { 0 } else { 1 })
+        }).await;
+        let pool = Arc::new(SessionPool::new(server.verifier(), SessionOptions::default()));
+        let gate = ProbeGate::new(Arc::new(HealthMonitor::new(pool, ContainerEngineKind::Docker)));
+        let host = server.host();
+
+        // Every container gets its own name and its slot's port
+        let exec = ProbeConfig {
+            failure_threshold: 1,
+            ..ProbeConfig::new(ProbeKind::Exec { command: "check {container} {port}".to_string() })
+        };
+        let probed = AppSpec { probes: vec![exec], ..spec() };
+        assert!(gate.healthy(&host, &probed, &["web-0-abc".to_string()]).await.unwrap());
+        assert!(!gate.healthy(&host, &probed, &["web-0-abc".to_string(), "web-1-abc".to_string()]).await.unwrap());
+
+        // Without probes the container engine's health status decides
+        assert!(gate.healthy(&host, &spec(), &["web-0-abc".to_string()]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn rolling_with_surge_never_drops_below_desired() {
+        let (server, docker, rollout) = setup("rolling", "none").await;
+        let hosts = [server.host()];
+        let strategy = RolloutStrategy::Rolling { max_unavailable: 0, max_surge: 1 };
+
+        let v1 = spec();
+        assert_eq!(rollout.run(&v1, &strategy, &hosts).await.unwrap().revision, 1);
+        docker.running.lock().unwrap().clear();
+
+        let v2 = AppSpec { image: "registry.local/web:build-2".to_string(), ..spec() };
+        let revision = rollout.run(&v2, &strategy, &hosts).await.unwrap();
+        assert_eq!(revision.revision, 2);
+        assert_eq!(revision.status, RevisionStatus::Succeeded);
+        assert_eq!(revision.hosts, ["test"]);
+        assert!(docker.running.lock().unwrap().iter().all(|running| *running >= 2));
+
+        let hash = v2.hash();
+        let names = names(&docker);
+        assert_eq!(names.len(), 2);
+        assert!(names.iter().all(|name| name.ends_with(&hash)));
+    }
+
+    #[tokio::test]
+    async fn unhealthy_canary_rolls_back_to_previous_revision() {
+        let (server, docker, rollout) = setup("canary", "registry.local/web:bad").await;
+        let hosts = [server.host()];
+        let v1 = spec();
+        rollout.run(&v1, &RolloutStrategy::default(), &hosts).await.unwrap();
+        let before = names(&docker);
+
+        let bad = AppSpec { image: "registry.local/web:bad".to_string(), ..spec() };
+        let canary = RolloutStrategy::Canary { steps: vec![CanaryStep { weight: 50, pause_secs: 0 }] };
+        let error = rollout.run(&bad, &canary, &hosts).await.unwrap_err();
+        assert!(matches!(error, RolloutError::Unhealthy { ref containers, .. } if containers.len() == 1));
+
+        assert_eq!(names(&docker), before);
+        let history = rollout.history.load("web").unwrap();
+        assert_eq!(history[1].status, RevisionStatus::RolledBack);
+        assert!(history[1].reason.as_deref().unwrap().contains("build-1"));
+
+        let audited = audited(&rollout);
+        assert_eq!(audited.len(), 4);
+        let last = &audited[3];
+        assert_eq!((last.actor.as_str(), last.action, last.outcome.status()), ("ana", AuditAction::Deploy, "failed"));
+        assert_eq!(last.parameters["image"], "registry.local/web:bad");
+    }
+
+    #[tokio::test]
+    async fn manual_rollback_after_blue_green() {
+        let (server, docker, rollout) = setup("blue-green", "none").await;
+        let hosts = [server.host()];
+        let v1 = spec();
+        rollout.run(&v1, &RolloutStrategy::default(), &hosts).await.unwrap();
+
+        let v2 = AppSpec { image: "registry.local/web:build-2".to_string(), ..spec() };
+        let blue_green = RolloutStrategy::BlueGreen { switch_command: None };
+        rollout.run(&v2, &blue_green, &hosts).await.unwrap();
+        assert!(names(&docker).iter().all(|name| name.ends_with(&v2.hash())));
+
+        let revision = rollout.rollback_to("web", 1, &hosts).await.unwrap();
+        assert_eq!((revision.revision, revision.rollback_of), (3, Some(1)));
+        let names = names(&docker);
+        assert_eq!(names.len(), 2);
+        assert!(names.iter().all(|name| name.ends_with(&v1.hash())));
+        let last = audited(&rollout).pop().unwrap();
+        assert_eq!((last.action, last.outcome), (AuditAction::Rollback, AuditOutcome::Succeeded));
+        assert_eq!(last.parameters["rollback_of"], 1);
+        assert_eq!(last.parameters["hosts"][0], hosts[0].name.as_str());
+
+        assert!(matches!(
+            rollout.rollback_to("web", 9, &hosts).await,
+            Err(RolloutError::UnknownRevision { revision: 9, .. })
+        ));
+    }
+}
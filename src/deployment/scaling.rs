@@ -0,0 +1,249 @@
+//-------------------------------------------------------------------------
+// Carries out the autoscaler's replica plan on deployment hosts. The app's
+// replicas are spread over the hosts: scale-out adds containers on the
+// hosts running the fewest, scale-in removes exactly the containers the
+// scaler picked, and resizes change a running container's CPU and memory
+// limits in place. The replica count scaled to is kept with the app's
+// current revision in the rollout history, and `reconcile` brings the
+// hosts back to it, e.g. after a container died or a host came back. Nothing
+// is changed while a rollout of the app is in progress, the hosts are in
+// between two specs then and reconciling to the old one would undo it.
+// Such repairs are audited as the autoscaler's, the scaler audits the
+// actions it hands to the driver. Instances are named `<host>/<container>`.
+//-------------------------------------------------------------------------
+
+use std::sync::Arc;
+
+use anyhow::{ Context, Result };
+use async_trait::async_trait;
+use serde_json::json;
+
+use super::reconcile::{ Action, AppSpec, DeployError, DeploymentEngine, ResourceLimits };
+use super::rollout::{ HistoryStore, RolloutError };
+use crate::audit::{ AuditAction, AuditEvent, AuditLog, AuditOutcome, AUTOSCALER };
+use crate::autoscalar::replicas::{ ReplicaAction, ReplicaDriver };
+use crate::ssh::Host;
+
+pub struct DeploymentDriver {
+    engine: Arc<DeploymentEngine>,
+    spec: AppSpec,
+    hosts: Vec<Host>,
+    history: HistoryStore,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl DeploymentDriver {
+    pub fn new(engine: Arc<DeploymentEngine>, spec: AppSpec, hosts: Vec<Host>, history: HistoryStore) -> Self {
+        DeploymentDriver { engine, spec, hosts, history, audit: None }
+    }
+
+    /// Audits every `reconcile` that had to add or remove containers
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Every container of the current spec, as instance ids
+    pub async fn instances(&self) -> Result<Vec<String>, DeployError> {
+        let hash = self.spec.hash();
+        let mut instances = Vec::new();
+        for host in &self.hosts {
+            for container in self.engine.containers(&self.spec.app_id, host).await? {
+                if container.hash == hash {
+                    instances.push(format!("{}/{}", host.name, container.name));
+                }
+            }
+        }
+        Ok(instances)
+    }
+
+    /// Replicas across all hosts: what the app was last scaled to, or `spec.replicas` on every host
+    pub fn desired(&self) -> Result<u32> {
+        let scaled = self.history.current(&self.spec.app_id)?.and_then(|revision| revision.scaled_replicas);
+        Ok(scaled.unwrap_or(self.spec.replicas * self.hosts.len() as u32))
+    }
+
+    /// Brings the hosts to the desired replica count, adding on the hosts running the fewest
+    /// and removing from the hosts running the most
+    pub async fn reconcile(&self) -> Result<()> {
+        let mut changed = Vec::new();
+        let result = self.converge(&mut changed).await;
+        if let Some(audit) = self.audit.as_ref().filter(|_| !changed.is_empty()) {
+            audit.record(AuditEvent {
+                actor: AUTOSCALER.to_string(),
+                action: AuditAction::Scale,
+                target: self.spec.app_id.clone(),
+                parameters: json!({ "reconcile": changed }),
+                outcome: AuditOutcome::of(&result),
+            });
+        }
+        result
+    }
+
+    /// `reconcile` without the audit, pushing each host it changes (or tries to) to `changed`
+    async fn converge(&self, changed: &mut Vec<serde_json::Value>) -> Result<()> {
+        self.not_rolling_out()?;
+        let desired = self.desired()?;
+        let hash = self.spec.hash();
+        let mut replicas = Vec::with_capacity(self.hosts.len());
+        for host in &self.hosts {
+            let existing = self.engine.containers(&self.spec.app_id, host).await?;
+            replicas.push(existing.iter().filter(|container| container.hash == hash).count() as u32);
+        }
+
+        let before = replicas.clone();
+        let mut total: u32 = replicas.iter().sum();
+        while total != desired {
+            if total < desired {
+                let fewest = (0..replicas.len()).min_by_key(|i| replicas[*i]).context("app has no hosts")?;
+                replicas[fewest] += 1;
+                total += 1;
+            } else {
+                let most = (0..replicas.len()).max_by_key(|i| replicas[*i]).context("app has no hosts")?;
+                replicas[most] -= 1;
+                total -= 1;
+            }
+        }
+
+        for ((host, replicas), before) in self.hosts.iter().zip(replicas).zip(before) {
+            if replicas != before {
+                changed.push(json!({ "host": host.name, "from": before, "to": replicas }));
+                let spec = AppSpec { replicas, ..self.spec.clone() };
+                self.engine.reconcile(&spec, host).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails while a rollout of the app is in progress
+    fn not_rolling_out(&self) -> Result<()> {
+        match self.history.rolling_out(&self.spec.app_id)? {
+            Some(revision) => Err(RolloutError::RollingOut { app: self.spec.app_id.clone(), revision: revision.revision }.into()),
+            None => Ok(()),
+        }
+    }
+
+    fn resolve<'a>(&'a self, instance: &'a str) -> Result<(&'a Host, &'a str)> {
+        let (host_name, container) = instance
+            .split_once('/')
+            .with_context(|| format!("`{}` is not a <host>/<container> instance id", instance))?;
+        let host = self
+            .hosts
+            .iter()
+            .find(|host| host.name == host_name)
+            .with_context(|| format!("{} is not one of this app's hosts", host_name))?;
+        Ok((host, container))
+    }
+}
+
+#[async_trait]
+impl ReplicaDriver for DeploymentDriver {
+    async fn execute(&self, action: &ReplicaAction) -> Result<()> {
+        self.not_rolling_out()?;
+        match action {
+            // The new count is saved first, so a scale that fails halfway is finished by the next reconcile
+            ReplicaAction::ScaleOut { to, .. } => {
+                self.history.scale(&self.spec.app_id, *to)?;
+                self.converge(&mut Vec::new()).await
+            }
+            ReplicaAction::ScaleIn { to, instances, .. } => {
+                let victims = instances.iter().map(|instance| self.resolve(instance)).collect::<Result<Vec<_>>>()?;
+                self.history.scale(&self.spec.app_id, *to)?;
+                for (host, container) in victims {
+                    self.engine.apply(&self.spec, host, &Action::Remove { name: container.to_string() }).await?;
+                }
+                self.converge(&mut Vec::new()).await
+            }
+            ReplicaAction::Resize { instance, allocation, .. } => {
+                let (host, container) = self.resolve(instance)?;
+                let resources = ResourceLimits {
+                    cpus: Some(allocation.allocated_cpu as f64 / 100.0),
+                    memory_mb: Some(allocation.allocated_memory),
+                };
+                let update = Action::Update { name: container.to_string(), resources };
+                self.engine.apply(&self.spec, host, &update).await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::autoscalar::{ AppInstance, ApplicationState, ScaleAction };
+    use crate::deployment::reconcile::tests::{ spec, FakeDocker };
+    use crate::deployment::rollout::tests::deployed;
+    use crate::deployment::rollout::RevisionStatus;
+    use crate::image_builder::engine::ContainerEngineKind;
+    use crate::ssh::test_server::TestServer;
+    use crate::ssh::{ SessionOptions, SessionPool };
+
+    #[tokio::test]
+    async fn executes_scale_out_scale_in_and_resize() {
+        let docker = FakeDocker::default();
+        let fake = docker.clone();
+        let server = TestServer::start(move |command| fake.handle(command)).await;
+        let pool = Arc::new(SessionPool::new(server.verifier(), SessionOptions::default()));
+        let engine = Arc::new(DeploymentEngine::new(pool, ContainerEngineKind::Docker, 1));
+        let spec = AppSpec { replicas: 1, ..spec() };
+        engine.reconcile(&spec, &server.host()).await.unwrap();
+        let history = deployed("scaling", &spec);
+        let driver = DeploymentDriver::new(engine.clone(), spec.clone(), vec![server.host()], history.clone());
+
+        let app_id = "web".to_string();
+        driver.execute(&ReplicaAction::ScaleOut { app_id: app_id.clone(), from: 1, to: 3 }).await.unwrap();
+        let instances = driver.instances().await.unwrap();
+        // The first container is kept, scaling doesn't change the spec hash
+        assert_eq!(instances.len(), 3);
+        assert_eq!(instances[0], format!("test/{}", spec.container_name(0, &spec.hash())));
+
+        let victim = instances[1].clone();
+        driver
+            .execute(&ReplicaAction::ScaleIn { app_id, from: 3, to: 2, instances: vec![victim.clone()] })
+            .await
+            .unwrap();
+        let remaining = driver.instances().await.unwrap();
+        assert_eq!(remaining.len(), 2);
+        assert!(!remaining.contains(&victim));
+
+        // The count is kept with the revision, a container that went away is replaced
+        assert_eq!(history.current("web").unwrap().unwrap().scaled_replicas, Some(2));
+        let (_, gone) = remaining[1].split_once('/').unwrap();
+        engine.apply(&spec, &server.host(), &Action::Remove { name: gone.to_string() }).await.unwrap();
+        DeploymentDriver::new(engine.clone(), spec.clone(), vec![server.host()], history.clone()).reconcile().await.unwrap();
+        let remaining = driver.instances().await.unwrap();
+        assert_eq!(remaining.len(), 2);
+
+        docker.take_commands();
+        let allocation = AppInstance {
+            state: ApplicationState::Healthy,
+            allocated_memory: 2048,
+            allocated_cpu: 150,
+            allocated_disk_bandwidth: 100,
+            allocated_network_bandwidth: 100,
+        };
+        let resize = ReplicaAction::Resize { instance: remaining[0].clone(), action: ScaleAction::ScaleUp, allocation };
+        driver.execute(&resize).await.unwrap();
+        let name = remaining[0].split_once('/').unwrap().1;
+        assert_eq!(
+            docker.take_commands(),
+            [format!("update --cpus 1.5 --memory 2048m --memory-swap 4096m {}", name)]
+        );
+
+        let unknown = ReplicaAction::ScaleIn { app_id: "web".to_string(), from: 2, to: 1, instances: vec!["elsewhere/web-0".to_string()] };
+        assert!(driver.execute(&unknown).await.is_err());
+        assert_eq!(driver.desired().unwrap(), 2);
+
+        // A rollout in progress is left alone, even by a reconcile that would replace a container
+        let mut rolling = history.current("web").unwrap().unwrap();
+        rolling.revision = 2;
+        rolling.status = RevisionStatus::InProgress;
+        history.record(&rolling).unwrap();
+        let (_, gone) = remaining[1].split_once('/').unwrap();
+        engine.apply(&spec, &server.host(), &Action::Remove { name: gone.to_string() }).await.unwrap();
+        assert!(driver.reconcile().await.is_err());
+        assert!(driver.execute(&resize).await.is_err());
+        assert_eq!(driver.instances().await.unwrap().len(), 1);
+    }
+}
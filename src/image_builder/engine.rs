@@ -0,0 +1,235 @@
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+use std::env;
+use std::fmt;
+use std::io;
+use std::process::{ Command, Output };
+use tracing::warn;
+
+/// Environment variable that forces a specific container engine instead of auto-detection
+pub const ENGINE_OVERRIDE_VAR: &str = "OMNIFORGE_CONTAINER_ENGINE";
+
+/// The container engines OmniForge knows how to drive
+///
+/// # Variants
+/// Docker - The docker CLI, either rootful or rootless
+/// Podman - The podman CLI, usually rootless on our build hosts
+/// Nerdctl - The containerd nerdctl CLI
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerEngineKind {
+    Docker,
+    Podman,
+    Nerdctl,
+}
+
+impl ContainerEngineKind {
+    /// Detection order when no override is given
+    pub const ALL: [ContainerEngineKind; 3] = [
+        ContainerEngineKind::Docker,
+        ContainerEngineKind::Podman,
+        ContainerEngineKind::Nerdctl,
+    ];
+
+    pub fn binary(&self) -> &'static str {
+        match self {
+            ContainerEngineKind::Docker => "docker",
+            ContainerEngineKind::Podman => "podman",
+            ContainerEngineKind::Nerdctl => "nerdctl",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "docker" => Some(ContainerEngineKind::Docker),
+            "podman" => Some(ContainerEngineKind::Podman),
+            "nerdctl" => Some(ContainerEngineKind::Nerdctl),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ContainerEngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.binary())
+    }
+}
+
+/// A detected container engine and the binary used to reach it
+///
+/// # Fields
+/// kind - Which engine the binary belongs to
+/// binary - Name or path of the CLI used for every call
+/// version - Version string reported by `<binary> --version`
+/// rootless - Whether the engine runs without root privileges
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerEngine {
+    pub kind: ContainerEngineKind,
+    pub binary: String,
+    pub version: String,
+    pub rootless: bool,
+}
+
+impl ContainerEngine {
+    /// Detects the container engine to use on this host.
+    ///
+    /// `OMNIFORGE_CONTAINER_ENGINE` wins if set, otherwise docker, podman and nerdctl are tried in order.
+    /// A `docker` binary that is really the podman compatibility shim is reported as podman.
+    pub fn detect() -> Option<Self> {
+        if let Ok(requested) = env::var(ENGINE_OVERRIDE_VAR) {
+            return match ContainerEngineKind::parse(&requested) {
+                Some(kind) => Self::probe(kind),
+                None => {
+                    warn!("Unknown container engine '{}' in {}", requested, ENGINE_OVERRIDE_VAR);
+                    None
+                }
+            };
+        }
+
+        ContainerEngineKind::ALL.iter().find_map(|kind| Self::probe(*kind))
+    }
+
+    /// Checks whether the given engine is installed and answering
+    pub fn probe(kind: ContainerEngineKind) -> Option<Self> {
+        let output = Command::new(kind.binary()).arg("--version").output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+        // podman-docker installs a `docker` wrapper that only prints podman's version
+        let kind = if kind == ContainerEngineKind::Docker && version.to_lowercase().contains("podman") {
+            ContainerEngineKind::Podman
+        } else {
+            kind
+        };
+
+        let mut engine = ContainerEngine {
+            kind,
+            binary: kind.binary().to_string(),
+            version,
+            rootless: false,
+        };
+        engine.rootless = engine.detect_rootless();
+        Some(engine)
+    }
+
+    fn detect_rootless(&self) -> bool {
+        let args: &[&str] = match self.kind {
+            ContainerEngineKind::Podman => &["info", "--format", "{{.Host.Security.Rootless}}"],
+            ContainerEngineKind::Docker | ContainerEngineKind::Nerdctl => &["info", "--format", "{{json .SecurityOptions}}"],
+        };
+        match Command::new(&self.binary).args(args).output() {
+            Ok(output) if output.status.success() => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                stdout.contains("true") || stdout.contains("rootless")
+            }
+            _ => false,
+        }
+    }
+
+    /// The value to hand to `devcontainer --docker-path`, if the CLI's default `docker` is not right
+    pub fn devcontainer_docker_path(&self) -> Option<&str> {
+        match self.kind {
+            ContainerEngineKind::Docker if self.binary == "docker" => None,
+            _ => Some(&self.binary),
+        }
+    }
+
+    pub fn tag(&self, source: &str, target: &str) -> io::Result<()> {
+        self.run(&["tag", source, target]).map(|_| ())
+    }
+
+    pub fn push(&self, image: &str) -> io::Result<()> {
+        let mut args = vec!["push"];
+        // docker treats localhost registries as insecure by default, the others need to be told
+        if is_local_registry(image) {
+            match self.kind {
+                ContainerEngineKind::Podman => args.push("--tls-verify=false"),
+                ContainerEngineKind::Nerdctl => args.push("--insecure-registry"),
+                ContainerEngineKind::Docker => {}
+            }
+        }
+        args.push(image);
+        self.run(&args).map(|_| ())
+    }
+
+    /// Returns the first object of `<engine> image inspect <image>`
+    pub fn inspect(&self, image: &str) -> io::Result<Value> {
+        let output = self.run(&["image", "inspect", image])?;
+        let parsed: Value = serde_json::from_slice(&output.stdout)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        match parsed {
+            Value::Array(mut items) if !items.is_empty() => Ok(items.swap_remove(0)),
+            Value::Array(_) => Err(io::Error::new(io::ErrorKind::NotFound, format!("image {} not found", image))),
+            other => Ok(other),
+        }
+    }
+
+    fn run(&self, args: &[&str]) -> io::Result<Output> {
+        let output = Command::new(&self.binary).args(args).output()?;
+        if !output.status.success() {
+            return Err(
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("{} {} failed: {}", self.binary, args.join(" "), String::from_utf8_lossy(&output.stderr))
+                )
+            );
+        }
+        Ok(output)
+    }
+}
+
+/// Whether the image's registry is on this host, e.g. `localhost:5000/app`. An image without
+/// a registry part (`app`, `library/app`) goes to a remote default registry.
+fn is_local_registry(image: &str) -> bool {
+    let Some((registry, _)) = image.split_once('/') else {
+        return false;
+    };
+    let host = match registry.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => registry,
+    };
+    matches!(host, "localhost" | "127.0.0.1" | "[::1]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(kind: ContainerEngineKind, binary: &str) -> ContainerEngine {
+        ContainerEngine { kind, binary: binary.to_string(), version: String::new(), rootless: false }
+    }
+
+    #[test]
+    fn parses_engine_names() {
+        assert_eq!(ContainerEngineKind::parse("docker"), Some(ContainerEngineKind::Docker));
+        assert_eq!(ContainerEngineKind::parse(" Podman\n"), Some(ContainerEngineKind::Podman));
+        assert_eq!(ContainerEngineKind::parse("NERDCTL"), Some(ContainerEngineKind::Nerdctl));
+        assert_eq!(ContainerEngineKind::parse("containerd"), None);
+        assert_eq!(ContainerEngineKind::parse(""), None);
+        for kind in ContainerEngineKind::ALL {
+            assert_eq!(ContainerEngineKind::parse(&kind.to_string()), Some(kind));
+        }
+    }
+
+    #[test]
+    fn recognizes_local_registries() {
+        assert!(is_local_registry("localhost:5000/app:build-1"));
+        assert!(is_local_registry("localhost/app"));
+        assert!(is_local_registry("127.0.0.1:5000/team/app"));
+        assert!(is_local_registry("[::1]:5000/app"));
+        assert!(!is_local_registry("registry.example.com/app"));
+        assert!(!is_local_registry("localhost.example.com:5000/app"));
+        assert!(!is_local_registry("localhost"));
+        assert!(!is_local_registry("app:latest"));
+    }
+
+    #[test]
+    fn devcontainer_only_gets_a_docker_path_when_not_plain_docker() {
+        assert_eq!(engine(ContainerEngineKind::Docker, "docker").devcontainer_docker_path(), None);
+        assert_eq!(engine(ContainerEngineKind::Docker, "/opt/bin/docker").devcontainer_docker_path(), Some("/opt/bin/docker"));
+        assert_eq!(engine(ContainerEngineKind::Podman, "podman").devcontainer_docker_path(), Some("podman"));
+        assert_eq!(engine(ContainerEngineKind::Nerdctl, "nerdctl").devcontainer_docker_path(), Some("nerdctl"));
+    }
+}
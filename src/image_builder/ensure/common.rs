@@ -1,3 +1,5 @@
+use crate::image_builder::engine::ContainerEngine;
+
 pub fn get_platform() -> String {
     if cfg!(target_os = "windows") {
         "windows".to_string()
@@ -14,4 +16,5 @@ pub struct InstallationStatus {
     pub npm: bool,
     pub docker: bool,
     pub devcontainers: bool,
+    pub engine: Option<ContainerEngine>,
 }
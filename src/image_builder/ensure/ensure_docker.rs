@@ -1,19 +1,20 @@
 use super::common::get_platform;
 use std::io::{self, Write};
 use std::process::Command;
+use tracing::info;
 
 pub fn install_docker_platform() -> io::Result<()> {
     let platform = get_platform();
 
     match platform.as_str() {
         "windows" => {
-            println!("Installing Docker Desktop for Windows...");
+            info!("Installing Docker Desktop for Windows...");
             Command::new("winget")
                 .args(["install", "Docker.DockerDesktop"])
                 .status()?;
         }
         "darwin" => {
-            println!("Installing Docker Desktop for Mac...");
+            info!("Installing Docker Desktop for Mac...");
             Command::new("brew")
                 .args(["install", "--cask", "docker"])
                 .status()?;
@@ -1,23 +1,24 @@
 use super::common::get_platform;
 use std::io;
 use std::process::Command;
+use tracing::info;
 
 pub fn install_node_platform() -> io::Result<()> {
     let platform = get_platform();
 
     match platform.as_str() {
         "windows" => {
-            println!("Installing Node.js using winget...");
+            info!("Installing Node.js using winget...");
             Command::new("winget")
                 .args(["install", "OpenJS.NodeJS"])
                 .status()?;
         }
         "darwin" => {
-            println!("Installing Node.js using Homebrew...");
+            info!("Installing Node.js using Homebrew...");
             Command::new("brew").args(["install", "node"]).status()?;
         }
         "linux" => {
-            println!("Installing Node.js using package manager...");
+            info!("Installing Node.js using package manager...");
             let apt_result = Command::new("apt")
                 .args(["install", "-y", "nodejs", "npm"])
                 .status();
@@ -0,0 +1,42 @@
+use super::common::get_platform;
+use std::io;
+use std::process::Command;
+use tracing::info;
+
+pub fn install_podman_platform() -> io::Result<()> {
+    let platform = get_platform();
+
+    match platform.as_str() {
+        "windows" => {
+            info!("Installing Podman using winget...");
+            Command::new("winget")
+                .args(["install", "RedHat.Podman"])
+                .status()?;
+        }
+        "darwin" => {
+            info!("Installing Podman using Homebrew...");
+            Command::new("brew").args(["install", "podman"]).status()?;
+        }
+        "linux" => {
+            info!("Installing Podman using package manager...");
+            let apt_result = Command::new("apt")
+                .args(["install", "-y", "podman", "uidmap", "slirp4netns"])
+                .status();
+
+            if apt_result.is_err() {
+                let dnf_result = Command::new("dnf")
+                    .args(["install", "-y", "podman"])
+                    .status();
+
+                if dnf_result.is_err() {
+                    Command::new("pacman")
+                        .args(["-S", "--noconfirm", "podman"])
+                        .status()?;
+                }
+            }
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::Other, "Unsupported platform")),
+    }
+
+    Ok(())
+}
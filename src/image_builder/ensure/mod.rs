@@ -1,9 +1,13 @@
 use anyhow::Context;
+use tracing::info;
 
 use super::common::InstallationStatus;
 use super::ensure_npm::install_node_platform;
 use super::ensure_docker::install_docker_platform;
 use super::ensure_devcontainers_cli::install_devcontainers;
+use super::ensure_podman::install_podman_platform;
+use super::engine::{ ContainerEngine, ContainerEngineKind, ENGINE_OVERRIDE_VAR };
+use std::env;
 use std::io;
 use std::process::Command;
 use anyhow::Result;
@@ -12,6 +16,7 @@ pub mod common;
 pub mod ensure_npm;
 pub mod ensure_docker;
 pub mod ensure_devcontainers_cli;
+pub mod ensure_podman;
 
 pub fn ensure_installations() -> Result<InstallationStatus> {
     let mut status = InstallationStatus {
@@ -19,16 +24,17 @@ pub fn ensure_installations() -> Result<InstallationStatus> {
         npm: false,
         docker: false,
         devcontainers: false,
+        engine: None,
     };
 
     // Check Node.js
     match Command::new("node").arg("--version").output() {
         Ok(output) if output.status.success() => {
-            println!("Node.js is installed: {}", String::from_utf8_lossy(&output.stdout));
+            info!("Node.js is installed: {}", String::from_utf8_lossy(&output.stdout));
             status.node = true;
         },
         _ => {
-            println!("Installing Node.js...");
+            info!("Installing Node.js...");
             install_node_platform().context("failed to install node platform")?;
             status.node = true;
         }
@@ -37,15 +43,15 @@ pub fn ensure_installations() -> Result<InstallationStatus> {
     // Check NPM
     match Command::new("npm").arg("--version").output() {
         Ok(output) if output.status.success() => {
-            println!("NPM is installed: {}", String::from_utf8_lossy(&output.stdout));
+            info!("NPM is installed: {}", String::from_utf8_lossy(&output.stdout));
             status.npm = true;
         },
         _ => {
-            println!("NPM not found. Attempting to install NPM...");
+            info!("NPM not found. Attempting to install NPM...");
             Command::new("npm").arg("install").arg("-g").arg("npm").status().context("Failed to instll with npm")?;
             match Command::new("npm").arg("--version").output() {
                 Ok(output) if output.status.success() => {
-                    println!("NPM is installed: {}", String::from_utf8_lossy(&output.stdout));
+                    info!("NPM is installed: {}", String::from_utf8_lossy(&output.stdout));
                     status.npm = true;
                 },
                 _ => {
@@ -55,27 +61,46 @@ pub fn ensure_installations() -> Result<InstallationStatus> {
         }
     }
 
-    // Check Docker
-    match Command::new("docker").arg("--version").output() {
-        Ok(output) if output.status.success() => {
-            println!("Docker is installed: {}", String::from_utf8_lossy(&output.stdout));
-            status.docker = true;
+    // Check for a container engine (docker, podman or nerdctl)
+    match ContainerEngine::detect() {
+        Some(engine) => {
+            info!(
+                "Container engine {} is installed{}: {}",
+                engine.kind,
+                if engine.rootless { " (rootless)" } else { "" },
+                engine.version
+            );
+            status.docker = engine.kind == ContainerEngineKind::Docker;
+            status.engine = Some(engine);
         },
         _ => {
-            println!("Installing Docker...");
-            install_docker_platform().context("failed to install docker platform")?;
-            status.docker = true;
+            // Only fall back to podman when it was asked for explicitly, docker stays the default
+            let requested = env::var(ENGINE_OVERRIDE_VAR)
+                .ok()
+                .and_then(|name| ContainerEngineKind::parse(&name))
+                .unwrap_or(ContainerEngineKind::Docker);
+            info!("Installing {}...", requested);
+            match requested {
+                ContainerEngineKind::Podman => install_podman_platform().context("failed to install podman platform")?,
+                ContainerEngineKind::Docker => install_docker_platform().context("failed to install docker platform")?,
+                ContainerEngineKind::Nerdctl => {
+                    return Err::<InstallationStatus, anyhow::Error>(io::Error::new(io::ErrorKind::NotFound, "nerdctl is not installed and cannot be installed automatically").into());
+                }
+            }
+            let engine = ContainerEngine::probe(requested).context("container engine installation failed")?;
+            status.docker = engine.kind == ContainerEngineKind::Docker;
+            status.engine = Some(engine);
         }
     }
 
     // Check Dev Containers CLI
     match Command::new("devcontainer").arg("--version").output() {
         Ok(output) if output.status.success() => {
-            println!("Dev Containers CLI is installed: {}", String::from_utf8_lossy(&output.stdout));
+            info!("Dev Containers CLI is installed: {}", String::from_utf8_lossy(&output.stdout));
             status.devcontainers = true;
         },
         _ => {
-            println!("Installing Dev Containers CLI...");
+            info!("Installing Dev Containers CLI...");
             install_devcontainers().context("Failed to install dev container")?;
             status.devcontainers = true;
         }
@@ -1,11 +1,12 @@
 pub mod scanner;
 use std::collections::HashMap;
+use tracing::{debug, info};
 
 use crate::image_builder::{DevContainer, FeatureData};
 use std::fs::File;
 use std::io::Write;
 pub fn gen_devcontainer(path: &str) {
-    println!("Generating devcontainer.json...");
+    info!("Generating devcontainer.json...");
 
     let features = scanner::scan(path);
 
@@ -20,7 +21,7 @@ pub fn gen_devcontainer(path: &str) {
             let mut map = HashMap::new();
             for url in features.unwrap() {
                 map.insert(url.clone(), Some(featuredata.clone()));
-                println!("Feature URL: {}", url);
+                debug!("Feature URL: {}", url);
             }
             map
         },
@@ -29,9 +30,9 @@ pub fn gen_devcontainer(path: &str) {
     let devcontainer_json = serde_json::to_string_pretty(&devcontainer).unwrap();
     let final_path = format!("{}/.devcontainer/devcontainer.json", path);
     std::fs::create_dir_all(format!("{}/.devcontainer", path)).unwrap();
-    println!("Final path: {}", final_path);
+    debug!("Final path: {}", final_path);
     let mut file = File::create(final_path).unwrap();
     file.write_all(devcontainer_json.as_bytes()).unwrap();
 
-    println!("devcontainer.json has been generated.");
+    info!("devcontainer.json has been generated.");
 }
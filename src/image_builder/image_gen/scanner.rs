@@ -7,6 +7,9 @@ use std::path::PathBuf;
 use std::{collections::HashSet, fs, path::Path, sync::Mutex};
 use std::{fs::DirEntry, io};
 use thiserror::Error;
+use tracing::{debug, error, warn};
+
+use crate::telemetry::METRICS;
 
 lazy_static! {
     static ref IS_READY: Mutex<bool> = Mutex::new(false);
@@ -61,7 +64,7 @@ pub fn scan(input_path: &str) -> Result<Vec<String>> {
                 type_urls.push(url.clone());
             }
         }
-        println!("{:?}", type_urls);
+        debug!("{:?}", type_urls);
         Ok(type_urls)
     } else {
         Err(anyhow!("Path does not exist"))
@@ -70,49 +73,54 @@ pub fn scan(input_path: &str) -> Result<Vec<String>> {
 
 fn get_file_types(path: PathBuf) -> Result<HashSet<String>> {
     let mut file_types = HashSet::new();
-    walk_dir(&path, test_callback).context("failed to walk directory")?;
+    let files = walk_dir(&path, test_callback).context("failed to walk directory")?;
+    METRICS.scanner_files.observe(&[], files as f64);
+    METRICS.scanner_scanned_files.add(&[], files as f64);
     match FILETYPES.lock() {
         std::result::Result::Ok(types) => {
-            println!("{:#?}", types.file_types);
+            debug!("{:#?}", types.file_types);
             file_types = types.file_types.clone()
         }
         Err(e) => {
-            println!("{e}")
+            error!("{e}")
         }
     }
-    println!("File types collected.");
+    debug!("File types collected.");
     Ok(file_types)
 }
 
-fn walk_dir(input_dir: &PathBuf, callback: fn(&DirEntry)) -> Result<()> {
+/// Calls `callback` for every file under `input_dir` and returns how many there were
+fn walk_dir(input_dir: &PathBuf, callback: fn(&DirEntry)) -> Result<u64> {
     if !input_dir.is_dir() {
-        return Ok(());
+        return Ok(0);
     }
 
     let entries: Vec<_> = fs::read_dir(input_dir)?
         .filter_map(|entry| match entry {
             std::result::Result::Ok(e) => Some(e),
             Err(err) => {
-                eprintln!("Error reading entry: {}", err);
+                warn!("Error reading entry: {}", err);
                 None
             }
         })
         .collect();
 
-    entries
+    let files: u64 = entries
         .par_iter()
-        .try_for_each::<_, Result<(), anyhow::Error>>(|entry: &DirEntry| {
+        .map(|entry: &DirEntry| {
             let path = entry.path();
             if path.is_dir() {
-                if let Err(err) = walk_dir(&path, callback) {
-                    eprintln!("Error walking directory {}: {}", path.display(), err);
-                }
+                walk_dir(&path, callback).unwrap_or_else(|err| {
+                    warn!("Error walking directory {}: {}", path.display(), err);
+                    0
+                })
             } else {
                 callback(entry);
+                1
             }
-            Ok(())
-        })?;
-    Ok(())
+        })
+        .sum();
+    Ok(files)
 }
 
 fn test_callback(foo: &DirEntry) {
@@ -121,7 +129,7 @@ fn test_callback(foo: &DirEntry) {
             if let std::result::Result::Ok(mut filetypes) = FILETYPES.lock() {
                 filetypes.file_types.insert(ext_str.to_string());
             } else {
-                eprintln!("Failed to lock FILETYPES mutex.");
+                error!("Failed to lock FILETYPES mutex.");
             }
         }
     }
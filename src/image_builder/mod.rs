@@ -1,4 +1,5 @@
 // main.rs
+pub mod engine;
 mod ensure;
 mod image_gen;
 
@@ -8,14 +9,19 @@ use ensure::common;
 use ensure::ensure_devcontainers_cli;
 use ensure::ensure_docker;
 use ensure::ensure_npm;
+use ensure::ensure_podman;
+use engine::ContainerEngine;
 use serde::{ Deserialize, Serialize };
 use serde_json::Value;
 use std::collections::HashMap;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{ Path, PathBuf };
 use std::process::Command;
 use anyhow::anyhow;
+use tracing::{ debug, error, info, info_span, Instrument, Span };
+use crate::scheduler::SchedulerError;
+use crate::telemetry::METRICS;
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DevContainer {
     pub name: String,
@@ -30,8 +36,8 @@ pub struct FeatureData {
 
 const DOCKER_REGISTRY: &str = "localhost:5000";
 
-pub fn build_devcontainer(devcontainer_path: &Path) -> Result<String> {
-    println!("Final path: {}", devcontainer_path.display());
+pub fn build_devcontainer(devcontainer_path: &Path, engine: &ContainerEngine) -> Result<String> {
+    debug!("Final path: {}", devcontainer_path.display());
 
     // Read and verify the devcontainer.json content
     let content = fs
@@ -54,18 +60,21 @@ pub fn build_devcontainer(devcontainer_path: &Path) -> Result<String> {
             io::Error::new(io::ErrorKind::Other, "Failed to determine workspace folder")
         )?;
 
-    println!("Path {}", workspace_folder.display());
+    debug!("Path {}", workspace_folder.display());
 
     // Use workspace folder path for the CLI command
-    let output = Command::new("devcontainer")
-        .args([
-            "build",
-            "--workspace-folder",
-            workspace_folder.to_str().unwrap(), // Pass the workspace folder, not the devcontainer.json path
-            "--image-name",
-            &image_name,
-        ])
-        .output()?;
+    let mut args = vec![
+        "build",
+        "--workspace-folder",
+        workspace_folder.to_str().unwrap(), // Pass the workspace folder, not the devcontainer.json path
+        "--image-name",
+        &image_name,
+    ];
+    // Point the CLI at podman/nerdctl, it only looks for `docker` on its own
+    if let Some(docker_path) = engine.devcontainer_docker_path() {
+        args.extend(["--docker-path", docker_path]);
+    }
+    let output = METRICS.build_stage_seconds.time(&["build"], || Command::new("devcontainer").args(&args).output())?;
 
     if !output.status.success() {
         return Err(
@@ -73,25 +82,24 @@ pub fn build_devcontainer(devcontainer_path: &Path) -> Result<String> {
         );
     }
 
+    // Make sure the image actually landed in the engine's store before tagging it
+    let image_info = engine.inspect(&image_name).context("Failed to inspect built image")?;
+    if let Some(id) = image_info.get("Id").and_then(|id| id.as_str()) {
+        info!("Built image {} ({})", image_name, id);
+    }
+
     // Tag the image for the local Docker registry
     let tagged_image = format!("{}/{}", DOCKER_REGISTRY, image_name);
-    let tag_output = Command::new("docker").args(["tag", &image_name, &tagged_image]).output()?;
-
-    if !tag_output.status.success() {
-        return Err(
-            io::Error::new(io::ErrorKind::Other, String::from_utf8_lossy(&tag_output.stderr)).into()
-        );
-    }
+    engine.tag(&image_name, &tagged_image).context("Failed to tag image")?;
 
     // Push the image to the local Docker registry
-    let push_output = Command::new("docker").args(["push", &tagged_image]).output()?;
-
-    if !push_output.status.success() {
-        return Err(
-            io::Error
-                ::new(io::ErrorKind::Other, String::from_utf8_lossy(&push_output.stderr))
-                .into()
-        );
+    let pushed = METRICS.build_stage_seconds.time(&["push"], || engine.push(&tagged_image));
+    let result = if pushed.is_ok() { "success" } else { "failure" };
+    METRICS.registry_pushes.inc(&[result]);
+    pushed.context("Failed to push image")?;
+    // Layers the registry already has are skipped, so this is an upper bound on what went over the wire
+    if let Some(size) = image_info.get("Size").and_then(|size| size.as_u64()) {
+        METRICS.registry_push_bytes.add(&[], size as f64);
     }
 
     Ok(tagged_image)
@@ -142,19 +150,130 @@ pub fn scan_and_build(path: &Path) -> Result<()> {
     if !path.exists() {
         return Err(anyhow!("Failed to locate application build path"));
     }
-    image_gen::gen_devcontainer(path.to_str().unwrap());
-    let status = ensure::ensure_installations().context("Failed to enture installation")?;
-    println!("Installation status: {:?}", status);
+    METRICS.build_stage_seconds.time(&["generate"], || image_gen::gen_devcontainer(path.to_str().unwrap()));
+    let status = METRICS.build_stage_seconds
+        .time(&["install"], ensure::ensure_installations)
+        .context("Failed to enture installation")?;
+    debug!("Installation status: {:?}", status);
 
-    println!("Building devcontainer image...");
+    info!("Building devcontainer image...");
 
     let dev_ctr_json_str = format!("{}/.devcontainer/devcontainer.json", path.to_str().unwrap());
     let dev_ctr_json_path: &Path = Path::new(&dev_ctr_json_str);
 
-    match build_devcontainer(dev_ctr_json_path) {
-        Ok(image) => println!("Built container image: {}", image),
-        Err(e) => eprintln!("Failed to build container: {}", e),
-    }
+    let engine = status.engine.as_ref().ok_or_else(|| anyhow!("No container engine available"))?;
+    let image = build_devcontainer(dev_ctr_json_path, engine).context("Failed to build container")?;
+    info!("Built container image: {}", image);
 
     Ok(())
 }
+
+
+/// App ids end up in paths, keep them to a safe character set
+pub fn is_valid_app_id(app_id: &str) -> bool {
+    !app_id.is_empty()
+        && !app_id.starts_with('.')
+        && app_id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Where an app's uploaded source is unpacked, `./App/<app_id>/src`
+pub fn app_workspace(app_id: &str) -> PathBuf {
+    PathBuf::from("./App").join(app_id).join("src")
+}
+
+/// Rebuilds the last uploaded source of a queued job's app, see `app_workspace`.
+/// Queued builds share the worker's capacity with uploaded ones through the scheduler.
+pub struct ImageBuildHandler {
+    pub scheduler: std::sync::Arc<crate::scheduler::BuildScheduler>,
+}
+
+#[async_trait::async_trait]
+impl crate::queue::JobHandler for ImageBuildHandler {
+    async fn handle(&self, job: &crate::queue::BuildJob) -> Result<()> {
+        // Jobs can also come from an edited queue file, check the id again before it becomes a path
+        if !is_valid_app_id(&job.app_id) {
+            return Err(anyhow!("job {} has an invalid app id {:?}", job.id, job.app_id));
+        }
+        let path = app_workspace(&job.app_id);
+        let build_id = self.scheduler.submit(crate::scheduler::BuildRequest {
+            tenant: job.payload.get("tenant").and_then(|t| t.as_str()).map(str::to_string),
+            actor: job.payload.get("actor").and_then(|a| a.as_str()).map(str::to_string),
+            priority: job.payload
+                .get("priority")
+                .and_then(|p| p.as_str())
+                .and_then(|p| p.parse().ok())
+                .unwrap_or_default(),
+            // A pending upload carries newer source than the workspace, it must not be cancelled for this
+            rebuild: true,
+            ..crate::scheduler::BuildRequest::new(&job.app_id)
+        });
+        let span = info_span!("build", app_id = %job.app_id, build_id = %build_id);
+        let build = async {
+            let permit = match self.scheduler.acquire(&build_id).await {
+                Ok(permit) => permit,
+                // Superseded by a newer build of the app, retrying the job would only build stale source again
+                Err(SchedulerError::Cancelled(_, reason)) => {
+                    info!("build cancelled, dropping job {}: {}", job.id, reason);
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            info!("build started");
+            let result = if path.is_dir() {
+                let build_span = Span::current();
+                tokio::task::spawn_blocking(move || build_span.in_scope(|| scan_and_build(&path))).await?
+            } else {
+                Err(anyhow!("app {} has no uploaded source to build", job.app_id))
+            };
+            match &result {
+                Ok(()) => info!("build succeeded"),
+                Err(e) => error!("build failed: {:#}", e),
+            }
+            permit.finish(&result);
+            result
+        };
+        build.instrument(span).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{ BuildJob, JobHandler };
+    use crate::scheduler::{ BuildRequest, BuildScheduler, Resources, SchedulerConfig, SupersedePolicy };
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn superseded_queued_build_completes_the_job() {
+        let scheduler = Arc::new(BuildScheduler::new(SchedulerConfig {
+            global_limit: 1,
+            per_app_limit: 1,
+            supersede: SupersedePolicy::CancelSuperseded,
+            default_request: Resources::new(1000, 1024),
+            host: Resources::new(4000, 8192),
+            aging_interval: std::time::Duration::from_secs(600),
+            tenant_weights: HashMap::new(),
+        }));
+        let blocker = scheduler.submit(BuildRequest::new("blocker"));
+        let handler = ImageBuildHandler { scheduler: scheduler.clone() };
+        let job = BuildJob {
+            id: "job-1".to_string(),
+            app_id: "app".to_string(),
+            payload: Value::Null,
+            attempts: 1,
+            created_at: chrono::Utc::now(),
+            available_at: chrono::Utc::now(),
+            lease: None,
+            last_error: None,
+        };
+
+        let handled = handler.handle(&job);
+        tokio::pin!(handled);
+        // Let the job queue up behind the blocker, then supersede it
+        assert!(futures_util::poll!(handled.as_mut()).is_pending());
+        scheduler.submit(BuildRequest::new("app"));
+
+        handled.await.unwrap();
+        drop(scheduler.acquire(&blocker).await.unwrap());
+    }
+}
@@ -0,0 +1,473 @@
+//-----------------------------------------------------------------------------
+// Director client - registers this OmniForge worker with the OmniCloud
+// orchestrator, keeps it alive with heartbeats and deregisters on shutdown.
+//-----------------------------------------------------------------------------
+
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{ Context, Result };
+use rocket::fairing::AdHoc;
+use serde::{ Deserialize, Serialize };
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{ error, info, warn };
+
+use crate::image_builder::engine::{ ContainerEngine, ContainerEngineKind };
+
+/// Env var holding the orchestrator base URL, registration is skipped when unset
+pub const DIRECTOR_URL_VAR: &str = "OMNIFORGE_DIRECTOR_URL";
+/// Env var holding the API key sent as a bearer token
+pub const DIRECTOR_API_KEY_VAR: &str = "OMNI_API_KEY";
+/// Env var overriding the number of concurrent builds this worker advertises
+pub const BUILD_CAPACITY_VAR: &str = "OMNIFORGE_BUILD_CAPACITY";
+
+const DEFAULT_BUILD_CAPACITY: u32 = 4;
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Connection settings for the director
+///
+/// # Fields
+/// url - Base URL of the orchestrator, e.g. `https://orchestrator.omnicloud.example.com`
+/// api_key - Optional bearer token
+/// heartbeat_interval - Used until the director hands out its own interval on registration
+#[derive(Debug, Clone)]
+pub struct DirectorConfig {
+    pub url: String,
+    pub api_key: Option<String>,
+    pub heartbeat_interval: Duration,
+}
+
+impl DirectorConfig {
+    /// Reads the director settings from the environment, `None` if no director is configured
+    pub fn from_env() -> Option<Self> {
+        let url = env::var(DIRECTOR_URL_VAR).ok().filter(|url| !url.trim().is_empty())?;
+        Some(DirectorConfig {
+            url: url.trim_end_matches('/').to_string(),
+            api_key: env::var(DIRECTOR_API_KEY_VAR).ok(),
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+        })
+    }
+}
+
+/// What this worker is able to build
+///
+/// # Fields
+/// backends - Container engines available for builds (docker, podman, nerdctl)
+/// architectures - CPU architectures images can be produced for
+/// capacity - Maximum number of concurrent builds
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WorkerCapabilities {
+    pub backends: Vec<ContainerEngineKind>,
+    pub architectures: Vec<String>,
+    pub capacity: u32,
+}
+
+impl WorkerCapabilities {
+    /// Probes the local host for engines and reads the advertised capacity from the environment
+    pub fn detect() -> Self {
+        let backends = ContainerEngineKind::ALL
+            .iter()
+            .filter_map(|kind| ContainerEngine::probe(*kind))
+            .map(|engine| engine.kind)
+            .fold(Vec::new(), |mut kinds, kind| {
+                if !kinds.contains(&kind) {
+                    kinds.push(kind);
+                }
+                kinds
+            });
+
+        let capacity = env::var(BUILD_CAPACITY_VAR)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_BUILD_CAPACITY);
+
+        WorkerCapabilities {
+            backends,
+            architectures: vec![env::consts::ARCH.to_string()],
+            capacity,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterRequest {
+    pub hostname: String,
+    pub version: String,
+    pub capabilities: WorkerCapabilities,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterResponse {
+    pub worker_id: String,
+    #[serde(default)]
+    pub heartbeat_interval_secs: Option<u64>,
+}
+
+/// Load reported with every heartbeat
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct WorkerLoad {
+    pub active_builds: u32,
+    pub queued_builds: u32,
+    pub capacity: u32,
+    pub cpu_load: Option<f32>,
+    pub memory_used_mb: Option<u64>,
+}
+
+/// Anything that can report the current worker load for a heartbeat
+pub trait LoadProbe: Send + Sync {
+    fn load(&self) -> WorkerLoad;
+}
+
+impl<F> LoadProbe for F where F: Fn() -> WorkerLoad + Send + Sync {
+    fn load(&self) -> WorkerLoad {
+        self()
+    }
+}
+
+/// HTTP client for the orchestrator's worker API
+pub struct DirectorClient {
+    config: DirectorConfig,
+    http: reqwest::Client,
+}
+
+impl DirectorClient {
+    pub fn new(config: DirectorConfig) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .context("failed to build director HTTP client")?;
+        Ok(DirectorClient { config, http })
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let builder = self.http.request(method, format!("{}{}", self.config.url, path));
+        match &self.config.api_key {
+            Some(key) => builder.bearer_auth(key),
+            None => builder,
+        }
+    }
+
+    /// Registers the worker and returns the id the director assigned to it
+    pub async fn register(&self, capabilities: &WorkerCapabilities) -> Result<RegisterResponse> {
+        let body = RegisterRequest {
+            hostname: hostname(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: capabilities.clone(),
+        };
+        let response = self
+            .request(reqwest::Method::POST, "/workers")
+            .json(&body)
+            .send().await
+            .context("failed to reach director")?
+            .error_for_status()
+            .context("director rejected worker registration")?;
+        response.json::<RegisterResponse>().await.context("invalid registration response from director")
+    }
+
+    /// Sends the worker's load. `Heartbeat::UnknownWorker` means the worker has to register again,
+    /// an error only that this beat did not get through.
+    pub async fn heartbeat(&self, worker_id: &str, load: &WorkerLoad) -> Result<Heartbeat> {
+        let response = self
+            .request(reqwest::Method::POST, &format!("/workers/{}/heartbeat", worker_id))
+            .json(load)
+            .send().await
+            .context("failed to reach director")?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(Heartbeat::UnknownWorker);
+        }
+        response.error_for_status().context("director rejected heartbeat")?;
+        Ok(Heartbeat::Accepted)
+    }
+
+    pub async fn deregister(&self, worker_id: &str) -> Result<()> {
+        self.request(reqwest::Method::DELETE, &format!("/workers/{}", worker_id))
+            .send().await
+            .context("failed to reach director")?
+            .error_for_status()
+            .context("director rejected worker deregistration")?;
+        Ok(())
+    }
+}
+
+/// How the director took a heartbeat
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Heartbeat {
+    Accepted,
+    /// The director has no worker with this id, e.g. after it restarted
+    UnknownWorker,
+}
+
+/// A registered worker with its heartbeat task running in the background
+pub struct WorkerRegistration {
+    worker_id: Arc<tokio::sync::RwLock<String>>,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl WorkerRegistration {
+    /// Registers with the director and starts sending heartbeats.
+    ///
+    /// If the director forgets the worker (e.g. after a restart) it is registered again on the next beat.
+    /// Any other failed beat keeps the worker id and is retried on the next tick, registering again
+    /// then would leave a duplicate worker behind on the director.
+    pub async fn start(
+        client: DirectorClient,
+        capabilities: WorkerCapabilities,
+        probe: Arc<dyn LoadProbe>
+    ) -> Result<Self> {
+        let registered = client.register(&capabilities).await?;
+        let interval = registered.heartbeat_interval_secs
+            .map(Duration::from_secs)
+            .unwrap_or(client.config.heartbeat_interval);
+        info!("Registered with director as worker {}", registered.worker_id);
+
+        let worker_id = Arc::new(tokio::sync::RwLock::new(registered.worker_id));
+        let (shutdown, mut shutdown_rx) = watch::channel(false);
+
+        let task_worker_id = worker_id.clone();
+        let task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            ticker.tick().await;
+
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = shutdown_rx.changed() => break,
+                }
+
+                let mut load = probe.load();
+                load.capacity = capabilities.capacity;
+                let id = task_worker_id.read().await.clone();
+                match client.heartbeat(&id, &load).await {
+                    Ok(Heartbeat::Accepted) => {}
+                    Ok(Heartbeat::UnknownWorker) => {
+                        warn!("Director no longer knows worker {}, registering again", id);
+                        match client.register(&capabilities).await {
+                            Ok(registered) => {
+                                info!("Re-registered with director as worker {}", registered.worker_id);
+                                *task_worker_id.write().await = registered.worker_id;
+                            }
+                            Err(e) => error!("Re-registration failed: {:#}", e),
+                        }
+                    }
+                    Err(e) => warn!("Heartbeat for worker {} failed, retrying: {:#}", id, e),
+                }
+            }
+
+            let id = task_worker_id.read().await.clone();
+            match client.deregister(&id).await {
+                Ok(()) => info!("Deregistered worker {} from director", id),
+                Err(e) => error!("Failed to deregister worker {}: {:#}", id, e),
+            }
+        });
+
+        Ok(WorkerRegistration { worker_id, shutdown, task })
+    }
+
+    pub async fn worker_id(&self) -> String {
+        self.worker_id.read().await.clone()
+    }
+
+    /// Stops the heartbeat loop and waits for the worker to be deregistered
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(true);
+        if let Err(e) = self.task.await {
+            error!("Heartbeat task ended abnormally: {}", e);
+        }
+    }
+}
+
+/// Holds the live registration so the shutdown fairing can deregister it
+#[derive(Default)]
+struct DirectorState(tokio::sync::Mutex<Option<WorkerRegistration>>);
+
+/// Rocket stage that registers the worker on liftoff and deregisters it on shutdown.
+///
+/// Does nothing unless `OMNIFORGE_DIRECTOR_URL` is set.
+pub fn stage(probe: Arc<dyn LoadProbe>) -> AdHoc {
+    AdHoc::on_ignite("Director", |rocket| async move {
+        let Some(config) = DirectorConfig::from_env() else {
+            info!("{} not set, running without a director", DIRECTOR_URL_VAR);
+            return rocket;
+        };
+
+        rocket
+            .manage(DirectorState::default())
+            .attach(AdHoc::on_liftoff("Director registration", move |rocket| Box::pin(async move {
+                let client = match DirectorClient::new(config) {
+                    Ok(client) => client,
+                    Err(e) => {
+                        error!("Director client setup failed: {:#}", e);
+                        return;
+                    }
+                };
+                match WorkerRegistration::start(client, WorkerCapabilities::detect(), probe).await {
+                    Ok(registration) => {
+                        if let Some(state) = rocket.state::<DirectorState>() {
+                            *state.0.lock().await = Some(registration);
+                        }
+                    }
+                    Err(e) => error!("Failed to register with director: {:#}", e),
+                }
+            })))
+            .attach(AdHoc::on_shutdown("Director deregistration", |rocket| Box::pin(async move {
+                if let Some(state) = rocket.state::<DirectorState>() {
+                    if let Some(registration) = state.0.lock().await.take() {
+                        registration.shutdown().await;
+                    }
+                }
+            })))
+    })
+}
+
+pub fn hostname() -> String {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "omniforge-worker".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::sync::Mutex;
+    use warp::Filter;
+
+    #[derive(Default)]
+    struct MockDirector {
+        registrations: Mutex<Vec<RegisterRequest>>,
+        heartbeats: Mutex<Vec<(String, WorkerLoad)>>,
+        deregistered: Mutex<Vec<String>>,
+        /// Statuses to answer the next heartbeats with, 200 once they run out
+        heartbeat_statuses: Mutex<Vec<u16>>,
+    }
+
+    async fn start_mock(state: Arc<MockDirector>) -> String {
+        let register_state = state.clone();
+        let register = warp::post()
+            .and(warp::path!("workers"))
+            .and(warp::body::json())
+            .map(move |body: RegisterRequest| {
+                let mut registrations = register_state.registrations.lock().unwrap();
+                registrations.push(body);
+                warp::reply::json(
+                    &(RegisterResponse {
+                        worker_id: format!("worker-{}", registrations.len()),
+                        heartbeat_interval_secs: Some(1),
+                    })
+                )
+            });
+
+        let heartbeat_state = state.clone();
+        let heartbeat = warp::post()
+            .and(warp::path!("workers" / String / "heartbeat"))
+            .and(warp::body::json())
+            .map(move |id: String, load: WorkerLoad| {
+                heartbeat_state.heartbeats.lock().unwrap().push((id, load));
+                let mut statuses = heartbeat_state.heartbeat_statuses.lock().unwrap();
+                let status = if statuses.is_empty() { 200 } else { statuses.remove(0) };
+                warp::reply::with_status(warp::reply(), warp::http::StatusCode::from_u16(status).unwrap())
+            });
+
+        let deregister_state = state.clone();
+        let deregister = warp::delete()
+            .and(warp::path!("workers" / String))
+            .map(move |id: String| {
+                deregister_state.deregistered.lock().unwrap().push(id);
+                warp::reply()
+            });
+
+        let (addr, server) = warp::serve(register.or(heartbeat).or(deregister)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{}", addr)
+    }
+
+    fn client(url: String) -> DirectorClient {
+        DirectorClient::new(DirectorConfig {
+            url,
+            api_key: None,
+            heartbeat_interval: Duration::from_secs(1),
+        }).unwrap()
+    }
+
+    fn capabilities() -> WorkerCapabilities {
+        WorkerCapabilities {
+            backends: vec![ContainerEngineKind::Podman],
+            architectures: vec!["x86_64".to_string()],
+            capacity: 2,
+        }
+    }
+
+    /// Drives `fut` while moving the paused clock forward in small steps. Left idle, the runtime would
+    /// jump straight to the next timer (the client's request timeout) while a request is in flight.
+    async fn settle<F: Future>(fut: F) -> F::Output {
+        tokio::pin!(fut);
+        loop {
+            tokio::select! {
+                biased;
+                out = &mut fut => return out,
+                _ = tokio::time::advance(Duration::from_millis(10)) => {}
+            }
+        }
+    }
+
+    async fn wait_for_heartbeats(state: &MockDirector, count: usize) {
+        settle(async {
+            while state.heartbeats.lock().unwrap().len() < count {
+                tokio::task::yield_now().await;
+            }
+        }).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn registers_heartbeats_and_deregisters() {
+        let state = Arc::new(MockDirector::default());
+        let url = start_mock(state.clone()).await;
+        let capabilities = capabilities();
+        let probe: Arc<dyn LoadProbe> = Arc::new(|| WorkerLoad {
+            active_builds: 1,
+            ..Default::default()
+        });
+
+        let registration = settle(WorkerRegistration::start(client(url), capabilities.clone(), probe)).await.unwrap();
+        assert_eq!(registration.worker_id().await, "worker-1");
+        wait_for_heartbeats(&state, 1).await;
+        settle(registration.shutdown()).await;
+
+        assert_eq!(state.registrations.lock().unwrap()[0].capabilities, capabilities);
+        let heartbeats = state.heartbeats.lock().unwrap();
+        assert!(!heartbeats.is_empty());
+        assert_eq!(heartbeats[0].0, "worker-1");
+        assert_eq!(heartbeats[0].1.active_builds, 1);
+        assert_eq!(heartbeats[0].1.capacity, 2);
+        assert_eq!(*state.deregistered.lock().unwrap(), vec!["worker-1".to_string()]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn registers_again_only_when_the_director_forgot_the_worker() {
+        let state = Arc::new(MockDirector::default());
+        *state.heartbeat_statuses.lock().unwrap() = vec![503, 500, 404];
+        let url = start_mock(state.clone()).await;
+        let probe: Arc<dyn LoadProbe> = Arc::new(WorkerLoad::default);
+
+        let registration = settle(WorkerRegistration::start(client(url), capabilities(), probe)).await.unwrap();
+        wait_for_heartbeats(&state, 2).await;
+        // Server errors keep the id
+        assert_eq!(state.registrations.lock().unwrap().len(), 1);
+        assert_eq!(registration.worker_id().await, "worker-1");
+
+        wait_for_heartbeats(&state, 4).await;
+        settle(registration.shutdown()).await;
+        assert_eq!(state.registrations.lock().unwrap().len(), 2);
+        let heartbeats = state.heartbeats.lock().unwrap();
+        let ids: Vec<&str> = heartbeats.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids[..4], ["worker-1", "worker-1", "worker-1", "worker-2"]);
+        assert_eq!(*state.deregistered.lock().unwrap(), vec!["worker-2".to_string()]);
+    }
+}
@@ -1 +1 @@
-pub mod director;
\ No newline at end of file
+pub mod director;
@@ -5,22 +5,80 @@
 // Authors: Tristan J. Poland, Chance Green, SafeShows
 //-----------------------------------------------------------------------------
 
-use rocket::launch;
+use std::sync::Arc;
+
 use rocket::routes;
+use rocket::{ Build, Rocket };
 
 pub mod api;
+mod audit;
 mod autoscalar;
+mod deployment;
 mod image_builder;
 pub mod interfaces;
+mod queue;
+mod scheduler;
+mod ssh;
+mod telemetry;
+
+#[rocket::main]
+async fn main() {
+    let logging = match telemetry::logging::init() {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("Failed to set up logging: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // `omniforge deploy ...` runs a deployment against the host inventory instead of starting the server
+    if std::env::args().nth(1).as_deref() == Some("deploy") {
+        if let Err(e) = deployment::run().await {
+            tracing::error!("Deployment failed: {:#}", e);
+            drop(logging);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // `omniforge simulate <trace> ...` replays a metric trace against the autoscaler offline
+    if std::env::args().nth(1).as_deref() == Some("simulate") {
+        if let Err(e) = autoscalar::simulator::run() {
+            tracing::error!("Simulation failed: {:#}", e);
+            drop(logging);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(e) = start_server().launch().await {
+        tracing::error!("Server failed: {}", e);
+        drop(logging);
+        std::process::exit(1);
+    }
+}
 
-#[launch]
-pub async fn start_server() -> _ {
+pub fn start_server() -> Rocket<Build> {
     let port = 3030;
+    let audit = Arc::new(audit::AuditLog::from_env());
+    let scheduler = Arc::new(
+        scheduler::BuildScheduler::new(scheduler::SchedulerConfig::from_env()).with_audit(audit.clone())
+    );
     rocket::build()
         .configure(rocket::Config {
             port,
             address: std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)),
+            cli_colors: false,
             ..Default::default()
         })
-        .mount("/", routes![api::build,api::deploy_permissions])
+        .attach(telemetry::logging::RequestTracing)
+        .manage(scheduler.clone())
+        .manage(audit)
+        .manage(audit::AuditAccess::from_env())
+        .manage(Arc::new(autoscalar::forecast::MetricHistory::from_env()))
+        .attach(autoscalar::ingest::stage())
+        .attach(deployment::fleet::stage())
+        .attach(interfaces::director::stage(scheduler.clone()))
+        .attach(queue::stage(interfaces::director::hostname(), Arc::new(image_builder::ImageBuildHandler { scheduler })))
+        .mount("/", routes![api::build,api::build_status,api::active_builds,api::scheduler_decisions,api::enqueue_job,api::queue_summary,api::deploy_permissions,api::app_forecast,api::push_metrics,api::metrics,api::audit_log,api::audit_export,api::audit_verify,api::blacklist_instance,api::clear_instance_blacklist,api::instance_transitions])
 }
\ No newline at end of file
@@ -0,0 +1,192 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::watch;
+use tracing::{ error, info, instrument, warn };
+
+use super::{ BuildJob, JobQueue, Lease, QueueError };
+
+/// Runs a leased build job
+#[async_trait]
+pub trait JobHandler: Send + Sync {
+    async fn handle(&self, job: &BuildJob) -> Result<()>;
+}
+
+/// Pulls jobs from a queue and runs them one at a time, renewing the lease while the handler runs
+pub struct QueueConsumer {
+    queue: Arc<dyn JobQueue>,
+    worker_id: String,
+    visibility_timeout: Duration,
+    poll_interval: Duration,
+}
+
+impl QueueConsumer {
+    pub fn new(queue: Arc<dyn JobQueue>, worker_id: impl Into<String>) -> Self {
+        QueueConsumer {
+            queue,
+            worker_id: worker_id.into(),
+            visibility_timeout: Duration::from_secs(60),
+            poll_interval: Duration::from_secs(2),
+        }
+    }
+
+    pub fn visibility_timeout(mut self, timeout: Duration) -> Self {
+        self.visibility_timeout = timeout;
+        self
+    }
+
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Consumes jobs until `shutdown` flips to true. A job in progress is finished first.
+    pub async fn run(&self, handler: Arc<dyn JobHandler>, mut shutdown: watch::Receiver<bool>) {
+        while !*shutdown.borrow() {
+            match self.queue.lease(&self.worker_id, self.visibility_timeout).await {
+                Ok(Some(lease)) => self.process(&lease, handler.as_ref()).await,
+                Ok(None) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(self.poll_interval) => {}
+                        _ = shutdown.changed() => {}
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to lease job: {}", e);
+                    tokio::time::sleep(self.poll_interval).await;
+                }
+            }
+        }
+    }
+
+    /// Runs one leased job to completion and reports the outcome to the queue
+    #[instrument(skip_all, fields(job_id = %lease.job.id, app_id = %lease.job.app_id))]
+    pub async fn process(&self, lease: &Lease, handler: &dyn JobHandler) {
+        info!("Worker {} leased job {} (attempt {})", self.worker_id, lease.job.id, lease.job.attempts);
+
+        let build = handler.handle(&lease.job);
+        tokio::pin!(build);
+        let mut renew = tokio::time::interval(self.visibility_timeout / 3);
+        renew.tick().await;
+
+        let outcome = loop {
+            tokio::select! {
+                result = &mut build => break Some(result),
+                _ = renew.tick() => {
+                    match self.queue.renew(lease, self.visibility_timeout).await {
+                        Ok(()) => {}
+                        Err(QueueError::LeaseLost(id)) => {
+                            // Someone else owns the job now, stop working on it
+                            warn!("Lost lease on job {}, abandoning build", id);
+                            break None;
+                        }
+                        Err(e) => warn!("Failed to renew lease on job {}: {}", lease.job.id, e),
+                    }
+                }
+            }
+        };
+
+        let report = match outcome {
+            Some(Ok(())) => self.queue.ack(lease).await,
+            Some(Err(e)) => {
+                error!("Job {} failed: {:#}", lease.job.id, e);
+                self.queue.nack(lease, &format!("{:#}", e)).await
+            }
+            None => Ok(()),
+        };
+        if let Err(e) = report {
+            error!("Failed to report outcome of job {}: {}", lease.job.id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{ MemoryQueue, NewJob, RetryPolicy };
+    use serde_json::json;
+
+    /// Takes `duration` to build, then fails with `error` if there is one
+    struct Build {
+        duration: Duration,
+        error: Option<&'static str>,
+    }
+
+    #[async_trait]
+    impl JobHandler for Build {
+        async fn handle(&self, _: &BuildJob) -> Result<()> {
+            tokio::time::sleep(self.duration).await;
+            match self.error {
+                Some(error) => Err(anyhow::anyhow!(error)),
+                None => Ok(()),
+            }
+        }
+    }
+
+    async fn leased(queue: &Arc<MemoryQueue>) -> Lease {
+        queue.enqueue(NewJob { app_id: "app".to_string(), payload: json!({}) }).await.unwrap();
+        queue.lease("worker", Duration::from_millis(300)).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn renews_the_lease_while_building() {
+        let queue = Arc::new(MemoryQueue::default());
+        let lease = leased(&queue).await;
+        let consumer = QueueConsumer::new(queue.clone(), "worker").visibility_timeout(Duration::from_millis(300));
+
+        let build = Build { duration: Duration::from_millis(900), error: None };
+        let process = consumer.process(&lease, &build);
+        let steal = async {
+            // Long past the first lease, the renewals keep the job
+            tokio::time::sleep(Duration::from_millis(600)).await;
+            queue.lease("other", Duration::from_millis(300)).await.unwrap()
+        };
+        let ((), stolen) = tokio::join!(process, steal);
+        assert!(stolen.is_none());
+        assert_eq!(queue.depth().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn abandons_the_build_once_the_lease_is_lost() {
+        let queue = Arc::new(MemoryQueue::default());
+        let lease = leased(&queue).await;
+        let consumer = QueueConsumer::new(queue.clone(), "worker").visibility_timeout(Duration::from_millis(300));
+
+        // Another worker holds the job now
+        let lost = Lease { token: "lease-taken-over".to_string(), ..lease.clone() };
+        let build = Build { duration: Duration::from_secs(60), error: None };
+        tokio::time::timeout(Duration::from_secs(5), consumer.process(&lost, &build)).await.unwrap();
+
+        // Neither acked nor nacked, the job is left to its holder
+        assert_eq!(queue.depth().await.unwrap(), 1);
+        queue.renew(&lease, Duration::from_millis(300)).await.unwrap();
+        assert!(queue.dead_letters().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_builds_back_off_then_dead_letter() {
+        let policy = RetryPolicy { max_attempts: 2, base_backoff: Duration::from_secs(60), max_backoff: Duration::from_secs(60) };
+        let queue = Arc::new(MemoryQueue::new(policy));
+        let lease = leased(&queue).await;
+        let consumer = QueueConsumer::new(queue.clone(), "worker").visibility_timeout(Duration::from_millis(300));
+        let build = Build { duration: Duration::ZERO, error: Some("image push failed") };
+
+        consumer.process(&lease, &build).await;
+        // Nacked and backing off: still queued but not visible yet
+        assert_eq!(queue.depth().await.unwrap(), 1);
+        assert!(queue.lease("worker", Duration::from_millis(300)).await.unwrap().is_none());
+        assert!(matches!(queue.ack(&lease).await, Err(QueueError::LeaseLost(_))));
+
+        // The last attempt failing parks it
+        let policy = RetryPolicy { max_attempts: 1, ..policy };
+        let queue = Arc::new(MemoryQueue::new(policy));
+        let lease = leased(&queue).await;
+        let consumer = QueueConsumer::new(queue.clone(), "worker");
+        consumer.process(&lease, &build).await;
+        let dead = queue.dead_letters().await.unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].last_error.as_deref(), Some("image push failed"));
+    }
+}
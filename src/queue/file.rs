@@ -0,0 +1,201 @@
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+
+use super::{ BuildJob, JobQueue, Lease, NewJob, QueueError, QueueState, RetryPolicy };
+
+/// Queue persisted to a JSON file so jobs survive restarts without any external service.
+///
+/// Every mutation rewrites the file through a temp file and a rename, so a crash leaves either
+/// the old or the new state on disk. Polling an idle queue changes nothing and writes nothing. Leases held at crash time simply expire and are retried.
+/// Mutations and writes run on the blocking pool, reads only touch the in-memory state.
+pub struct FileQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: PathBuf,
+    state: Mutex<QueueState>,
+    policy: RetryPolicy,
+    /// Bumped by every mutation while the state is locked
+    version: AtomicU64,
+    /// Last version written to disk, held while writing
+    written: Mutex<u64>,
+}
+
+impl FileQueue {
+    /// Opens the queue file, creating an empty queue if it does not exist yet
+    pub fn open<P: AsRef<Path>>(path: P, policy: RetryPolicy) -> Result<Self, QueueError> {
+        let path = path.as_ref().to_path_buf();
+        let state = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => QueueState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(FileQueue {
+            inner: Arc::new(Inner {
+                path,
+                state: Mutex::new(state),
+                policy,
+                version: AtomicU64::new(0),
+                written: Mutex::new(0),
+            }),
+        })
+    }
+
+    /// Runs a mutation and writes the result to disk, off the async worker threads
+    async fn update<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut QueueState, &RetryPolicy) -> Result<T, QueueError> + Send + 'static
+    ) -> Result<T, QueueError> {
+        self.update_if_changed(move |state, policy| f(state, policy).map(|result| (true, result))).await
+    }
+
+    /// Like `update`, for a mutation that says whether it changed anything. Unchanged state isn't written.
+    async fn update_if_changed<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut QueueState, &RetryPolicy) -> Result<(bool, T), QueueError> + Send + 'static
+    ) -> Result<T, QueueError> {
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || inner.update(f)).await.map_err(|e| QueueError::Io(io::Error::other(e)))?
+    }
+}
+
+impl Inner {
+    fn update<T>(
+        &self,
+        f: impl FnOnce(&mut QueueState, &RetryPolicy) -> Result<(bool, T), QueueError>
+    ) -> Result<T, QueueError> {
+        // Snapshot under the state lock so readers only wait for the mutation, not the disk
+        let (result, version, snapshot) = {
+            let mut state = self.state.lock().unwrap();
+            let (changed, result) = f(&mut state, &self.policy)?;
+            if !changed {
+                return Ok(result);
+            }
+            let snapshot = serde_json::to_vec_pretty(&*state)?;
+            (result, self.version.fetch_add(1, Ordering::SeqCst) + 1, snapshot)
+        };
+
+        let mut written = self.written.lock().unwrap();
+        // A later mutation may have been written already, never replace it with an older snapshot
+        if *written < version {
+            self.persist(&snapshot)?;
+            *written = version;
+        }
+        Ok(result)
+    }
+
+    fn persist(&self, snapshot: &[u8]) -> Result<(), QueueError> {
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, snapshot)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl JobQueue for FileQueue {
+    async fn enqueue(&self, job: NewJob) -> Result<String, QueueError> {
+        self.update(move |state, _| Ok(state.enqueue(job, Utc::now()))).await
+    }
+
+    async fn lease(&self, worker_id: &str, visibility_timeout: Duration) -> Result<Option<Lease>, QueueError> {
+        let worker_id = worker_id.to_string();
+        self.update_if_changed(move |state, policy| {
+            let now = Utc::now();
+            let expired = state.expire(policy, now);
+            let lease = state.lease(&worker_id, visibility_timeout, policy, now);
+            Ok((expired || lease.is_some(), lease))
+        }).await
+    }
+
+    async fn renew(&self, lease: &Lease, visibility_timeout: Duration) -> Result<(), QueueError> {
+        let lease = lease.clone();
+        self.update(move |state, _| state.renew(&lease, visibility_timeout, Utc::now())).await
+    }
+
+    async fn ack(&self, lease: &Lease) -> Result<(), QueueError> {
+        let lease = lease.clone();
+        self.update(move |state, _| state.ack(&lease)).await
+    }
+
+    async fn nack(&self, lease: &Lease, error: &str) -> Result<(), QueueError> {
+        let (lease, error) = (lease.clone(), error.to_string());
+        self.update(move |state, policy| state.nack(&lease, &error, policy, Utc::now())).await
+    }
+
+    async fn dead_letters(&self) -> Result<Vec<BuildJob>, QueueError> {
+        Ok(self.inner.state.lock().unwrap().dead_letters.clone())
+    }
+
+    async fn depth(&self) -> Result<usize, QueueError> {
+        Ok(self.inner.state.lock().unwrap().jobs.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn jobs_survive_reopening() {
+        let path = std::env::temp_dir().join(format!("omniforge-queue-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let queue = FileQueue::open(&path, RetryPolicy::default()).unwrap();
+        let id = queue.enqueue(NewJob { app_id: "app".to_string(), payload: json!({ "ref": "main" }) }).await.unwrap();
+        drop(queue);
+
+        let queue = FileQueue::open(&path, RetryPolicy::default()).unwrap();
+        let lease = queue.lease("worker", Duration::from_secs(30)).await.unwrap().unwrap();
+        assert_eq!(lease.job.id, id);
+        assert_eq!(lease.job.payload, json!({ "ref": "main" }));
+        queue.ack(&lease).await.unwrap();
+        assert_eq!(queue.depth().await.unwrap(), 0);
+
+        // Polling the empty queue leaves the file alone
+        fs::remove_file(&path).unwrap();
+        assert!(queue.lease("worker", Duration::from_secs(30)).await.unwrap().is_none());
+        assert!(!path.exists());
+        queue.enqueue(NewJob { app_id: "app".to_string(), payload: json!({}) }).await.unwrap();
+        assert!(path.exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_updates_leave_the_latest_state_on_disk() {
+        let path = std::env::temp_dir().join(format!("omniforge-queue-concurrent-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let queue = Arc::new(FileQueue::open(&path, RetryPolicy::default()).unwrap());
+        let tasks: Vec<_> = (0..20)
+            .map(|i| {
+                let queue = queue.clone();
+                tokio::spawn(async move {
+                    queue.enqueue(NewJob { app_id: format!("app-{}", i), payload: json!({}) }).await.unwrap()
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        drop(queue);
+
+        let queue = FileQueue::open(&path, RetryPolicy::default()).unwrap();
+        assert_eq!(queue.depth().await.unwrap(), 20);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
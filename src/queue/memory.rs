@@ -0,0 +1,51 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+
+use super::{ BuildJob, JobQueue, Lease, NewJob, QueueError, QueueState, RetryPolicy };
+
+/// In-process queue, lost on restart. Enough for a single node or tests.
+#[derive(Default)]
+pub struct MemoryQueue {
+    state: Mutex<QueueState>,
+    policy: RetryPolicy,
+}
+
+impl MemoryQueue {
+    pub fn new(policy: RetryPolicy) -> Self {
+        MemoryQueue { state: Mutex::new(QueueState::default()), policy }
+    }
+}
+
+#[async_trait]
+impl JobQueue for MemoryQueue {
+    async fn enqueue(&self, job: NewJob) -> Result<String, QueueError> {
+        Ok(self.state.lock().unwrap().enqueue(job, Utc::now()))
+    }
+
+    async fn lease(&self, worker_id: &str, visibility_timeout: Duration) -> Result<Option<Lease>, QueueError> {
+        Ok(self.state.lock().unwrap().lease(worker_id, visibility_timeout, &self.policy, Utc::now()))
+    }
+
+    async fn renew(&self, lease: &Lease, visibility_timeout: Duration) -> Result<(), QueueError> {
+        self.state.lock().unwrap().renew(lease, visibility_timeout, Utc::now())
+    }
+
+    async fn ack(&self, lease: &Lease) -> Result<(), QueueError> {
+        self.state.lock().unwrap().ack(lease)
+    }
+
+    async fn nack(&self, lease: &Lease, error: &str) -> Result<(), QueueError> {
+        self.state.lock().unwrap().nack(lease, error, &self.policy, Utc::now())
+    }
+
+    async fn dead_letters(&self) -> Result<Vec<BuildJob>, QueueError> {
+        Ok(self.state.lock().unwrap().dead_letters.clone())
+    }
+
+    async fn depth(&self) -> Result<usize, QueueError> {
+        Ok(self.state.lock().unwrap().jobs.len())
+    }
+}
@@ -0,0 +1,352 @@
+//-----------------------------------------------------------------------------
+// Pull-based build job queue. Workers lease jobs with a visibility timeout,
+// renew the lease while building, then ack or nack. Failed jobs are retried
+// with exponential backoff and parked in a dead-letter list after too many
+// attempts, which keeps the latest MAX_DEAD_LETTERS. Expired leases make a
+// job visible again, which is how work moves off a worker that died
+// mid-build.
+//-----------------------------------------------------------------------------
+
+pub mod consumer;
+pub mod file;
+pub mod memory;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{ DateTime, Utc };
+use rocket::fairing::AdHoc;
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+use thiserror::Error;
+use tokio::sync::watch;
+use tracing::{ error, warn };
+
+pub use consumer::{ JobHandler, QueueConsumer };
+pub use file::FileQueue;
+pub use memory::MemoryQueue;
+
+#[derive(Debug, Error)]
+pub enum QueueError {
+    #[error("job not found: {0}")]
+    NotFound(String),
+    #[error("lease on job {0} is no longer held by this worker")]
+    LeaseLost(String),
+    #[error("IO error occurred: {0}")]
+    Io(#[from] io::Error),
+    #[error("could not (de)serialize queue state: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+/// How failed jobs are retried
+///
+/// # Fields
+/// max_attempts - Attempts before the job is moved to the dead-letter list
+/// base_backoff - Delay after the first failure, doubled on every following one
+/// max_backoff - Upper bound for the delay
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_backoff: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Backoff to apply after the given (1-based) failed attempt
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.base_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// A job waiting to be queued
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewJob {
+    pub app_id: String,
+    pub payload: Value,
+}
+
+/// Lease held by a worker on a job
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LeaseInfo {
+    pub token: String,
+    pub worker_id: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildJob {
+    pub id: String,
+    pub app_id: String,
+    pub payload: Value,
+    pub attempts: u32,
+    pub created_at: DateTime<Utc>,
+    pub available_at: DateTime<Utc>,
+    pub lease: Option<LeaseInfo>,
+    pub last_error: Option<String>,
+}
+
+/// What a worker gets back from `lease`, it needs the token to renew, ack or nack
+#[derive(Debug, Clone)]
+pub struct Lease {
+    pub job: BuildJob,
+    pub token: String,
+}
+
+/// A queue of build jobs that workers pull from
+#[async_trait]
+pub trait JobQueue: Send + Sync {
+    async fn enqueue(&self, job: NewJob) -> Result<String, QueueError>;
+    /// Leases the oldest visible job, `None` if nothing is ready
+    async fn lease(&self, worker_id: &str, visibility_timeout: Duration) -> Result<Option<Lease>, QueueError>;
+    /// Extends a lease the caller still holds
+    async fn renew(&self, lease: &Lease, visibility_timeout: Duration) -> Result<(), QueueError>;
+    /// Marks the job as done and removes it
+    async fn ack(&self, lease: &Lease) -> Result<(), QueueError>;
+    /// Marks the attempt as failed, the job is retried later or dead-lettered
+    async fn nack(&self, lease: &Lease, error: &str) -> Result<(), QueueError>;
+    async fn dead_letters(&self) -> Result<Vec<BuildJob>, QueueError>;
+    /// Number of jobs not yet acked, leased ones included
+    async fn depth(&self) -> Result<usize, QueueError>;
+}
+
+/// Dead-lettered jobs kept for inspection, the oldest are dropped first
+pub const MAX_DEAD_LETTERS: usize = 100;
+
+/// Env var pointing at the file backing the local job queue, an in-memory queue is used when unset
+pub const QUEUE_FILE_VAR: &str = "OMNIFORGE_QUEUE_FILE";
+
+/// Rocket stage that manages the job queue and consumes it from liftoff until shutdown.
+///
+/// Launch is aborted if the queue file cannot be opened, falling back to memory would silently drop
+/// the jobs it holds.
+pub fn stage(worker_id: String, handler: Arc<dyn JobHandler>) -> AdHoc {
+    AdHoc::try_on_ignite("Job queue", |rocket| async move {
+        let queue: Arc<dyn JobQueue> = match std::env::var(QUEUE_FILE_VAR) {
+            Ok(path) => match FileQueue::open(&path, RetryPolicy::default()) {
+                Ok(queue) => Arc::new(queue),
+                Err(e) => {
+                    error!("Failed to open job queue at {} ({}), fix or move the file to start: {}", path, QUEUE_FILE_VAR, e);
+                    return Err(rocket);
+                }
+            },
+            Err(_) => Arc::new(MemoryQueue::new(RetryPolicy::default())),
+        };
+        let (shutdown, shutdown_rx) = watch::channel(false);
+
+        Ok(rocket
+            .manage(queue.clone())
+            .attach(AdHoc::on_liftoff("Job queue consumer", move |_| Box::pin(async move {
+                let consumer = QueueConsumer::new(queue, worker_id);
+                tokio::spawn(async move { consumer.run(handler, shutdown_rx).await });
+            })))
+            .attach(AdHoc::on_shutdown("Job queue shutdown", move |_| Box::pin(async move {
+                let _ = shutdown.send(true);
+            }))))
+    })
+}
+
+/// The queue bookkeeping shared by every backend, time is passed in so it can be tested without sleeping
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct QueueState {
+    pub jobs: BTreeMap<String, BuildJob>,
+    pub dead_letters: Vec<BuildJob>,
+}
+
+impl QueueState {
+    pub fn enqueue(&mut self, job: NewJob, now: DateTime<Utc>) -> String {
+        let id = next_id("job");
+        self.jobs.insert(id.clone(), BuildJob {
+            id: id.clone(),
+            app_id: job.app_id,
+            payload: job.payload,
+            attempts: 0,
+            created_at: now,
+            available_at: now,
+            lease: None,
+            last_error: None,
+        });
+        id
+    }
+
+    /// Takes back the leases that ran out, a job whose lease did counts as a failed attempt by a
+    /// worker that went away. Returns whether there were any.
+    pub fn expire(&mut self, policy: &RetryPolicy, now: DateTime<Utc>) -> bool {
+        let expired: Vec<String> = self.jobs
+            .values()
+            .filter(|job| job.lease.as_ref().is_some_and(|lease| lease.expires_at <= now))
+            .map(|job| job.id.clone())
+            .collect();
+        for id in &expired {
+            if let Some(job) = self.jobs.get_mut(id) {
+                let worker = job.lease.take().map(|lease| lease.worker_id).unwrap_or_default();
+                job.last_error = Some(format!("lease expired on worker {}", worker));
+                if job.attempts >= policy.max_attempts {
+                    let job = self.jobs.remove(id).unwrap();
+                    self.dead_letter(job);
+                }
+            }
+        }
+        !expired.is_empty()
+    }
+
+    pub fn lease(
+        &mut self,
+        worker_id: &str,
+        visibility_timeout: Duration,
+        policy: &RetryPolicy,
+        now: DateTime<Utc>
+    ) -> Option<Lease> {
+        self.expire(policy, now);
+        let job = self.jobs
+            .values_mut()
+            .filter(|job| job.lease.is_none() && job.available_at <= now)
+            .min_by_key(|job| (job.available_at, job.created_at))?;
+
+        let token = next_id("lease");
+        job.attempts += 1;
+        job.lease = Some(LeaseInfo {
+            token: token.clone(),
+            worker_id: worker_id.to_string(),
+            expires_at: now + to_chrono(visibility_timeout),
+        });
+        Some(Lease { job: job.clone(), token })
+    }
+
+    fn held_mut(&mut self, lease: &Lease) -> Result<&mut BuildJob, QueueError> {
+        let job = self.jobs.get_mut(&lease.job.id).ok_or_else(|| QueueError::NotFound(lease.job.id.clone()))?;
+        match &job.lease {
+            Some(held) if held.token == lease.token => Ok(job),
+            _ => Err(QueueError::LeaseLost(lease.job.id.clone())),
+        }
+    }
+
+    pub fn renew(&mut self, lease: &Lease, visibility_timeout: Duration, now: DateTime<Utc>) -> Result<(), QueueError> {
+        let job = self.held_mut(lease)?;
+        if let Some(held) = job.lease.as_mut() {
+            held.expires_at = now + to_chrono(visibility_timeout);
+        }
+        Ok(())
+    }
+
+    pub fn ack(&mut self, lease: &Lease) -> Result<(), QueueError> {
+        self.held_mut(lease)?;
+        self.jobs.remove(&lease.job.id);
+        Ok(())
+    }
+
+    pub fn nack(&mut self, lease: &Lease, error: &str, policy: &RetryPolicy, now: DateTime<Utc>) -> Result<(), QueueError> {
+        let job = self.held_mut(lease)?;
+        job.lease = None;
+        job.last_error = Some(error.to_string());
+        if job.attempts >= policy.max_attempts {
+            let job = self.jobs.remove(&lease.job.id).unwrap();
+            self.dead_letter(job);
+        } else {
+            job.available_at = now + to_chrono(policy.backoff(job.attempts));
+        }
+        Ok(())
+    }
+
+    fn dead_letter(&mut self, job: BuildJob) {
+        self.dead_letters.push(job);
+        if self.dead_letters.len() > MAX_DEAD_LETTERS {
+            let dropped = self.dead_letters.remove(0);
+            warn!("Dropping dead-lettered job {} of {}, more than {} are kept", dropped.id, dropped.app_id, MAX_DEAD_LETTERS);
+        }
+    }
+}
+
+fn to_chrono(duration: Duration) -> chrono::Duration {
+    chrono::Duration::from_std(duration).unwrap_or(chrono::Duration::MAX)
+}
+
+/// Unique enough ids for a single node: a timestamp plus a process-wide counter
+fn next_id(prefix: &str) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    format!("{}-{:x}-{:x}", prefix, nanos, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(app_id: &str) -> NewJob {
+        NewJob { app_id: app_id.to_string(), payload: Value::Null }
+    }
+
+    #[test]
+    fn expired_lease_is_handed_to_another_worker() {
+        let policy = RetryPolicy::default();
+        let mut state = QueueState::default();
+        let now = Utc::now();
+        state.enqueue(job("app"), now);
+
+        let first = state.lease("worker-a", Duration::from_secs(30), &policy, now).unwrap();
+        assert!(state.lease("worker-b", Duration::from_secs(30), &policy, now).is_none());
+
+        let later = now + chrono::Duration::seconds(31);
+        let second = state.lease("worker-b", Duration::from_secs(30), &policy, later).unwrap();
+        assert_eq!(second.job.id, first.job.id);
+        assert_eq!(second.job.attempts, 2);
+        assert!(matches!(state.ack(&first), Err(QueueError::LeaseLost(_))));
+        state.ack(&second).unwrap();
+        assert!(state.jobs.is_empty());
+    }
+
+    #[test]
+    fn failed_jobs_back_off_then_dead_letter() {
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            base_backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(60),
+        };
+        let mut state = QueueState::default();
+        let now = Utc::now();
+        state.enqueue(job("app"), now);
+
+        let lease = state.lease("worker", Duration::from_secs(30), &policy, now).unwrap();
+        state.nack(&lease, "build failed", &policy, now).unwrap();
+        assert!(state.lease("worker", Duration::from_secs(30), &policy, now).is_none());
+
+        let retry_at = now + chrono::Duration::seconds(10);
+        let lease = state.lease("worker", Duration::from_secs(30), &policy, retry_at).unwrap();
+        state.nack(&lease, "build failed again", &policy, retry_at).unwrap();
+
+        assert!(state.jobs.is_empty());
+        assert_eq!(state.dead_letters.len(), 1);
+        assert_eq!(state.dead_letters[0].last_error.as_deref(), Some("build failed again"));
+    }
+
+    #[test]
+    fn only_the_latest_dead_letters_are_kept() {
+        let policy = RetryPolicy { max_attempts: 1, ..RetryPolicy::default() };
+        let mut state = QueueState::default();
+        let now = Utc::now();
+        for i in 0..=MAX_DEAD_LETTERS {
+            state.enqueue(job(&format!("app-{}", i)), now);
+            let lease = state.lease("worker", Duration::from_secs(30), &policy, now).unwrap();
+            state.nack(&lease, "build failed", &policy, now).unwrap();
+        }
+        assert_eq!(state.dead_letters.len(), MAX_DEAD_LETTERS);
+        assert_eq!(state.dead_letters[0].app_id, "app-1");
+
+        // Nothing expires without a lease running out
+        assert!(!state.expire(&policy, now));
+    }
+}
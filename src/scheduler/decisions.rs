@@ -0,0 +1,79 @@
+use std::collections::VecDeque;
+
+use chrono::{ DateTime, Utc };
+use serde::{ Deserialize, Serialize };
+use tracing::info;
+
+use super::Priority;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecisionKind {
+    Queued,
+    Started,
+    Waiting,
+    Cancelled,
+}
+
+/// One scheduling decision and why it was made
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Decision {
+    pub at: DateTime<Utc>,
+    pub build_id: String,
+    pub app_id: String,
+    pub tenant: String,
+    pub kind: DecisionKind,
+    pub priority: Priority,
+    pub reason: String,
+}
+
+/// Filters for `DecisionLog::query`, every set field has to match
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DecisionQuery {
+    pub build_id: Option<String>,
+    pub app_id: Option<String>,
+    pub tenant: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Bounded in-memory log of scheduling decisions, newest last
+#[derive(Debug)]
+pub struct DecisionLog {
+    entries: VecDeque<Decision>,
+    capacity: usize,
+}
+
+impl DecisionLog {
+    pub fn new(capacity: usize) -> Self {
+        DecisionLog { entries: VecDeque::new(), capacity }
+    }
+
+    pub fn record(&mut self, decision: Decision) {
+        info!(
+            app_id = %decision.app_id,
+            build_id = %decision.build_id,
+            tenant = %decision.tenant,
+            priority = %decision.priority,
+            "{:?}: {}",
+            decision.kind,
+            decision.reason
+        );
+        self.entries.push_back(decision);
+        while self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Matching decisions, oldest first, limited to the most recent `limit`
+    pub fn query(&self, query: &DecisionQuery) -> Vec<Decision> {
+        let matches: Vec<Decision> = self.entries
+            .iter()
+            .filter(|d| query.build_id.as_ref().map_or(true, |id| &d.build_id == id))
+            .filter(|d| query.app_id.as_ref().map_or(true, |app| &d.app_id == app))
+            .filter(|d| query.tenant.as_ref().map_or(true, |tenant| &d.tenant == tenant))
+            .cloned()
+            .collect();
+        let skip = query.limit.map_or(0, |limit| matches.len().saturating_sub(limit));
+        matches.into_iter().skip(skip).collect()
+    }
+}
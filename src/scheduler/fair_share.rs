@@ -0,0 +1,98 @@
+use std::collections::{ HashMap, HashSet };
+
+/// Weighted fair share bookkeeping between tenants.
+///
+/// Every started build advances its tenant's virtual time by `1 / weight`, the tenant with the
+/// lowest virtual time goes next. A tenant that was idle is brought up to the slowest active
+/// tenant when it comes back, so it cannot cash in on time it did not use.
+#[derive(Debug, Default)]
+pub struct FairShare {
+    weights: HashMap<String, u32>,
+    virtual_time: HashMap<String, f64>,
+}
+
+impl FairShare {
+    pub fn new(weights: HashMap<String, u32>) -> Self {
+        FairShare { weights, virtual_time: HashMap::new() }
+    }
+
+    pub fn weight(&self, tenant: &str) -> u32 {
+        self.weights.get(tenant).copied().unwrap_or(1).max(1)
+    }
+
+    pub fn virtual_time(&self, tenant: &str) -> f64 {
+        self.virtual_time.get(tenant).copied().unwrap_or(0.0)
+    }
+
+    /// Called when a tenant with no queued or running builds submits one, never for an active tenant:
+    /// that would take away the share it is still owed
+    pub fn activate<'a>(&mut self, tenant: &str, active_tenants: impl Iterator<Item = &'a str>) {
+        let floor = active_tenants
+            .filter(|other| *other != tenant)
+            .map(|other| self.virtual_time(other))
+            .fold(None, |min: Option<f64>, vt| Some(min.map_or(vt, |m| m.min(vt))));
+        if let Some(floor) = floor {
+            let entry = self.virtual_time.entry(tenant.to_string()).or_insert(0.0);
+            *entry = entry.max(floor);
+        }
+    }
+
+    pub fn charge(&mut self, tenant: &str) {
+        let weight = self.weight(tenant) as f64;
+        *self.virtual_time.entry(tenant.to_string()).or_insert(0.0) += 1.0 / weight;
+    }
+
+    /// Forgets idle tenants that `activate` would bring up to the floor anyway, and everyone once
+    /// no tenant is active, so the map only holds tenants that still matter
+    pub fn prune<'a>(&mut self, active_tenants: impl Iterator<Item = &'a str>) {
+        let active: HashSet<&str> = active_tenants.collect();
+        let floor = active
+            .iter()
+            .map(|tenant| self.virtual_time(tenant))
+            .fold(None, |min: Option<f64>, vt| Some(min.map_or(vt, |m| m.min(vt))));
+        match floor {
+            Some(floor) => self.virtual_time.retain(|tenant, vt| active.contains(tenant.as_str()) || *vt > floor),
+            None => self.virtual_time.clear(),
+        }
+    }
+
+    /// Tenants with a virtual time on record
+    pub fn tracked(&self) -> usize {
+        self.virtual_time.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heavier_tenant_gets_proportionally_more_starts() {
+        let mut share = FairShare::new(HashMap::from([("big".to_string(), 3)]));
+        let mut starts = HashMap::new();
+        for _ in 0..8 {
+            let next = if share.virtual_time("big") <= share.virtual_time("small") { "big" } else { "small" };
+            share.charge(next);
+            *starts.entry(next).or_insert(0) += 1;
+        }
+        assert_eq!(starts["big"], 6);
+        assert_eq!(starts["small"], 2);
+    }
+
+    #[test]
+    fn idle_tenants_at_or_below_the_floor_are_forgotten() {
+        let mut share = FairShare::default();
+        share.charge("ahead");
+        share.charge("ahead");
+        share.charge("behind");
+        share.charge("active");
+
+        share.prune(["active"].into_iter());
+        assert_eq!(share.tracked(), 2);
+        assert_eq!(share.virtual_time("ahead"), 2.0);
+        assert_eq!(share.virtual_time("behind"), 0.0);
+
+        share.prune(std::iter::empty());
+        assert_eq!(share.tracked(), 0);
+    }
+}
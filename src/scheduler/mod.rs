@@ -0,0 +1,809 @@
+//-----------------------------------------------------------------------------
+// Build scheduler - decides when a submitted build may start. Enforces the
+// worker's build capacity, a per-app limit, and CPU/memory admission against
+// what the host actually has. Builds that cannot start yet wait in a queue
+// ordered by priority class (aged while waiting) and weighted fair share
+// between tenants. Every decision is logged so a wait can be explained.
+// Submitted builds and how they ended go to the audit log, written in the
+// background once the scheduler's lock is released.
+//-----------------------------------------------------------------------------
+
+pub mod decisions;
+pub mod fair_share;
+pub mod priority;
+pub mod resources;
+
+use std::collections::{ HashMap, VecDeque };
+use std::env;
+use std::sync::{ Arc, Mutex, MutexGuard };
+use std::time::Duration;
+
+use chrono::{ DateTime, Utc };
+use serde::{ Deserialize, Serialize };
+use serde_json::json;
+use thiserror::Error;
+use tokio::sync::Notify;
+
+use crate::audit::{ AuditAction, AuditEvent, AuditLog, AuditOutcome };
+use crate::interfaces::director::{ LoadProbe, WorkerLoad, BUILD_CAPACITY_VAR };
+use crate::telemetry::METRICS;
+pub use decisions::{ Decision, DecisionKind, DecisionLog, DecisionQuery };
+use fair_share::FairShare;
+pub use priority::Priority;
+pub use resources::Resources;
+
+/// Finished builds kept around so their status can still be queried
+const FINISHED_HISTORY: usize = 256;
+/// Scheduling decisions kept for `GET /scheduler/decisions`
+const DECISION_HISTORY: usize = 2048;
+
+#[derive(Debug, Error)]
+pub enum SchedulerError {
+    #[error("build {0} is unknown")]
+    Unknown(String),
+    #[error("build {0} was cancelled: {1}")]
+    Cancelled(String, String),
+}
+
+/// What happens to a queued build when a newer build for the same app is submitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SupersedePolicy {
+    /// Keep it, builds for the app run one after another
+    Queue,
+    /// Cancel it, only the newest build for the app is worth running
+    CancelSuperseded,
+}
+
+/// # Fields
+/// global_limit - Builds allowed to run at once on this worker
+/// per_app_limit - Builds allowed to run at once for one app
+/// supersede - What to do with queued builds when a newer one for the same app arrives
+/// default_request - Resources reserved by a build that does not ask for anything specific
+/// host - Resources available to builds on this host
+/// aging_interval - Waiting this long raises a build one priority class
+/// tenant_weights - Fair share weight per tenant, tenants not listed weigh 1
+#[derive(Debug, Clone)]
+pub struct SchedulerConfig {
+    pub global_limit: usize,
+    pub per_app_limit: usize,
+    pub supersede: SupersedePolicy,
+    pub default_request: Resources,
+    pub host: Resources,
+    pub aging_interval: Duration,
+    pub tenant_weights: HashMap<String, u32>,
+}
+
+impl SchedulerConfig {
+    /// Reads limits from `OMNIFORGE_BUILD_CAPACITY`, `OMNIFORGE_BUILD_CPU_MILLIS`, `OMNIFORGE_BUILD_MEMORY_MB`,
+    /// `OMNIFORGE_BUILD_AGING_SECS` and `OMNIFORGE_TENANT_WEIGHTS` (`team-a=3,team-b=1`)
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            env::var(name).ok().and_then(|value| value.parse().ok())
+        }
+        SchedulerConfig {
+            global_limit: var(BUILD_CAPACITY_VAR).unwrap_or(4),
+            per_app_limit: 1,
+            supersede: SupersedePolicy::CancelSuperseded,
+            default_request: Resources::new(
+                var("OMNIFORGE_BUILD_CPU_MILLIS").unwrap_or(1000),
+                var("OMNIFORGE_BUILD_MEMORY_MB").unwrap_or(2048)
+            ),
+            host: Resources::host(512),
+            aging_interval: Duration::from_secs(var("OMNIFORGE_BUILD_AGING_SECS").unwrap_or(600)),
+            tenant_weights: env::var("OMNIFORGE_TENANT_WEIGHTS")
+                .map(|weights| parse_tenant_weights(&weights))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum BuildState {
+    /// Waiting to start, position 1 is next in line
+    Queued { position: usize },
+    Running,
+    Succeeded,
+    Failed { error: String },
+    Cancelled { reason: String },
+}
+
+impl BuildState {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, BuildState::Succeeded | BuildState::Failed { .. } | BuildState::Cancelled { .. })
+    }
+}
+
+/// What is being asked of the scheduler
+///
+/// # Fields
+/// tenant - Who the build is accounted to for fair sharing, defaults to the app
+/// resources - Overrides the configured default request
+/// actor - Who asked for the build, for the audit log
+/// rebuild - Builds the source the app already has rather than bringing new source,
+///           so it never supersedes a queued build that may carry newer source
+#[derive(Debug, Clone, Default)]
+pub struct BuildRequest {
+    pub app_id: String,
+    pub tenant: Option<String>,
+    pub actor: Option<String>,
+    pub priority: Priority,
+    pub resources: Option<Resources>,
+    pub rebuild: bool,
+}
+
+impl BuildRequest {
+    pub fn new(app_id: &str) -> Self {
+        BuildRequest { app_id: app_id.to_string(), ..Default::default() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildStatus {
+    pub build_id: String,
+    pub app_id: String,
+    pub tenant: String,
+    pub priority: Priority,
+    #[serde(flatten)]
+    pub state: BuildState,
+    /// Why a queued build has not started yet
+    pub wait_reason: Option<String>,
+    pub submitted_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
+struct BuildRecord {
+    status: BuildStatus,
+    resources: Resources,
+    actor: String,
+}
+
+struct SchedulerState {
+    next_id: u64,
+    queue: VecDeque<String>,
+    running: Vec<String>,
+    records: HashMap<String, BuildRecord>,
+    finished: VecDeque<String>,
+    fair_share: FairShare,
+    decisions: DecisionLog,
+    /// Events for the audit log, written once the lock is released. `None` without one.
+    audit: Option<Vec<AuditEvent>>,
+}
+
+impl SchedulerState {
+    fn reserved(&self) -> Resources {
+        self.running
+            .iter()
+            .filter_map(|id| self.records.get(id))
+            .fold(Resources::default(), |total, record| total.add(&record.resources))
+    }
+
+    fn running_for_app(&self, app_id: &str) -> usize {
+        self.running
+            .iter()
+            .filter_map(|id| self.records.get(id))
+            .filter(|record| record.status.app_id == app_id)
+            .count()
+    }
+
+    fn effective_priority(&self, record: &BuildRecord, config: &SchedulerConfig, now: DateTime<Utc>) -> Priority {
+        let waited = (now - record.status.submitted_at).to_std().unwrap_or_default();
+        record.status.priority.aged(waited, config.aging_interval)
+    }
+
+    /// Queued builds in the order they would start: aged priority, then tenant fair share, then age
+    fn ranked_queue(&self, config: &SchedulerConfig, now: DateTime<Utc>) -> Vec<String> {
+        let mut ranked: Vec<&String> = self.queue.iter().collect();
+        ranked.sort_by(|a, b| {
+            let (a, b) = (&self.records[*a], &self.records[*b]);
+            self.effective_priority(b, config, now)
+                .cmp(&self.effective_priority(a, config, now))
+                .then(
+                    self.fair_share
+                        .virtual_time(&a.status.tenant)
+                        .total_cmp(&self.fair_share.virtual_time(&b.status.tenant))
+                )
+                .then(a.status.submitted_at.cmp(&b.status.submitted_at))
+        });
+        ranked.into_iter().cloned().collect()
+    }
+
+    /// Picks the next build allowed to start. Every build that has to keep waiting gets its reason set.
+    fn next_runnable(&mut self, config: &SchedulerConfig, now: DateTime<Utc>) -> Option<String> {
+        let ranked = self.ranked_queue(config, now);
+        let mut waiting: Vec<(String, String)> = Vec::new();
+        let mut chosen = None;
+
+        if self.running.len() >= config.global_limit {
+            let reason = format!("worker at capacity ({}/{} builds running)", self.running.len(), config.global_limit);
+            waiting.extend(ranked.into_iter().map(|id| (id, reason.clone())));
+        } else {
+            let reserved = self.reserved();
+            let mut blocked_by: Option<String> = None;
+            for id in ranked {
+                let record = &self.records[&id];
+                if let Some(ahead) = &blocked_by {
+                    waiting.push((id, format!("behind higher ranked build {}", ahead)));
+                    continue;
+                }
+                if self.running_for_app(&record.status.app_id) >= config.per_app_limit {
+                    waiting.push((id, format!("app {} already has a build running", record.status.app_id)));
+                    continue;
+                }
+                // An oversized build still runs on an idle host rather than waiting forever
+                if self.running.is_empty() || reserved.add(&record.resources).fits_within(&config.host) {
+                    chosen = Some(id);
+                    break;
+                }
+                // Head-of-line blocking on resources, so small builds cannot starve a big one
+                let free_cpu = config.host.cpu_millis.saturating_sub(reserved.cpu_millis);
+                let free_memory = config.host.memory_mb.saturating_sub(reserved.memory_mb);
+                waiting.push((
+                    id.clone(),
+                    format!(
+                        "waiting for resources: needs {}m CPU / {} MB, {}m / {} MB free",
+                        record.resources.cpu_millis,
+                        record.resources.memory_mb,
+                        free_cpu,
+                        free_memory
+                    ),
+                ));
+                blocked_by = Some(id);
+            }
+        }
+
+        for (id, reason) in waiting {
+            self.set_waiting(&id, reason, config, now);
+        }
+        chosen
+    }
+
+    /// Records a wait reason, the decision log only sees it when the reason changes
+    fn set_waiting(&mut self, build_id: &str, reason: String, config: &SchedulerConfig, now: DateTime<Utc>) {
+        let Some(record) = self.records.get(build_id) else {
+            return;
+        };
+        if record.status.wait_reason.as_deref() == Some(reason.as_str()) {
+            return;
+        }
+        let priority = self.effective_priority(record, config, now);
+        self.log(build_id, DecisionKind::Waiting, priority, reason.clone());
+        if let Some(record) = self.records.get_mut(build_id) {
+            record.status.wait_reason = Some(reason);
+        }
+    }
+
+    fn log(&mut self, build_id: &str, kind: DecisionKind, priority: Priority, reason: String) {
+        let Some(record) = self.records.get(build_id) else {
+            return;
+        };
+        let decision = Decision {
+            at: Utc::now(),
+            build_id: build_id.to_string(),
+            app_id: record.status.app_id.clone(),
+            tenant: record.status.tenant.clone(),
+            kind,
+            priority,
+            reason,
+        };
+        self.decisions.record(decision);
+    }
+
+    fn audit(&mut self, build_id: &str, outcome: AuditOutcome) {
+        let (Some(audit), Some(record)) = (&mut self.audit, self.records.get(build_id)) else {
+            return;
+        };
+        audit.push(AuditEvent {
+            actor: record.actor.clone(),
+            action: AuditAction::Build,
+            target: record.status.app_id.clone(),
+            parameters: json!({
+                "build_id": build_id,
+                "tenant": record.status.tenant,
+                "priority": record.status.priority,
+                "resources": record.resources,
+            }),
+            outcome,
+        });
+    }
+
+    fn active_tenants(&self) -> Vec<String> {
+        self.queue
+            .iter()
+            .chain(self.running.iter())
+            .filter_map(|id| self.records.get(id))
+            .map(|record| record.status.tenant.clone())
+            .collect()
+    }
+
+    fn finish(&mut self, build_id: &str, state: BuildState) {
+        let outcome = match &state {
+            BuildState::Succeeded => "succeeded",
+            BuildState::Failed { .. } => "failed",
+            BuildState::Cancelled { .. } => "cancelled",
+            BuildState::Queued { .. } | BuildState::Running => "other",
+        };
+        METRICS.builds.inc(&[outcome]);
+        let audited = match &state {
+            BuildState::Succeeded => Some(AuditOutcome::Succeeded),
+            BuildState::Failed { error } => Some(AuditOutcome::Failed { reason: error.clone() }),
+            BuildState::Cancelled { reason } => Some(AuditOutcome::Cancelled { reason: reason.clone() }),
+            BuildState::Queued { .. } | BuildState::Running => None,
+        };
+        if let Some(outcome) = audited {
+            self.audit(build_id, outcome);
+        }
+        self.running.retain(|id| id != build_id);
+        self.queue.retain(|id| id != build_id);
+        let active = self.active_tenants();
+        self.fair_share.prune(active.iter().map(String::as_str));
+        if let BuildState::Cancelled { reason } = &state {
+            let priority = self.records.get(build_id).map(|record| record.status.priority).unwrap_or_default();
+            self.log(build_id, DecisionKind::Cancelled, priority, reason.clone());
+        }
+        if let Some(record) = self.records.get_mut(build_id) {
+            record.status.state = state;
+            record.status.wait_reason = None;
+            record.status.finished_at = Some(Utc::now());
+        }
+        self.finished.push_back(build_id.to_string());
+        while self.finished.len() > FINISHED_HISTORY {
+            if let Some(old) = self.finished.pop_front() {
+                self.records.remove(&old);
+            }
+        }
+    }
+}
+
+/// Admits builds according to the worker's limits. Shared as `Arc<BuildScheduler>`.
+pub struct BuildScheduler {
+    config: SchedulerConfig,
+    state: Mutex<SchedulerState>,
+    changed: Notify,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl BuildScheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        let state = SchedulerState {
+            next_id: 0,
+            queue: VecDeque::new(),
+            running: Vec::new(),
+            records: HashMap::new(),
+            finished: VecDeque::new(),
+            fair_share: FairShare::new(config.tenant_weights.clone()),
+            decisions: DecisionLog::new(DECISION_HISTORY),
+            audit: None,
+        };
+        BuildScheduler {
+            config,
+            state: Mutex::new(state),
+            changed: Notify::new(),
+            audit: None,
+        }
+    }
+
+    pub fn config(&self) -> &SchedulerConfig {
+        &self.config
+    }
+
+    /// Audits every submitted build and how it ended. Also records the scheduling policy
+    /// as a policy change if it differs from the one recorded last.
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        let policy = json!({
+            "global_limit": self.config.global_limit,
+            "per_app_limit": self.config.per_app_limit,
+            "supersede": self.config.supersede,
+            "default_request": self.config.default_request,
+            "aging_secs": self.config.aging_interval.as_secs(),
+            "tenant_weights": self.config.tenant_weights,
+        });
+        audit.record_policy(&crate::audit::local_actor(), "scheduler", policy);
+        self.state.get_mut().unwrap().audit = Some(Vec::new());
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Releases the lock, then has what was audited under it written in the background
+    fn unlock(&self, mut state: MutexGuard<'_, SchedulerState>) {
+        let events = state.audit.as_mut().map(std::mem::take).unwrap_or_default();
+        drop(state);
+        if let Some(audit) = &self.audit {
+            audit.record_later(events);
+        }
+    }
+
+    /// Queues a build and returns its id. The build may start right away.
+    pub fn submit(&self, request: BuildRequest) -> String {
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        let build_id = format!("{}-{}", Utc::now().format("%Y%m%d%H%M%S"), state.next_id);
+        let app_id = request.app_id;
+        let tenant = request.tenant.unwrap_or_else(|| app_id.clone());
+        let actor = request.actor.unwrap_or_else(|| "anonymous".to_string());
+
+        if self.config.supersede == SupersedePolicy::CancelSuperseded && !request.rebuild {
+            let superseded: Vec<String> = state.queue
+                .iter()
+                .filter(|id| state.records[*id].status.app_id == app_id)
+                .cloned()
+                .collect();
+            for id in superseded {
+                state.finish(&id, BuildState::Cancelled { reason: format!("superseded by {}", build_id) });
+            }
+        }
+
+        let active = state.active_tenants();
+        if !active.contains(&tenant) {
+            state.fair_share.activate(&tenant, active.iter().map(String::as_str));
+        }
+
+        state.records.insert(build_id.clone(), BuildRecord {
+            status: BuildStatus {
+                build_id: build_id.clone(),
+                app_id,
+                tenant,
+                priority: request.priority,
+                state: BuildState::Queued { position: 0 },
+                wait_reason: None,
+                submitted_at: Utc::now(),
+                started_at: None,
+                finished_at: None,
+            },
+            resources: request.resources.unwrap_or(self.config.default_request),
+            actor,
+        });
+        state.audit(&build_id, AuditOutcome::Accepted);
+        state.queue.push_back(build_id.clone());
+        state.log(&build_id, DecisionKind::Queued, request.priority, format!("submitted as {}", request.priority));
+        self.dispatch(&mut state);
+        self.unlock(state);
+        build_id
+    }
+
+    /// Starts every queued build that may run, best ranked first
+    fn dispatch(&self, state: &mut SchedulerState) {
+        let now = Utc::now();
+        while let Some(build_id) = state.next_runnable(&self.config, now) {
+            state.queue.retain(|id| id != &build_id);
+            let record = &state.records[&build_id];
+            let tenant = record.status.tenant.clone();
+            let base = record.status.priority;
+            let effective = state.effective_priority(record, &self.config, now);
+            let reason = format!(
+                "started at {} priority{}, tenant {} share {:.2} (weight {})",
+                effective,
+                if effective != base { format!(" (aged from {})", base) } else { String::new() },
+                tenant,
+                state.fair_share.virtual_time(&tenant),
+                state.fair_share.weight(&tenant)
+            );
+            state.fair_share.charge(&tenant);
+            if let Some(record) = state.records.get_mut(&build_id) {
+                record.status.state = BuildState::Running;
+                record.status.wait_reason = None;
+                record.status.started_at = Some(now);
+            }
+            state.log(&build_id, DecisionKind::Started, effective, reason);
+            state.running.push(build_id);
+        }
+        self.changed.notify_waiters();
+    }
+
+    /// Waits until the build has been admitted. Dropping or finishing the permit frees its slot.
+    pub async fn acquire(self: &Arc<Self>, build_id: &str) -> Result<BuildPermit, SchedulerError> {
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            {
+                let state = self.state.lock().unwrap();
+                let record = state.records.get(build_id).ok_or_else(|| SchedulerError::Unknown(build_id.to_string()))?;
+                match &record.status.state {
+                    BuildState::Running => {
+                        return Ok(BuildPermit {
+                            scheduler: self.clone(),
+                            build_id: build_id.to_string(),
+                            finished: false,
+                        });
+                    }
+                    BuildState::Cancelled { reason } => {
+                        return Err(SchedulerError::Cancelled(build_id.to_string(), reason.clone()));
+                    }
+                    _ => {}
+                }
+            }
+
+            changed.await;
+        }
+    }
+
+    /// Cancels a queued build. Running builds are left alone, returns whether anything was cancelled.
+    pub fn cancel(&self, build_id: &str, reason: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.queue.iter().any(|id| id == build_id) {
+            return false;
+        }
+        state.finish(build_id, BuildState::Cancelled { reason: reason.to_string() });
+        self.dispatch(&mut state);
+        self.unlock(state);
+        true
+    }
+
+    fn complete(&self, build_id: &str, outcome: BuildState) {
+        let mut state = self.state.lock().unwrap();
+        state.finish(build_id, outcome);
+        self.dispatch(&mut state);
+        self.unlock(state);
+    }
+
+    /// Current status of a build, with its position if it is still queued
+    pub fn status(&self, build_id: &str) -> Option<BuildStatus> {
+        let state = self.state.lock().unwrap();
+        let mut status = state.records.get(build_id)?.status.clone();
+        if let BuildState::Queued { position } = &mut status.state {
+            *position = state
+                .ranked_queue(&self.config, Utc::now())
+                .iter()
+                .position(|id| id == build_id)
+                .map(|index| index + 1)
+                .unwrap_or(0);
+        }
+        Some(status)
+    }
+
+    /// All builds for an app that are still queued or running
+    pub fn active_for_app(&self, app_id: &str) -> Vec<BuildStatus> {
+        let ids: Vec<String> = {
+            let state = self.state.lock().unwrap();
+            state.running.iter().chain(state.queue.iter()).cloned().collect()
+        };
+        ids.iter()
+            .filter_map(|id| self.status(id))
+            .filter(|status| status.app_id == app_id)
+            .collect()
+    }
+}
+
+impl BuildScheduler {
+    /// Scheduling decisions matching the query, oldest first
+    pub fn decisions(&self, query: &DecisionQuery) -> Vec<Decision> {
+        self.state.lock().unwrap().decisions.query(query)
+    }
+}
+
+impl LoadProbe for BuildScheduler {
+    fn load(&self) -> WorkerLoad {
+        let state = self.state.lock().unwrap();
+        let reserved = state.reserved();
+        WorkerLoad {
+            active_builds: state.running.len() as u32,
+            queued_builds: state.queue.len() as u32,
+            capacity: self.config.global_limit as u32,
+            cpu_load: Some(reserved.cpu_millis as f32 / self.config.host.cpu_millis.max(1) as f32),
+            memory_used_mb: Some(reserved.memory_mb),
+        }
+    }
+}
+
+/// `team-a=3,team-b=1` into a weight map, malformed entries are skipped
+fn parse_tenant_weights(spec: &str) -> HashMap<String, u32> {
+    spec.split(',')
+        .filter_map(|entry| entry.split_once('='))
+        .filter_map(|(tenant, weight)| Some((tenant.trim().to_string(), weight.trim().parse().ok()?)))
+        .collect()
+}
+
+/// Held by a running build. Finish it with the build's outcome, dropping it counts as a failure.
+pub struct BuildPermit {
+    scheduler: Arc<BuildScheduler>,
+    build_id: String,
+    finished: bool,
+}
+
+impl BuildPermit {
+    pub fn build_id(&self) -> &str {
+        &self.build_id
+    }
+
+    pub fn finish(mut self, result: &anyhow::Result<()>) {
+        let outcome = match result {
+            Ok(()) => BuildState::Succeeded,
+            Err(e) => BuildState::Failed { error: format!("{:#}", e) },
+        };
+        self.scheduler.complete(&self.build_id, outcome);
+        self.finished = true;
+    }
+}
+
+impl Drop for BuildPermit {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.scheduler.complete(&self.build_id, BuildState::Failed { error: "build abandoned".to_string() });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(global_limit: usize, supersede: SupersedePolicy) -> SchedulerConfig {
+        SchedulerConfig {
+            global_limit,
+            per_app_limit: 1,
+            supersede,
+            default_request: Resources::new(1000, 1024),
+            host: Resources::new(4000, 8192),
+            aging_interval: Duration::from_secs(600),
+            tenant_weights: HashMap::new(),
+        }
+    }
+
+    fn scheduler(global_limit: usize, supersede: SupersedePolicy) -> Arc<BuildScheduler> {
+        Arc::new(BuildScheduler::new(config(global_limit, supersede)))
+    }
+
+    #[tokio::test]
+    async fn queues_beyond_capacity_and_reports_position() {
+        let scheduler = scheduler(1, SupersedePolicy::Queue);
+        let first = scheduler.submit(BuildRequest::new("a"));
+        let second = scheduler.submit(BuildRequest::new("b"));
+        let third = scheduler.submit(BuildRequest::new("c"));
+
+        assert_eq!(scheduler.status(&second).unwrap().state, BuildState::Queued { position: 1 });
+        assert_eq!(scheduler.status(&third).unwrap().state, BuildState::Queued { position: 2 });
+
+        let permit = scheduler.acquire(&first).await.unwrap();
+        permit.finish(&Ok(()));
+        assert_eq!(scheduler.status(&first).unwrap().state, BuildState::Succeeded);
+        assert_eq!(scheduler.status(&second).unwrap().state, BuildState::Running);
+        assert_eq!(scheduler.status(&third).unwrap().state, BuildState::Queued { position: 1 });
+    }
+
+    #[tokio::test]
+    async fn newer_build_supersedes_queued_one_for_same_app() {
+        let scheduler = scheduler(4, SupersedePolicy::CancelSuperseded);
+        let running = scheduler.submit(BuildRequest::new("app"));
+        let stale = scheduler.submit(BuildRequest::new("app"));
+        let latest = scheduler.submit(BuildRequest::new("app"));
+
+        assert_eq!(scheduler.status(&running).unwrap().state, BuildState::Running);
+        assert!(matches!(scheduler.acquire(&stale).await, Err(SchedulerError::Cancelled(..))));
+        assert_eq!(scheduler.status(&latest).unwrap().state, BuildState::Queued { position: 1 });
+    }
+
+    #[tokio::test]
+    async fn rebuilds_never_supersede_an_upload() {
+        let scheduler = scheduler(4, SupersedePolicy::CancelSuperseded);
+        let running = scheduler.submit(BuildRequest::new("app"));
+        let upload = scheduler.submit(BuildRequest::new("app"));
+        let rebuild = scheduler.submit(BuildRequest { rebuild: true, ..BuildRequest::new("app") });
+        assert_eq!(scheduler.status(&upload).unwrap().state, BuildState::Queued { position: 1 });
+        assert_eq!(scheduler.status(&rebuild).unwrap().state, BuildState::Queued { position: 2 });
+
+        // A newer upload still replaces both
+        let latest = scheduler.submit(BuildRequest::new("app"));
+        assert!(matches!(scheduler.acquire(&upload).await, Err(SchedulerError::Cancelled(..))));
+        assert!(matches!(scheduler.acquire(&rebuild).await, Err(SchedulerError::Cancelled(..))));
+        assert_eq!(scheduler.status(&running).unwrap().state, BuildState::Running);
+        assert_eq!(scheduler.status(&latest).unwrap().state, BuildState::Queued { position: 1 });
+    }
+
+    #[tokio::test]
+    async fn audits_builds_in_order_and_the_policy_once() {
+        let path = std::env::temp_dir().join(format!("omniforge-scheduler-audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let audit = Arc::new(AuditLog::new(&path));
+        let scheduler = Arc::new(BuildScheduler::new(config(4, SupersedePolicy::CancelSuperseded)).with_audit(audit.clone()));
+        let running = scheduler.submit(BuildRequest { actor: Some("ana".to_string()), ..BuildRequest::new("app") });
+        let stale = scheduler.submit(BuildRequest::new("app"));
+        let latest = scheduler.submit(BuildRequest::new("app"));
+        scheduler.acquire(&running).await.unwrap().finish(&Ok(()));
+
+        // Written by the background writer, in the order they happened
+        audit.flush();
+        let entries = audit.query(&Default::default()).unwrap();
+        let seen: Vec<(AuditAction, &str, &str)> = entries
+            .iter()
+            .map(|entry| (entry.action, entry.outcome.status(), entry.parameters["build_id"].as_str().unwrap_or("")))
+            .collect();
+        assert_eq!(seen, [
+            (AuditAction::PolicyChange, "succeeded", ""),
+            (AuditAction::Build, "accepted", running.as_str()),
+            (AuditAction::Build, "accepted", stale.as_str()),
+            (AuditAction::Build, "cancelled", stale.as_str()),
+            (AuditAction::Build, "accepted", latest.as_str()),
+            (AuditAction::Build, "succeeded", running.as_str()),
+        ]);
+        assert_eq!(entries[1].actor, "ana");
+
+        // The same policy isn't recorded again by the next scheduler
+        BuildScheduler::new(config(4, SupersedePolicy::CancelSuperseded)).with_audit(audit.clone());
+        assert_eq!(audit.query(&Default::default()).unwrap().len(), entries.len());
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}.head", path.display()));
+    }
+
+    #[test]
+    fn memory_admission_holds_back_builds_that_do_not_fit() {
+        let scheduler = scheduler(4, SupersedePolicy::Queue);
+        let big = Some(Resources::new(1000, 6000));
+        scheduler.submit(BuildRequest { resources: big, ..BuildRequest::new("a") });
+        let waiting = scheduler.submit(BuildRequest { resources: big, ..BuildRequest::new("b") });
+        let status = scheduler.status(&waiting).unwrap();
+        assert_eq!(status.state, BuildState::Queued { position: 1 });
+        assert!(status.wait_reason.unwrap().starts_with("waiting for resources"));
+    }
+
+    #[tokio::test]
+    async fn hotfix_jumps_queue_and_tenants_share_fairly() {
+        let scheduler = scheduler(1, SupersedePolicy::Queue);
+        let blocker = scheduler.submit(BuildRequest::new("blocker"));
+        let noisy: Vec<String> = (0..3)
+            .map(|i| scheduler.submit(BuildRequest {
+                tenant: Some("noisy".to_string()),
+                ..BuildRequest::new(&format!("noisy-{}", i))
+            }))
+            .collect();
+        let quiet = scheduler.submit(BuildRequest { tenant: Some("quiet".to_string()), ..BuildRequest::new("quiet") });
+        let hotfix = scheduler.submit(BuildRequest {
+            priority: Priority::Hotfix,
+            tenant: Some("noisy".to_string()),
+            ..BuildRequest::new("fix")
+        });
+
+        assert_eq!(scheduler.status(&hotfix).unwrap().state, BuildState::Queued { position: 1 });
+
+        let mut order = Vec::new();
+        let mut current = blocker;
+        for _ in 0..5 {
+            scheduler.acquire(&current).await.unwrap().finish(&Ok(()));
+            current = scheduler.decisions(&DecisionQuery::default())
+                .into_iter()
+                .rev()
+                .find(|d| d.kind == DecisionKind::Started)
+                .unwrap()
+                .build_id;
+            order.push(current.clone());
+        }
+        assert_eq!(order[0], hotfix);
+        // noisy just got a slot for its hotfix, so quiet goes before the rest of noisy's backlog
+        assert_eq!(order[1], quiet);
+        assert_eq!(&order[2..], &noisy[..]);
+
+        let waits = scheduler.decisions(&DecisionQuery { build_id: Some(quiet), ..Default::default() });
+        assert!(waits.iter().any(|d| d.kind == DecisionKind::Waiting));
+    }
+
+    #[tokio::test]
+    async fn resubmitting_tenant_keeps_its_place() {
+        let scheduler = scheduler(1, SupersedePolicy::Queue);
+        let submit = |tenant: &str, app: &str| scheduler.submit(BuildRequest {
+            tenant: Some(tenant.to_string()),
+            ..BuildRequest::new(app)
+        });
+        let blocker = submit("c", "blocker");
+        let a1 = submit("a", "a1");
+        submit("a", "a2");
+        let b1 = submit("b", "b1");
+
+        scheduler.acquire(&blocker).await.unwrap().finish(&Ok(()));
+        let permit = scheduler.acquire(&a1).await.unwrap();
+        // b is still owed a start, queuing more must not raise it to a's share
+        submit("b", "b2");
+        permit.finish(&Ok(()));
+        assert_eq!(scheduler.status(&b1).unwrap().state, BuildState::Running);
+    }
+
+    #[test]
+    fn waiting_builds_age_upward() {
+        assert_eq!(Priority::Background.aged(Duration::from_secs(599), Duration::from_secs(600)), Priority::Background);
+        assert_eq!(Priority::Background.aged(Duration::from_secs(600), Duration::from_secs(600)), Priority::Normal);
+        assert_eq!(Priority::Normal.aged(Duration::from_secs(6000), Duration::from_secs(600)), Priority::Hotfix);
+    }
+}
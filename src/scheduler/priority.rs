@@ -0,0 +1,70 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{ Deserialize, Serialize };
+
+/// How urgent a build is
+///
+/// # Variants
+/// Background - Housekeeping rebuilds, runs when nothing else wants the worker
+/// Normal - Regular pushes
+/// Hotfix - Production fixes, jump ahead of everything else
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Background,
+    #[default]
+    Normal,
+    Hotfix,
+}
+
+impl Priority {
+    fn rank(&self) -> u8 {
+        match self {
+            Priority::Background => 0,
+            Priority::Normal => 1,
+            Priority::Hotfix => 2,
+        }
+    }
+
+    fn from_rank(rank: u8) -> Self {
+        match rank {
+            0 => Priority::Background,
+            1 => Priority::Normal,
+            _ => Priority::Hotfix,
+        }
+    }
+
+    /// Priority after waiting: one class up for every full `aging_interval`, capped at hotfix
+    pub fn aged(&self, waited: Duration, aging_interval: Duration) -> Priority {
+        if aging_interval.is_zero() {
+            return *self;
+        }
+        let steps = (waited.as_secs() / aging_interval.as_secs().max(1)).min(u8::MAX as u64) as u8;
+        Priority::from_rank(self.rank().saturating_add(steps))
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Priority::Background => "background",
+            Priority::Normal => "normal",
+            Priority::Hotfix => "hotfix",
+        })
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "background" => Ok(Priority::Background),
+            "normal" => Ok(Priority::Normal),
+            "hotfix" => Ok(Priority::Hotfix),
+            other => Err(format!("unknown priority '{}', expected hotfix, normal or background", other)),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::fs;
+
+use serde::{ Deserialize, Serialize };
+
+/// CPU and memory a build reserves while it runs
+///
+/// # Fields
+/// cpu_millis - CPU in thousandths of a core
+/// memory_mb - Memory in MB
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resources {
+    pub cpu_millis: u64,
+    pub memory_mb: u64,
+}
+
+impl Resources {
+    pub fn new(cpu_millis: u64, memory_mb: u64) -> Self {
+        Resources { cpu_millis, memory_mb }
+    }
+
+    pub fn add(&self, other: &Resources) -> Resources {
+        Resources {
+            cpu_millis: self.cpu_millis + other.cpu_millis,
+            memory_mb: self.memory_mb + other.memory_mb,
+        }
+    }
+
+    pub fn fits_within(&self, limit: &Resources) -> bool {
+        self.cpu_millis <= limit.cpu_millis && self.memory_mb <= limit.memory_mb
+    }
+
+    /// What this host can hand out to builds: all cores and total memory minus a reserve for the OS
+    pub fn host(memory_reserve_mb: u64) -> Resources {
+        let cores = std::thread::available_parallelism().map(|n| n.get() as u64).unwrap_or(1);
+        let memory_mb = read_mem_total_mb().unwrap_or(4096);
+        Resources {
+            cpu_millis: cores * 1000,
+            memory_mb: memory_mb.saturating_sub(memory_reserve_mb),
+        }
+    }
+}
+
+/// Reads `MemTotal` from /proc/meminfo, `None` on hosts without procfs
+fn read_mem_total_mb() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo
+        .lines()
+        .find(|line| line.starts_with("MemTotal:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb / 1024)
+}
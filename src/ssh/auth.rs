@@ -0,0 +1,154 @@
+//-------------------------------------------------------------------------
+// Client authentication. Methods are tried in order until the server
+// accepts one: ssh-agent identities, the configured private key (with a
+// passphrase from a secret source if it is encrypted), an OpenSSH user
+// certificate for that key, then the password. Hosts with `use_key: false`
+// only use the password.
+//-------------------------------------------------------------------------
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use russh::client;
+use russh_keys::agent::client::AgentClient;
+use russh_keys::key::KeyPair;
+use russh_keys::{load_openssh_certificate, load_secret_key};
+use tracing::{debug, warn};
+
+use super::session::ClientHandler;
+use super::{Host, SshError};
+
+/// Env var holding the passphrase for encrypted keys, hosts can name their own with `passphrase_env`
+pub const PASSPHRASE_VAR: &str = "OMNIFORGE_SSH_KEY_PASSPHRASE";
+
+/// Where passphrases for encrypted private keys come from
+pub trait SecretSource: fmt::Debug + Send + Sync {
+    fn passphrase(&self, host: &Host, key_path: &Path) -> Option<String>;
+}
+
+/// Reads passphrases from the environment
+#[derive(Debug, Default)]
+pub struct EnvSecrets;
+
+impl SecretSource for EnvSecrets {
+    fn passphrase(&self, host: &Host, _key_path: &Path) -> Option<String> {
+        std::env::var(host.passphrase_env.as_deref().unwrap_or(PASSPHRASE_VAR)).ok()
+    }
+}
+
+/// Runs the method chain on a freshly connected handle
+pub(crate) async fn authenticate(
+    handle: &mut client::Handle<ClientHandler>,
+    host: &Host,
+    secrets: &dyn SecretSource,
+) -> Result<(), SshError> {
+    let user = host.username.clone();
+    let mut tried = Vec::new();
+
+    if host.use_key {
+        if try_agent(handle, &user, &mut tried).await? {
+            return Ok(());
+        }
+
+        if let Some(key_path) = &host.key_path {
+            match load_key(host, key_path, secrets) {
+                Ok(key_pair) => {
+                    let key_pair = Arc::new(key_pair);
+                    tried.push(format!("publickey {}", key_path.display()));
+                    if handle.authenticate_publickey(user.clone(), key_pair.clone()).await? {
+                        return Ok(());
+                    }
+
+                    if let Some(cert_path) = certificate_path(host, key_path) {
+                        tried.push(format!("certificate {}", cert_path.display()));
+                        match load_openssh_certificate(&cert_path) {
+                            Ok(cert) => {
+                                if handle.authenticate_openssh_cert(user.clone(), key_pair, cert).await? {
+                                    return Ok(());
+                                }
+                            }
+                            Err(e) => warn!("Skipping certificate {}: {}", cert_path.display(), e),
+                        }
+                    }
+                }
+                Err(reason) => tried.push(format!("publickey {} ({})", key_path.display(), reason)),
+            }
+        }
+    }
+
+    if let Some(password) = &host.password {
+        tried.push("password".to_string());
+        if handle.authenticate_password(user.clone(), password.clone()).await? {
+            return Ok(());
+        }
+    }
+
+    if tried.is_empty() {
+        return Err(SshError::NoAuthMethod(host.name.clone()));
+    }
+    Err(SshError::AuthFailed {
+        user,
+        host: host.name.clone(),
+        tried,
+    })
+}
+
+/// Offers every identity the agent holds, skipped quietly when no agent is running
+async fn try_agent(
+    handle: &mut client::Handle<ClientHandler>,
+    user: &str,
+    tried: &mut Vec<String>,
+) -> Result<bool, SshError> {
+    let Ok(mut agent) = AgentClient::connect_env().await else {
+        return Ok(false);
+    };
+    let identities = match agent.request_identities().await {
+        Ok(identities) => identities,
+        Err(e) => {
+            debug!("Could not list ssh-agent identities: {}", e);
+            return Ok(false);
+        }
+    };
+    if identities.is_empty() {
+        return Ok(false);
+    }
+
+    tried.push(format!("agent ({} identities)", identities.len()));
+    for key in identities {
+        let (returned, result) = handle.authenticate_future(user, key, agent).await;
+        agent = returned;
+        match result {
+            Ok(true) => return Ok(true),
+            Ok(false) => {}
+            // The agent refusing to sign one key shouldn't stop us from offering the next
+            Err(e) => warn!("ssh-agent could not sign: {}", e),
+        }
+    }
+    Ok(false)
+}
+
+/// Loads a private key, asking the secret source only when the key turns out to be encrypted
+fn load_key(host: &Host, key_path: &Path, secrets: &dyn SecretSource) -> Result<KeyPair, String> {
+    match load_secret_key(key_path, None) {
+        Ok(key_pair) => Ok(key_pair),
+        Err(russh_keys::Error::KeyIsEncrypted) => {
+            let passphrase = secrets
+                .passphrase(host, key_path)
+                .ok_or_else(|| "encrypted, no passphrase available".to_string())?;
+            load_secret_key(key_path, Some(&passphrase)).map_err(|e| e.to_string())
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// The configured certificate, or `<key>-cert.pub` next to the key like OpenSSH looks for
+fn certificate_path(host: &Host, key_path: &Path) -> Option<PathBuf> {
+    if let Some(path) = &host.certificate_path {
+        return Some(path.clone());
+    }
+    let mut default = key_path.as_os_str().to_owned();
+    default.push("-cert.pub");
+    let default = PathBuf::from(default);
+    default.exists().then_some(default)
+}
@@ -0,0 +1,325 @@
+//-------------------------------------------------------------------------
+// Runs one command across many hosts concurrently. Parallelism is bounded,
+// every host gets its own timeout, a hung host only holds up its own slot,
+// and the results come back as a table in inventory order. In fail-fast
+// mode hosts that have not started yet are skipped after the first
+// failure; hosts already running finish.
+//-------------------------------------------------------------------------
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+
+use super::pool::SessionPool;
+use super::session::CommandOutput;
+use super::Host;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureMode {
+    /// Stop starting new hosts once one has failed
+    FailFast,
+    /// Run on every host and report all failures at the end
+    #[default]
+    Continue,
+}
+
+/// # Fields
+/// parallelism - Hosts the command runs on at the same time
+/// host_timeout - Limit for connecting and running the command on one host
+/// failure_mode - What to do with the remaining hosts after a failure
+#[derive(Debug, Clone)]
+pub struct FleetOptions {
+    pub parallelism: usize,
+    pub host_timeout: Duration,
+    pub failure_mode: FailureMode,
+}
+
+impl Default for FleetOptions {
+    fn default() -> Self {
+        FleetOptions {
+            parallelism: 8,
+            host_timeout: Duration::from_secs(60),
+            failure_mode: FailureMode::Continue,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum HostOutcome {
+    /// The command ran, it may still have exited non-zero
+    Completed(CommandOutput),
+    /// Connecting or running the command failed
+    Error(String),
+    TimedOut,
+    /// Not attempted because an earlier host failed in fail-fast mode
+    Skipped,
+}
+
+#[derive(Debug, Clone)]
+pub struct HostResult {
+    pub host: String,
+    pub outcome: HostOutcome,
+    pub duration: Duration,
+}
+
+impl HostResult {
+    pub fn success(&self) -> bool {
+        matches!(&self.outcome, HostOutcome::Completed(output) if output.success())
+    }
+
+    fn status(&self) -> &'static str {
+        match &self.outcome {
+            HostOutcome::Completed(output) if output.success() => "ok",
+            HostOutcome::Completed(_) => "failed",
+            HostOutcome::Error(_) => "error",
+            HostOutcome::TimedOut => "timeout",
+            HostOutcome::Skipped => "skipped",
+        }
+    }
+
+    fn exit_code(&self) -> String {
+        match &self.outcome {
+            HostOutcome::Completed(output) => match (output.exit_status, &output.exit_signal) {
+                (Some(code), _) => code.to_string(),
+                (None, Some(signal)) => signal.clone(),
+                (None, None) => "?".to_string(),
+            },
+            _ => "-".to_string(),
+        }
+    }
+
+    /// First line of whatever best explains the result
+    fn summary(&self) -> String {
+        let text = match &self.outcome {
+            HostOutcome::Completed(output) if output.success() => output.stdout_str(),
+            HostOutcome::Completed(output) if !output.stderr.is_empty() => output.stderr_str(),
+            HostOutcome::Completed(output) => output.stdout_str(),
+            HostOutcome::Error(e) => e.clone(),
+            HostOutcome::TimedOut | HostOutcome::Skipped => String::new(),
+        };
+        let line = text.lines().next().unwrap_or("").trim();
+        if line.chars().count() > 60 {
+            format!("{}...", line.chars().take(57).collect::<String>())
+        } else {
+            line.to_string()
+        }
+    }
+}
+
+/// Per-host results in the order the hosts were given
+#[derive(Debug, Clone)]
+pub struct FleetReport {
+    pub command: String,
+    pub results: Vec<HostResult>,
+}
+
+impl FleetReport {
+    pub fn all_succeeded(&self) -> bool {
+        self.results.iter().all(HostResult::success)
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &HostResult> {
+        self.results.iter().filter(|result| !result.success())
+    }
+}
+
+impl fmt::Display for FleetReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self
+            .results
+            .iter()
+            .map(|result| result.host.len())
+            .chain(std::iter::once(4))
+            .max()
+            .unwrap_or(4);
+        writeln!(f, "{:<width$}  {:<8}  {:>6}  {:>9}  OUTPUT", "HOST", "STATUS", "EXIT", "DURATION")?;
+        for result in &self.results {
+            writeln!(
+                f,
+                "{:<width$}  {:<8}  {:>6}  {:>8.1}s  {}",
+                result.host,
+                result.status(),
+                result.exit_code(),
+                result.duration.as_secs_f64(),
+                result.summary()
+            )?;
+        }
+        let ok = self.results.iter().filter(|result| result.success()).count();
+        write!(f, "{}/{} hosts succeeded", ok, self.results.len())
+    }
+}
+
+pub struct FleetExecutor {
+    pool: Arc<SessionPool>,
+    options: FleetOptions,
+}
+
+impl FleetExecutor {
+    pub fn new(pool: Arc<SessionPool>, options: FleetOptions) -> Self {
+        FleetExecutor { pool, options }
+    }
+
+    pub async fn run(&self, hosts: &[Host], command: &str) -> FleetReport {
+        let aborted = AtomicBool::new(false);
+        let aborted = &aborted;
+
+        // `buffer_unordered` keeps at most `parallelism` hosts in flight and starts the next host as
+        // soon as any of them finishes, so a hung host only holds up its own slot. Results come
+        // back in completion order and are put back in host order after.
+        let mut results: Vec<(usize, HostResult)> = stream::iter(hosts.iter().enumerate())
+            .map(|(index, host)| async move {
+                if aborted.load(Ordering::SeqCst) {
+                    let skipped = HostResult {
+                        host: host.name.clone(),
+                        outcome: HostOutcome::Skipped,
+                        duration: Duration::ZERO,
+                    };
+                    return (index, skipped);
+                }
+                let result = self.run_on(host, command).await;
+                if !result.success() && self.options.failure_mode == FailureMode::FailFast {
+                    aborted.store(true, Ordering::SeqCst);
+                }
+                (index, result)
+            })
+            .buffer_unordered(self.options.parallelism.max(1))
+            .collect()
+            .await;
+        results.sort_by_key(|(index, _)| *index);
+        let results = results.into_iter().map(|(_, result)| result).collect();
+
+        FleetReport {
+            command: command.to_string(),
+            results,
+        }
+    }
+
+    async fn run_on(&self, host: &Host, command: &str) -> HostResult {
+        let started = Instant::now();
+        let outcome = match tokio::time::timeout(self.options.host_timeout, self.pool.exec(host, command)).await {
+            Ok(Ok(output)) => HostOutcome::Completed(output),
+            Ok(Err(e)) => HostOutcome::Error(e.to_string()),
+            Err(_) => {
+                // The session may be stuck mid-command, don't hand it to the next caller
+                self.pool.evict(host).await;
+                HostOutcome::TimedOut
+            }
+        };
+        HostResult {
+            host: host.name.clone(),
+            outcome,
+            duration: started.elapsed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::test_server::TestServer;
+    use crate::ssh::SessionOptions;
+    use tokio::net::TcpListener;
+
+    fn named(mut host: Host, name: &str) -> Host {
+        host.name = name.to_string();
+        host
+    }
+
+    #[tokio::test]
+    async fn continue_mode_reports_every_host() {
+        let good = TestServer::start(|_| (b"done\n".to_vec(), Vec::new(), 0)).await;
+        let bad = TestServer::start(|_| (Vec::new(), b"disk full\n".to_vec(), 3)).await;
+        // Accepts TCP but never speaks SSH, so the host timeout has to kick in
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut hung = good.host();
+        hung.port = silent.local_addr().unwrap().port();
+
+        let pool = Arc::new(SessionPool::new(good.verifier(), SessionOptions::default()));
+        let executor = FleetExecutor::new(
+            pool,
+            FleetOptions {
+                parallelism: 2,
+                host_timeout: Duration::from_millis(500),
+                failure_mode: FailureMode::Continue,
+            },
+        );
+        let hosts = vec![named(good.host(), "web-1"), named(bad.host(), "web-2"), named(hung, "web-3")];
+        let report = executor.run(&hosts, "deploy").await;
+
+        let names: Vec<&str> = report.results.iter().map(|r| r.host.as_str()).collect();
+        assert_eq!(names, ["web-1", "web-2", "web-3"]);
+        assert!(report.results[0].success());
+        assert!(matches!(&report.results[1].outcome, HostOutcome::Completed(o) if o.exit_status == Some(3)));
+        assert!(matches!(report.results[2].outcome, HostOutcome::TimedOut));
+        assert!(!report.all_succeeded());
+        assert_eq!(report.failures().count(), 2);
+
+        let table = report.to_string();
+        assert!(table.contains("disk full"));
+        assert!(table.ends_with("1/3 hosts succeeded"));
+    }
+
+    #[tokio::test]
+    async fn a_hung_host_does_not_hold_up_the_others() {
+        let started = Instant::now();
+        let ran = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = ran.clone();
+        let good = TestServer::start(move |_| {
+            log.lock().unwrap().push(started.elapsed());
+            (Vec::new(), Vec::new(), 0)
+        }).await;
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut hung = good.host();
+        hung.port = silent.local_addr().unwrap().port();
+
+        let pool = Arc::new(SessionPool::new(good.verifier(), SessionOptions::default()));
+        let timeout = Duration::from_secs(2);
+        let executor = FleetExecutor::new(
+            pool,
+            FleetOptions { parallelism: 2, host_timeout: timeout, failure_mode: FailureMode::Continue },
+        );
+        let hosts = vec![
+            named(hung, "app-1"),
+            named(good.host(), "app-2"),
+            named(good.host(), "app-3"),
+            named(good.host(), "app-4"),
+        ];
+        let report = executor.run(&hosts, "restart").await;
+
+        // The other slot worked through every other host while the first one hung
+        let ran = ran.lock().unwrap();
+        assert_eq!(ran.len(), 3);
+        assert!(ran.iter().all(|at| *at < timeout), "{:?}", ran);
+        let names: Vec<&str> = report.results.iter().map(|r| r.host.as_str()).collect();
+        assert_eq!(names, ["app-1", "app-2", "app-3", "app-4"]);
+        assert!(matches!(report.results[0].outcome, HostOutcome::TimedOut));
+    }
+
+    #[tokio::test]
+    async fn fail_fast_skips_hosts_not_yet_started() {
+        let good = TestServer::start(|_| (Vec::new(), Vec::new(), 0)).await;
+        let bad = TestServer::start(|_| (Vec::new(), Vec::new(), 1)).await;
+
+        let pool = Arc::new(SessionPool::new(good.verifier(), SessionOptions::default()));
+        let executor = FleetExecutor::new(
+            pool,
+            FleetOptions {
+                parallelism: 1,
+                host_timeout: Duration::from_secs(5),
+                failure_mode: FailureMode::FailFast,
+            },
+        );
+        let hosts = vec![named(bad.host(), "db-1"), named(good.host(), "db-2"), named(good.host(), "db-3")];
+        let report = executor.run(&hosts, "migrate").await;
+
+        assert!(matches!(report.results[0].outcome, HostOutcome::Completed(_)));
+        assert!(matches!(report.results[1].outcome, HostOutcome::Skipped));
+        assert!(matches!(report.results[2].outcome, HostOutcome::Skipped));
+        assert_eq!(good.connections(), 0);
+    }
+}
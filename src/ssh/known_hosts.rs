@@ -0,0 +1,421 @@
+//-------------------------------------------------------------------------
+// OpenSSH known_hosts parsing and host key verification. Supports plain
+// and hashed (`|1|salt|hash`) host entries, wildcard and negated patterns,
+// non-default ports (`[host]:port`) and `@revoked`. `@cert-authority`
+// lines are read so a CA key is never taken for a host key, but host
+// certificates are not accepted: russh only negotiates plain host key
+// algorithms, so servers always present their plain key.
+//-------------------------------------------------------------------------
+
+use std::fs::{ self, OpenOptions };
+use std::io::{ self, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Mutex;
+
+use base64::engine::general_purpose::{ STANDARD, STANDARD_NO_PAD };
+use base64::Engine;
+use hmac::{ Hmac, Mac };
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::{ Digest, Sha256 };
+use thiserror::Error;
+use tracing::warn;
+
+#[derive(Debug, Error)]
+pub enum HostKeyError {
+    #[error(
+        "host key for {host} does not match known_hosts!\n  expected: {}\n  received: {received}\nSomeone may be intercepting the connection, or the host key was changed",
+        expected.join(", ")
+    )]
+    Mismatch {
+        host: String,
+        expected: Vec<String>,
+        received: String,
+    },
+    #[error("host {host} is not in known_hosts (key fingerprint {fingerprint}) and strict checking is enabled")]
+    Unknown {
+        host: String,
+        fingerprint: String,
+    },
+    #[error("host key {fingerprint} for {host} has been revoked")]
+    Revoked {
+        host: String,
+        fingerprint: String,
+    },
+    #[error("could not update known_hosts: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// How unknown hosts are treated
+///
+/// # Variants
+/// Strict - Only hosts already in known_hosts are accepted
+/// AcceptNew - Trust on first use, unknown hosts are accepted and recorded. Changed keys are still rejected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyPolicy {
+    #[default]
+    Strict,
+    AcceptNew,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Marker {
+    None,
+    CertAuthority,
+    Revoked,
+}
+
+#[derive(Debug, Clone)]
+enum HostPattern {
+    /// Comma separated patterns, `*`/`?` wildcards and `!` negation
+    Plain(Vec<String>),
+    Hashed { salt: Vec<u8>, hash: Vec<u8> },
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    marker: Marker,
+    hosts: HostPattern,
+    key_type: String,
+    key: Vec<u8>,
+}
+
+/// Result of looking a host key up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostKeyStatus {
+    Trusted,
+    /// No entry for this host and key type
+    Unknown,
+    /// The host has entries of the same key type, none of them match. Holds their fingerprints.
+    Mismatch(Vec<String>),
+    Revoked,
+}
+
+/// SHA256 fingerprint in the format `ssh-keygen -l` prints
+pub fn fingerprint(key: &[u8]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(key)))
+}
+
+/// The name known_hosts uses for a host, `[host]:port` for anything but port 22. Lowercase like
+/// OpenSSH, hashed entries are hashes of the lowercase name.
+pub fn host_name(host: &str, port: u16) -> String {
+    let host = host.to_lowercase();
+    if port == 22 {
+        host
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+/// ~/.ssh/known_hosts of the current user
+pub fn default_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".ssh").join("known_hosts"))
+}
+
+#[derive(Debug, Default)]
+pub struct KnownHosts {
+    path: Option<PathBuf>,
+    entries: Vec<Entry>,
+}
+
+impl KnownHosts {
+    /// Loads a known_hosts file, a missing file is an empty list
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut known_hosts = Self::parse(&content);
+        known_hosts.path = Some(path.to_path_buf());
+        Ok(known_hosts)
+    }
+
+    /// Parses known_hosts content, lines that cannot be parsed are skipped like OpenSSH does
+    pub fn parse(content: &str) -> Self {
+        let entries = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(parse_line)
+            .collect();
+        KnownHosts { path: None, entries }
+    }
+
+    /// Checks a plain host key
+    pub fn check(&self, host: &str, port: u16, key_type: &str, key: &[u8]) -> HostKeyStatus {
+        let name = host_name(host, port);
+        let matching: Vec<&Entry> = self.entries
+            .iter()
+            .filter(|entry| entry.matches_host(&name))
+            .collect();
+
+        if matching.iter().any(|entry| entry.marker == Marker::Revoked && entry.key == key) {
+            return HostKeyStatus::Revoked;
+        }
+        let plain = matching.iter().filter(|entry| entry.marker == Marker::None);
+        if plain.clone().any(|entry| entry.key_type == key_type && entry.key == key) {
+            return HostKeyStatus::Trusted;
+        }
+        let expected: Vec<String> = plain
+            .filter(|entry| entry.key_type == key_type)
+            .map(|entry| fingerprint(&entry.key))
+            .collect();
+        if expected.is_empty() {
+            HostKeyStatus::Unknown
+        } else {
+            HostKeyStatus::Mismatch(expected)
+        }
+    }
+
+    /// Adds a host key and appends it to the file this list was loaded from
+    pub fn learn(&mut self, host: &str, port: u16, key_type: &str, key: &[u8], hash: bool) -> io::Result<()> {
+        let name = host_name(host, port);
+        let hosts = if hash {
+            let salt = new_salt();
+            let hash = hash_host(&salt, &name);
+            HostPattern::Hashed { salt, hash }
+        } else {
+            HostPattern::Plain(vec![name])
+        };
+        let entry = Entry {
+            marker: Marker::None,
+            hosts,
+            key_type: key_type.to_string(),
+            key: key.to_vec(),
+        };
+
+        if let Some(path) = &self.path {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", entry.to_line())?;
+        }
+        self.entries.push(entry);
+        Ok(())
+    }
+}
+
+impl Entry {
+    fn matches_host(&self, name: &str) -> bool {
+        match &self.hosts {
+            HostPattern::Hashed { salt, hash } => &hash_host(salt, name) == hash,
+            HostPattern::Plain(patterns) => {
+                let mut matched = false;
+                for pattern in patterns {
+                    if let Some(negated) = pattern.strip_prefix('!') {
+                        if wildcard_match(negated, name) {
+                            return false;
+                        }
+                    } else if wildcard_match(pattern, name) {
+                        matched = true;
+                    }
+                }
+                matched
+            }
+        }
+    }
+
+    fn to_line(&self) -> String {
+        let hosts = match &self.hosts {
+            HostPattern::Plain(patterns) => patterns.join(","),
+            HostPattern::Hashed { salt, hash } => format!("|1|{}|{}", STANDARD.encode(salt), STANDARD.encode(hash)),
+        };
+        let marker = match self.marker {
+            Marker::None => "",
+            Marker::CertAuthority => "@cert-authority ",
+            Marker::Revoked => "@revoked ",
+        };
+        format!("{}{} {} {}", marker, hosts, self.key_type, STANDARD.encode(&self.key))
+    }
+}
+
+fn parse_line(line: &str) -> Option<Entry> {
+    let mut fields = line.split_whitespace();
+    let mut first = fields.next()?;
+    let marker = match first {
+        "@cert-authority" => Marker::CertAuthority,
+        "@revoked" => Marker::Revoked,
+        _ => Marker::None,
+    };
+    if marker != Marker::None {
+        first = fields.next()?;
+    }
+    let key_type = fields.next()?.to_string();
+    let key = STANDARD.decode(fields.next()?).ok()?;
+
+    let hosts = match first.strip_prefix("|1|") {
+        Some(hashed) => {
+            let (salt, hash) = hashed.split_once('|')?;
+            HostPattern::Hashed {
+                salt: STANDARD.decode(salt).ok()?,
+                hash: STANDARD.decode(hash).ok()?,
+            }
+        }
+        None => HostPattern::Plain(first.split(',').map(|p| p.to_lowercase()).collect()),
+    };
+    Some(Entry { marker, hosts, key_type, key })
+}
+
+fn hash_host(salt: &[u8], name: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(salt).expect("HMAC accepts keys of any length");
+    mac.update(name.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Salts only need to be unique, not secret
+fn new_salt() -> Vec<u8> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = Sha1::new();
+    hasher.update(chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default().to_le_bytes());
+    hasher.update(std::process::id().to_le_bytes());
+    hasher.update(COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    hasher.finalize().to_vec()
+}
+
+/// OpenSSH style glob: `*` matches any run of characters, `?` exactly one
+pub(crate) fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.to_lowercase().chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let (mut star, mut star_n) = (None, 0);
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some(p);
+            star_n = n;
+            p += 1;
+        } else if let Some(star_p) = star {
+            p = star_p + 1;
+            star_n += 1;
+            n = star_n;
+        } else {
+            return false;
+        }
+    }
+    while p < pattern.len() && pattern[p] == '*' {
+        p += 1;
+    }
+    p == pattern.len()
+}
+
+/// Verifies server keys against a known_hosts file according to a policy. Shared by every connection.
+pub struct HostKeyVerifier {
+    known_hosts: Mutex<KnownHosts>,
+    policy: HostKeyPolicy,
+    hash_new_entries: bool,
+}
+
+impl HostKeyVerifier {
+    pub fn new(known_hosts: KnownHosts, policy: HostKeyPolicy) -> Self {
+        HostKeyVerifier {
+            known_hosts: Mutex::new(known_hosts),
+            policy,
+            hash_new_entries: true,
+        }
+    }
+
+    /// Loads the given file, or ~/.ssh/known_hosts when none is given
+    pub fn from_file(path: Option<&Path>, policy: HostKeyPolicy) -> io::Result<Self> {
+        let known_hosts = match path.map(Path::to_path_buf).or_else(default_path) {
+            Some(path) => KnownHosts::load(path)?,
+            None => KnownHosts::default(),
+        };
+        Ok(Self::new(known_hosts, policy))
+    }
+
+    pub fn verify(&self, host: &str, port: u16, key_type: &str, key: &[u8]) -> Result<(), HostKeyError> {
+        let mut known_hosts = self.known_hosts.lock().unwrap();
+        let name = host_name(host, port);
+        match known_hosts.check(host, port, key_type, key) {
+            HostKeyStatus::Trusted => Ok(()),
+            HostKeyStatus::Mismatch(expected) => Err(HostKeyError::Mismatch {
+                host: name,
+                expected,
+                received: fingerprint(key),
+            }),
+            HostKeyStatus::Revoked => Err(HostKeyError::Revoked { host: name, fingerprint: fingerprint(key) }),
+            HostKeyStatus::Unknown => match self.policy {
+                HostKeyPolicy::Strict => Err(HostKeyError::Unknown { host: name, fingerprint: fingerprint(key) }),
+                HostKeyPolicy::AcceptNew => {
+                    warn!("Permanently adding {} ({} {}) to known_hosts", name, key_type, fingerprint(key));
+                    known_hosts.learn(host, port, key_type, key, self.hash_new_entries)?;
+                    Ok(())
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: &[u8] = b"\x00\x00\x00\x0bssh-ed25519\x00\x00\x00\x20aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    const KEY_B: &[u8] = b"\x00\x00\x00\x0bssh-ed25519\x00\x00\x00\x20bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+
+    fn line(hosts: &str, key: &[u8]) -> String {
+        format!("{} ssh-ed25519 {}", hosts, STANDARD.encode(key))
+    }
+
+    #[test]
+    fn matches_plain_hashed_and_wildcard_entries() {
+        let salt = b"0123456789abcdef0123";
+        let hashed = format!("|1|{}|{}", STANDARD.encode(salt), STANDARD.encode(hash_host(salt, "[db.internal]:2222")));
+        let content = [
+            line("web1.example.com,10.0.0.5", KEY_A),
+            line(&hashed, KEY_A),
+            line("*.build.example.com,!evil.build.example.com", KEY_B),
+        ].join("\n");
+        let known_hosts = KnownHosts::parse(&content);
+
+        assert_eq!(known_hosts.check("10.0.0.5", 22, "ssh-ed25519", KEY_A), HostKeyStatus::Trusted);
+        assert_eq!(known_hosts.check("db.internal", 2222, "ssh-ed25519", KEY_A), HostKeyStatus::Trusted);
+        assert_eq!(known_hosts.check("db.internal", 22, "ssh-ed25519", KEY_A), HostKeyStatus::Unknown);
+        // Host names are case-insensitive, hashed ones too
+        assert_eq!(known_hosts.check("DB.Internal", 2222, "ssh-ed25519", KEY_A), HostKeyStatus::Trusted);
+        assert_eq!(known_hosts.check("Web1.Example.com", 22, "ssh-ed25519", KEY_A), HostKeyStatus::Trusted);
+        assert_eq!(known_hosts.check("w3.build.example.com", 22, "ssh-ed25519", KEY_B), HostKeyStatus::Trusted);
+        assert_eq!(known_hosts.check("evil.build.example.com", 22, "ssh-ed25519", KEY_B), HostKeyStatus::Unknown);
+        assert_eq!(
+            known_hosts.check("web1.example.com", 22, "ssh-ed25519", KEY_B),
+            HostKeyStatus::Mismatch(vec![fingerprint(KEY_A)])
+        );
+    }
+
+    #[test]
+    fn cert_authority_and_revoked_markers() {
+        let content = [
+            format!("@cert-authority {}", line("*.example.com", KEY_A)),
+            format!("@revoked {}", line("*", KEY_B)),
+        ].join("\n");
+        let known_hosts = KnownHosts::parse(&content);
+
+        // A CA key is not trusted as a plain host key
+        assert_eq!(known_hosts.check("web1.example.com", 22, "ssh-ed25519", KEY_A), HostKeyStatus::Unknown);
+        assert_eq!(known_hosts.check("anything", 22, "ssh-ed25519", KEY_B), HostKeyStatus::Revoked);
+    }
+
+    #[test]
+    fn accept_new_records_first_key_and_rejects_changes() {
+        let path = std::env::temp_dir().join(format!("omniforge-known-hosts-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let verifier = HostKeyVerifier::from_file(Some(&path), HostKeyPolicy::AcceptNew).unwrap();
+        verifier.verify("10.1.1.1", 22, "ssh-ed25519", KEY_A).unwrap();
+        let error = verifier.verify("10.1.1.1", 22, "ssh-ed25519", KEY_B).unwrap_err().to_string();
+        assert!(error.contains(&fingerprint(KEY_A)) && error.contains(&fingerprint(KEY_B)));
+
+        let strict = HostKeyVerifier::from_file(Some(&path), HostKeyPolicy::Strict).unwrap();
+        strict.verify("10.1.1.1", 22, "ssh-ed25519", KEY_A).unwrap();
+        assert!(matches!(strict.verify("10.1.1.2", 22, "ssh-ed25519", KEY_A), Err(HostKeyError::Unknown { .. })));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
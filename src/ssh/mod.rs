@@ -0,0 +1,122 @@
+//-------------------------------------------------------------------------
+// SSH client library used by deployment and fleet commands. One place for
+// host connection settings, host key verification, agent/key/certificate/
+// password authentication, sessions with keepalives, a pool that reuses
+// one connection per host, jump host tunnelling, a fleet executor that
+// runs commands on many hosts at once, and SFTP transfers.
+//-------------------------------------------------------------------------
+
+pub mod auth;
+pub mod fleet;
+pub mod known_hosts;
+pub mod pool;
+pub mod session;
+#[cfg(test)]
+pub(crate) mod test_server;
+pub mod transfer;
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::telemetry::redact::MASK;
+use known_hosts::HostKeyError;
+pub use fleet::{FailureMode, FleetExecutor, FleetOptions, FleetReport};
+pub use pool::SessionPool;
+pub use session::{CommandOutput, SessionOptions, SshSession};
+pub use transfer::{Transfer, TransferOptions, TransferStats};
+
+#[derive(Debug, Error)]
+pub enum SshError {
+    #[error(transparent)]
+    Protocol(#[from] russh::Error),
+    /// The command was never sent, so it is safe to run it again elsewhere
+    #[error("could not open a channel: {0}")]
+    ChannelOpen(#[source] russh::Error),
+    #[error(transparent)]
+    HostKey(#[from] HostKeyError),
+    #[error("could not load key: {0}")]
+    Key(#[from] russh_keys::Error),
+    #[error("authentication as {user}@{host} failed (tried: {})", tried.join(", "))]
+    AuthFailed { user: String, host: String, tried: Vec<String> },
+    #[error("no authentication method configured for {0}")]
+    NoAuthMethod(String),
+    #[error("SFTP error: {0}")]
+    Sftp(#[from] russh_sftp::client::error::Error),
+    #[error("checksum of {path} does not match after transfer (expected {expected}, got {received})")]
+    ChecksumMismatch { path: String, expected: String, received: String },
+    #[error("`{command}` exited with {status:?}: {stderr}")]
+    RemoteCommand { command: String, status: Option<u32>, stderr: String },
+    #[error("via jump host {hop}: {source}")]
+    Jump {
+        hop: String,
+        #[source]
+        source: Box<SshError>,
+    },
+    #[error("connecting to {0} timed out")]
+    ConnectTimeout(String),
+    #[error("IO error occurred: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// A host from the inventory file. Its Debug output leaves out the password.
+#[derive(Clone, Deserialize)]
+pub struct Host {
+    pub name: String,
+    pub address: String,
+    pub port: u16,
+    pub username: String,
+    pub password: Option<String>,
+    /// Try the agent, `key_path` and its certificate before the password
+    #[serde(default)]
+    pub use_key: bool,
+    pub key_path: Option<PathBuf>,
+    /// OpenSSH user certificate, defaults to `<key_path>-cert.pub` when that exists
+    #[serde(default)]
+    pub certificate_path: Option<PathBuf>,
+    /// Env var with the passphrase for `key_path`, defaults to OMNIFORGE_SSH_KEY_PASSPHRASE
+    #[serde(default)]
+    pub passphrase_env: Option<String>,
+    /// Jump hosts to tunnel through, outermost first. Each has its own credentials, their own
+    /// `proxy_jump` is ignored.
+    #[serde(default)]
+    pub proxy_jump: Vec<Host>,
+}
+
+impl fmt::Debug for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Host")
+            .field("name", &self.name)
+            .field("address", &self.address)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| MASK))
+            .field("use_key", &self.use_key)
+            .field("key_path", &self.key_path)
+            .field("certificate_path", &self.certificate_path)
+            .field("passphrase_env", &self.passphrase_env)
+            .field("proxy_jump", &self.proxy_jump)
+            .finish()
+    }
+}
+
+impl Host {
+    /// Identifies a connection: the same user on the same address and port, reached through the
+    /// same jump hosts, shares a session
+    pub fn pool_key(&self) -> String {
+        let mut key = String::new();
+        for hop in &self.proxy_jump {
+            key.push_str(&format!("{}@{}:{},", hop.username, hop.address, hop.port));
+        }
+        key.push_str(&format!("{}@{}:{}", self.username, self.address, self.port));
+        key
+    }
+}
+
+/// Quotes a value for a POSIX shell command line on the remote side
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
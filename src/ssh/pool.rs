@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+use super::known_hosts::HostKeyVerifier;
+use super::session::{CommandOutput, SessionOptions, SshSession};
+use super::{Host, SshError};
+
+type Slot = Arc<tokio::sync::Mutex<Option<Arc<SshSession>>>>;
+
+/// Keeps one authenticated session per host (see `Host::pool_key`) and reconnects when it dies
+pub struct SessionPool {
+    verifier: Arc<HostKeyVerifier>,
+    options: SessionOptions,
+    slots: Mutex<HashMap<String, Slot>>,
+}
+
+impl SessionPool {
+    pub fn new(verifier: Arc<HostKeyVerifier>, options: SessionOptions) -> Self {
+        SessionPool {
+            verifier,
+            options,
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn slot(&self, key: &str) -> Slot {
+        self.slots
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Returns the open session for this host, connecting if there is none or the last one closed
+    pub async fn get(&self, host: &Host) -> Result<Arc<SshSession>, SshError> {
+        // The per-host lock makes concurrent callers wait for one connect instead of racing
+        let slot = self.slot(&host.pool_key());
+        let mut slot = slot.lock().await;
+        if let Some(session) = slot.as_ref() {
+            if !session.is_closed() {
+                return Ok(session.clone());
+            }
+            info!("Connection to {} was closed, reconnecting", host.name);
+        }
+
+        let session = Arc::new(SshSession::connect(host, self.verifier.clone(), &self.options).await?);
+        *slot = Some(session.clone());
+        Ok(session)
+    }
+
+    /// Runs a command on the host. If the pooled connection turns out to be dead before the command
+    /// was sent, it is sent once more on a fresh connection. A command that may have reached the
+    /// server is never run again, it might not be safe to repeat.
+    pub async fn exec(&self, host: &Host, command: &str) -> Result<CommandOutput, SshError> {
+        let session = self.get(host).await?;
+        match session.exec(command).await {
+            Err(SshError::ChannelOpen(e)) if session.is_closed() => {
+                warn!("Lost connection to {} ({}), retrying", host.name, e);
+                self.evict(host).await;
+                self.get(host).await?.exec(command).await
+            }
+            result => result,
+        }
+    }
+
+    /// Drops the pooled session for a host, the next `get` reconnects
+    pub async fn evict(&self, host: &Host) {
+        let slot = self.slot(&host.pool_key());
+        let session = slot.lock().await.take();
+        if let Some(session) = session {
+            let _ = session.close().await;
+        }
+    }
+
+    pub async fn close_all(&self) {
+        let slots: Vec<Slot> = self.slots.lock().unwrap().drain().map(|(_, slot)| slot).collect();
+        for slot in slots {
+            let session = slot.lock().await.take();
+            if let Some(session) = session {
+                let _ = session.close().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::test_server::{TestServer, DROP_CONNECTION};
+
+    #[tokio::test]
+    async fn reuses_one_connection_and_reconnects_after_close() {
+        let server = TestServer::start(|command| (command.as_bytes().to_vec(), Vec::new(), 0)).await;
+        let pool = SessionPool::new(server.verifier(), SessionOptions::default());
+        let host = server.host();
+
+        assert_eq!(pool.exec(&host, "one").await.unwrap().stdout_str(), "one");
+        assert_eq!(pool.exec(&host, "two").await.unwrap().stdout_str(), "two");
+        assert_eq!(server.connections(), 1);
+
+        pool.get(&host).await.unwrap().close().await.unwrap();
+        // The disconnect is processed by the session task, give it a moment
+        for _ in 0..50 {
+            if pool.get(&host).await.is_ok() && server.connections() == 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(pool.exec(&host, "three").await.unwrap().stdout_str(), "three");
+        assert_eq!(server.connections(), 2);
+
+        pool.close_all().await;
+    }
+
+    #[tokio::test]
+    async fn does_not_rerun_a_command_when_the_connection_drops_during_it() {
+        let runs = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = runs.clone();
+        let server = TestServer::start(move |command| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            (command.as_bytes().to_vec(), Vec::new(), 0)
+        }).await;
+        let pool = SessionPool::new(server.verifier(), SessionOptions::default());
+        let host = server.host();
+
+        let dropped = pool.exec(&host, DROP_CONNECTION).await;
+        assert!(!dropped.is_ok_and(|output| output.success()));
+        assert_eq!(runs.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // The dead connection is replaced on the next call
+        assert_eq!(pool.exec(&host, "after").await.unwrap().stdout_str(), "after");
+        assert_eq!(server.connections(), 2);
+
+        pool.close_all().await;
+    }
+}
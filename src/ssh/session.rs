@@ -0,0 +1,324 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use russh::client;
+use russh::{ChannelMsg, Disconnect};
+use russh_keys::PublicKeyBase64;
+use russh_sftp::client::SftpSession;
+use tracing::debug;
+
+use super::auth::{self, EnvSecrets, SecretSource};
+use super::known_hosts::HostKeyVerifier;
+use super::{Host, SshError};
+use crate::telemetry::METRICS;
+
+/// Connection tuning shared by every session
+///
+/// # Fields
+/// connect_timeout - Limit for TCP connect, key exchange and authentication together
+/// keepalive_interval - How often to probe an idle connection
+/// keepalive_max - Unanswered keepalives before the connection is considered dead
+/// inactivity_timeout - Close the connection after this long without any traffic
+/// secrets - Passphrases for encrypted private keys
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    pub connect_timeout: Duration,
+    pub keepalive_interval: Option<Duration>,
+    pub keepalive_max: usize,
+    pub inactivity_timeout: Option<Duration>,
+    pub secrets: Arc<dyn SecretSource>,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        SessionOptions {
+            connect_timeout: Duration::from_secs(10),
+            keepalive_interval: Some(Duration::from_secs(15)),
+            keepalive_max: 3,
+            inactivity_timeout: None,
+            secrets: Arc::new(EnvSecrets),
+        }
+    }
+}
+
+impl SessionOptions {
+    fn client_config(&self) -> client::Config {
+        client::Config {
+            inactivity_timeout: self.inactivity_timeout,
+            keepalive_interval: self.keepalive_interval,
+            keepalive_max: self.keepalive_max,
+            ..<_>::default()
+        }
+    }
+}
+
+/// Everything a remote command produced
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// `None` if the command was killed by a signal or the channel closed without a status
+    pub exit_status: Option<u32>,
+    pub exit_signal: Option<String>,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.exit_status == Some(0)
+    }
+
+    pub fn stdout_str(&self) -> String {
+        String::from_utf8_lossy(&self.stdout).into_owned()
+    }
+
+    pub fn stderr_str(&self) -> String {
+        String::from_utf8_lossy(&self.stderr).into_owned()
+    }
+}
+
+pub(crate) struct ClientHandler {
+    host: String,
+    port: u16,
+    verifier: Arc<HostKeyVerifier>,
+}
+
+#[async_trait]
+impl client::Handler for ClientHandler {
+    type Error = SshError;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &russh_keys::key::PublicKey,
+    ) -> Result<bool, Self::Error> {
+        // Always a plain key, russh doesn't offer the certificate host key algorithms
+        self.verifier
+            .verify(&self.host, self.port, server_public_key.name(), &server_public_key.public_key_bytes())?;
+        Ok(true)
+    }
+}
+
+/// An authenticated SSH connection to one host, possibly tunnelled through jump hosts
+pub struct SshSession {
+    handle: client::Handle<ClientHandler>,
+    /// Connections to the jump hosts in `proxy_jump` order, kept open for as long as the tunnel is used
+    jumps: Vec<client::Handle<ClientHandler>>,
+}
+
+impl SshSession {
+    pub async fn connect(
+        host: &Host,
+        verifier: Arc<HostKeyVerifier>,
+        options: &SessionOptions,
+    ) -> Result<Self, SshError> {
+        let mut handles = tokio::time::timeout(options.connect_timeout, Self::open(host, verifier, options))
+            .await
+            .map_err(|_| SshError::ConnectTimeout(host.pool_key()))??;
+
+        let handle = handles.pop().expect("the chain always ends with the target host");
+        Ok(SshSession { handle, jumps: handles })
+    }
+
+    /// Connects to every jump host in turn, then the target. Each hop after the first is reached
+    /// through a direct-tcpip channel on the previous one and verified and authenticated on its own.
+    async fn open(
+        host: &Host,
+        verifier: Arc<HostKeyVerifier>,
+        options: &SessionOptions,
+    ) -> Result<Vec<client::Handle<ClientHandler>>, SshError> {
+        let config = Arc::new(options.client_config());
+        let mut handles: Vec<client::Handle<ClientHandler>> = Vec::new();
+
+        for (index, hop) in host.proxy_jump.iter().chain(std::iter::once(host)).enumerate() {
+            let is_jump = index < host.proxy_jump.len();
+            if let Some(via) = index.checked_sub(1).map(|previous| &host.proxy_jump[previous]) {
+                debug!("Tunnelling to {}:{} via {}", hop.address, hop.port, via.name);
+            }
+            match Self::connect_hop(handles.last(), hop, config.clone(), verifier.clone(), options).await {
+                Ok(handle) => handles.push(handle),
+                Err(e) if is_jump => {
+                    return Err(SshError::Jump {
+                        hop: hop.name.clone(),
+                        source: Box::new(e),
+                    })
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(handles)
+    }
+
+    async fn connect_hop(
+        previous: Option<&client::Handle<ClientHandler>>,
+        hop: &Host,
+        config: Arc<client::Config>,
+        verifier: Arc<HostKeyVerifier>,
+        options: &SessionOptions,
+    ) -> Result<client::Handle<ClientHandler>, SshError> {
+        let handler = ClientHandler {
+            host: hop.address.clone(),
+            port: hop.port,
+            verifier,
+        };
+        let mut handle = match previous {
+            None => client::connect(config, (hop.address.as_str(), hop.port), handler).await?,
+            Some(previous) => {
+                let channel = previous
+                    .channel_open_direct_tcpip(hop.address.clone(), hop.port as u32, "127.0.0.1", 0)
+                    .await?;
+                client::connect_stream(config, channel.into_stream(), handler).await?
+            }
+        };
+        auth::authenticate(&mut handle, hop, options.secrets.as_ref()).await?;
+        Ok(handle)
+    }
+
+    /// Whether the connection or any tunnel under it has gone away, e.g. after missed keepalives.
+    /// The pool reconnects closed sessions.
+    pub fn is_closed(&self) -> bool {
+        self.handle.is_closed() || self.jumps.iter().any(|jump| jump.is_closed())
+    }
+
+    /// Runs a command and collects its output and exit status
+    pub async fn exec(&self, command: &str) -> Result<CommandOutput, SshError> {
+        let started = Instant::now();
+        let result = self.exec_inner(command).await;
+        let outcome = match &result {
+            Ok(output) if output.success() => "success",
+            Ok(_) => "nonzero",
+            Err(_) => "error",
+        };
+        METRICS.ssh_command_seconds.observe(&[outcome], started.elapsed().as_secs_f64());
+        result
+    }
+
+    async fn exec_inner(&self, command: &str) -> Result<CommandOutput, SshError> {
+        let mut channel = self.handle.channel_open_session().await.map_err(SshError::ChannelOpen)?;
+        // From here on the command may have reached the server, failures must not be retried blindly
+        channel.exec(true, command).await?;
+
+        let mut output = CommandOutput::default();
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::Data { ref data } => output.stdout.extend_from_slice(data),
+                // ext 1 is stderr, nothing else is defined
+                ChannelMsg::ExtendedData { ref data, ext: 1 } => {
+                    output.stderr.extend_from_slice(data)
+                }
+                ChannelMsg::ExitStatus { exit_status } => output.exit_status = Some(exit_status),
+                ChannelMsg::ExitSignal { signal_name, .. } => {
+                    output.exit_signal = Some(format!("{:?}", signal_name))
+                }
+                _ => {}
+            }
+        }
+        Ok(output)
+    }
+
+    /// Opens an SFTP subsystem channel on this connection
+    pub async fn sftp(&self) -> Result<SftpSession, SshError> {
+        let channel = self.handle.channel_open_session().await?;
+        channel.request_subsystem(true, "sftp").await?;
+        Ok(SftpSession::new(channel.into_stream()).await?)
+    }
+
+    /// Disconnects from the target, then from the jump hosts nearest first
+    pub async fn close(&self) -> Result<(), SshError> {
+        self.handle
+            .disconnect(Disconnect::ByApplication, "", "English")
+            .await?;
+        for jump in self.jumps.iter().rev() {
+            jump.disconnect(Disconnect::ByApplication, "", "English").await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::test_server::TestServer;
+
+    #[tokio::test]
+    async fn exec_captures_stdout_stderr_and_status() {
+        let server = TestServer::start(|command| match command {
+            "hello" => (b"hi\n".to_vec(), Vec::new(), 0),
+            _ => (Vec::new(), b"unknown command\n".to_vec(), 127),
+        })
+        .await;
+
+        let session = SshSession::connect(&server.host(), server.verifier(), &SessionOptions::default())
+            .await
+            .unwrap();
+
+        let output = session.exec("hello").await.unwrap();
+        assert!(output.success());
+        assert_eq!(output.stdout_str(), "hi\n");
+
+        let output = session.exec("nope").await.unwrap();
+        assert_eq!(output.exit_status, Some(127));
+        assert_eq!(output.stderr_str(), "unknown command\n");
+        assert!(output.stdout.is_empty());
+
+        session.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn wrong_password_is_reported() {
+        let server = TestServer::start(|_| (Vec::new(), Vec::new(), 0)).await;
+        let mut host = server.host();
+        host.password = Some("wrong".to_string());
+
+        let result = SshSession::connect(&host, server.verifier(), &SessionOptions::default()).await;
+        assert!(matches!(result, Err(SshError::AuthFailed { ref tried, .. }) if tried == &["password"]));
+    }
+
+    #[tokio::test]
+    async fn failed_auth_lists_every_method_tried() {
+        let server = TestServer::start(|_| (Vec::new(), Vec::new(), 0)).await;
+        let mut host = server.host();
+        host.use_key = true;
+        host.key_path = Some("/nonexistent/id_ed25519".into());
+        host.password = Some("wrong".to_string());
+
+        let Err(SshError::AuthFailed { tried, .. }) =
+            SshSession::connect(&host, server.verifier(), &SessionOptions::default()).await
+        else {
+            panic!("expected authentication to fail");
+        };
+        assert!(tried.iter().any(|method| method.starts_with("publickey /nonexistent/id_ed25519 (")));
+        assert_eq!(tried.last().map(String::as_str), Some("password"));
+
+        // Without use_key the key is ignored and the password alone is enough
+        host.use_key = false;
+        host.password = Some(crate::ssh::test_server::PASSWORD.to_string());
+        SshSession::connect(&host, server.verifier(), &SessionOptions::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn tunnels_through_jump_hosts() {
+        let outer = TestServer::start(|_| (Vec::new(), Vec::new(), 0)).await;
+        let inner = TestServer::start(|_| (Vec::new(), Vec::new(), 0)).await;
+        let target = TestServer::start(|_| (b"private\n".to_vec(), Vec::new(), 0)).await;
+
+        let mut host = target.host();
+        host.proxy_jump = vec![outer.host(), inner.host()];
+        let session = SshSession::connect(&host, target.verifier(), &SessionOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(session.exec("hostname").await.unwrap().stdout_str(), "private\n");
+        assert_eq!((outer.connections(), inner.connections(), target.connections()), (1, 1, 1));
+        session.close().await.unwrap();
+
+        // A bad password on a bastion is reported against that hop
+        let mut bastion = outer.host();
+        bastion.name = "bastion".to_string();
+        bastion.password = Some("wrong".to_string());
+        host.proxy_jump = vec![bastion];
+        let result = SshSession::connect(&host, target.verifier(), &SessionOptions::default()).await;
+        assert!(matches!(result, Err(SshError::Jump { ref hop, .. }) if hop == "bastion"));
+    }
+}
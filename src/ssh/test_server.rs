@@ -0,0 +1,315 @@
+//-------------------------------------------------------------------------
+// In-process russh server for tests. Accepts one password on 127.0.0.1,
+// answers exec requests from a closure instead of a real shell, serves
+// SFTP from the local filesystem and forwards direct-tcpip channels so
+// it can act as a jump host.
+//-------------------------------------------------------------------------
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use russh::server::{self, Auth, Msg, Session};
+use russh::{Channel, ChannelId, CryptoVec};
+use russh_keys::key::KeyPair;
+use russh_sftp::protocol::{Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use super::known_hosts::{HostKeyPolicy, HostKeyVerifier, KnownHosts};
+use super::Host;
+
+pub(crate) const USER: &str = "deploy";
+pub(crate) const PASSWORD: &str = "hunter2";
+
+/// After running this command the server drops the connection without answering, like a host
+/// going away mid-command
+pub(crate) const DROP_CONNECTION: &str = "omniforge-test-drop-connection";
+
+/// Maps a command line to (stdout, stderr, exit status)
+pub(crate) type ExecFn = Arc<dyn Fn(&str) -> (Vec<u8>, Vec<u8>, u32) + Send + Sync>;
+
+/// Runs commands with the local `sh`, for tests that need a real remote side
+pub(crate) fn shell(command: &str) -> (Vec<u8>, Vec<u8>, u32) {
+    let output = std::process::Command::new("sh").arg("-c").arg(command).output().unwrap();
+    (output.stdout, output.stderr, output.status.code().unwrap_or(255) as u32)
+}
+
+pub(crate) struct TestServer {
+    pub addr: SocketAddr,
+    connections: Arc<AtomicUsize>,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    pub async fn start<F>(exec: F) -> Self
+    where
+        F: Fn(&str) -> (Vec<u8>, Vec<u8>, u32) + Send + Sync + 'static,
+    {
+        let exec: ExecFn = Arc::new(exec);
+        let config = Arc::new(server::Config {
+            keys: vec![KeyPair::generate_ed25519()],
+            auth_rejection_time: Duration::from_millis(10),
+            auth_rejection_time_initial: Some(Duration::from_millis(0)),
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let accepted = connections.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let handler = TestHandler { exec: exec.clone(), channels: HashMap::new() };
+                let config = config.clone();
+                tokio::spawn(async move {
+                    if let Ok(session) = server::run_stream(config, stream, handler).await {
+                        let _ = session.await;
+                    }
+                });
+            }
+        });
+
+        TestServer { addr, connections, task }
+    }
+
+    /// Inventory entry pointing at this server
+    pub fn host(&self) -> Host {
+        Host {
+            name: "test".to_string(),
+            address: self.addr.ip().to_string(),
+            port: self.addr.port(),
+            username: USER.to_string(),
+            password: Some(PASSWORD.to_string()),
+            use_key: false,
+            key_path: None,
+            certificate_path: None,
+            passphrase_env: None,
+            proxy_jump: Vec::new(),
+        }
+    }
+
+    /// A verifier with an empty, in-memory known_hosts that trusts the first key it sees
+    pub fn verifier(&self) -> Arc<HostKeyVerifier> {
+        Arc::new(HostKeyVerifier::new(KnownHosts::default(), HostKeyPolicy::AcceptNew))
+    }
+
+    /// TCP connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct TestHandler {
+    exec: ExecFn,
+    /// Session channels kept until they ask for a subsystem
+    channels: HashMap<ChannelId, Channel<Msg>>,
+}
+
+#[async_trait]
+impl server::Handler for TestHandler {
+    type Error = russh::Error;
+
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+        if user == USER && password == PASSWORD {
+            Ok(Auth::Accept)
+        } else {
+            Ok(Auth::Reject { proceed_with_methods: None })
+        }
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.channels.insert(channel.id(), channel);
+        Ok(true)
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        match self.channels.remove(&channel) {
+            Some(stream) if name == "sftp" => {
+                session.channel_success(channel);
+                russh_sftp::server::run(stream.into_stream(), LocalSftp::default()).await;
+            }
+            _ => session.channel_failure(channel),
+        }
+        Ok(())
+    }
+
+    async fn channel_open_direct_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        host_to_connect: &str,
+        port_to_connect: u32,
+        _originator_address: &str,
+        _originator_port: u32,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let Ok(mut target) = TcpStream::connect((host_to_connect, port_to_connect as u16)).await else {
+            return Ok(false);
+        };
+        tokio::spawn(async move {
+            let mut stream = channel.into_stream();
+            let _ = tokio::io::copy_bidirectional(&mut stream, &mut target).await;
+        });
+        Ok(true)
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.channels.remove(&channel);
+        let command = String::from_utf8_lossy(data);
+        let (stdout, stderr, status) = (self.exec)(&command);
+        if command == DROP_CONNECTION {
+            return Err(russh::Error::Disconnect);
+        }
+
+        session.channel_success(channel);
+        if !stdout.is_empty() {
+            session.data(channel, CryptoVec::from(stdout));
+        }
+        if !stderr.is_empty() {
+            session.extended_data(channel, 1, CryptoVec::from(stderr));
+        }
+        session.exit_status_request(channel, status);
+        session.eof(channel);
+        session.close(channel);
+        Ok(())
+    }
+}
+
+/// SFTP straight on the local filesystem, with just the requests the transfer code sends
+#[derive(Default)]
+struct LocalSftp {
+    files: HashMap<String, fs::File>,
+    next_handle: u64,
+}
+
+impl LocalSftp {
+    fn file(&mut self, handle: &str) -> Result<&mut fs::File, StatusCode> {
+        self.files.get_mut(handle).ok_or(StatusCode::Failure)
+    }
+}
+
+fn ok(id: u32) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Ok,
+        error_message: "Ok".to_string(),
+        language_tag: "en-US".to_string(),
+    }
+}
+
+fn status_code(e: io::Error) -> StatusCode {
+    match e.kind() {
+        io::ErrorKind::NotFound => StatusCode::NoSuchFile,
+        io::ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+        _ => StatusCode::Failure,
+    }
+}
+
+impl russh_sftp::server::Handler for LocalSftp {
+    type Error = StatusCode;
+
+    fn unimplemented(&self) -> Self::Error {
+        StatusCode::OpUnsupported
+    }
+
+    async fn open(
+        &mut self,
+        id: u32,
+        filename: String,
+        pflags: OpenFlags,
+        _attrs: FileAttributes,
+    ) -> Result<Handle, Self::Error> {
+        let file = fs::OpenOptions::from(pflags).open(&filename).map_err(status_code)?;
+        self.next_handle += 1;
+        let handle = self.next_handle.to_string();
+        self.files.insert(handle.clone(), file);
+        Ok(Handle { id, handle })
+    }
+
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        self.files.remove(&handle);
+        Ok(ok(id))
+    }
+
+    async fn read(&mut self, id: u32, handle: String, offset: u64, len: u32) -> Result<Data, Self::Error> {
+        let file = self.file(&handle)?;
+        file.seek(SeekFrom::Start(offset)).map_err(status_code)?;
+        let mut data = Vec::new();
+        file.take(len as u64).read_to_end(&mut data).map_err(status_code)?;
+        if data.is_empty() {
+            return Err(StatusCode::Eof);
+        }
+        Ok(Data { id, data })
+    }
+
+    async fn write(&mut self, id: u32, handle: String, offset: u64, data: Vec<u8>) -> Result<Status, Self::Error> {
+        let file = self.file(&handle)?;
+        file.seek(SeekFrom::Start(offset)).map_err(status_code)?;
+        file.write_all(&data).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let metadata = fs::metadata(path).map_err(status_code)?;
+        Ok(Attrs { id, attrs: FileAttributes::from(&metadata) })
+    }
+
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let metadata = fs::symlink_metadata(path).map_err(status_code)?;
+        Ok(Attrs { id, attrs: FileAttributes::from(&metadata) })
+    }
+
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+        let metadata = self.file(&handle)?.metadata().map_err(status_code)?;
+        Ok(Attrs { id, attrs: FileAttributes::from(&metadata) })
+    }
+
+    /// Only the permission bits are applied, ownership would need root
+    async fn setstat(&mut self, id: u32, path: String, attrs: FileAttributes) -> Result<Status, Self::Error> {
+        if let Some(mode) = attrs.permissions {
+            let mut permissions = fs::metadata(&path).map_err(status_code)?.permissions();
+            #[cfg(unix)]
+            std::os::unix::fs::PermissionsExt::set_mode(&mut permissions, mode & 0o7777);
+            #[cfg(not(unix))]
+            permissions.set_readonly(mode & 0o222 == 0);
+            fs::set_permissions(&path, permissions).map_err(status_code)?;
+        }
+        Ok(ok(id))
+    }
+
+    async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
+        fs::remove_file(filename).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        let path = fs::canonicalize(&path).map(|path| path.to_string_lossy().into_owned()).unwrap_or(path);
+        Ok(Name { id, files: vec![File::dummy(path)] })
+    }
+}
@@ -0,0 +1,492 @@
+//-------------------------------------------------------------------------
+// File transfer over SFTP on an existing SSH connection. Uploads are
+// written to a temporary name next to the target, checked against the
+// local SHA-256, then renamed into place so a half-written file is never
+// visible. Directory sync skips files whose checksum already matches.
+// Symlinks are followed, except those leading back into a directory the
+// walk is already in, which would never end.
+//-------------------------------------------------------------------------
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use russh_sftp::client::SftpSession;
+use russh_sftp::protocol::FileAttributes;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, warn};
+
+use super::session::SshSession;
+use super::{shell_quote, SshError};
+
+/// # Fields
+/// skip_unchanged - Don't copy files whose checksum already matches on the other side
+/// preserve_owner - Copy uid/gid along with the permission bits, needs root on the remote host.
+///                  Ignored where local files have no uid/gid.
+#[derive(Debug, Clone, Copy)]
+pub struct TransferOptions {
+    pub skip_unchanged: bool,
+    pub preserve_owner: bool,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        TransferOptions {
+            skip_unchanged: true,
+            preserve_owner: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferStats {
+    pub copied: usize,
+    pub skipped: usize,
+    pub bytes: u64,
+}
+
+impl TransferStats {
+    fn add(&mut self, other: TransferStats) {
+        self.copied += other.copied;
+        self.skipped += other.skipped;
+        self.bytes += other.bytes;
+    }
+}
+
+/// An SFTP channel plus the session it runs on, which is also used for checksums and renames
+pub struct Transfer<'a> {
+    session: &'a SshSession,
+    sftp: SftpSession,
+    /// Prefix for progress lines, usually the host name
+    label: String,
+    options: TransferOptions,
+}
+
+impl<'a> Transfer<'a> {
+    pub async fn open(session: &'a SshSession, label: &str, options: TransferOptions) -> Result<Self, SshError> {
+        Ok(Transfer {
+            session,
+            sftp: session.sftp().await?,
+            label: label.to_string(),
+            options,
+        })
+    }
+
+    pub async fn upload_file(&self, local: &Path, remote: &str) -> Result<TransferStats, SshError> {
+        let checksum = sha256_file(local).await?;
+        if self.options.skip_unchanged && self.remote_checksum(remote).await?.as_deref() == Some(checksum.as_str()) {
+            debug!("[{}] {} is up to date", self.label, remote);
+            return Ok(TransferStats { skipped: 1, ..Default::default() });
+        }
+        let bytes = self.put(local, remote, &checksum).await?;
+        info!("[{}] uploaded {} -> {} ({} bytes)", self.label, local.display(), remote, bytes);
+        Ok(TransferStats { copied: 1, bytes, ..Default::default() })
+    }
+
+    pub async fn download_file(&self, remote: &str, local: &Path) -> Result<TransferStats, SshError> {
+        let expected = self.remote_checksum(remote).await?;
+        if self.options.skip_unchanged && expected.is_some() && sha256_file(local).await.ok() == expected {
+            debug!("[{}] {} is up to date", self.label, local.display());
+            return Ok(TransferStats { skipped: 1, ..Default::default() });
+        }
+
+        let tmp = temp_path(&local.to_string_lossy());
+        let mut source = self.sftp.open(remote).await?;
+        let mut target = tokio::fs::File::create(&tmp).await?;
+        let bytes = tokio::io::copy(&mut source, &mut target).await?;
+        target.flush().await?;
+        drop(target);
+
+        let received = sha256_file(Path::new(&tmp)).await?;
+        if let Some(expected) = expected.filter(|expected| *expected != received) {
+            let _ = fs::remove_file(&tmp);
+            return Err(SshError::ChecksumMismatch { path: remote.to_string(), expected, received });
+        }
+
+        let attrs = self.sftp.metadata(remote).await?;
+        if let Some(mode) = attrs.permissions {
+            set_local_mode(Path::new(&tmp), mode)?;
+        }
+        fs::rename(&tmp, local)?;
+        info!("[{}] downloaded {} -> {} ({} bytes)", self.label, remote, local.display(), bytes);
+        Ok(TransferStats { copied: 1, bytes, ..Default::default() })
+    }
+
+    /// Mirrors a local directory tree under `remote_dir`. Remote files that don't exist locally are left alone.
+    pub async fn sync_dir(&self, local_dir: &Path, remote_dir: &str) -> Result<TransferStats, SshError> {
+        let remote_dir = remote_root(remote_dir);
+        let tree = LocalTree::scan(local_dir)?;
+
+        let mut dirs: Vec<String> = vec![remote_dir.to_string()];
+        dirs.extend(tree.dirs.iter().map(|dir| remote_join(remote_dir, dir)));
+        let quoted: Vec<String> = dirs.iter().map(|dir| shell_quote(dir)).collect();
+        self.run(&format!("mkdir -p -- {}", quoted.join(" "))).await?;
+        for (dir, remote) in tree.dirs.iter().zip(dirs.iter().skip(1)) {
+            self.set_attributes(remote, &fs::metadata(local_dir.join(dir))?).await?;
+        }
+
+        let remote_sums = if self.options.skip_unchanged {
+            // One round trip for the whole tree instead of one per file
+            let output = self
+                .session
+                .exec(&format!("find {} -type f -exec sha256sum {{}} + 2>/dev/null", shell_quote(remote_dir)))
+                .await?;
+            parse_checksums(&output.stdout_str())
+        } else {
+            HashMap::new()
+        };
+
+        let total = tree.files.len();
+        let mut stats = TransferStats::default();
+        for (index, file) in tree.files.iter().enumerate() {
+            let local = local_dir.join(file);
+            let remote = remote_join(remote_dir, file);
+            let checksum = sha256_file(&local).await?;
+            if remote_sums.get(&remote) == Some(&checksum) {
+                stats.skipped += 1;
+                continue;
+            }
+            let bytes = self.put(&local, &remote, &checksum).await?;
+            info!("[{}] ({}/{}) {} ({} bytes)", self.label, index + 1, total, remote, bytes);
+            stats.add(TransferStats { copied: 1, bytes, ..Default::default() });
+        }
+
+        info!(
+            "[{}] synced {} -> {}: {} copied, {} unchanged, {} bytes",
+            self.label,
+            local_dir.display(),
+            remote_dir,
+            stats.copied,
+            stats.skipped,
+            stats.bytes
+        );
+        Ok(stats)
+    }
+
+    /// Uploads to a temporary file, verifies it, then moves it over `remote`
+    async fn put(&self, local: &Path, remote: &str, checksum: &str) -> Result<u64, SshError> {
+        let metadata = fs::metadata(local)?;
+        let tmp = temp_path(remote);
+
+        let mut source = tokio::fs::File::open(local).await?;
+        let mut target = self.sftp.create(&tmp).await?;
+        let bytes = tokio::io::copy(&mut source, &mut target).await?;
+        target.shutdown().await?;
+        drop(target);
+
+        self.set_attributes(&tmp, &metadata).await?;
+
+        let received = self.remote_checksum(&tmp).await?.unwrap_or_default();
+        if received != checksum {
+            let _ = self.sftp.remove_file(&tmp).await;
+            return Err(SshError::ChecksumMismatch {
+                path: remote.to_string(),
+                expected: checksum.to_string(),
+                received,
+            });
+        }
+
+        // SFTP v3 rename refuses to replace an existing file, mv is a plain rename(2) and atomic
+        self.run(&format!("mv -f -- {} {}", shell_quote(&tmp), shell_quote(remote))).await?;
+        Ok(bytes)
+    }
+
+    async fn set_attributes(&self, remote: &str, metadata: &fs::Metadata) -> Result<(), SshError> {
+        let mut attrs = FileAttributes::empty();
+        attrs.permissions = Some(local_mode(metadata));
+        if self.options.preserve_owner {
+            if let Some((uid, gid)) = local_owner(metadata) {
+                attrs.uid = Some(uid);
+                attrs.gid = Some(gid);
+            }
+        }
+        self.sftp.set_metadata(remote, attrs).await?;
+        Ok(())
+    }
+
+    /// SHA-256 of a remote file, `None` if it doesn't exist or can't be read
+    async fn remote_checksum(&self, remote: &str) -> Result<Option<String>, SshError> {
+        let output = self
+            .session
+            .exec(&format!("sha256sum -- {} 2>/dev/null", shell_quote(remote)))
+            .await?;
+        if !output.success() {
+            return Ok(None);
+        }
+        Ok(output.stdout_str().split_whitespace().next().map(str::to_string))
+    }
+
+    async fn run(&self, command: &str) -> Result<(), SshError> {
+        let output = self.session.exec(command).await?;
+        if !output.success() {
+            return Err(SshError::RemoteCommand {
+                command: command.to_string(),
+                status: output.exit_status,
+                stderr: output.stderr_str().trim().to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Relative paths (with `/` separators) under a local directory, parents before children
+#[derive(Debug, Default, PartialEq)]
+struct LocalTree {
+    dirs: Vec<String>,
+    files: Vec<String>,
+}
+
+impl LocalTree {
+    fn scan(root: &Path) -> io::Result<Self> {
+        let mut tree = LocalTree::default();
+        let mut parents = vec![dir_id(&fs::metadata(root)?)];
+        tree.walk(root, PathBuf::new(), &mut parents)?;
+        Ok(tree)
+    }
+
+    /// `parents` identifies the directories from `root` down to `relative`
+    fn walk(&mut self, root: &Path, relative: PathBuf, parents: &mut Vec<Option<DirId>>) -> io::Result<()> {
+        let mut entries: Vec<_> = fs::read_dir(root.join(&relative))?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = relative.join(entry.file_name());
+            let name = path.to_string_lossy().replace('\\', "/");
+            // Follows symlinks, the target's content is what gets deployed
+            let metadata = fs::metadata(entry.path())?;
+            if metadata.is_dir() {
+                let id = dir_id(&metadata);
+                let cycle = match id {
+                    Some(id) => parents.contains(&Some(id)),
+                    // Without a way to tell directories apart, links to them aren't followed at all
+                    None => entry.file_type()?.is_symlink(),
+                };
+                if cycle {
+                    warn!("Not following {}, it links back to a directory that is being copied", name);
+                    continue;
+                }
+                self.dirs.push(name);
+                parents.push(id);
+                let walked = self.walk(root, path, parents);
+                parents.pop();
+                walked?;
+            } else if metadata.is_file() {
+                self.files.push(name);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Device and inode, the same however the directory was reached
+type DirId = (u64, u64);
+
+#[cfg(unix)]
+fn dir_id(metadata: &fs::Metadata) -> Option<DirId> {
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn dir_id(_metadata: &fs::Metadata) -> Option<DirId> {
+    None
+}
+
+/// Parses `sha256sum` output into path -> checksum
+fn parse_checksums(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (sum, path) = line.split_once(' ')?;
+            // Binary mode marks the path with a leading `*`, text mode with a second space
+            let path = path.strip_prefix(' ').or_else(|| path.strip_prefix('*')).unwrap_or(path);
+            Some((path.to_string(), sum.to_string()))
+        })
+        .collect()
+}
+
+/// `remote_dir` without trailing slashes. `/` stays the root, an empty path is the login directory.
+fn remote_root(remote_dir: &str) -> &str {
+    match remote_dir.trim_end_matches('/') {
+        "" if remote_dir.starts_with('/') => "/",
+        "" => ".",
+        trimmed => trimmed,
+    }
+}
+
+/// Joins like `find` prints paths, so checksums found under the directory line up
+fn remote_join(dir: &str, relative: &str) -> String {
+    if dir.ends_with('/') {
+        format!("{}{}", dir, relative)
+    } else {
+        format!("{}/{}", dir, relative)
+    }
+}
+
+/// Permission bits to give the remote copy
+#[cfg(unix)]
+fn local_mode(metadata: &fs::Metadata) -> u32 {
+    metadata.permissions().mode() & 0o7777
+}
+
+/// Without unix modes only the read-only flag carries over
+#[cfg(not(unix))]
+fn local_mode(metadata: &fs::Metadata) -> u32 {
+    let mode = if metadata.is_dir() { 0o755 } else { 0o644 };
+    if metadata.permissions().readonly() {
+        mode & !0o222
+    } else {
+        mode
+    }
+}
+
+#[cfg(unix)]
+fn local_owner(metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    Some((metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn local_owner(_metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    None
+}
+
+#[cfg(unix)]
+fn set_local_mode(path: &Path, mode: u32) -> io::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn set_local_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+/// Hidden temporary name in the same directory, so the final rename never crosses filesystems
+fn temp_path(path: &str) -> String {
+    match path.rsplit_once('/') {
+        Some((dir, name)) => format!("{}/.{}.omniforge-tmp", dir, name),
+        None => format!(".{}.omniforge-tmp", path),
+    }
+}
+
+async fn sha256_file(path: &Path) -> io::Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut file = fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_text_and_binary_checksum_lines() {
+        let sums = parse_checksums("abc  /srv/app/compose.yml\ndef */srv/app/with space.bin\n");
+        assert_eq!(sums.get("/srv/app/compose.yml").map(String::as_str), Some("abc"));
+        assert_eq!(sums.get("/srv/app/with space.bin").map(String::as_str), Some("def"));
+    }
+
+    #[test]
+    fn temp_file_stays_in_target_directory() {
+        assert_eq!(temp_path("/srv/app/compose.yml"), "/srv/app/.compose.yml.omniforge-tmp");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn remote_paths_under_root_and_login_directory() {
+        assert_eq!(remote_join(remote_root("/srv/app//"), "compose.yml"), "/srv/app/compose.yml");
+        assert_eq!(remote_join(remote_root("/"), "compose.yml"), "/compose.yml");
+        assert_eq!(remote_join(remote_root(""), "compose.yml"), "./compose.yml");
+    }
+
+    #[tokio::test]
+    async fn scans_tree_parents_first_and_hashes_files() {
+        let root = std::env::temp_dir().join(format!("omniforge-transfer-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("config/nginx")).unwrap();
+        fs::write(root.join("compose.yml"), "services: {}\n").unwrap();
+        fs::write(root.join("config/nginx/site.conf"), "").unwrap();
+
+        let tree = LocalTree::scan(&root).unwrap();
+        assert_eq!(tree.dirs, ["config", "config/nginx"]);
+        assert_eq!(tree.files, ["compose.yml", "config/nginx/site.conf"]);
+        assert_eq!(
+            sha256_file(&root.join("config/nginx/site.conf")).await.unwrap(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinks_but_not_back_into_parents() {
+        let root = std::env::temp_dir().join(format!("omniforge-transfer-links-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("shared")).unwrap();
+        fs::create_dir_all(root.join("app")).unwrap();
+        fs::write(root.join("shared/env"), "").unwrap();
+        std::os::unix::fs::symlink("../shared", root.join("app/shared")).unwrap();
+        std::os::unix::fs::symlink("..", root.join("app/loop")).unwrap();
+
+        let tree = LocalTree::scan(&root).unwrap();
+        assert_eq!(tree.dirs, ["app", "app/shared", "shared"]);
+        assert_eq!(tree.files, ["app/shared/env", "shared/env"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn syncs_and_downloads_over_sftp() {
+        use crate::ssh::session::SessionOptions;
+        use crate::ssh::test_server::{shell, TestServer};
+
+        let root = std::env::temp_dir().join(format!("omniforge-sftp-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (local, remote) = (root.join("local"), root.join("remote"));
+        fs::create_dir_all(local.join("config")).unwrap();
+        fs::write(local.join("compose.yml"), "services: {}\n").unwrap();
+        fs::write(local.join("config/run.sh"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(local.join("config/run.sh"), fs::Permissions::from_mode(0o750)).unwrap();
+
+        let server = TestServer::start(shell).await;
+        let session = SshSession::connect(&server.host(), server.verifier(), &SessionOptions::default()).await.unwrap();
+        let transfer = Transfer::open(&session, "test", TransferOptions::default()).await.unwrap();
+        let remote_dir = remote.to_string_lossy().into_owned();
+
+        let stats = transfer.sync_dir(&local, &remote_dir).await.unwrap();
+        assert_eq!((stats.copied, stats.skipped), (2, 0));
+        assert_eq!(fs::read_to_string(remote.join("compose.yml")).unwrap(), "services: {}\n");
+        assert_eq!(fs::metadata(remote.join("config/run.sh")).unwrap().permissions().mode() & 0o777, 0o750);
+
+        fs::write(local.join("compose.yml"), "services: { web: {} }\n").unwrap();
+        let stats = transfer.sync_dir(&local, &format!("{}/", remote_dir)).await.unwrap();
+        assert_eq!((stats.copied, stats.skipped), (1, 1));
+        assert_eq!(fs::read_to_string(remote.join("compose.yml")).unwrap(), "services: { web: {} }\n");
+
+        let downloaded = root.join("run.sh");
+        transfer.download_file(&format!("{}/config/run.sh", remote_dir), &downloaded).await.unwrap();
+        assert_eq!(fs::read_to_string(&downloaded).unwrap(), "#!/bin/sh\n");
+        assert_eq!(fs::metadata(&downloaded).unwrap().permissions().mode() & 0o777, 0o750);
+
+        session.close().await.unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
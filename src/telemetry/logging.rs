@@ -0,0 +1,286 @@
+use std::io::{ self, Write };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::Instant;
+
+use anyhow::{ anyhow, Result };
+use rocket::fairing::{ Fairing, Info, Kind };
+use rocket::http::Header;
+use rocket::request::{ self, FromRequest };
+use rocket::{ Data, Request, Response };
+use tracing::{ info, info_span, Span };
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{ EnvFilter, Layer };
+
+use super::redact::redact;
+#[cfg(feature = "otlp")]
+use super::redact::redact_field;
+
+
+/// `EnvFilter` directives, e.g. `info,omni_forge::ssh=debug`. Defaults to `info`.
+pub const LOG_FILTER_VAR: &str = "OMNIFORGE_LOG";
+/// `text` (default) or `json`, one object per line
+pub const LOG_FORMAT_VAR: &str = "OMNIFORGE_LOG_FORMAT";
+/// OTLP/HTTP collector to export spans to, e.g. `http://otel-collector:4318/v1/traces`
+#[cfg(feature = "otlp")]
+pub const OTLP_ENDPOINT_VAR: &str = "OMNIFORGE_OTLP_ENDPOINT";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    fn from_env() -> Result<Self> {
+        match std::env::var(LOG_FORMAT_VAR).as_deref() {
+            Err(_) | Ok("") | Ok("text") => Ok(LogFormat::Text),
+            Ok("json") => Ok(LogFormat::Json),
+            Ok(other) => Err(anyhow!("unknown log format '{}' in {}, expected text or json", other, LOG_FORMAT_VAR)),
+        }
+    }
+}
+
+/// Keeps the exporters alive, dropping it flushes the spans still buffered
+pub struct LogGuard {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush traces: {}", e);
+            }
+        }
+    }
+}
+
+/// Installs the global subscriber. Logs go to stderr, every line passes through `redact`
+/// first, so credentials that end up in a message or field are masked. Spans exported over
+/// OTLP are redacted the same way, field by field.
+pub fn init() -> Result<LogGuard> {
+    let filter = EnvFilter::try_from_env(LOG_FILTER_VAR).unwrap_or_else(|_| EnvFilter::new("info"));
+    // Colour codes would split `key=value` pairs apart and hide them from redaction
+    let output = tracing_subscriber::fmt::layer().with_ansi(false).with_writer(|| Redacting(io::stderr()));
+    let output = match LogFormat::from_env()? {
+        LogFormat::Text => output.boxed(),
+        LogFormat::Json => output.json().with_current_span(true).with_span_list(true).boxed(),
+    };
+    let subscriber = tracing_subscriber::registry().with(output).with(filter);
+
+    #[cfg(feature = "otlp")]
+    {
+        let provider = otlp_provider()?;
+        let layer = provider.as_ref().map(|provider| {
+            use opentelemetry::trace::TracerProvider;
+            tracing_opentelemetry::layer().with_tracer(provider.tracer("omniforge"))
+        });
+        subscriber.with(layer).try_init()?;
+        Ok(LogGuard { provider })
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    {
+        subscriber.try_init()?;
+        Ok(LogGuard {})
+    }
+}
+
+/// A batching OTLP/HTTP span exporter if `OMNIFORGE_OTLP_ENDPOINT` is set
+#[cfg(feature = "otlp")]
+fn otlp_provider() -> Result<Option<opentelemetry_sdk::trace::SdkTracerProvider>> {
+    use opentelemetry_otlp::WithExportConfig;
+
+    let Ok(endpoint) = std::env::var(OTLP_ENDPOINT_VAR) else {
+        return Ok(None);
+    };
+    let exporter = opentelemetry_otlp::SpanExporter::builder().with_http().with_endpoint(endpoint).build()?;
+    let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_batch_exporter(RedactingExporter(exporter))
+        .with_resource(opentelemetry_sdk::Resource::builder().with_service_name("omniforge").build())
+        .build();
+    Ok(Some(provider))
+}
+
+/// Redacts span and event attributes, event messages and error descriptions before they
+/// leave the process, they never pass through `Redacting`
+#[cfg(feature = "otlp")]
+#[derive(Debug)]
+struct RedactingExporter<E>(E);
+
+#[cfg(feature = "otlp")]
+impl<E: opentelemetry_sdk::trace::SpanExporter> opentelemetry_sdk::trace::SpanExporter for RedactingExporter<E> {
+    fn export(
+        &self,
+        mut batch: Vec<opentelemetry_sdk::trace::SpanData>
+    ) -> impl std::future::Future<Output = opentelemetry_sdk::error::OTelSdkResult> + Send {
+        for span in &mut batch {
+            redact_attributes(&mut span.attributes);
+            for event in &mut span.events.events {
+                if let std::borrow::Cow::Owned(name) = redact(&event.name) {
+                    event.name = name.into();
+                }
+                redact_attributes(&mut event.attributes);
+            }
+            if let opentelemetry::trace::Status::Error { description } = &mut span.status {
+                if let std::borrow::Cow::Owned(redacted) = redact(description) {
+                    *description = redacted.into();
+                }
+            }
+        }
+        self.0.export(batch)
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: std::time::Duration) -> opentelemetry_sdk::error::OTelSdkResult {
+        self.0.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&mut self) -> opentelemetry_sdk::error::OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &opentelemetry_sdk::Resource) {
+        self.0.set_resource(resource);
+    }
+}
+
+#[cfg(feature = "otlp")]
+fn redact_attributes(attributes: &mut [opentelemetry::KeyValue]) {
+    for attribute in attributes {
+        let opentelemetry::Value::String(value) = &attribute.value else {
+            continue;
+        };
+        let redacted = redact_field(attribute.key.as_str(), value.as_str());
+        if redacted != value.as_str() {
+            attribute.value = opentelemetry::Value::String(redacted.into_owned().into());
+        }
+    }
+}
+
+/// Redacts whatever the formatter writes, which is one whole event per write
+struct Redacting<W>(W);
+
+impl<W: Write> Write for Redacting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.0.write_all(redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// The span of one HTTP request, kept in the request's local cache
+struct RequestTrace {
+    id: Option<u64>,
+    span: Span,
+    started: Instant,
+}
+
+impl RequestTrace {
+    fn of<'r>(request: &'r Request<'_>) -> &'r Self {
+        request.local_cache(|| RequestTrace { id: None, span: Span::none(), started: Instant::now() })
+    }
+}
+
+/// Opens a span for every request and logs how it ended. The request id is returned
+/// in `X-Request-Id`. Only the path is recorded, query strings may carry secrets.
+/// Rocket doesn't run handlers inside it, handlers take `RequestSpan` to log under it.
+pub struct RequestTracing;
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+#[rocket::async_trait]
+impl Fairing for RequestTracing {
+    fn info(&self) -> Info {
+        Info { name: "Request tracing", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+        let span = info_span!("request", request_id = id, method = %request.method(), path = %request.uri().path());
+        request.local_cache(|| RequestTrace { id: Some(id), span, started: Instant::now() });
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let request_span = RequestTrace::of(request);
+        if let Some(id) = request_span.id {
+            response.set_header(Header::new("X-Request-Id", id.to_string()));
+        }
+        request_span.span.in_scope(|| {
+            info!(
+                status = response.status().code,
+                elapsed_ms = request_span.started.elapsed().as_millis() as u64,
+                "request finished"
+            )
+        });
+    }
+}
+
+/// The span `RequestTracing` opened for the request, a disabled one without the fairing.
+/// Handlers parent their own span on it, `#[instrument(parent = &span.0, ...)]`, so what
+/// they log carries the `request_id`.
+pub struct RequestSpan(pub Span);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestSpan {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(RequestSpan(RequestTrace::of(request).span.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{ Arc, Mutex };
+
+    use rocket::local::asynchronous::Client;
+    use rocket::{ get, routes };
+    use tracing::instrument;
+
+    #[get("/traced")]
+    #[instrument(parent = &span.0, skip_all)]
+    fn traced(span: RequestSpan) -> &'static str {
+        info!("handled");
+        "ok"
+    }
+
+    /// Keeps what the subscriber writes
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[rocket::async_test]
+    async fn handler_logs_carry_the_request_id() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt().with_ansi(false).with_writer(move || writer.clone()).finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let rocket = rocket::build().attach(RequestTracing).mount("/", routes![traced]);
+        let client = Client::tracked(rocket).await.unwrap();
+        let response = client.get("/traced").dispatch().await;
+        let id = response.headers().get_one("X-Request-Id").unwrap().to_string();
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let handled = output.lines().find(|line| line.ends_with("handled")).unwrap();
+        assert!(handled.contains(&format!("request{{request_id={}", id)), "{}", output);
+    }
+}
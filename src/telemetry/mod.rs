@@ -0,0 +1,144 @@
+//-----------------------------------------------------------------------------
+// OmniForge's own metrics and logs. Metrics are served at `GET /metrics` in
+// the Prometheus text format from one process-wide registry. Label values
+// come from fixed lists or are capped at a number of distinct values, with
+// the rest folded into `other`, so no input can grow the series count. Logs
+// go through `tracing` with a span per request and per build, as text or
+// JSON, optionally exported over OTLP, and with credentials redacted.
+//-----------------------------------------------------------------------------
+
+pub mod logging;
+pub mod redact;
+pub mod registry;
+
+use lazy_static::lazy_static;
+
+use registry::{ Family, Label };
+
+/// Distinct app ids reported per family before the rest become `other`
+pub const MAX_APP_LABELS: usize = 100;
+
+const OUTCOMES: &[&str] = &["succeeded", "failed", "cancelled"];
+const STAGES: &[&str] = &["unpack", "generate", "install", "build", "push"];
+const QUEUES: &[&str] = &["jobs", "scheduler"];
+const RESULTS: &[&str] = &["success", "failure"];
+const SSH_RESULTS: &[&str] = &["success", "nonzero", "error"];
+const SCALE_ACTIONS: &[&str] = &[
+    "scale_out",
+    "scale_in",
+    "scale_up",
+    "scale_down",
+    "scale_left",
+    "scale_right",
+];
+
+static OUTCOME: [Label; 1] = [Label::fixed("outcome", OUTCOMES)];
+static STAGE: [Label; 1] = [Label::fixed("stage", STAGES)];
+static QUEUE: [Label; 1] = [Label::fixed("queue", QUEUES)];
+static RESULT: [Label; 1] = [Label::fixed("result", RESULTS)];
+static SSH_RESULT: [Label; 1] = [Label::fixed("result", SSH_RESULTS)];
+static DECISION: [Label; 2] = [Label::capped("app_id", MAX_APP_LABELS), Label::fixed("action", SCALE_ACTIONS)];
+
+/// Build stages run from seconds to most of an hour
+const STAGE_BUCKETS: &[f64] = &[0.5, 1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0];
+const SSH_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0];
+/// Files per scan, the upload limit is a few thousand
+const SCAN_BUCKETS: &[f64] = &[10.0, 50.0, 100.0, 500.0, 1000.0, 2500.0, 4500.0, 10000.0, 50000.0];
+
+/// Every metric OmniForge exports
+pub struct Metrics {
+    pub builds: Family,
+    pub build_stage_seconds: Family,
+    pub builds_active: Family,
+    pub queue_depth: Family,
+    pub registry_pushes: Family,
+    pub registry_push_bytes: Family,
+    pub scanner_files: Family,
+    pub scanner_scanned_files: Family,
+    pub ssh_command_seconds: Family,
+    pub autoscaler_decisions: Family,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Metrics {
+            builds: Family::counter(
+                "omniforge_builds_total",
+                "Finished builds by outcome",
+                &OUTCOME
+            ),
+            build_stage_seconds: Family::histogram(
+                "omniforge_build_stage_duration_seconds",
+                "Time spent in each build stage",
+                &STAGE,
+                STAGE_BUCKETS
+            ),
+            builds_active: Family::gauge(
+                "omniforge_builds_active",
+                "Builds running on this worker",
+                &[]
+            ),
+            queue_depth: Family::gauge(
+                "omniforge_queue_depth",
+                "Work waiting to start, queued jobs and builds waiting on the scheduler",
+                &QUEUE
+            ),
+            registry_pushes: Family::counter(
+                "omniforge_registry_pushes_total",
+                "Image pushes to the registry by result",
+                &RESULT
+            ),
+            registry_push_bytes: Family::counter(
+                "omniforge_registry_push_bytes_total",
+                "Size of the images pushed successfully",
+                &[]
+            ),
+            scanner_files: Family::histogram(
+                "omniforge_scanner_files",
+                "Files looked at per source scan",
+                &[],
+                SCAN_BUCKETS
+            ),
+            scanner_scanned_files: Family::counter(
+                "omniforge_scanner_files_total",
+                "Files looked at by the source scanner",
+                &[]
+            ),
+            ssh_command_seconds: Family::histogram(
+                "omniforge_ssh_command_duration_seconds",
+                "Remote command latency by result",
+                &SSH_RESULT,
+                SSH_BUCKETS
+            ),
+            autoscaler_decisions: Family::counter(
+                "omniforge_autoscaler_decisions_total",
+                "Scaling actions planned per app and action",
+                &DECISION
+            ),
+        }
+    }
+
+    /// Every family in the text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for family in [
+            &self.builds,
+            &self.build_stage_seconds,
+            &self.builds_active,
+            &self.queue_depth,
+            &self.registry_pushes,
+            &self.registry_push_bytes,
+            &self.scanner_files,
+            &self.scanner_scanned_files,
+            &self.ssh_command_seconds,
+            &self.autoscaler_decisions,
+        ] {
+            family.render(&mut out);
+        }
+        out
+    }
+}
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::new();
+}
@@ -0,0 +1,295 @@
+//-----------------------------------------------------------------------------
+// The metric families behind `GET /metrics`. A Family is one metric name with
+// a fixed set of labels, holding a counter, gauge or histogram per label
+// combination. Every label is bounded: its values come from a fixed list or
+// are capped at a number of distinct values, anything else is recorded under
+// `other`, so the series count can't grow with the input. Families render
+// themselves in the Prometheus text exposition format.
+//-----------------------------------------------------------------------------
+
+use std::collections::{ BTreeMap, BTreeSet };
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// What a label value is overwritten with once it would grow the family past its bounds
+pub const OVERFLOW: &str = "other";
+
+/// Which values a label may take
+///
+/// # Variants
+/// Fixed - One of a known list, anything else is reported as `other`
+/// Capped - The first n distinct values seen, later ones are reported as `other`
+#[derive(Debug, Clone, Copy)]
+pub enum Values {
+    Fixed(&'static [&'static str]),
+    Capped(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Label {
+    pub name: &'static str,
+    pub values: Values,
+}
+
+impl Label {
+    pub const fn fixed(name: &'static str, values: &'static [&'static str]) -> Self {
+        Label { name, values: Values::Fixed(values) }
+    }
+
+    pub const fn capped(name: &'static str, max: usize) -> Self {
+        Label { name, values: Values::Capped(max) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    /// Upper bounds of the buckets, ascending. `+Inf` is implied.
+    Histogram(&'static [f64]),
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram(_) => "histogram",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Scalar(f64),
+    Histogram { buckets: Vec<u64>, sum: f64, count: u64 },
+}
+
+#[derive(Debug, Default)]
+struct FamilyState {
+    series: BTreeMap<Vec<String>, Value>,
+    /// Values admitted so far for each capped label
+    seen: Vec<BTreeSet<String>>,
+}
+
+/// A metric and all of its labelled series
+pub struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    labels: &'static [Label],
+    state: Mutex<FamilyState>,
+}
+
+impl Family {
+    pub fn counter(name: &'static str, help: &'static str, labels: &'static [Label]) -> Self {
+        Self::new(name, help, Kind::Counter, labels)
+    }
+
+    pub fn gauge(name: &'static str, help: &'static str, labels: &'static [Label]) -> Self {
+        Self::new(name, help, Kind::Gauge, labels)
+    }
+
+    pub fn histogram(name: &'static str, help: &'static str, labels: &'static [Label], buckets: &'static [f64]) -> Self {
+        Self::new(name, help, Kind::Histogram(buckets), labels)
+    }
+
+    fn new(name: &'static str, help: &'static str, kind: Kind, labels: &'static [Label]) -> Self {
+        let family = Family {
+            name,
+            help,
+            kind,
+            labels,
+            state: Mutex::new(FamilyState { series: BTreeMap::new(), seen: vec![BTreeSet::new(); labels.len()] }),
+        };
+        // Without labels the one series exists from the start, so it reads 0 rather than missing
+        if labels.is_empty() {
+            family.update(&[], |_| {});
+        }
+        family
+    }
+
+    pub fn inc(&self, labels: &[&str]) {
+        self.add(labels, 1.0);
+    }
+
+    /// Counters only go up, negative amounts are ignored for them
+    pub fn add(&self, labels: &[&str], amount: f64) {
+        if self.kind == Kind::Counter && amount < 0.0 {
+            return;
+        }
+        self.update(labels, |value| {
+            if let Value::Scalar(current) = value {
+                *current += amount;
+            }
+        });
+    }
+
+    pub fn set(&self, labels: &[&str], to: f64) {
+        debug_assert_eq!(self.kind, Kind::Gauge, "{} is not a gauge", self.name);
+        self.update(labels, |value| {
+            if let Value::Scalar(current) = value {
+                *current = to;
+            }
+        });
+    }
+
+    pub fn observe(&self, labels: &[&str], observed: f64) {
+        let Kind::Histogram(bounds) = self.kind else {
+            debug_assert!(false, "{} is not a histogram", self.name);
+            return;
+        };
+        self.update(labels, |value| {
+            if let Value::Histogram { buckets, sum, count } = value {
+                if let Some(index) = bounds.iter().position(|bound| observed <= *bound) {
+                    buckets[index] += 1;
+                }
+                *sum += observed;
+                *count += 1;
+            }
+        });
+    }
+
+    /// Runs `f` and observes how long it took in seconds
+    pub fn time<T>(&self, labels: &[&str], f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        self.observe(labels, started.elapsed().as_secs_f64());
+        result
+    }
+
+    fn update(&self, labels: &[&str], apply: impl FnOnce(&mut Value)) {
+        debug_assert_eq!(labels.len(), self.labels.len(), "wrong number of labels for {}", self.name);
+        let mut state = self.state.lock().unwrap();
+        let key = self.bound(&mut state, labels);
+        let kind = self.kind;
+        let value = state.series.entry(key).or_insert_with(|| match kind {
+            Kind::Histogram(bounds) => Value::Histogram { buckets: vec![0; bounds.len()], sum: 0.0, count: 0 },
+            Kind::Counter | Kind::Gauge => Value::Scalar(0.0),
+        });
+        apply(value);
+    }
+
+    /// The series key for the given values, with anything out of bounds replaced by `other`
+    fn bound(&self, state: &mut FamilyState, values: &[&str]) -> Vec<String> {
+        self.labels
+            .iter()
+            .enumerate()
+            .map(|(index, label)| {
+                let value = values.get(index).copied().unwrap_or_default();
+                let admitted = match label.values {
+                    Values::Fixed(allowed) => allowed.contains(&value),
+                    Values::Capped(max) => {
+                        let seen = &mut state.seen[index];
+                        seen.contains(value) || (seen.len() < max && seen.insert(value.to_string()))
+                    }
+                };
+                if admitted { value.to_string() } else { OVERFLOW.to_string() }
+            })
+            .collect()
+    }
+
+    /// Appends the family in the Prometheus text exposition format
+    pub fn render(&self, out: &mut String) {
+        let state = self.state.lock().unwrap();
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} {}", self.name, self.kind.name());
+        for (key, value) in &state.series {
+            let pairs: Vec<(&str, &str)> = self.labels
+                .iter()
+                .zip(key)
+                .map(|(label, value)| (label.name, value.as_str()))
+                .collect();
+            match value {
+                Value::Scalar(value) => {
+                    let _ = writeln!(out, "{}{} {}", self.name, label_set(&pairs, None), value);
+                }
+                Value::Histogram { buckets, sum, count } => {
+                    let Kind::Histogram(bounds) = self.kind else {
+                        continue;
+                    };
+                    let mut cumulative = 0;
+                    for (bound, in_bucket) in bounds.iter().zip(buckets) {
+                        cumulative += in_bucket;
+                        let le = bound.to_string();
+                        let _ = writeln!(out, "{}_bucket{} {}", self.name, label_set(&pairs, Some(&le)), cumulative);
+                    }
+                    let _ = writeln!(out, "{}_bucket{} {}", self.name, label_set(&pairs, Some("+Inf")), count);
+                    let _ = writeln!(out, "{}_sum{} {}", self.name, label_set(&pairs, None), sum);
+                    let _ = writeln!(out, "{}_count{} {}", self.name, label_set(&pairs, None), count);
+                }
+            }
+        }
+    }
+}
+
+/// `{name="value",...}`, empty without labels
+fn label_set(pairs: &[(&str, &str)], le: Option<&str>) -> String {
+    let rendered: Vec<String> = pairs
+        .iter()
+        .copied()
+        .chain(le.map(|le| ("le", le)))
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if rendered.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", rendered.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', "\\\"").replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_stay_within_their_bounds() {
+        static LABELS: [Label; 2] = [Label::capped("app_id", 2), Label::fixed("action", &["scale_out", "scale_in"])];
+        let family = Family::counter("decisions_total", "Decisions", &LABELS);
+        family.inc(&["a", "scale_out"]);
+        family.inc(&["b", "scale_in"]);
+        family.inc(&["c", "scale_out"]);
+        family.inc(&["d", "scale_out"]);
+        family.add(&["a", "reboot"], 2.0);
+        family.add(&["a", "scale_out"], -5.0);
+
+        let mut out = String::new();
+        family.render(&mut out);
+        assert_eq!(
+            out,
+            "# HELP decisions_total Decisions\n\
+             # TYPE decisions_total counter\n\
+             decisions_total{app_id=\"a\",action=\"other\"} 2\n\
+             decisions_total{app_id=\"a\",action=\"scale_out\"} 1\n\
+             decisions_total{app_id=\"b\",action=\"scale_in\"} 1\n\
+             decisions_total{app_id=\"other\",action=\"scale_out\"} 2\n"
+        );
+    }
+
+    #[test]
+    fn histograms_render_cumulative_buckets() {
+        static LABELS: [Label; 1] = [Label::fixed("stage", &["push"])];
+        let family = Family::histogram("stage_seconds", "Stage time", &LABELS, &[0.5, 1.0]);
+        for seconds in [0.25, 0.75, 0.875, 3.0] {
+            family.observe(&["push"], seconds);
+        }
+
+        let mut out = String::new();
+        family.render(&mut out);
+        assert!(out.contains("stage_seconds_bucket{stage=\"push\",le=\"0.5\"} 1\n"));
+        assert!(out.contains("stage_seconds_bucket{stage=\"push\",le=\"1\"} 3\n"));
+        assert!(out.contains("stage_seconds_bucket{stage=\"push\",le=\"+Inf\"} 4\n"));
+        assert!(out.contains("stage_seconds_sum{stage=\"push\"} 4.875\n"));
+        assert!(out.contains("stage_seconds_count{stage=\"push\"} 4\n"));
+
+        let unlabelled = Family::gauge("depth", "Depth", &[]);
+        let mut out = String::new();
+        unlabelled.render(&mut out);
+        assert!(out.ends_with("depth 0\n"));
+    }
+}